@@ -1,15 +1,19 @@
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{Parser, Subcommand};
 use anyhow::Result;
-use binrw::{BinRead, BinWrite, binrw};
-use num_enum::TryFromPrimitive;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom};
+use std::fs::File;
+use std::io::Write;
+
+use cpm86_tools::cmd::{self, prl, CmdSpec, GType, PARAGRAPH_SIZE, RECORD_SIZE};
+use cpm86_tools::output;
 
 #[derive(Parser)]
 #[clap(version, about = "Create a CP/M 86 .CMD-file from a .BIN-file")]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+    /// Print lengths and sizes in hex
+    #[clap(long, global = true)]
+    hex: bool,
 }
 
 #[derive(Subcommand)]
@@ -27,6 +31,14 @@ enum Commands {
         // Optional load address
         #[clap(long)]
         load_address: Option<u32>,
+        /// Stack size in bytes, rounded up to paragraphs. Adds a stack group
+        /// so CP/M-86 sets up a stack of its own for the program.
+        #[clap(long)]
+        stack_size: Option<u32>,
+        /// Pad each group in the file to whole 128-byte records, for loaders
+        /// that expect every group to start on a record boundary.
+        #[clap(long)]
+        record_align: bool,
     },
     /// Create a new .CMD-file
     /// Ex: bin2cmd memory-model-small myprog.cmd myprog.bin mydata.bin
@@ -66,158 +78,159 @@ enum Commands {
         #[clap(long)]
         data_load_address: Option<u32>,
     },
-
-
-}
-
-//
-// CMD header definition 
-// http://www.s100computers.com/Software%20Folder/CPM86/CPM-86_System_Guide_Jun83.pdf
-//
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
-#[repr(u8)]
-pub enum GType {
-    Null = 0x0,
-    Code = 0x1,
-    Data = 0x2,
-    Extra = 0x3,
-    Stack = 0x4,
-    AuxiliaryGroup1 = 0x5,
-    AuxiliaryGroup2 = 0x6,
-    AuxiliaryGroup3 = 0x7,
-    AuxiliaryGroup4 = 0x8,
-    SharedCodeGroup = 0x9,
-    EsacepCode = 0xf,
-}
-
-impl GType {
-    #[inline]
-    pub fn from_low_nibble(n: u8) -> Self {
-        GType::try_from(n & 0x0F).unwrap()
-    }
-
-    #[inline]
-    pub fn to_low_nibble(self) -> u8 {
-        self as u8
-    }
+    /// Create a page relocatable .PRL or .RSP-file from the program assembled at 0000h and at 0100h
+    /// Ex: bin2cmd prl myprog.prl myprog0.bin myprog1.bin
+    Prl {
+        /// Path to the new .PRL or .RSP-file.
+        #[clap(name = "OUTPUT_FILE")]
+        prl_path: String,
+        /// Path to the code assembled at 0000h
+        #[clap(name = "CODE_FILE_0000")]
+        low_path: String,
+        /// Path to the code assembled at 0100h
+        #[clap(name = "CODE_FILE_0100")]
+        high_path: String,
+        /// Bytes of memory needed after the code
+        #[clap(long, default_value_t = 0)]
+        extra: u16,
+    },
+    /// Show the group descriptors of a .CMD-file, or the header of a .PRL or .RSP-file.
+    /// For a .SYS-file also the CCP, BDOS and BIOS and the BIOS jump vector.
+    /// Ex: bin2cmd inspect myprog.cmd
+    Inspect {
+        /// Path to the .CMD, .PRL or .RSP-file.
+        #[clap(name = "CMD_FILE")]
+        cmd_path: String,
+    },
+    /// Write a copy of a CPM.SYS with another BIOS, assembled to run at 2500h
+    /// in the system's code segment. CCP, BDOS and the load segment are kept.
+    /// Ex: bin2cmd replace-bios CPM.SYS mybios.bin NEWCPM.SYS
+    ReplaceBios {
+        /// Path to the CPM.SYS to start from
+        #[clap(name = "SYSTEM_FILE")]
+        system_path: String,
+        /// Path to the assembled BIOS
+        #[clap(name = "BIOS_FILE")]
+        bios_path: String,
+        /// Path to the new system file
+        #[clap(name = "OUTPUT_FILE")]
+        output_path: String,
+    },
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, BinRead, BinWrite)]
-pub struct GForm(pub u8);
-
-impl GForm {
-    #[inline] pub fn raw(self) -> u8 { self.0 }
-
-    #[inline] pub fn g_type(self) -> GType {
-        GType::from_low_nibble(self.0 & 0x0F)
-    }
-
-    #[inline] pub fn hi_nibble(self) -> u8 {
-        self.0 >> 4
-    }
-
-    #[inline] pub fn with_type(self, t: GType) -> Self {
-        GForm((self.0 & 0xF0) | t.to_low_nibble())
-    }
+fn create_image(cmd_path: &str, code_path: &str, load_address: &Option<u32>, data_path: &Option<String>, data_load_address: &Option<u32>, stack_size: &Option<u32>, record_align: bool) -> Result<()> {
+    let spec = CmdSpec {
+        code: std::fs::read(code_path)?,
+        load_address: *load_address,
+        data: match data_path {
+            Some(data_path) => Some(std::fs::read(data_path)?),
+            None => None,
+        },
+        data_load_address: *data_load_address,
+        stack_size: *stack_size,
+        record_align,
+    };
 
-    #[inline] pub fn with_hi(self, hi: u8) -> Self {
-        GForm(((hi & 0x0F) << 4) | (self.0 & 0x0F))
-    }
+    // Only create the output once everything fits
+    let cmd = cmd::build(spec)?;
+    File::create(cmd_path)?.write_all(&cmd)?;
 
-    #[inline] pub fn from_parts(t: GType, hi: u8) -> Self {
-        GForm(((hi & 0x0F) << 4) | t.to_low_nibble())
-    }
+    Ok(())
 }
 
-#[binrw]
-#[brw(little)]
-#[derive(Debug, Copy, Clone)]
-pub struct GroupDescriptor {
-    pub g_form: GForm,   
-    pub g_length: u16,   // paragraphs (16-byte units)
-    pub a_base: u16,     // base paragraph (0 = relocatable)
-    pub g_min: u16,      // min paragraphs
-    pub g_max: u16,      // max paragraphs
+fn create_prl(prl_path: &str, low_path: &str, high_path: &str, extra: u16) -> Result<()> {
+    let prl = prl::Prl::from_two_origins(&std::fs::read(low_path)?, &std::fs::read(high_path)?, extra)?;
+    File::create(prl_path)?.write_all(&prl.to_bytes())?;
+    println!("{} bytes of code, {} relocated bytes", prl.code.len(), prl.relocations().len());
+    Ok(())
 }
 
-#[binrw]
-#[brw(little)]
-#[derive(Debug, Clone)]
-pub struct CmdHeader {
-    pub groups: [GroupDescriptor; 8], // 72 bytes
-    pub padding: [u8; 56],           // padding to 128
+fn inspect_prl(prl_path: &str) -> Result<()> {
+    let prl = prl::Prl::read(&std::fs::read(prl_path)?)?;
+    println!("Code     {:>6} bytes", output::number(prl.code.len()));
+    println!("Extra    {:>6} bytes", output::number(prl.extra));
+    println!("Relocated {:>5} bytes", output::number(prl.relocations().len()));
+    Ok(())
 }
 
+fn inspect(cmd_path: &str) -> Result<()> {
+    let lower = cmd_path.to_lowercase();
+    if lower.ends_with(".prl") || lower.ends_with(".rsp") {
+        return inspect_prl(cmd_path);
+    }
 
-fn create_image(cmd_path: &str, code_path: &str, load_address: &Option<u32>, data_path: &Option<String>, data_load_address: &Option<u32>) -> Result<()> {
-
-    // The header, 8 GroupDescriptors and padding
-    let mut header = CmdHeader {
-    groups: [GroupDescriptor {
-            g_form: GForm(0),
-            g_length: 0,
-            a_base: 0,
-            g_min: 0,
-            g_max: 0,
-        }; 8],
-        padding: [0u8; 56],
+    let file = std::fs::read(cmd_path)?;
+    let file_size = file.len() as u64;
+    let header = match cmd::read_header(&file) {
+        Ok(header) => header,
+        Err(e) => anyhow::bail!("{}: {}", cmd_path, e),
     };
 
-    let mut out = File::create(cmd_path)?;
-
-    let mut code_file= File::open(code_path)?;
-    let mut code_data = Vec::new();
-
-    if data_path.is_none() {
-        // prepend 0x100 empty bytes
-        while code_data.len() != 0x100 {
-            code_data.push(0);
+    println!("Type     Length  Base     Min     Max  Offset");
+    let mut offset = RECORD_SIZE as u64;
+    let mut misaligned = 0;
+    for group in header.groups.iter().filter(|g| g.g_form.raw() & 0x0f != 0) {
+        let name = match GType::try_from(group.g_form.raw() & 0x0f) {
+            Ok(g_type) => format!("{:?}", g_type),
+            Err(_) => format!("{:#x}", group.g_form.raw() & 0x0f),
+        };
+        let aligned = group.g_length == 0 || offset.is_multiple_of(RECORD_SIZE as u64);
+        println!("{:<8} {:>6}  {:04x} {:>7} {:>7}  {:#07x}{}", name, output::number(group.g_length), group.a_base,
+            output::number(group.g_min), output::number(group.g_max), offset,
+            if aligned { "" } else { "  not on a record boundary" });
+        if !aligned {
+            misaligned += 1;
         }
+        offset += group.g_length as u64 * PARAGRAPH_SIZE as u64;
     }
 
-    code_file.read_to_end(&mut code_data)?;
+    if offset > file_size {
+        println!("Warning: groups need {} bytes but the file is only {} bytes", output::number(offset), output::number(file_size));
+    }
+    if misaligned > 0 {
+        println!("Warning: {} group(s) do not start on a 128-byte record boundary", misaligned);
+    }
 
-    let code_len = code_data.len();
-    let code_paragraphs = ((code_len + 15) / 16) as u16;
-    while code_data.len() < code_paragraphs as usize*16 {
-        code_data.push(0);
+    if lower.ends_with(".sys") {
+        inspect_system(&file)?;
     }
-    let code_a_base = (load_address.unwrap_or(0) / 16) as u16;
-
-    header.groups[0] = GroupDescriptor {
-        g_form: GForm(GType::Code as u8),
-        g_length: code_paragraphs,
-        a_base: code_a_base,
-        g_min: code_paragraphs,
-        g_max: 0,
-    };
 
-    let mut data_data = Vec::new();
-    if let Some(data_path) = data_path {
-        let mut data_file = File::open(data_path)?;
-        data_file.read_to_end(&mut data_data)?;
+    Ok(())
+}
 
-        let data_len = data_data.len();
-        let data_paragraphs = ((data_len + 15) / 16) as u16;
-        while data_data.len() < data_paragraphs as usize*16 {
-            data_data.push(0);
+fn inspect_system(file: &[u8]) -> Result<()> {
+    let system = match cmd::read_system(file) {
+        Ok(system) => system,
+        Err(e) => {
+            println!("Not a CP/M-86 system image: {}", e);
+            return Ok(());
         }
-        let data_a_base = (data_load_address.unwrap_or(0) / 16) as u16;
-
-        header.groups[1] = GroupDescriptor {
-            g_form: GForm(GType::Data as u8),
-            g_length: data_paragraphs,
-            a_base: data_a_base,
-            g_min: data_paragraphs,
-            g_max: 0,
-        };
+    };
+    println!();
+    println!("System loaded at {:04x}:0000", system.load_segment);
+    println!("Module   Offset    Size");
+    for module in system.modules() {
+        println!("{:<8} {:04x}h  {:>6}", module.name, module.offset, output::number(module.size));
     }
+    println!();
+    println!("BIOS entry  Jump to");
+    for (i, (name, target)) in system.bios_entries().into_iter().enumerate() {
+        let target = target.map_or("not a jump".to_string(), |t| format!("{:04x}h", t));
+        println!("{:04x}h {:<8} {}", cmd::BIOS_OFFSET + i * 3, name, target);
+    }
+    Ok(())
+}
 
-    header.write(&mut out)?;
-    out.write(&code_data)?;
-    out.write(&data_data)?;
-
+fn replace_bios(system_path: &str, bios_path: &str, output_path: &str) -> Result<()> {
+    let system = match cmd::read_system(&std::fs::read(system_path)?) {
+        Ok(system) => system,
+        Err(e) => anyhow::bail!("{}: {}", system_path, e),
+    };
+    let bios = std::fs::read(bios_path)?;
+    let old_size = system.code.len() - cmd::BIOS_OFFSET;
+    let system = system.with_bios(&bios)?;
+    File::create(output_path)?.write_all(&system.to_bytes()?)?;
+    println!("BIOS of {} bytes replaced with {} bytes, code group is {} bytes", output::number(old_size), output::number(bios.len()), output::number(system.code.len()));
     Ok(())
 }
 
@@ -225,24 +238,34 @@ fn create_image(cmd_path: &str, code_path: &str, load_address: &Option<u32>, dat
 fn main() -> Result<()> {
 
     let cli = Cli::parse();
+    output::set_hex(cli.hex);
 
     match &cli.command {
-        Commands::MemoryModel8080 { cmd_path, code_path , load_address} => {
+        Commands::MemoryModel8080 { cmd_path, code_path , load_address, stack_size, record_align} => {
             println!("MemoryModel8080 {} {} {}",cmd_path,code_path,load_address.unwrap_or(0));
             println!("Note: code must start at org $100");
-            create_image(cmd_path, code_path, load_address, &None, &None)?;
+            create_image(cmd_path, code_path, load_address, &None, &None, stack_size, *record_align)?;
         },
         Commands::MemoryModelSmall { cmd_path, code_path , load_address, data_path, data_load_address} => {
             println!("MemoryModelSmall {} {} {} {} {}",cmd_path,code_path,load_address.unwrap_or(0),data_path,data_load_address.unwrap_or(0));
             println!("Note: data must start at org $100");
             println!("TODO: Not implemented");
-//            create_image(cmd_path, code_path, load_address, data_path, data_load_address)?;
+//            create_image(cmd_path, code_path, load_address, data_path, data_load_address, &None, false)?;
         },
         Commands::MemoryModelCompact { cmd_path, code_path , load_address, data_path, data_load_address} => {
             println!("MemoryModelCompact {} {} {} {} {}",cmd_path,code_path,load_address.unwrap_or(0),data_path,data_load_address.unwrap_or(0));
             println!("Note: data must start at org $100");
             println!("TODO: Not implemented");
-//            create_image(cmd_path, code_path, load_address, data_path, data_load_address)?;
+//            create_image(cmd_path, code_path, load_address, data_path, data_load_address, &None, false)?;
+        },
+        Commands::Prl { prl_path, low_path, high_path, extra } => {
+            create_prl(prl_path, low_path, high_path, *extra)?;
+        },
+        Commands::Inspect { cmd_path } => {
+            inspect(cmd_path)?;
+        }
+        Commands::ReplaceBios { system_path, bios_path, output_path } => {
+            replace_bios(system_path, bios_path, output_path)?;
         }
     }
 
@@ -0,0 +1,350 @@
+use clap::Parser;
+use anyhow::Result;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use cpm86_tools::cpmimg;
+use cpm86_tools::encryption::KeyCache;
+use cpm86_tools::imagefile::ImageOptions;
+use cpm86_tools::output;
+
+/// The user areas of the image are shown as the directories /0 to /15.
+/// There is no authentication, any user name and password is accepted,
+/// so only listen on addresses you trust.
+/// Ex: cpmserve mycompis.img --listen 127.0.0.1:2121
+#[derive(Parser)]
+#[clap(version, about = "Serve a COMPIS CP/M 86 floppy image over FTP.")]
+struct Cli {
+    /// Path to the floppy image
+    #[clap(name = "IMAGE_FILE")]
+    image_path: String,
+    /// Address to listen on
+    #[clap(long, default_value = "127.0.0.1:2121")]
+    listen: String,
+    /// Refuse uploads, deletes and renames
+    #[clap(long)]
+    read_only: bool,
+    /// Memory map the image
+    #[clap(long)]
+    mmap: bool,
+    /// File with the passphrase for an encrypted image on its first line,
+    /// encrypted images are always served read only
+    #[clap(long)]
+    passphrase_file: Option<String>,
+    /// Byte offset of the CP/M image in the file, 0x prefix for hex.
+    /// Found from an MBR partition of type 52 or DB if not given.
+    #[clap(long, value_parser = output::parse_offset)]
+    offset: Option<u64>,
+}
+
+const NUM_USERS: u8 = 16;
+
+struct Session<'a> {
+    image_path: &'a str,
+    options: &'a ImageOptions,
+    read_only: bool,
+    control: BufReader<TcpStream>,
+    // None is the root, listing the user areas
+    user: Option<u8>,
+    passive: Option<TcpListener>,
+    rename_from: Option<String>,
+}
+
+impl Session<'_> {
+    fn reply(&mut self, code: u32, text: &str) -> Result<()> {
+        let stream = self.control.get_mut();
+        stream.write_all(format!("{} {}\r\n", code, text).as_bytes())?;
+        Ok(())
+    }
+
+    // "/3/FOO.TXT", "FOO.TXT" or "../3/FOO.TXT" to user and file name
+    fn resolve_file(&self, path: &str) -> Option<String> {
+        let mut user = if path.starts_with('/') { None } else { self.user };
+        let mut name = None;
+        for part in path.split('/').filter(|p| !p.is_empty() && *p != ".") {
+            if name.is_some() {
+                return None;
+            }
+            if part == ".." {
+                user = None;
+            } else if user.is_none() {
+                user = Some(part.parse::<u8>().ok().filter(|u| *u < NUM_USERS)?);
+            } else {
+                name = Some(part);
+            }
+        }
+        let name = name?;
+        if !name.contains('.') {
+            return Some(format!("{}:{}.", user?, name));
+        }
+        Some(format!("{}:{}", user?, name))
+    }
+
+    fn resolve_dir(&self, path: &str) -> Option<Option<u8>> {
+        let mut user = if path.starts_with('/') { None } else { self.user };
+        for part in path.split('/').filter(|p| !p.is_empty() && *p != ".") {
+            if part == ".." {
+                user = None;
+            } else if user.is_none() {
+                user = Some(part.parse::<u8>().ok().filter(|u| *u < NUM_USERS)?);
+            } else {
+                return None;
+            }
+        }
+        Some(user)
+    }
+
+    fn open_data(&mut self) -> Result<Option<TcpStream>> {
+        match self.passive.take() {
+            Some(listener) => {
+                self.reply(150, "Opening data connection")?;
+                let (stream, _) = listener.accept()?;
+                Ok(Some(stream))
+            }
+            None => {
+                self.reply(425, "Use PASV or EPSV first")?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn list(&mut self, arg: &str, names_only: bool) -> Result<()> {
+        let dir = if arg.is_empty() || arg.starts_with('-') { Some(self.user) } else { self.resolve_dir(arg) };
+        let dir = match dir {
+            Some(dir) => dir,
+            None => return self.reply(550, "No such directory"),
+        };
+
+        let mut listing = String::new();
+        match dir {
+            None => {
+                for user in 0..NUM_USERS {
+                    if names_only {
+                        listing.push_str(&format!("{}\r\n", user));
+                    } else {
+                        listing.push_str(&format!("drwxr-xr-x 1 cpm cpm 0 Jan  1  1978 {}\r\n", user));
+                    }
+                }
+            }
+            Some(user) => {
+                let files = cpmimg::file_infos(self.image_path, self.options, cpmimg::SortKey::Name, false)?;
+                for info in files.iter().filter(|f| f.user_number == user) {
+                    let name = format!("{}.{}", info.filename, info.filetype);
+                    if names_only {
+                        listing.push_str(&format!("{}\r\n", name));
+                    } else {
+                        let mode = if info.attributes.readonly { "-r--r--r--" } else { "-rw-r--r--" };
+                        listing.push_str(&format!("{} 1 cpm cpm {} Jan  1  1978 {}\r\n", mode, info.size, name));
+                    }
+                }
+            }
+        }
+
+        if let Some(mut data) = self.open_data()? {
+            data.write_all(listing.as_bytes())?;
+            drop(data);
+            self.reply(226, "Transfer complete")?;
+        }
+        Ok(())
+    }
+
+    fn retrieve(&mut self, arg: &str) -> Result<()> {
+        let cpm_file_name = match self.resolve_file(arg) {
+            Some(name) => name,
+            None => return self.reply(550, "No such file"),
+        };
+        let contents = match cpmimg::read_file(self.image_path, self.options, &cpm_file_name) {
+            Ok(contents) => contents,
+            Err(e) => return self.reply(550, &e.to_string()),
+        };
+        if let Some(mut data) = self.open_data()? {
+            data.write_all(&contents)?;
+            drop(data);
+            self.reply(226, "Transfer complete")?;
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, arg: &str) -> Result<()> {
+        if self.read_only {
+            return self.reply(550, "Image is served read only");
+        }
+        let cpm_file_name = match self.resolve_file(arg) {
+            Some(name) => name,
+            None => return self.reply(553, "Files can only be stored in a user area, /0 to /15"),
+        };
+        if let Some(data) = self.open_data()? {
+            // A byte over the limit is enough to refuse it
+            let limit = cpmimg::geometry().max_file_size();
+            let mut contents = Vec::new();
+            data.take(limit as u64 + 1).read_to_end(&mut contents)?;
+            let result = cpmimg::check_file_size(&cpm_file_name, contents.len())
+                .and_then(|_| cpmimg::write_file(self.image_path, self.options, &cpm_file_name, &contents));
+            match result {
+                Ok(()) => self.reply(226, "Transfer complete")?,
+                // Only the first bytes over the limit were read, the size in the error is not the upload's
+                Err(e) => match e.downcast_ref::<cpmimg::FileTooLarge>() {
+                    Some(too_large) => self.reply(552, &format!("Files can be at most {} bytes", too_large.limit))?,
+                    None => self.reply(552, &e.to_string())?,
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn passive_mode(&mut self, extended: bool) -> Result<()> {
+        let local = self.control.get_ref().local_addr()?;
+        let listener = TcpListener::bind((local.ip(), 0))?;
+        let port = listener.local_addr()?.port();
+        self.passive = Some(listener);
+
+        if extended {
+            return self.reply(229, &format!("Entering Extended Passive Mode (|||{}|)", port));
+        }
+        match local.ip() {
+            std::net::IpAddr::V4(ip) => {
+                let o = ip.octets();
+                let text = format!("Entering Passive Mode ({},{},{},{},{},{})", o[0], o[1], o[2], o[3], port >> 8, port & 0xff);
+                self.reply(227, &text)
+            }
+            std::net::IpAddr::V6(_) => self.reply(522, "Use EPSV on IPv6"),
+        }
+    }
+
+    fn modify(&mut self, result: Result<()>, ok: &str) -> Result<()> {
+        match result {
+            Ok(()) => self.reply(250, ok),
+            Err(e) => self.reply(550, &e.to_string()),
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        self.reply(220, "cpmserve ready")?;
+
+        loop {
+            let mut line = String::new();
+            if self.control.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end();
+            let (command, arg) = match line.split_once(' ') {
+                Some((command, arg)) => (command.to_uppercase(), arg.trim().to_string()),
+                None => (line.to_uppercase(), String::new()),
+            };
+
+            let writing = matches!(command.as_str(), "DELE" | "RNFR" | "RNTO");
+            if writing && self.read_only {
+                self.reply(550, "Image is served read only")?;
+                continue;
+            }
+
+            match command.as_str() {
+                "USER" => self.reply(331, "Any password will do")?,
+                "PASS" => self.reply(230, "Logged in")?,
+                "SYST" => self.reply(215, "UNIX Type: L8")?,
+                "FEAT" => {
+                    self.control.get_mut().write_all(b"211-Features\r\n EPSV\r\n PASV\r\n SIZE\r\n")?;
+                    self.reply(211, "End")?;
+                }
+                "OPTS" | "NOOP" | "MODE" | "STRU" => self.reply(200, "OK")?,
+                // Everything is transferred as is
+                "TYPE" => self.reply(200, "Type set")?,
+                "PWD" | "XPWD" => {
+                    let dir = match self.user {
+                        Some(user) => format!("\"/{}\"", user),
+                        None => "\"/\"".to_string(),
+                    };
+                    self.reply(257, &dir)?;
+                }
+                "CWD" | "XCWD" => match self.resolve_dir(&arg) {
+                    Some(user) => {
+                        self.user = user;
+                        self.reply(250, "Directory changed")?;
+                    }
+                    None => self.reply(550, "No such directory")?,
+                },
+                "CDUP" | "XCUP" => {
+                    self.user = None;
+                    self.reply(250, "Directory changed")?;
+                }
+                "PASV" => self.passive_mode(false)?,
+                "EPSV" => self.passive_mode(true)?,
+                "LIST" => self.list(&arg, false)?,
+                "NLST" => self.list(&arg, true)?,
+                "RETR" => self.retrieve(&arg)?,
+                "STOR" => self.store(&arg)?,
+                "SIZE" => {
+                    let size = self.resolve_file(&arg)
+                        .and_then(|name| cpmimg::read_file(self.image_path, self.options, &name).ok())
+                        .map(|data| data.len());
+                    match size {
+                        Some(size) => self.reply(213, &size.to_string())?,
+                        None => self.reply(550, "No such file")?,
+                    }
+                }
+                "DELE" => match self.resolve_file(&arg) {
+                    Some(name) => {
+                        let result = cpmimg::delete_file(self.image_path, self.options, &name);
+                        self.modify(result, "Deleted")?;
+                    }
+                    None => self.reply(550, "No such file")?,
+                },
+                "RNFR" => match self.resolve_file(&arg) {
+                    Some(name) => {
+                        self.rename_from = Some(name);
+                        self.reply(350, "Ready for RNTO")?;
+                    }
+                    None => self.reply(550, "No such file")?,
+                },
+                "RNTO" => match (self.rename_from.take(), self.resolve_file(&arg)) {
+                    (Some(from), Some(to)) => {
+                        let result = cpmimg::rename_file(self.image_path, self.options, &from, &to);
+                        self.modify(result, "Renamed")?;
+                    }
+                    _ => self.reply(503, "Bad sequence of commands")?,
+                },
+                "QUIT" => {
+                    self.reply(221, "Bye")?;
+                    return Ok(());
+                }
+                _ => self.reply(502, "Command not implemented")?,
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
+
+    let cli = Cli::parse();
+    let options = ImageOptions { mmap: cli.mmap, passphrase_file: cli.passphrase_file.clone(), offset: cli.offset, ..Default::default() };
+
+    // Fail early on a bad image instead of at the first LIST
+    cpmimg::file_infos(&cli.image_path, &options, cpmimg::SortKey::Index, false)?;
+
+    let listener = TcpListener::bind(&cli.listen)?;
+    println!("Serving '{}' on ftp://{}", cli.image_path, listener.local_addr()?);
+
+    // One client at a time, every command opens the image again,
+    // so two sessions can never write to it at once
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        println!("Connection from {}", peer);
+        // The key of an encrypted image is derived once for the session
+        let session_options = ImageOptions { keys: KeyCache::default(), ..options.clone() };
+        let mut session = Session {
+            image_path: &cli.image_path,
+            options: &session_options,
+            read_only: cli.read_only || cli.passphrase_file.is_some(),
+            control: BufReader::new(stream),
+            user: None,
+            passive: None,
+            rename_from: None,
+        };
+        if let Err(e) = session.run() {
+            println!("Connection from {} failed: {}", peer, e);
+        }
+    }
+
+    Ok(())
+}
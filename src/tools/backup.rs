@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use crate::cpmimg;
+use crate::durable;
+use crate::imagefile::is_url;
+
+// Copies of an image taken before a command changes it. Next to the image
+// they rotate as IMAGE.bak1 to IMAGE.bakN, bak1 being the newest. In a
+// backup directory they are named by the time they were taken, like
+// game.img.20261015-142530-123.bak, so images of the same name from
+// different directories share the count. The whole file is copied, with a
+// partition table or a chunked image's header as they are; a chunked image
+// copied to another directory no longer finds a relative base.
+
+#[derive(Debug, Clone)]
+pub struct BackupOptions {
+    /// Copies to keep, none are made for 0
+    pub keep: usize,
+    /// Directory for dated copies, rotated ones next to the image without it
+    pub dir: Option<String>,
+}
+
+fn rotated_path(image_path: &str, n: usize) -> PathBuf {
+    PathBuf::from(format!("{}.bak{}", image_path, n))
+}
+
+// UTC, with milliseconds so two commands in a row don't share a name
+fn stamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since.as_secs();
+    let (year, month, day) = cpmimg::civil_from_days((seconds / 86400) as i64);
+    let second = seconds % 86400;
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}", year, month, day,
+        second / 3600, second / 60 % 60, second % 60, since.subsec_millis())
+}
+
+fn rotate(image_path: &str, keep: usize) -> Result<PathBuf> {
+    // Copies beyond a smaller --backups than before go as well
+    let mut n = keep;
+    while rotated_path(image_path, n + 1).exists() {
+        n += 1;
+    }
+    for n in (keep..=n).rev().filter(|&n| n > 0) {
+        let path = rotated_path(image_path, n);
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    for n in (1..keep).rev() {
+        let path = rotated_path(image_path, n);
+        if path.exists() {
+            std::fs::rename(&path, rotated_path(image_path, n + 1))?;
+        }
+    }
+    let newest = rotated_path(image_path, 1);
+    std::fs::copy(image_path, &newest)?;
+    durable::sync_path(&newest.to_string_lossy())?;
+    Ok(newest)
+}
+
+fn dated(image_path: &str, dir: &str, keep: usize) -> Result<PathBuf> {
+    let name = match Path::new(image_path).file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => anyhow::bail!("'{}' is not a file to back up", image_path),
+    };
+    std::fs::create_dir_all(dir)?;
+    let newest = Path::new(dir).join(format!("{}.{}.bak", name, stamp(SystemTime::now())));
+    std::fs::copy(image_path, &newest)?;
+    durable::sync_path(&newest.to_string_lossy())?;
+
+    // The stamps sort by time
+    let prefix = format!("{}.", name);
+    let mut copies: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.file_name().map(|f| f.to_string_lossy()).is_some_and(|f| {
+            f.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(".bak"))
+                .is_some_and(|stamp| stamp.len() == 19 && stamp.bytes().all(|b| b.is_ascii_digit() || b == b'-'))
+        }))
+        .collect();
+    copies.sort();
+    let excess = copies.len().saturating_sub(keep);
+    for path in &copies[..excess] {
+        std::fs::remove_file(path)?;
+    }
+    Ok(newest)
+}
+
+/// Copy the image before it is changed and drop the oldest copies,
+/// None if there is nothing to copy
+pub fn backup_image(image_path: &str, options: &BackupOptions) -> Result<Option<PathBuf>> {
+    if options.keep == 0 || is_url(image_path) || !Path::new(image_path).is_file() {
+        return Ok(None);
+    }
+    let path = match &options.dir {
+        Some(dir) => dated(image_path, dir, options.keep),
+        None => rotate(image_path, options.keep),
+    };
+    match path {
+        Ok(path) => Ok(Some(path)),
+        Err(e) => anyhow::bail!("Could not back up '{}', nothing was changed: {}", image_path, e),
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::Result;
+
+// Imports and exports of many files go on when one of them fails, and list
+// all failures at the end, so one bad file doesn't cost the rest. With
+// fail_fast the first failure stops the operation, as it used to.
+
+/// Successes and failures of the items of a bulk operation
+#[derive(Debug, Default)]
+pub struct Batch {
+    fail_fast: bool,
+    succeeded: usize,
+    failed: Vec<(String, String)>,
+}
+
+impl Batch {
+    pub fn new(fail_fast: bool) -> Batch {
+        Batch { fail_fast, ..Batch::default() }
+    }
+
+    /// Count the result for one item, an error only when the operation should stop
+    pub fn record(&mut self, item: &str, result: Result<()>) -> Result<()> {
+        match result {
+            Ok(()) => self.succeeded += 1,
+            Err(e) if self.fail_fast => anyhow::bail!("{}: {}", item, e),
+            Err(e) => self.failed.push((item.to_string(), e.to_string())),
+        }
+        Ok(())
+    }
+
+    pub fn succeeded(&self) -> usize {
+        self.succeeded
+    }
+
+    /// Print the failures and fail if there were any, else the number of successes
+    pub fn finish(self) -> Result<usize> {
+        if self.failed.is_empty() {
+            return Ok(self.succeeded);
+        }
+        for (item, error) in &self.failed {
+            eprintln!("Failed: {}: {}", item, error);
+        }
+        anyhow::bail!("{} of {} files failed, {} succeeded", self.failed.len(), self.failed.len() + self.succeeded, self.succeeded)
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::Result;
+use clap::ValueEnum;
+use crate::cmd;
+use crate::cpmimg::{self, DISKSIZE_OFFSET, DiskSize, SortKey};
+use crate::imagefile::{ImageOptions, open_image};
+
+// Static checks that a disk has what COMPIS needs to boot from it.
+// The boot ROM loads the reserved tracks, the loader there reads CPM.SYS
+// from the directory. None of this runs any code, so a disk that passes
+// may still not boot, but one that fails will not.
+
+// Opcodes an x86 boot sector normally starts with: JMP short, near and far
+const JUMP_OPCODES: [u8; 3] = [0xeb, 0xe9, 0xea];
+
+/// Files the loader needs, always in user 0
+const REQUIRED_FILES: &[&str] = &["CPM.SYS"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    /// Unusual, but not known to stop the boot
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub status: Status,
+    pub text: String,
+}
+
+fn check(status: Status, text: String) -> Check {
+    Check { status, text }
+}
+
+/// Run all checks, `required` are extra files that must be present, as User:Name.Type
+pub fn boot_checks(image_path: &str, options: &ImageOptions, required: &[String]) -> Result<Vec<Check>> {
+    let geometry = cpmimg::geometry();
+    let mut checks = Vec::new();
+
+    let mut disk = open_image(image_path, false, options)?;
+    let image_size = disk.size()?;
+    let mut system = vec![0u8; geometry.catalog_offset() as usize];
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_exact(&mut system)?;
+
+    // Boot code in the system tracks, taking the most common byte as the format fill
+    let mut counts = [0usize; 256];
+    for &b in &system {
+        counts[b as usize] += 1;
+    }
+    let fill = (0..=255u8).max_by_key(|&b| counts[b as usize]).unwrap_or(0xe5);
+    let used = system.iter().filter(|&&b| b != fill).count();
+    if used == 0 {
+        checks.push(check(Status::Fail, format!("System tracks are all {:02X}, there is no boot code", fill)));
+    } else {
+        checks.push(check(Status::Pass, format!("System tracks hold {} bytes that are not fill", used)));
+    }
+    if JUMP_OPCODES.contains(&system[0]) {
+        checks.push(check(Status::Pass, format!("Boot sector starts with a jump ({:02X})", system[0])));
+    } else {
+        checks.push(check(Status::Warn, format!("Boot sector starts with {:02X}, not a jump", system[0])));
+    }
+
+    // Media byte against the size of the image
+    let media_byte = system[DISKSIZE_OFFSET];
+    let matching: Vec<&DiskSize> = DiskSize::value_variants().iter()
+        .filter(|size| size.hex_value() == media_byte)
+        .collect();
+    if matching.iter().any(|size| size.num_bytes() as u64 == image_size) {
+        checks.push(check(Status::Pass, format!("Media byte {:02X} matches the image size {}", media_byte, image_size)));
+    } else if matching.is_empty() {
+        checks.push(check(Status::Fail, format!("Media byte {:02X} is not a known format", media_byte)));
+    } else {
+        checks.push(check(Status::Fail, format!("Media byte {:02X} does not match the image size {}", media_byte, image_size)));
+    }
+
+    // System files
+    let files = cpmimg::file_infos(image_path, options, SortKey::Index, false)?;
+    let names: Vec<String> = REQUIRED_FILES.iter().map(|name| format!("0:{}", name))
+        .chain(required.iter().cloned())
+        .collect();
+    for name in names {
+        let (user, filename, filetype) = cpmimg::split_cpm_file_name(&name)?;
+        let found = files.iter().find(|f| {
+            f.user_number == user && f.filename.to_uppercase() == filename && f.filetype.to_uppercase() == filetype
+        });
+        match found {
+            None => checks.push(check(Status::Fail, format!("{} is missing", name))),
+            Some(info) if !info.attributes.system => checks.push(check(Status::Warn, format!("{} is present but not marked SYS", name))),
+            Some(info) => checks.push(check(Status::Pass, format!("{} is present with SYS, {} bytes", name, info.size))),
+        }
+    }
+
+    // The system itself, a .CMD-file with the BIOS jump vector where the System Guide has it
+    if let Ok(file) = cpmimg::read_file(image_path, options, "0:CPM.SYS") {
+        match cmd::read_system(&file) {
+            Ok(system) => checks.push(check(Status::Pass, format!("CPM.SYS loads at {:04X}:0000 with a BIOS of {} bytes",
+                system.load_segment, system.code.len() - cmd::BIOS_OFFSET))),
+            Err(e) => checks.push(check(Status::Warn, format!("CPM.SYS is not laid out like a CP/M-86 1.1 system: {}", e))),
+        }
+    }
+
+    Ok(checks)
+}
+
+/// Print the checklist, true if nothing failed
+pub fn verify_bootable(image_path: &str, options: &ImageOptions, required: &[String]) -> Result<bool> {
+    let checks = boot_checks(image_path, options, required)?;
+    for c in &checks {
+        let status = match c.status {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        println!("[{}] {}", status, c.text);
+    }
+    let bootable = checks.iter().all(|c| c.status != Status::Fail);
+    println!("{}", if bootable { "Bootable" } else { "Not bootable" });
+    Ok(bootable)
+}
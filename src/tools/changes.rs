@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::checksum::sha256_hex;
+use crate::cpmimg::{self, Attributes};
+use crate::imagefile::{ImageOptions, open_image};
+
+// What happened to the files of an image between two moments, for example
+// before and after a program ran on it in an emulator. A snapshot keeps a
+// hash of every file, so it is small enough to save and compare later.
+
+/// A file as it was when the snapshot was taken
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    /// Rounded up to whole records, as stored
+    pub size: usize,
+    pub sha256: String,
+    pub attributes: Attributes,
+}
+
+/// The files of an image at one moment, by User:Name.Type
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub files: BTreeMap<String, FileState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum FileChange {
+    Added { name: String, after: FileState },
+    Removed { name: String, before: FileState },
+    /// Contents or attributes differ
+    Modified { name: String, before: FileState, after: FileState },
+}
+
+impl FileChange {
+    pub fn name(&self) -> &str {
+        match self {
+            FileChange::Added { name, .. } | FileChange::Removed { name, .. } | FileChange::Modified { name, .. } => name,
+        }
+    }
+}
+
+impl Snapshot {
+    /// What changed going from this snapshot to `later`, ordered by name
+    pub fn diff(&self, later: &Snapshot) -> Vec<FileChange> {
+        let mut changes = Vec::new();
+        for (name, before) in &self.files {
+            match later.files.get(name) {
+                None => changes.push(FileChange::Removed { name: name.clone(), before: before.clone() }),
+                Some(after) if after != before => changes.push(FileChange::Modified { name: name.clone(), before: before.clone(), after: after.clone() }),
+                Some(_) => {}
+            }
+        }
+        for (name, after) in &later.files {
+            if !self.files.contains_key(name) {
+                changes.push(FileChange::Added { name: name.clone(), after: after.clone() });
+            }
+        }
+        changes.sort_by(|a, b| a.name().cmp(b.name()));
+        changes
+    }
+}
+
+/// Hash every file of the image
+pub fn snapshot(image_path: &str, options: &ImageOptions) -> Result<Snapshot> {
+    let mut snapshot = Snapshot::default();
+    cpmimg::read_all_files(image_path, options, &mut |info, data| {
+        let name = format!("{}:{}.{}", info.user_number, info.filename, info.filetype);
+        snapshot.files.insert(name, FileState { size: data.len(), sha256: sha256_hex(&data), attributes: info.attributes });
+        Ok(())
+    })?;
+    Ok(snapshot)
+}
+
+// Formatters and programs leave 00, ^Z, E5 or F6 after the end of a file
+// in its last record, so a file copied by another tool can differ there
+const PADDING: [u8; 4] = [0x00, 0x1a, 0xe5, 0xf6];
+
+// The file without the padding in its last record
+fn without_padding(data: &[u8]) -> &[u8] {
+    let last_record = data.len().saturating_sub(1) / 128 * 128;
+    let fill_byte = cpmimg::geometry().fill_byte;
+    match data.last() {
+        Some(&pad) if PADDING.contains(&pad) || pad == fill_byte => {
+            let end = data[last_record..].iter().rposition(|&b| b != pad).map_or(last_record, |i| last_record + i + 1);
+            &data[..end]
+        }
+        _ => data,
+    }
+}
+
+/// Hash every file of the image without the padding after its end, for
+/// comparing images where the files were written by different tools.
+/// Sizes are in bytes up to the padding.
+pub fn logical_snapshot(image_path: &str, options: &ImageOptions) -> Result<Snapshot> {
+    let mut snapshot = Snapshot::default();
+    cpmimg::read_all_files(image_path, options, &mut |info, data| {
+        let name = format!("{}:{}.{}", info.user_number, info.filename, info.filetype);
+        let data = without_padding(&data);
+        snapshot.files.insert(name, FileState { size: data.len(), sha256: sha256_hex(data), attributes: info.attributes });
+        Ok(())
+    })?;
+    Ok(snapshot)
+}
+
+/// Files added, removed or modified in the image since `earlier` was taken
+pub fn changes_since(image_path: &str, options: &ImageOptions, earlier: &Snapshot) -> Result<Vec<FileChange>> {
+    Ok(earlier.diff(&snapshot(image_path, options)?))
+}
+
+// "R/O SYS", or "none"
+fn attribute_names(attributes: &Attributes) -> String {
+    let names: Vec<&str> = [(attributes.readonly, "R/O"), (attributes.system, "SYS"), (attributes.archived, "ARC")]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect();
+    if names.is_empty() { "none".to_string() } else { names.join(" ") }
+}
+
+/// Print how two images differ, true if they don't. Logically only the
+/// files count, their names, contents and attributes, so free blocks, the
+/// fill byte, padding after the end of a file and where the files are on
+/// the disk don't matter. Otherwise every sector is compared.
+pub fn diff_images(image_a: &str, image_b: &str, options: &ImageOptions, logical: bool) -> Result<bool> {
+    if logical {
+        let changes = logical_snapshot(image_a, options)?.diff(&logical_snapshot(image_b, options)?);
+        for change in &changes {
+            match change {
+                FileChange::Removed { name, before } => println!("- {} ({} bytes), only in {}", name, before.size, image_a),
+                FileChange::Added { name, after } => println!("+ {} ({} bytes), only in {}", name, after.size, image_b),
+                FileChange::Modified { name, before, after } if before.sha256 == after.sha256 => {
+                    println!("M {} attributes {} -> {}", name, attribute_names(&before.attributes), attribute_names(&after.attributes));
+                }
+                FileChange::Modified { name, before, after } => println!("M {} contents differ, {} -> {} bytes", name, before.size, after.size),
+            }
+        }
+        if changes.is_empty() {
+            println!("The files in '{}' and '{}' are the same", image_a, image_b);
+        }
+        return Ok(changes.is_empty());
+    }
+
+    let read = |path: &str| -> Result<Vec<u8>> {
+        let mut disk = open_image(path, false, options)?;
+        let mut image = Vec::new();
+        disk.seek(SeekFrom::Start(0))?;
+        disk.read_to_end(&mut image)?;
+        Ok(image)
+    };
+    let (a, b) = (read(image_a)?, read(image_b)?);
+    let sector_size = cpmimg::geometry().bytes_per_sector;
+    let sectors = a.len().max(b.len()).div_ceil(sector_size);
+    let differs = |sector: usize| {
+        let range = |image: &[u8]| image.get(sector * sector_size..((sector + 1) * sector_size).min(image.len())).unwrap_or(&[]).to_vec();
+        range(&a) != range(&b)
+    };
+
+    // Runs of differing sectors
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for sector in 0..=sectors {
+        match (sector < sectors && differs(sector), start) {
+            (true, None) => start = Some(sector),
+            (false, Some(first)) => {
+                ranges.push((first, sector));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    for &(first, end) in &ranges {
+        println!("{:#08x}-{:#08x}  {} sectors differ", first * sector_size, end * sector_size - 1, end - first);
+    }
+    if a.len() != b.len() {
+        println!("'{}' is {} bytes, '{}' is {} bytes", image_a, a.len(), image_b, b.len());
+    }
+    if ranges.is_empty() {
+        println!("'{}' and '{}' are the same", image_a, image_b);
+    } else {
+        println!("{} sectors differ, use --logical to compare only the files", ranges.iter().map(|(first, end)| end - first).sum::<usize>());
+    }
+    Ok(ranges.is_empty())
+}
@@ -0,0 +1,86 @@
+use std::sync::Mutex;
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::cpmimg;
+use crate::imagefile::ImageOptions;
+
+// Each image is read by one thread, front to back, and the hashing of the
+// files it reads is spread over the pool. The disk sees sequential reads
+// no matter how many jobs there are.
+
+/// The hash of one file in an image
+#[derive(Debug, Clone, Serialize)]
+pub struct FileChecksum {
+    pub image: String,
+    pub user_number: u8,
+    pub filename: String,
+    pub filetype: String,
+    pub directory_index: usize,
+    /// Rounded up to whole records, as stored
+    pub size: usize,
+    pub sha256: String,
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hash all files in the images with `jobs` threads, all cores if None.
+/// The result is ordered by image, then directory index.
+pub fn checksum_images(image_paths: &[String], options: &ImageOptions, jobs: Option<usize>) -> Result<Vec<FileChecksum>> {
+    let mut builder = rayon::ThreadPoolBuilder::new();
+    if let Some(jobs) = jobs {
+        builder = builder.num_threads(jobs);
+    }
+    let pool = builder.build()?;
+
+    let checksums = Mutex::new(Vec::new());
+    let errors = Mutex::new(Vec::new());
+    pool.scope(|scope| {
+        for (image_idx, image_path) in image_paths.iter().enumerate() {
+            let (checksums, errors) = (&checksums, &errors);
+            scope.spawn(move |scope| {
+                let result = cpmimg::read_all_files(image_path, options, &mut |info, data| {
+                    scope.spawn(move |_| {
+                        let checksum = FileChecksum {
+                            image: image_path.clone(),
+                            user_number: info.user_number,
+                            filename: info.filename,
+                            filetype: info.filetype,
+                            directory_index: info.directory_index,
+                            size: data.len(),
+                            sha256: sha256_hex(&data),
+                        };
+                        checksums.lock().unwrap().push((image_idx, checksum));
+                    });
+                    Ok(())
+                });
+                if let Err(e) = result {
+                    errors.lock().unwrap().push(format!("{}: {}", image_path, e));
+                }
+            });
+        }
+    });
+
+    let errors = errors.into_inner().unwrap();
+    if !errors.is_empty() {
+        anyhow::bail!("{}", errors.join("\n"));
+    }
+    let mut checksums = checksums.into_inner().unwrap();
+    checksums.sort_by_key(|(image_idx, c)| (*image_idx, c.directory_index));
+    Ok(checksums.into_iter().map(|(_, c)| c).collect())
+}
+
+/// Print a sha256sum style line per file, or JSON
+pub fn print_checksums(image_paths: &[String], options: &ImageOptions, jobs: Option<usize>, json: bool) -> Result<()> {
+    let checksums = checksum_images(image_paths, options, jobs)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&checksums)?);
+        return Ok(());
+    }
+    for c in &checksums {
+        println!("{}  {} {}:{}.{}", c.sha256, c.image, c.user_number, c.filename, c.filetype);
+    }
+    Ok(())
+}
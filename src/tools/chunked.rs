@@ -0,0 +1,416 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::Path;
+use anyhow::Result;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use crate::cpmimg;
+use crate::durable;
+use crate::formats;
+use crate::imagefile::{ImageFile, ImageOptions, open_image};
+
+// Chunked images, for keeping many mostly empty disks. The image is cut in
+// chunks of CHUNK_SIZE bytes, each stored deflated. Chunks that hold only
+// the fill byte are not stored at all. An image can have a base image, then
+// chunks that are the same as in the base are not stored either, and are
+// read from the base. A copy of a master disk with a few files changed
+// costs little more than the changed blocks.
+//
+// Header, little endian:
+//   "CPMCHUNK", version u32, chunk size u32, image size u64,
+//   length of the base path u32, fill byte u8, 3 unused bytes
+//   the base path, relative to the directory of the chunked image
+//   one table entry per chunk: offset u64, stored length u32, kind u32
+//   stored chunks
+// Changed chunks are always appended and the table is written after them,
+// so an update that is cut short leaves the old contents readable. The
+// space of replaced chunks is given back by packing the image again.
+
+pub const MAGIC: &[u8; 8] = b"CPMCHUNK";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 32;
+const ENTRY_SIZE: usize = 16;
+pub const CHUNK_SIZE: usize = 4096;
+/// Changed chunks kept in memory before they are written
+const MAX_DIRTY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Not stored, the base image or the fill byte
+    Absent = 0,
+    Deflated = 1,
+    /// Deflating made it larger
+    Stored = 2,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    offset: u64,
+    len: u32,
+    kind: Kind,
+}
+
+const ABSENT: Entry = Entry { offset: 0, len: 0, kind: Kind::Absent };
+
+// No disk is larger than the largest format or the geometry in use, a
+// header that says otherwise is damaged
+fn largest_disk() -> u64 {
+    let largest = formats::all().iter().map(|format| format.capacity).max().unwrap_or(0);
+    largest.max(cpmimg::geometry().total_size()) as u64
+}
+
+/// True if the file is a chunked image
+pub fn is_chunked(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+}
+
+// The base path is relative to the chunked image
+fn base_path(image_path: &str, base: &str) -> String {
+    match Path::new(image_path).parent() {
+        Some(dir) if Path::new(base).is_relative() => dir.join(base).to_string_lossy().into_owned(),
+        _ => base.to_string(),
+    }
+}
+
+/// A chunked image, read and written chunk by chunk
+pub struct ChunkedImage {
+    file: File,
+    writable: bool,
+    chunk_size: usize,
+    size: u64,
+    fill: u8,
+    base_name: String,
+    base: Option<Box<dyn ImageFile>>,
+    table: Vec<Entry>,
+    chunks: HashMap<usize, Vec<u8>>,
+    dirty: BTreeSet<usize>,
+    // Stored chunks the table in the file doesn't point to yet
+    table_changed: bool,
+    end: u64,
+    pos: u64,
+}
+
+impl ChunkedImage {
+    pub fn open(path: &str, writable: bool) -> Result<ChunkedImage> {
+        let mut file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if !is_chunked(&header) {
+            anyhow::bail!("{} is not a chunked image", path);
+        }
+        let version = u32_at(&header, 8);
+        if version != VERSION {
+            anyhow::bail!("{} is a chunked image of version {}, only version {} is known", path, version, VERSION);
+        }
+        // Everything the header gives a length for is checked before it is allocated
+        let file_size = file.metadata()?.len();
+        let chunk_size = u32_at(&header, 12) as usize;
+        if chunk_size != CHUNK_SIZE {
+            anyhow::bail!("{} has chunks of {} bytes, only {} is known", path, chunk_size, CHUNK_SIZE);
+        }
+        let size = u64_at(&header, 16);
+        if size > largest_disk() {
+            anyhow::bail!("{} says it holds {} bytes, larger than any disk of {} bytes", path, size, largest_disk());
+        }
+        let base_name_len = u32_at(&header, 24) as u64;
+        let count = size.div_ceil(chunk_size as u64) as usize;
+        let table_end = HEADER_SIZE + base_name_len + (count * ENTRY_SIZE) as u64;
+        if table_end > file_size {
+            anyhow::bail!("{} is {} bytes, too short for the base path and chunk table of its header", path, file_size);
+        }
+        let mut base_name = vec![0u8; base_name_len as usize];
+        file.read_exact(&mut base_name)?;
+        let base_name = String::from_utf8(base_name)?;
+
+        let mut entries = vec![0u8; count * ENTRY_SIZE];
+        file.read_exact(&mut entries)?;
+        let mut table = Vec::with_capacity(count);
+        for (index, entry) in entries.chunks(ENTRY_SIZE).enumerate() {
+            let kind = match u32_at(entry, 12) {
+                0 => Kind::Absent,
+                1 => Kind::Deflated,
+                2 => Kind::Stored,
+                kind => anyhow::bail!("{} has a chunk of unknown kind {}", path, kind),
+            };
+            let entry = Entry { offset: u64_at(entry, 0), len: u32_at(entry, 8), kind };
+            if kind != Kind::Absent && entry.offset.saturating_add(entry.len as u64) > file_size {
+                anyhow::bail!("Chunk {} of {} is past the end of the file", index, path);
+            }
+            table.push(entry);
+        }
+
+        let base = if base_name.is_empty() {
+            None
+        } else {
+            let mut base = open_image(&base_path(path, &base_name), false, &ImageOptions::default())?;
+            if base.size()? != size {
+                anyhow::bail!("The base image {} of {} is {} bytes, not {}", base_name, path, base.size()?, size);
+            }
+            Some(base)
+        };
+
+        let end = file.seek(SeekFrom::End(0))?;
+        Ok(ChunkedImage {
+            file, writable, chunk_size, size, fill: header[28], base_name, base, table,
+            chunks: HashMap::new(), dirty: BTreeSet::new(), table_changed: false, end, pos: 0,
+        })
+    }
+
+    /// A new chunked image of `size` bytes, all fill bytes or the base image
+    pub fn create(path: &str, size: u64, fill: u8, base: Option<&str>) -> Result<ChunkedImage> {
+        let count = size.div_ceil(CHUNK_SIZE as u64) as usize;
+        let base_name = base.unwrap_or("");
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&(CHUNK_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&(base_name.len() as u32).to_le_bytes());
+        header.extend_from_slice(&[fill, 0, 0, 0]);
+        header.extend_from_slice(base_name.as_bytes());
+        header.resize(header.len() + count * ENTRY_SIZE, 0);
+        durable::write(path, &header)?;
+        ChunkedImage::open(path, true)
+    }
+
+    fn table_offset(&self) -> u64 {
+        HEADER_SIZE + self.base_name.len() as u64
+    }
+
+    fn chunk_len(&self, index: usize) -> usize {
+        (self.size - (index * self.chunk_size) as u64).min(self.chunk_size as u64) as usize
+    }
+
+    // What the chunk holds when it isn't stored
+    fn unstored(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![self.fill; self.chunk_len(index)];
+        if let Some(base) = &mut self.base {
+            base.seek(SeekFrom::Start((index * self.chunk_size) as u64))?;
+            base.read_exact(&mut data)?;
+        }
+        Ok(data)
+    }
+
+    fn load(&mut self, index: usize) -> io::Result<&mut Vec<u8>> {
+        if !self.chunks.contains_key(&index) {
+            let entry = self.table[index];
+            let data = match entry.kind {
+                Kind::Absent => self.unstored(index)?,
+                Kind::Deflated | Kind::Stored => {
+                    let mut stored = vec![0u8; entry.len as usize];
+                    self.file.seek(SeekFrom::Start(entry.offset))?;
+                    self.file.read_exact(&mut stored)?;
+                    if entry.kind == Kind::Stored {
+                        stored
+                    } else {
+                        // One byte more than the chunk is enough to see it is too long
+                        let mut data = Vec::with_capacity(self.chunk_size);
+                        DeflateDecoder::new(&stored[..]).take(self.chunk_len(index) as u64 + 1).read_to_end(&mut data)?;
+                        data
+                    }
+                }
+            };
+            if data.len() != self.chunk_len(index) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("chunk {} is {} bytes, not {}", index, data.len(), self.chunk_len(index))));
+            }
+            self.chunks.insert(index, data);
+        }
+        Ok(self.chunks.get_mut(&index).unwrap())
+    }
+
+    // Append the changed chunks to the file, the table is only changed in memory
+    fn store_dirty(&mut self) -> io::Result<()> {
+        for index in std::mem::take(&mut self.dirty) {
+            self.table_changed = true;
+            let data = self.chunks[&index].clone();
+            if data == self.unstored(index)? {
+                self.table[index] = ABSENT;
+                continue;
+            }
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            let deflated = encoder.finish()?;
+            let (kind, stored) = if deflated.len() < data.len() { (Kind::Deflated, deflated) } else { (Kind::Stored, data) };
+            self.file.seek(SeekFrom::Start(self.end))?;
+            self.file.write_all(&stored)?;
+            self.table[index] = Entry { offset: self.end, len: stored.len() as u32, kind };
+            self.end += stored.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn write_table(&mut self) -> io::Result<()> {
+        let mut entries = Vec::with_capacity(self.table.len() * ENTRY_SIZE);
+        for entry in &self.table {
+            entries.extend_from_slice(&entry.offset.to_le_bytes());
+            entries.extend_from_slice(&entry.len.to_le_bytes());
+            entries.extend_from_slice(&(entry.kind as u32).to_le_bytes());
+        }
+        self.file.seek(SeekFrom::Start(self.table_offset()))?;
+        self.file.write_all(&entries)
+    }
+
+    /// Bytes of chunk data in the file, and the number of chunks stored
+    pub fn stored(&self) -> (u64, usize) {
+        let stored: Vec<&Entry> = self.table.iter().filter(|entry| entry.kind != Kind::Absent).collect();
+        (stored.iter().map(|entry| entry.len as u64).sum(), stored.len())
+    }
+}
+
+impl Read for ChunkedImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = (self.pos / self.chunk_size as u64) as usize;
+        let start = (self.pos % self.chunk_size as u64) as usize;
+        let chunk = self.load(index)?;
+        let len = buf.len().min(chunk.len() - start);
+        buf[..len].copy_from_slice(&chunk[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for ChunkedImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "image is opened read only"));
+        }
+        if self.pos + buf.len() as u64 > self.size {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "write past end of image"));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let index = (self.pos / self.chunk_size as u64) as usize;
+        let start = (self.pos % self.chunk_size as u64) as usize;
+        let chunk = self.load(index)?;
+        let len = buf.len().min(chunk.len() - start);
+        chunk[start..start + len].copy_from_slice(&buf[..len]);
+        self.dirty.insert(index);
+        self.pos += len as u64;
+        if self.dirty.len() >= MAX_DIRTY {
+            self.store_dirty()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ChunkedImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(d) => self.pos as i64 + d,
+            SeekFrom::End(d) => self.size as i64 + d,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the image"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl ImageFile for ChunkedImage {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if !self.writable {
+            return Ok(());
+        }
+        self.store_dirty()?;
+        if durable::is_enabled() {
+            self.file.sync_data()?;
+        }
+        // The chunks are on the disk before the table points to them
+        self.write_table()?;
+        self.table_changed = false;
+        durable::sync_file(&self.file)?;
+        Ok(())
+    }
+}
+
+// Not all commands sync after writing, File doesn't need it
+impl Drop for ChunkedImage {
+    fn drop(&mut self) {
+        if self.writable && (!self.dirty.is_empty() || self.table_changed) && let Err(e) = self.sync() {
+            eprintln!("Error: could not write the changes to the chunked image: {}", e);
+        }
+    }
+}
+
+/// Write a chunked copy of an image, with only the chunks that differ from
+/// `base` when it is given. The base path is relative to the output.
+pub fn pack_image(image_path: &str, options: &ImageOptions, output_path: &str, base: Option<&str>) -> Result<()> {
+    let mut disk = open_image(image_path, false, options)?;
+    let size = disk.size()?;
+    let mut data = vec![0u8; size as usize];
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_exact(&mut data)?;
+
+    let mut packed = ChunkedImage::create(output_path, size, cpmimg::geometry().fill_byte, base)?;
+    packed.write_all(&data)?;
+    packed.sync()?;
+    let (stored, chunks) = packed.stored();
+    println!("Packed {} bytes to {} bytes in {}, {} of {} chunks stored", size, stored + packed.table_offset()
+        + (packed.table.len() * ENTRY_SIZE) as u64, output_path, chunks, packed.table.len());
+    Ok(())
+}
+
+/// Write a plain copy of an image, chunked or not
+pub fn unpack_image(image_path: &str, options: &ImageOptions, output_path: &str) -> Result<()> {
+    let mut disk = open_image(image_path, false, options)?;
+    let mut data = Vec::new();
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_to_end(&mut data)?;
+    durable::write(output_path, &data)?;
+    println!("Wrote {} bytes to {}", data.len(), output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpmimg::Geometry;
+    use crate::cpmimg::tests::{Scratch, UseGeometry, lock_globals, test_data};
+    use super::*;
+
+    #[test]
+    fn changes_kept_without_sync() {
+        let _globals = lock_globals();
+        // A disk large enough for MAX_DIRTY chunks
+        let _geometry = UseGeometry::new(Geometry { sectors_per_track: 16, ..Geometry::COMPIS });
+        let scratch = Scratch::new("chunked-drop");
+        let path = scratch.path("disk.cpc");
+        let size = cpmimg::geometry().total_size() as u64;
+        // Exactly MAX_DIRTY changed chunks, stored by write itself
+        let data = test_data(5, MAX_DIRTY * CHUNK_SIZE);
+        {
+            let mut image = ChunkedImage::create(&path, size, 0xe5, None).unwrap();
+            image.write_all(&data).unwrap();
+            assert!(image.dirty.is_empty());
+        }
+
+        let mut image = ChunkedImage::open(&path, false).unwrap();
+        let mut read = vec![0u8; data.len()];
+        image.read_exact(&mut read).unwrap();
+        assert!(read == data);
+        assert_eq!(image.stored().1, MAX_DIRTY);
+    }
+}
@@ -0,0 +1,417 @@
+use anyhow::Result;
+use binrw::{BinRead, BinWrite, binrw};
+use num_enum::TryFromPrimitive;
+use std::io::Cursor;
+
+pub mod prl;
+
+//
+// CMD header definition 
+// http://www.s100computers.com/Software%20Folder/CPM86/CPM-86_System_Guide_Jun83.pdf
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum GType {
+    Null = 0x0,
+    Code = 0x1,
+    Data = 0x2,
+    Extra = 0x3,
+    Stack = 0x4,
+    AuxiliaryGroup1 = 0x5,
+    AuxiliaryGroup2 = 0x6,
+    AuxiliaryGroup3 = 0x7,
+    AuxiliaryGroup4 = 0x8,
+    SharedCodeGroup = 0x9,
+    EsacepCode = 0xf,
+}
+
+impl GType {
+    #[inline]
+    pub fn from_low_nibble(n: u8) -> Self {
+        GType::try_from(n & 0x0F).unwrap()
+    }
+
+    #[inline]
+    pub fn to_low_nibble(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BinRead, BinWrite)]
+pub struct GForm(pub u8);
+
+impl GForm {
+    #[inline] pub fn raw(self) -> u8 { self.0 }
+
+    #[inline] pub fn g_type(self) -> GType {
+        GType::from_low_nibble(self.0 & 0x0F)
+    }
+
+    #[inline] pub fn hi_nibble(self) -> u8 {
+        self.0 >> 4
+    }
+
+    #[inline] pub fn with_type(self, t: GType) -> Self {
+        GForm((self.0 & 0xF0) | t.to_low_nibble())
+    }
+
+    #[inline] pub fn with_hi(self, hi: u8) -> Self {
+        GForm(((hi & 0x0F) << 4) | (self.0 & 0x0F))
+    }
+
+    #[inline] pub fn from_parts(t: GType, hi: u8) -> Self {
+        GForm(((hi & 0x0F) << 4) | t.to_low_nibble())
+    }
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Copy, Clone)]
+pub struct GroupDescriptor {
+    pub g_form: GForm,   
+    pub g_length: u16,   // paragraphs (16-byte units)
+    pub a_base: u16,     // base paragraph (0 = relocatable)
+    pub g_min: u16,      // min paragraphs
+    pub g_max: u16,      // max paragraphs
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub struct CmdHeader {
+    pub groups: [GroupDescriptor; 8], // 72 bytes
+    pub padding: [u8; 56],           // padding to 128
+}
+
+pub const PARAGRAPH_SIZE: usize = 16;
+pub const RECORD_SIZE: usize = 128;
+
+// A group length is a 16-bit paragraph count, so at most just below 1MB
+const MAX_GROUP_SIZE: usize = 0xffff * PARAGRAPH_SIZE;
+
+/// What goes into a .CMD-file. Without a data group the code is the 8080
+/// memory model: code and data share one group, and the code gets 0x100
+/// bytes in front of it for the base page, so it must be assembled at org $100.
+/// Ex: cmd::build(CmdSpec::new(code).load_address(0x400).stack_size(512))
+#[derive(Debug, Clone, Default)]
+pub struct CmdSpec {
+    pub code: Vec<u8>,
+    pub load_address: Option<u32>,
+    pub data: Option<Vec<u8>>,
+    pub data_load_address: Option<u32>,
+    /// Bytes of stack, rounded up to paragraphs, CP/M-86 sets up the stack if given
+    pub stack_size: Option<u32>,
+    /// Pad each group to whole 128-byte records instead of paragraphs
+    pub record_align: bool,
+}
+
+impl CmdSpec {
+    pub fn new(code: Vec<u8>) -> CmdSpec {
+        CmdSpec { code, ..CmdSpec::default() }
+    }
+
+    pub fn load_address(mut self, address: u32) -> CmdSpec {
+        self.load_address = Some(address);
+        self
+    }
+
+    pub fn data(mut self, data: Vec<u8>) -> CmdSpec {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn data_load_address(mut self, address: u32) -> CmdSpec {
+        self.data_load_address = Some(address);
+        self
+    }
+
+    pub fn stack_size(mut self, bytes: u32) -> CmdSpec {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    pub fn record_align(mut self, record_align: bool) -> CmdSpec {
+        self.record_align = record_align;
+        self
+    }
+}
+
+// Pad the group with zeroes to a whole number of `granularity` bytes
+// and return its length in paragraphs
+fn pad_group(name: &str, data: &mut Vec<u8>, granularity: usize) -> Result<u16> {
+    data.resize(data.len().div_ceil(granularity) * granularity, 0);
+    match u16::try_from(data.len() / PARAGRAPH_SIZE) {
+        Ok(paragraphs) => Ok(paragraphs),
+        Err(_) => anyhow::bail!("{} group is {} bytes, a group can be at most {} bytes", name, data.len(), MAX_GROUP_SIZE),
+    }
+}
+
+// The base of a group is a paragraph number within the 1MB address space
+fn base_paragraph(name: &str, address: &Option<u32>) -> Result<u16> {
+    let address = address.unwrap_or(0);
+    match u16::try_from(address / PARAGRAPH_SIZE as u32) {
+        Ok(paragraph) => Ok(paragraph),
+        Err(_) => anyhow::bail!("{} load address {:#x} is above the 1MB address space", name, address),
+    }
+}
+
+/// The .CMD-file for the spec, header and groups
+pub fn build(spec: CmdSpec) -> Result<Vec<u8>> {
+
+    // The System Guide only asks for paragraphs, but some loaders read whole records
+    let granularity = if spec.record_align { RECORD_SIZE } else { PARAGRAPH_SIZE };
+
+    // The header, 8 GroupDescriptors and padding
+    let mut header = CmdHeader {
+    groups: [GroupDescriptor {
+            g_form: GForm(0),
+            g_length: 0,
+            a_base: 0,
+            g_min: 0,
+            g_max: 0,
+        }; 8],
+        padding: [0u8; 56],
+    };
+
+    let mut code_data = Vec::new();
+
+    if spec.data.is_none() {
+        // prepend 0x100 empty bytes
+        while code_data.len() != 0x100 {
+            code_data.push(0);
+        }
+    }
+
+    code_data.extend_from_slice(&spec.code);
+
+    let code_paragraphs = pad_group("Code", &mut code_data, granularity)?;
+    let code_a_base = base_paragraph("Code", &spec.load_address)?;
+
+    header.groups[0] = GroupDescriptor {
+        g_form: GForm(GType::Code as u8),
+        g_length: code_paragraphs,
+        a_base: code_a_base,
+        g_min: code_paragraphs,
+        g_max: 0,
+    };
+
+    let mut data_data = Vec::new();
+    if let Some(data) = spec.data {
+        data_data = data;
+
+        let data_paragraphs = pad_group("Data", &mut data_data, granularity)?;
+        let data_a_base = base_paragraph("Data", &spec.data_load_address)?;
+
+        header.groups[1] = GroupDescriptor {
+            g_form: GForm(GType::Data as u8),
+            g_length: data_paragraphs,
+            a_base: data_a_base,
+            g_min: data_paragraphs,
+            g_max: 0,
+        };
+    }
+
+    if let Some(stack_size) = spec.stack_size {
+        // Only memory is reserved for the stack, nothing of it is in the file
+        let stack_paragraphs = match u16::try_from(stack_size.div_ceil(16)) {
+            Ok(paragraphs) if paragraphs > 0 => paragraphs,
+            _ => anyhow::bail!("Stack size {} must be between 1 and {} bytes", stack_size, MAX_GROUP_SIZE),
+        };
+        let slot = header.groups.iter().position(|g| g.g_form.g_type() == GType::Null).unwrap();
+        header.groups[slot] = GroupDescriptor {
+            g_form: GForm(GType::Stack as u8),
+            g_length: 0,
+            a_base: 0,
+            g_min: stack_paragraphs,
+            g_max: stack_paragraphs,
+        };
+    }
+
+    let mut out = Cursor::new(Vec::new());
+    header.write(&mut out)?;
+    let mut out = out.into_inner();
+    out.extend_from_slice(&code_data);
+    out.extend_from_slice(&data_data);
+
+    Ok(out)
+}
+
+/// The header of a .CMD-file
+pub fn read_header(cmd: &[u8]) -> Result<CmdHeader> {
+    if cmd.len() < RECORD_SIZE {
+        anyhow::bail!("File is too short to hold a .CMD header");
+    }
+    Ok(CmdHeader::read(&mut Cursor::new(cmd))?)
+}
+
+//
+// CPM.SYS, the system image the loader reads, as in section 5 of the System Guide.
+// It is a .CMD-file with one code group loaded at a fixed paragraph, in the
+// 8080 model. The code group holds the CCP at 0000h, the BDOS at 0B00h and
+// the BIOS at 2500h, which starts with a vector of 21 jumps. A data group,
+// if there is one, is passed on to the BIOS as it is.
+//
+
+pub const CCP_OFFSET: usize = 0x0000;
+pub const BDOS_OFFSET: usize = 0x0b00;
+pub const BIOS_OFFSET: usize = 0x2500;
+
+/// The BIOS jump vector entries in order, each a 3-byte jump
+pub const BIOS_ENTRIES: [&str; 21] = [
+    "INIT", "WBOOT", "CONST", "CONIN", "CONOUT", "LIST", "PUNCH", "READER",
+    "HOME", "SELDSK", "SETTRK", "SETSEC", "SETDMA", "READ", "WRITE", "LISTST",
+    "SECTRAN", "SETDMAB", "GETSEGB", "GETIOB", "SETIOB",
+];
+
+/// One of the CCP, BDOS and BIOS in the code group
+#[derive(Debug, Clone, Copy)]
+pub struct SystemModule {
+    pub name: &'static str,
+    /// Offset in the code group
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// A parsed CPM.SYS
+#[derive(Debug, Clone)]
+pub struct SystemImage {
+    pub header: CmdHeader,
+    /// Paragraph the code group is loaded at
+    pub load_segment: u16,
+    /// The code group, without the header
+    pub code: Vec<u8>,
+    /// What follows the code group in the file, the data group if any
+    pub rest: Vec<u8>,
+}
+
+// Target of the jump at `offset` in the code group: JMP near or short
+fn jump_target(code: &[u8], offset: usize) -> Option<usize> {
+    match code.get(offset..offset + 3)? {
+        [0xe9, lo, hi] => Some((offset + 3).wrapping_add(u16::from_le_bytes([*lo, *hi]) as i16 as isize as usize) & 0xffff),
+        [0xeb, rel, _] => Some((offset + 2).wrapping_add(*rel as i8 as isize as usize) & 0xffff),
+        _ => None,
+    }
+}
+
+impl SystemImage {
+    pub fn modules(&self) -> [SystemModule; 3] {
+        [
+            SystemModule { name: "CCP", offset: CCP_OFFSET, size: BDOS_OFFSET - CCP_OFFSET },
+            SystemModule { name: "BDOS", offset: BDOS_OFFSET, size: BIOS_OFFSET - BDOS_OFFSET },
+            SystemModule { name: "BIOS", offset: BIOS_OFFSET, size: self.code.len() - BIOS_OFFSET },
+        ]
+    }
+
+    /// Name and target offset in the code group of each BIOS entry,
+    /// None for entries that are not a jump
+    pub fn bios_entries(&self) -> Vec<(&'static str, Option<usize>)> {
+        BIOS_ENTRIES.iter().enumerate()
+            .map(|(i, &name)| (name, jump_target(&self.code, BIOS_OFFSET + i * 3)))
+            .collect()
+    }
+
+    /// The code of one module
+    pub fn module(&self, name: &str) -> Option<&[u8]> {
+        let module = self.modules().into_iter().find(|m| m.name.eq_ignore_ascii_case(name))?;
+        self.code.get(module.offset..module.offset + module.size)
+    }
+
+    /// The system with another BIOS, assembled to run at 2500h. The code
+    /// group grows or shrinks with it, its load segment stays.
+    pub fn with_bios(&self, bios: &[u8]) -> Result<SystemImage> {
+        let mut code = self.code[..BIOS_OFFSET].to_vec();
+        code.extend_from_slice(bios);
+        let paragraphs = pad_group("Code", &mut code, PARAGRAPH_SIZE)?;
+        let mut header = self.header.clone();
+        let code_group = &mut header.groups[0];
+        code_group.g_length = paragraphs;
+        code_group.g_min = code_group.g_min.max(paragraphs);
+        let system = SystemImage { header, load_segment: self.load_segment, code, rest: self.rest.clone() };
+        if system.bios_entries().iter().any(|(_, target)| target.is_none()) {
+            anyhow::bail!("The new BIOS does not start with a vector of {} jumps", BIOS_ENTRIES.len());
+        }
+        Ok(system)
+    }
+
+    /// The CPM.SYS file
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut out = Cursor::new(Vec::new());
+        self.header.write(&mut out)?;
+        let mut out = out.into_inner();
+        out.extend_from_slice(&self.code);
+        out.extend_from_slice(&self.rest);
+        Ok(out)
+    }
+}
+
+/// Parse a CPM.SYS, failing for .CMD-files that are not a CP/M-86 system
+pub fn read_system(file: &[u8]) -> Result<SystemImage> {
+    let header = read_header(file)?;
+    // Types are checked raw, other files may have any nibble there
+    let code_group = &header.groups[0];
+    if code_group.g_form.raw() & 0x0f != GType::Code as u8 {
+        anyhow::bail!("The first group is not a code group");
+    }
+    if code_group.a_base == 0 {
+        anyhow::bail!("The code group is relocatable, a system image loads at a fixed paragraph");
+    }
+    let code_len = code_group.g_length as usize * PARAGRAPH_SIZE;
+    if code_len < BIOS_OFFSET + BIOS_ENTRIES.len() * 3 {
+        anyhow::bail!("The code group is {} bytes, too short to hold a BIOS at {:04X}h", code_len, BIOS_OFFSET);
+    }
+    let code = match file.get(RECORD_SIZE..RECORD_SIZE + code_len) {
+        Some(code) => code.to_vec(),
+        None => anyhow::bail!("The code group needs {} bytes but the file has {}", code_len, file.len() - RECORD_SIZE),
+    };
+    let system = SystemImage {
+        load_segment: code_group.a_base,
+        header: header.clone(),
+        code,
+        rest: file[RECORD_SIZE + code_len..].to_vec(),
+    };
+    let jumps = system.bios_entries().iter().filter(|(_, target)| target.is_some()).count();
+    if jumps < BIOS_ENTRIES.len() {
+        anyhow::bail!("Only {} of the {} BIOS entries at {:04X}h are jumps", jumps, BIOS_ENTRIES.len(), BIOS_OFFSET);
+    }
+    Ok(system)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_sizes() {
+        assert_eq!(pad_group("Code", &mut vec![0u8; 1], PARAGRAPH_SIZE).unwrap(), 1);
+        assert_eq!(pad_group("Code", &mut vec![0u8; MAX_GROUP_SIZE], PARAGRAPH_SIZE).unwrap(), 0xffff);
+        assert!(pad_group("Code", &mut vec![0u8; MAX_GROUP_SIZE + 1], PARAGRAPH_SIZE).is_err());
+        // Padding to records reaches 64K paragraphs before the data does
+        assert!(pad_group("Code", &mut vec![0u8; MAX_GROUP_SIZE], RECORD_SIZE).is_err());
+        assert_eq!(pad_group("Code", &mut vec![0u8; 0x10000 * PARAGRAPH_SIZE - RECORD_SIZE], RECORD_SIZE).unwrap(), 0xfff8);
+    }
+
+    #[test]
+    fn load_addresses() {
+        assert_eq!(base_paragraph("Code", &None).unwrap(), 0);
+        assert_eq!(base_paragraph("Code", &Some(0xffff0)).unwrap(), 0xffff);
+        assert!(base_paragraph("Code", &Some(0x100000)).is_err());
+        assert!(base_paragraph("Code", &Some(u32::MAX)).is_err());
+    }
+
+    #[test]
+    fn oversized_specs() {
+        // The 8080 model puts 0x100 bytes in front of the code
+        assert!(build(CmdSpec::new(vec![0u8; MAX_GROUP_SIZE - 0x100])).is_ok());
+        assert!(build(CmdSpec::new(vec![0u8; MAX_GROUP_SIZE - 0xff])).is_err());
+        assert!(build(CmdSpec::new(vec![0u8; 16]).data(vec![0u8; MAX_GROUP_SIZE + 1])).is_err());
+        assert!(build(CmdSpec::new(vec![0u8; 16]).load_address(0x100000)).is_err());
+        assert!(build(CmdSpec::new(vec![0u8; 16]).data(vec![0u8; 16]).data_load_address(0x100000)).is_err());
+        assert!(build(CmdSpec::new(vec![0u8; 16]).stack_size(0)).is_err());
+        assert!(build(CmdSpec::new(vec![0u8; 16]).stack_size(MAX_GROUP_SIZE as u32 + 1)).is_err());
+
+        let cmd = build(CmdSpec::new(vec![0u8; 16]).stack_size(MAX_GROUP_SIZE as u32)).unwrap();
+        let header = read_header(&cmd).unwrap();
+        assert_eq!(header.groups[1].g_min, 0xffff);
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::Result;
+
+// Page relocatable files, .PRL and the resident system processes (.RSP)
+// of MP/M that use the same layout:
+//   a 256 byte header, byte 1-2 code length and byte 4-5 extra memory,
+//   the code assembled for 0000h,
+//   a bitmap with one bit per code byte, MSB first, set for the bytes
+//   that hold a page number and get the load page added.
+
+const HEADER_SIZE: usize = 0x100;
+
+#[derive(Debug, Clone)]
+pub struct Prl {
+    pub code: Vec<u8>,
+    pub bitmap: Vec<u8>,
+    /// Bytes needed after the code, for uninitialized data and stack
+    pub extra: u16,
+}
+
+impl Prl {
+    /// Build the bitmap from the same program assembled at 0000h and 0100h,
+    /// the usual way since assemblers don't write relocation information.
+    pub fn from_two_origins(low: &[u8], high: &[u8], extra: u16) -> Result<Prl> {
+        if low.len() != high.len() {
+            anyhow::bail!("The two assemblies differ in length, {} and {} bytes", low.len(), high.len());
+        }
+        if u16::try_from(low.len()).is_err() {
+            anyhow::bail!("Code is {} bytes, a page relocatable file can hold at most {}", low.len(), u16::MAX);
+        }
+
+        let mut bitmap = vec![0u8; low.len().div_ceil(8)];
+        for (i, (&l, &h)) in low.iter().zip(high).enumerate() {
+            if l == h {
+                continue;
+            }
+            if h.wrapping_sub(l) != 1 {
+                anyhow::bail!("Byte {:#06x} is {:02x} and {:02x}, relocated bytes must differ by one page", i, l, h);
+            }
+            bitmap[i / 8] |= 0x80 >> (i % 8);
+        }
+
+        Ok(Prl { code: low.to_vec(), bitmap, extra })
+    }
+
+    pub fn read(data: &[u8]) -> Result<Prl> {
+        if data.len() < HEADER_SIZE {
+            anyhow::bail!("File is too short to hold a page relocatable header");
+        }
+        let code_len = u16::from_le_bytes([data[1], data[2]]) as usize;
+        let extra = u16::from_le_bytes([data[4], data[5]]);
+        let bitmap_len = code_len.div_ceil(8);
+        let end = HEADER_SIZE + code_len + bitmap_len;
+        if data.len() < end {
+            anyhow::bail!("File is {} bytes, the header says {} bytes of code and bitmap", data.len(), code_len + bitmap_len);
+        }
+
+        Ok(Prl {
+            code: data[HEADER_SIZE..HEADER_SIZE + code_len].to_vec(),
+            bitmap: data[HEADER_SIZE + code_len..end].to_vec(),
+            extra,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![0u8; HEADER_SIZE];
+        out[1..3].copy_from_slice(&(self.code.len() as u16).to_le_bytes());
+        out[4..6].copy_from_slice(&self.extra.to_le_bytes());
+        out.extend_from_slice(&self.code);
+        out.extend_from_slice(&self.bitmap);
+        out
+    }
+
+    /// Offsets of the bytes that get relocated
+    pub fn relocations(&self) -> Vec<usize> {
+        (0..self.code.len()).filter(|&i| self.bitmap[i / 8] & (0x80 >> (i % 8)) != 0).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // A jump and a word pointer, the high bytes are page numbers
+        let low = [0xe9, 0x05, 0x00, 0x90, 0x90, 0x10, 0x02, 0x00, 0xc3];
+        let mut high = low;
+        high[2] += 1;
+        high[7] += 1;
+        let prl = Prl::from_two_origins(&low, &high, 0x40).unwrap();
+        assert_eq!(prl.relocations(), [2, 7]);
+        assert_eq!(prl.bitmap, [0x21, 0x00]);
+
+        let bytes = prl.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + low.len() + 2);
+        let read = Prl::read(&bytes).unwrap();
+        assert_eq!(read.code, low);
+        assert_eq!(read.bitmap, prl.bitmap);
+        assert_eq!(read.extra, 0x40);
+        assert_eq!(read.to_bytes(), bytes);
+
+        assert!(Prl::read(&bytes[..bytes.len() - 1]).is_err());
+        high[3] += 2;
+        assert!(Prl::from_two_origins(&low, &high, 0).is_err());
+    }
+}
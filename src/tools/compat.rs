@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::Result;
+use crate::cpmimg::{self, DiskSize};
+use crate::imagefile::ImageOptions;
+
+// Checking this tool against cpmtools, which most people use to read CP/M
+// disks on other systems. cpmtools can't describe side 1 counting down, so
+// it is given a linear copy of the image with the allocation blocks in
+// order after the boot tracks, and a diskdefs file for the geometry in use.
+// Files written here are read with cpmls and cpmcp, and files written with
+// cpmcp are read here. Any difference is a bug in one of the tools, most
+// likely in the geometry.
+
+const DISKDEF_NAME: &str = "cpm86tools";
+
+/// Name and size of the files written to the test image, chosen to cover
+/// one record, one block, a full extent and files over several extents
+const TEST_FILES: [(&str, usize); 6] = [
+    ("0:RECORD.BIN", 128),
+    ("0:BLOCK.BIN", 2048),
+    ("0:EXTENT.BIN", 16384),
+    ("0:OVER.BIN", 16512),
+    ("0:LARGE.BIN", 40960),
+    ("3:USER3.TXT", 3000),
+];
+
+// Contents that differ between files and between records of a file
+fn test_data(seed: usize, size: usize) -> Vec<u8> {
+    let mut data: Vec<u8> = (0..size).map(|i| ((i / 128) * 31 + i * 7 + seed * 13) as u8).collect();
+    data.resize(size.div_ceil(128) * 128, 0x1a);
+    data
+}
+
+fn diskdef() -> String {
+    let g = cpmimg::geometry();
+    format!("diskdef {}\n  seclen {}\n  tracks {}\n  sectrk {}\n  blocksize {}\n  maxdir {}\n  skew {}\n  boottrk {}\n  os 2.2\nend\n",
+        DISKDEF_NAME, g.bytes_per_sector, g.tracks * g.sides, g.sectors_per_track, g.block_size, g.dir_entries(), g.skew, g.reserved_tracks * g.sides)
+}
+
+/// The image with the boot tracks first and then the blocks in order,
+/// the way cpmtools reads a disk with one side
+pub fn to_linear(image: &[u8]) -> Result<Vec<u8>> {
+    let g = cpmimg::geometry();
+    let start = g.catalog_offset() as usize;
+    let mut linear = image[..start.min(image.len())].to_vec();
+    linear.resize(start + g.max_blocks() * g.block_size, g.fill_byte);
+    for al in 0..g.max_blocks() {
+        let offset = g.block_offset(al as u16)? as usize;
+        let end = (offset + g.block_size).min(image.len());
+        if offset < end {
+            let to = start + al * g.block_size;
+            linear[to..to + end - offset].copy_from_slice(&image[offset..end]);
+        }
+    }
+    Ok(linear)
+}
+
+/// The other way around, a linear image back in the layout of this tool
+pub fn from_linear(linear: &[u8]) -> Result<Vec<u8>> {
+    let g = cpmimg::geometry();
+    let start = g.catalog_offset() as usize;
+    let mut image = linear[..start.min(linear.len())].to_vec();
+    image.resize(g.total_size(), g.fill_byte);
+    for al in 0..g.max_blocks() {
+        let offset = g.block_offset(al as u16)? as usize;
+        let from = start + al * g.block_size;
+        if from < linear.len() {
+            let end = (from + g.block_size).min(linear.len());
+            image[offset..offset + end - from].copy_from_slice(&linear[from..end]);
+        }
+    }
+    Ok(image)
+}
+
+fn run(dir: &Path, program: &str, args: &[&str]) -> Result<String> {
+    let output = match Command::new(program).args(args).current_dir(dir).output() {
+        Ok(output) => output,
+        Err(e) => anyhow::bail!("Could not run {}: {}", program, e),
+    };
+    if !output.status.success() {
+        anyhow::bail!("{} {} failed: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn is_installed(program: &str) -> bool {
+    Command::new(program).arg("-h").output().is_ok()
+}
+
+// cpmls lists the names in lower case under a "user:" line per user
+fn parse_cpmls(listing: &str) -> Vec<String> {
+    let mut user = 0;
+    let mut names = Vec::new();
+    for line in listing.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        match line.strip_suffix(':').and_then(|u| u.parse().ok()) {
+            Some(u) => user = u,
+            None => names.push(format!("{}:{}", user, line.to_uppercase())),
+        }
+    }
+    names.sort();
+    names
+}
+
+// "0:NAME.TYP" as cpmcp wants it
+fn cpmtools_name(cpm_file_name: &str) -> String {
+    cpm_file_name.to_lowercase()
+}
+
+/// Differences between this tool and cpmtools, in both directions
+fn compare(dir: &Path) -> Result<Vec<String>> {
+    // The images are plain files made here, whatever the command line says
+    let options = &ImageOptions::default();
+    let mut problems = Vec::new();
+    std::fs::write(dir.join("diskdefs"), diskdef())?;
+
+    // Written here, read by cpmtools
+    let image = dir.join("written.img");
+    let image_path = image.to_string_lossy().into_owned();
+    cpmimg::create_image(&image_path, &DiskSize::K640)?;
+    let mut expected = BTreeMap::new();
+    for (seed, (name, size)) in TEST_FILES.iter().enumerate() {
+        let data = test_data(seed, *size);
+        cpmimg::write_file(&image_path, options, name, &data)?;
+        expected.insert(name.to_string(), data);
+    }
+    std::fs::write(dir.join("written.lin"), to_linear(&std::fs::read(&image)?)?)?;
+
+    let listed = parse_cpmls(&run(dir, "cpmls", &["-f", DISKDEF_NAME, "written.lin"])?);
+    let names: Vec<String> = expected.keys().cloned().collect();
+    if listed != names {
+        problems.push(format!("cpmls lists {}, written were {}", listed.join(" "), names.join(" ")));
+    }
+    for (name, data) in &expected {
+        let out = dir.join("out.bin");
+        run(dir, "cpmcp", &["-f", DISKDEF_NAME, "written.lin", &cpmtools_name(name), "out.bin"])?;
+        let read = std::fs::read(&out)?;
+        if &read != data {
+            problems.push(format!("cpmcp reads {} as {} bytes that differ from the {} written", name, read.len(), data.len()));
+        }
+    }
+
+    // Written by cpmtools, read here
+    run(dir, "mkfs.cpm", &["-f", DISKDEF_NAME, "cpmtools.lin"])?;
+    for (seed, (name, size)) in TEST_FILES.iter().enumerate() {
+        std::fs::write(dir.join("in.bin"), test_data(seed, *size))?;
+        run(dir, "cpmcp", &["-f", DISKDEF_NAME, "cpmtools.lin", "in.bin", &cpmtools_name(name)])?;
+    }
+    let converted = dir.join("cpmtools.img");
+    std::fs::write(&converted, from_linear(&std::fs::read(dir.join("cpmtools.lin"))?)?)?;
+    let mut read = BTreeMap::new();
+    cpmimg::read_all_files(&converted.to_string_lossy(), options, &mut |info, data| {
+        read.insert(format!("{}:{}.{}", info.user_number, info.filename, info.filetype), data);
+        Ok(())
+    })?;
+    if read.keys().ne(expected.keys()) {
+        problems.push(format!("Files written by cpmcp are read as {}", read.keys().cloned().collect::<Vec<_>>().join(" ")));
+    }
+    for (name, data) in &expected {
+        if let Some(contents) = read.get(name) && contents != data {
+            problems.push(format!("{} written by cpmcp is read as {} bytes that differ from the {} written", name, contents.len(), data.len()));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Compare with cpmtools if cpmls, cpmcp and mkfs.cpm are installed.
+/// Prints the differences, true if there are none or cpmtools is missing.
+pub fn check_compat(keep: bool) -> Result<bool> {
+    if let Some(missing) = ["cpmls", "cpmcp", "mkfs.cpm"].into_iter().find(|program| !is_installed(program)) {
+        println!("{} is not installed, skipping the comparison with cpmtools", missing);
+        return Ok(true);
+    }
+
+    let dir: PathBuf = std::env::temp_dir().join(format!("cpm86tools-compat-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let result = compare(&dir);
+    if keep {
+        println!("Images and diskdefs are kept in {}", dir.display());
+    } else {
+        std::fs::remove_dir_all(&dir)?;
+    }
+
+    let problems = result?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("cpmtools and this tool agree on all {} files in both directions", TEST_FILES.len());
+    }
+    Ok(problems.is_empty())
+}
@@ -0,0 +1,106 @@
+use std::collections::HashSet;
+use std::io::{BufRead, IsTerminal, Write};
+use anyhow::Result;
+use clap::ValueEnum;
+use crate::cpmimg::{self, SortKey};
+use crate::imagefile::ImageOptions;
+
+// What to do when a file being imported has the name of a file already in
+// the image. Every import of many files asks here, so the prompt and the
+// policies are the same for all of them. Asking needs a terminal, without
+// one a name that is taken fails that file as it always did.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Ask for each file, fail when not run from a terminal
+    Ask,
+    /// Replace the file in the image
+    Overwrite,
+    /// Keep the file in the image and leave out the new one
+    Skip,
+    /// Store the new file under a free name, GAME.CMD as GAME1.CMD
+    Rename,
+    /// Fail the file
+    Fail,
+}
+
+/// Where a file goes after its name was checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// Store under this User:Name.Type, replacing a file of that name if `replace`
+    Store { cpm_file_name: String, replace: bool },
+    Skip,
+}
+
+/// Settles name collisions of one import, remembering an answer given for all files
+pub struct Resolver {
+    policy: ConflictPolicy,
+}
+
+// GAME.CMD as GAME1.CMD, LONGNAME.TXT as LONGNAM1.TXT, up to 99
+fn free_name(user: u8, filename: &str, filetype: &str, taken: &HashSet<String>) -> Result<String> {
+    for n in 1..100 {
+        let suffix = n.to_string();
+        let stem: String = filename.chars().take(8 - suffix.len()).collect();
+        let candidate = format!("{}:{}{}.{}", user, stem, suffix, filetype);
+        if !taken.contains(&candidate) {
+            return Ok(candidate);
+        }
+    }
+    anyhow::bail!("No free name for {}:{}.{}", user, filename, filetype)
+}
+
+impl Resolver {
+    pub fn new(policy: ConflictPolicy) -> Resolver {
+        let policy = match policy {
+            ConflictPolicy::Ask if !std::io::stdin().is_terminal() => ConflictPolicy::Fail,
+            policy => policy,
+        };
+        Resolver { policy }
+    }
+
+    fn ask(&mut self, cpm_file_name: &str) -> Result<ConflictPolicy> {
+        loop {
+            eprint!("{} is already in the image. [o]verwrite, [s]kip, [r]ename, capital for all files, [q]uit? ", cpm_file_name);
+            std::io::stderr().flush()?;
+            let mut answer = String::new();
+            if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+                anyhow::bail!("No answer for {}", cpm_file_name);
+            }
+            let choice = match answer.trim() {
+                "o" | "O" => ConflictPolicy::Overwrite,
+                "s" | "S" => ConflictPolicy::Skip,
+                "r" | "R" => ConflictPolicy::Rename,
+                "q" | "Q" => anyhow::bail!("Stopped at {}", cpm_file_name),
+                _ => continue,
+            };
+            if answer.trim().chars().all(|c| c.is_ascii_uppercase()) {
+                self.policy = choice;
+            }
+            return Ok(choice);
+        }
+    }
+
+    /// Where to store `cpm_file_name` in the image
+    pub fn resolve(&mut self, image_path: &str, options: &ImageOptions, cpm_file_name: &str) -> Result<Resolution> {
+        let (user, filename, filetype) = cpmimg::split_cpm_file_name(cpm_file_name)?;
+        let name = format!("{}:{}.{}", user, filename, filetype);
+        let taken: HashSet<String> = cpmimg::file_infos(image_path, options, SortKey::Name, false)?.iter()
+            .map(|f| format!("{}:{}.{}", f.user_number, f.filename, f.filetype))
+            .collect();
+        if !taken.contains(&name) {
+            return Ok(Resolution::Store { cpm_file_name: cpm_file_name.to_string(), replace: false });
+        }
+
+        let policy = match self.policy {
+            ConflictPolicy::Ask => self.ask(&name)?,
+            policy => policy,
+        };
+        match policy {
+            ConflictPolicy::Overwrite => Ok(Resolution::Store { cpm_file_name: cpm_file_name.to_string(), replace: true }),
+            ConflictPolicy::Skip => Ok(Resolution::Skip),
+            ConflictPolicy::Rename => Ok(Resolution::Store { cpm_file_name: free_name(user, &filename, &filetype, &taken)?, replace: false }),
+            ConflictPolicy::Ask | ConflictPolicy::Fail => anyhow::bail!("File {} already exists in image", name),
+        }
+    }
+}
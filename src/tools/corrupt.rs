@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use anyhow::Result;
+use crate::cpmimg;
+use crate::durable;
+use crate::imagefile::{ImageOptions, irregular_sectors, open_image};
+use crate::imd;
+
+// Damaged copies of good images, to try the recovery features on.
+// The source image is never changed.
+
+/// What to do to the copy
+#[derive(Debug, Clone, Default)]
+pub struct Damage {
+    /// Number of random bits to flip
+    pub flip_bits: usize,
+    /// Sectors to make unreadable, as (cylinder, head, sector)
+    pub kill_sectors: Vec<(usize, usize, u8)>,
+    /// Seed for the bit positions, random if not given
+    pub seed: Option<u64>,
+}
+
+// xorshift64*, plenty for picking bit positions
+// and the same sequence for the same seed everywhere
+struct Positions(u64);
+
+impl Positions {
+    fn next(&mut self, below: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d) % below
+    }
+}
+
+fn is_imd_path(path: &str) -> bool {
+    path.to_lowercase().ends_with(".imd")
+}
+
+/// Write a damaged copy of the image to `output_path`. Killed sectors are
+/// only unreadable in an IMD copy (a name ending in .imd), a plain image
+/// can't say that, so there they are filled with 00.
+pub fn corrupt_image(image_path: &str, options: &ImageOptions, output_path: &str, damage: &Damage) -> Result<()> {
+    let layout = cpmimg::geometry().track_layout();
+    let mut disk = open_image(image_path, false, options)?;
+    let mut image = Vec::new();
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_to_end(&mut image)?;
+    if image.is_empty() {
+        anyhow::bail!("{} is empty", image_path);
+    }
+
+    for &(cylinder, head, sector) in &damage.kill_sectors {
+        let inside = cylinder < layout.cylinders && head < layout.heads
+            && sector >= layout.first_sector && ((sector - layout.first_sector) as usize) < layout.sectors;
+        if !inside {
+            anyhow::bail!("Sector {}:{}:{} is not on the disk", cylinder, head, sector);
+        }
+    }
+
+    if damage.flip_bits > 0 {
+        let seed = match damage.seed {
+            Some(seed) => seed,
+            None => {
+                let mut bytes = [0u8; 8];
+                getrandom::fill(&mut bytes).map_err(|e| anyhow::anyhow!("No random numbers for the seed: {}", e))?;
+                u64::from_le_bytes(bytes)
+            }
+        };
+        // xorshift never leaves 0
+        let mut positions = Positions(seed.max(1));
+        for _ in 0..damage.flip_bits {
+            let bit = positions.next(image.len() as u64 * 8);
+            image[(bit / 8) as usize] ^= 1 << (bit % 8);
+            println!("Flipped bit {} of byte {:#x}", bit % 8, bit / 8);
+        }
+        println!("Seed {}, give it with --seed to flip the same bits again", seed);
+    }
+
+    // Copy protection sectors of a container source are kept where the output can hold them
+    let irregular = irregular_sectors(image_path)?;
+    let output = if is_imd_path(output_path) {
+        let dropped = irregular.iter().filter(|s| s.id.size != layout.sector_size).count();
+        if dropped > 0 {
+            eprintln!("Warning: {} sectors of another size than {} bytes can't be kept in an IMD track", dropped, layout.sector_size);
+        }
+        // Images that stop before the end of the last track read as formatted
+        image.resize(image.len().max(layout.cylinders * layout.heads * layout.sectors * layout.sector_size), cpmimg::geometry().fill_byte);
+        imd::encode_imd(&image, &layout, &damage.kill_sectors, &irregular, &format!("damaged copy of {}", image_path))?
+    } else {
+        if !irregular.is_empty() {
+            eprintln!("Warning: {} irregular sectors of {} can't be kept in a plain image, write an .imd to keep them", irregular.len(), image_path);
+        }
+        for &(cylinder, head, sector) in &damage.kill_sectors {
+            let offset = layout.image_offset(cylinder, head, sector);
+            if let Some(contents) = image.get_mut(offset..offset + layout.sector_size) {
+                contents.fill(0);
+            }
+        }
+        image
+    };
+    for &(cylinder, head, sector) in &damage.kill_sectors {
+        println!("Killed sector {}:{}:{}", cylinder, head, sector);
+    }
+
+    let mut out = File::create(output_path)?;
+    out.write_all(&output)?;
+    durable::sync_file(&out)?;
+    durable::sync_dir_of(std::path::Path::new(output_path))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpmimg::CopyOutOptions;
+    use crate::cpmimg::tests::{Scratch, blank_image, lock_globals, store, test_data};
+    use crate::flux::TrackLayout;
+    use crate::repair;
+    use super::*;
+
+    const FILE_NAME: &str = "0:DATA.BIN";
+
+    // A 640K image with one file of three blocks, stored from the first block after the directory
+    fn image_with_file(scratch: &Scratch) -> (String, Vec<u8>) {
+        let mut disk = blank_image();
+        let data = test_data(7, 3 * cpmimg::geometry().block_size);
+        store(&mut disk, FILE_NAME, &data, false).unwrap();
+        (scratch.save("good.img", disk), data)
+    }
+
+    // Cylinder, head and sector at an image offset
+    fn sector_at(offset: u64) -> (usize, usize, u8) {
+        let layout = TrackLayout::COMPIS;
+        let sector = offset as usize / layout.sector_size;
+        let track = sector / layout.sectors;
+        (track / layout.heads, track % layout.heads, (sector % layout.sectors) as u8 + layout.first_sector)
+    }
+
+    fn copy_out(image_path: &str, output_path: &str, salvage: bool) -> Result<Vec<u8>> {
+        let copy = CopyOutOptions { salvage, ..CopyOutOptions::default() };
+        cpmimg::copy_file_out(image_path, &ImageOptions::default(), FILE_NAME, output_path, &copy)?;
+        Ok(std::fs::read(output_path)?)
+    }
+
+    #[test]
+    fn killed_sector_is_salvaged() {
+        let _globals = lock_globals();
+        let scratch = Scratch::new("corrupt-salvage");
+        let (image_path, data) = image_with_file(&scratch);
+        let geometry = cpmimg::geometry();
+        let first_block = geometry.block_offset(geometry.dir_blocks as u16).unwrap();
+        // The second sector of the file
+        let killed = first_block + 512;
+        let damaged = scratch.path("damaged.imd");
+        let damage = Damage { kill_sectors: vec![sector_at(killed)], ..Damage::default() };
+        corrupt_image(&image_path, &ImageOptions::default(), &damaged, &damage).unwrap();
+
+        let error = copy_out(&damaged, &scratch.path("plain.bin"), false).unwrap_err();
+        assert!(error.to_string().contains("--salvage"), "{}", error);
+
+        let salvaged = copy_out(&damaged, &scratch.path("salvaged.bin"), true).unwrap();
+        assert_eq!(salvaged.len(), data.len());
+        assert_eq!(salvaged[..512], data[..512]);
+        assert!(salvaged[512..1024].iter().all(|&b| b == geometry.fill_byte));
+        assert_eq!(salvaged[1024..], data[1024..]);
+    }
+
+    #[test]
+    fn killed_directory_is_repaired() {
+        let _globals = lock_globals();
+        let scratch = Scratch::new("corrupt-directory");
+        let (image_path, _) = image_with_file(&scratch);
+        let damaged = scratch.path("damaged.img");
+        let damage = Damage { kill_sectors: vec![sector_at(cpmimg::geometry().catalog_offset())], ..Damage::default() };
+        corrupt_image(&image_path, &ImageOptions::default(), &damaged, &damage).unwrap();
+
+        let mut disk = open_image(&damaged, false, &ImageOptions::default()).unwrap();
+        assert!(!repair::find_repairs(disk.as_mut()).unwrap().is_empty());
+        let mut disk = open_image(&image_path, false, &ImageOptions::default()).unwrap();
+        assert!(repair::find_repairs(disk.as_mut()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn same_seed_same_damage() {
+        let _globals = lock_globals();
+        let scratch = Scratch::new("corrupt-seed");
+        let (image_path, _) = image_with_file(&scratch);
+        let good = std::fs::read(&image_path).unwrap();
+        let damage = Damage { flip_bits: 16, seed: Some(1985), ..Damage::default() };
+        let (first, second) = (scratch.path("first.img"), scratch.path("second.img"));
+        corrupt_image(&image_path, &ImageOptions::default(), &first, &damage).unwrap();
+        corrupt_image(&image_path, &ImageOptions::default(), &second, &damage).unwrap();
+
+        let (first, second) = (std::fs::read(first).unwrap(), std::fs::read(second).unwrap());
+        assert_eq!(first, second);
+        let changed = good.iter().zip(&first).filter(|(a, b)| a != b).count();
+        assert!((1..=16).contains(&changed), "{} bytes changed", changed);
+        // The source is never changed
+        assert_eq!(std::fs::read(&image_path).unwrap(), good);
+    }
+}
@@ -0,0 +1,2634 @@
+
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use clap::{ValueEnum};
+use serde::{Deserialize, Serialize};
+use crate::durable;
+use crate::events::{Event, Observer};
+use crate::flux::{self, TrackFormat, TrackLayout};
+use crate::imagefile::{self, ImageFile, ImageOptions, open_image};
+use crate::output;
+use crate::quota;
+use crate::texttype;
+
+/// Physical layout of a floppy format and where CP/M keeps its data on it.
+/// Every offset in the image is derived from this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Geometry {
+    pub sides: usize,
+    pub tracks: usize,
+    pub sectors_per_track: usize,
+    pub bytes_per_sector: usize,
+    pub block_size: usize,
+    pub dir_blocks: usize,
+    /// Tracks before the directory, on both sides, holding the boot loader
+    pub reserved_tracks: usize,
+    /// Byte the formatter writes to every sector. Unwritten blocks and
+    /// directory entries read as this, 0xE5 on COMPIS disks but 0x00 or
+    /// 0xF6 on disks formatted by other tools.
+    pub fill_byte: u8,
+    /// Sector skew of an image in physical sector order, like skew in a
+    /// cpmtools diskdef. 0 for images in logical order, as COMPIS images are.
+    pub skew: usize,
+}
+
+impl Geometry {
+    // empirically tested with copydisk, and repeated usage of pip to fill a large disk image
+    // data equal to or above 0xa0000 is never touched
+    pub const COMPIS: Geometry = Geometry {
+        sides: 2,
+        tracks: 80,
+        sectors_per_track: 8,
+        bytes_per_sector: 512,
+        block_size: 16*128, // 16: 128 Byte Records / Block $800 bytes
+        dir_blocks: 2,
+        reserved_tracks: 1,
+        fill_byte: 0xe5,
+        skew: 0,
+    };
+
+    /// Laid out like a COMPIS disk, whatever the fill byte and sector order
+    pub fn is_compis_layout(&self) -> bool {
+        Geometry { fill_byte: Geometry::COMPIS.fill_byte, skew: Geometry::COMPIS.skew, ..*self } == Geometry::COMPIS
+    }
+
+    pub const fn track_size(&self) -> usize {
+        self.sectors_per_track * self.bytes_per_sector
+    }
+
+    /// The tracks of a disk of this geometry, for decoding and writing
+    /// flux and IMD containers
+    pub fn track_layout(&self) -> TrackLayout {
+        TrackLayout {
+            cylinders: self.tracks,
+            heads: self.sides,
+            sectors: self.sectors_per_track,
+            sector_size: self.bytes_per_sector,
+            ..TrackLayout::COMPIS
+        }
+    }
+
+    pub const fn total_size(&self) -> usize {
+        self.tracks * self.track_size() * self.sides
+    }
+
+    /// The directory starts right after the reserved tracks
+    pub const fn catalog_offset(&self) -> u64 {
+        (self.reserved_tracks * self.track_size() * self.sides) as u64
+    }
+
+    /// Where allocation block 0 starts. The directory is the first blocks
+    /// of the data area, so this is the same as the catalog offset.
+    pub const fn data_offset(&self) -> u64 {
+        self.catalog_offset()
+    }
+
+    /// Allocation blocks on each side outside the reserved tracks
+    pub const fn blocks_per_side(&self) -> usize {
+        (self.tracks - self.reserved_tracks) * self.track_size() / self.block_size
+    }
+
+    /// Bytes in the directory
+    pub const fn dir_size(&self) -> usize {
+        self.dir_blocks * self.block_size
+    }
+
+    /// Directory entries, DRM + 1 in the disk parameter block. Can be more
+    /// than 255 on large formats, so slots are always counted in usize.
+    pub const fn dir_entries(&self) -> usize {
+        self.dir_size() / 32
+    }
+
+    /// Low bits of EX that count the 16K logical extents before the last one
+    /// in a directory entry. Zero when an entry holds 16K, as on COMPIS disks.
+    pub const fn extent_mask(&self) -> u8 {
+        (self.block_size * BLOCKS_PER_ENTRY / (128 * 128) - 1) as u8
+    }
+
+    /// Fail for geometries the directory code can't handle
+    pub fn validate(&self) -> Result<()> {
+        if !self.block_size.is_power_of_two() || !(2048..=16384).contains(&self.block_size) {
+            anyhow::bail!("Block size must be 2048, 4096, 8192 or 16384, not {}", self.block_size);
+        }
+        if self.sides == 0 || self.sectors_per_track == 0 || self.bytes_per_sector == 0 {
+            anyhow::bail!("Sides, sectors per track and bytes per sector must not be 0");
+        }
+        if self.reserved_tracks >= self.tracks {
+            anyhow::bail!("{} boot tracks leave no room for data on a disk of {} tracks", self.reserved_tracks, self.tracks);
+        }
+        // The tracks of a cylinder alternate between the heads in the image, so a block can't span tracks
+        if self.sides > 1 && !self.track_size().is_multiple_of(self.block_size) {
+            anyhow::bail!("Blocks of {} bytes don't fit tracks of {} bytes, a track must hold whole blocks", self.block_size, self.track_size());
+        }
+        if self.sides == 1 && !self.track_size().is_multiple_of(self.block_size) && !self.block_size.is_multiple_of(self.track_size()) {
+            anyhow::bail!("Blocks of {} bytes don't fit tracks of {} bytes", self.block_size, self.track_size());
+        }
+        if self.skew >= self.sectors_per_track {
+            anyhow::bail!("A skew of {} is not below the {} sectors of a track", self.skew, self.sectors_per_track);
+        }
+        if !(1..=16).contains(&self.dir_blocks) {
+            anyhow::bail!("The directory must be 1 to 16 blocks, not {}", self.dir_blocks);
+        }
+        // Up to 256 blocks CP/M uses one byte block numbers, which this tool doesn't read
+        if self.max_blocks() <= 256 {
+            anyhow::bail!("A disk of {} blocks has 8 bit block numbers, only 16 bit block numbers are supported", self.max_blocks());
+        }
+        if self.max_blocks() <= self.dir_blocks {
+            anyhow::bail!("The directory takes all {} blocks of the disk", self.max_blocks());
+        }
+        Ok(())
+    }
+
+    /// Physical position in a data track of each logical sector, the way
+    /// cpmtools makes its table: every skew-th sector, or the next free one
+    pub fn skew_table(&self) -> Vec<usize> {
+        let sectors = self.sectors_per_track;
+        let mut used = vec![false; sectors];
+        (0..sectors).map(|logical| {
+            let mut physical = logical * self.skew.max(1) % sectors;
+            while used[physical] {
+                physical = (physical + 1) % sectors;
+            }
+            used[physical] = true;
+            physical
+        }).collect()
+    }
+
+    pub const fn max_blocks(&self) -> usize {
+        self.blocks_per_side() * self.sides
+    }
+
+    /// Largest file the format can hold. CP/M-86 counts at most 512 logical
+    /// extents of 16K, 8MB, and a file can't have more blocks than the data
+    /// area or more entries than the directory.
+    pub fn max_file_size(&self) -> usize {
+        let extents = MAX_LOGICAL_EXTENTS * 128 * 128;
+        let blocks = (self.max_blocks() - self.dir_blocks) * self.block_size;
+        let entries = self.dir_entries() * BLOCKS_PER_ENTRY * self.block_size;
+        min(extents, min(blocks, entries))
+    }
+
+    /// Image offset of an allocation block. Side 0 is filled first, counting
+    /// up the cylinders, then side 1 counting down from the last cylinder,
+    /// each track holding a whole number of blocks. On a single sided disk
+    /// the data area is one run of blocks.
+    pub fn block_offset(&self, al: u16) -> Result<u64> {
+        if al as usize >= self.max_blocks() {
+            anyhow::bail!("Block number {:#x} is outside the disk, which has {:#x} blocks", al, self.max_blocks());
+        }
+        if self.sides == 1 {
+            return Ok(self.data_offset() + (al as usize * self.block_size) as u64);
+        }
+        let blocks_per_track = self.track_size() / self.block_size;
+        let head = al as usize / self.blocks_per_side();
+        let on_side = al as usize % self.blocks_per_side();
+        let track = on_side / blocks_per_track;
+        let cylinder = if head == 0 { self.reserved_tracks + track } else { self.tracks - 1 - track };
+        let offset = (cylinder * self.sides + head) * self.track_size() + on_side % blocks_per_track * self.block_size;
+        Ok(offset as u64)
+    }
+}
+
+static GEOMETRY: RwLock<Geometry> = RwLock::new(Geometry::COMPIS);
+
+/// The geometry every image is read and written with, COMPIS unless changed with set_geometry
+pub fn geometry() -> Geometry {
+    *GEOMETRY.read().unwrap()
+}
+
+/// Read and write all images with another geometry, for one-off disks
+/// that are laid out like COMPIS disks but with other numbers
+pub fn set_geometry(geometry: Geometry) -> Result<()> {
+    geometry.validate()?;
+    *GEOMETRY.write().unwrap() = geometry;
+    Ok(())
+}
+
+const DIRENTRY_SIZE: usize = 32; // 128: 32 Byte  Directory Entries
+const BLOCKS_PER_ENTRY: usize = 8; // 16 bit block numbers, 8 in each directory entry
+const MAX_LOGICAL_EXTENTS: usize = 512; // EX 0-31 and S2 0-15
+
+/// A file that is larger than the format can hold, see Geometry::max_file_size.
+/// Front-ends can downcast to it to tell this apart from a full disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileTooLarge {
+    pub cpm_file_name: String,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for FileTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} is {} bytes, files can be at most {} bytes on this format",
+            self.cpm_file_name, output::number(self.size), output::number(self.limit))
+    }
+}
+
+impl std::error::Error for FileTooLarge {}
+
+/// FileTooLarge if a file of `size` bytes can't be stored with the geometry in use
+pub fn check_file_size(cpm_file_name: &str, size: usize) -> Result<()> {
+    // Stored in whole records
+    let limit = geometry().max_file_size();
+    if size.div_ceil(128) * 128 > limit {
+        return Err(FileTooLarge { cpm_file_name: cpm_file_name.to_string(), size, limit }.into());
+    }
+    Ok(())
+}
+
+// Data in the image is stored like this:
+// $0000-$1000 side 0
+// $1000-$2000 side 1
+// $2000-$3000 side 0
+// $3000-$4000 side 1
+// ... and so on
+// When copying to disk with pip
+// side 0 is used first, increasing track number until track 80 is reached
+// then side 1 is used, BUT backwards, decreasing track number
+// The bios (or drive) hides this from CP/M-86 and it is not
+// reflected in the directory structure, AL (allocations) keep increasing 
+
+// If I run => stat dsk:
+// 5,088: 128 Byte Record Capacity
+//   636: Kilobyte Drive Capacity
+//   128: 32 Byte  Directory Entries
+//   128: Checked  Directory Entries
+//   128: 128 Byte Records / Directory Entry
+//    16: 128 Byte Records / Block
+//    32: 128 Byte Records / Track
+//     1: Reserved  Tracks
+//
+
+
+// https://forum.vcfed.org/index.php?threads/more-on-exidy-sorcerer-disk-images.68900/
+
+// John Elliott
+// 30 mars 2022 20:24:47
+// till
+// mkfs.cpm is likely not populating the boot sector. 
+//If you examine it in a hex editor, the last byte (offset 01FFh) 
+// is used by CP/M-86 to determine the capacity. This should be one of:
+// 00h: 160k
+// 01h: 320k
+// 0Ch: 1200k (144FEAT)
+// 10h: 360k (PCP/M-86)
+// 11h: 720k (PCP/M-86)
+// 40h: 360k (PCP/M-86)
+// 48h: 720k (144FEAT)
+// 90h: 1440k (144FEAT)
+
+// 00h: 160k
+// 01h: 320k
+// 0Ch: 1200k (144FEAT)
+// 10h: 360k (PCP/M-86)
+// 11h: 720k (PCP/M-86)
+// 40h: 360k (PCP/M-86)
+// 48h: 720k (144FEAT)
+// 90h: 1440k (144FEAT)
+
+pub(crate) const DISKSIZE_OFFSET: usize = 0x1ff;
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum DiskSize {
+    #[clap(name = "160K")]
+    K160,
+    #[clap(name = "320K")]
+    K320,
+    #[clap(name = "1200K")]
+    K1200,
+    #[clap(name = "360K")]
+    K360,
+    #[clap(name = "720K")]
+    K720,
+    #[clap(name = "360K2")]
+    K360_2,
+    #[clap(name = "720K2")]
+    K720_2,
+    #[clap(name = "1440K")]
+    K1440,
+    #[clap(name = "640K")]
+    K640,
+}
+
+impl DiskSize {
+    /// Returnerar ett hexvärde (kan vara typiskt för DPB, media descriptor byte etc.)
+    pub(crate) fn hex_value(&self) -> u8 {
+        match self {
+            DiskSize::K160  => 0x00,
+            DiskSize::K320  => 0x01,
+            DiskSize::K1200  => 0x0c,
+            DiskSize::K360  => 0x10,
+            DiskSize::K720  => 0x11,
+            DiskSize::K360_2  => 0x40,
+            DiskSize::K720_2  => 0x48,
+            DiskSize::K1440  => 0x90,
+            DiskSize::K640 => 0xe5, // Don't know what to write here, 
+        }
+    }
+
+    pub(crate) fn num_bytes(&self) -> usize {
+        match self {
+            DiskSize::K160  => 160*1024,
+            DiskSize::K320  => 320*1024,
+            DiskSize::K1200  => 1200*1024,
+            DiskSize::K360  => 360*1024,
+            DiskSize::K720  => 720*1024,
+            DiskSize::K360_2  => 360*1024,
+            DiskSize::K720_2  => 720*1024,
+            DiskSize::K1440  => 1440*1024,
+            DiskSize::K640 => 636*1024,
+        }
+    }
+}
+
+/// Sort order for file listings.
+/// Ties are always broken by name, type, user and directory index,
+/// so the output is the same no matter how the directory is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortKey {
+    /// Position of the first extent in the directory (the order DIR uses)
+    Index,
+    /// Filename, then type
+    Name,
+    /// File size in bytes
+    Size,
+    /// User number
+    User,
+    /// Filetype, then filename
+    Extension,
+}
+
+/// A date stamp in the CP/M encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CpmDate {
+    pub day: u16,    // Day 1 = 1 Jan 1978
+    pub hour: u8,    // BCD
+    pub minute: u8,  // BCD
+}
+
+// Days from 1 Jan 1970 to 1 Jan 1978, two leap years
+const DAYS_1970_TO_1978: u64 = 8 * 365 + 2;
+
+fn from_bcd(value: u8) -> Option<u64> {
+    let (high, low) = (value >> 4, value & 0x0f);
+    if high > 9 || low > 9 {
+        return None;
+    }
+    Some((high * 10 + low) as u64)
+}
+
+/// Days since 1 Jan 1970 to year, month and day, after Howard Hinnant's civil_from_days
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl CpmDate {
+    /// As host time, None if the hour or minute is not valid BCD.
+    /// CP/M has no time zones, the stamp is taken as UTC.
+    pub fn to_system_time(&self) -> Option<SystemTime> {
+        let hour = from_bcd(self.hour).filter(|&h| h < 24)?;
+        let minute = from_bcd(self.minute).filter(|&m| m < 60)?;
+        let days = DAYS_1970_TO_1978 + self.day.checked_sub(1)? as u64;
+        let seconds = days * 86400 + hour * 3600 + minute * 60;
+        Some(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    /// ISO 8601 date and time without a zone, like 1985-03-02T14:30,
+    /// None if the stamp is not valid
+    pub fn to_iso8601(&self) -> Option<String> {
+        let seconds = self.to_system_time()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+        let minutes = seconds % 86400 / 60;
+        Some(format!("{:04}-{:02}-{:02}T{:02}:{:02}", year, month, day, minutes / 60, minutes % 60))
+    }
+
+    /// The stamp as stored, day number since 1978 and BCD hour and minute,
+    /// which is what to compare with the machine when the ISO form looks wrong
+    pub fn to_raw(&self) -> String {
+        format!("{:>5} {:02x}:{:02x}", self.day, self.hour, self.minute)
+    }
+}
+
+/// Date stamps for a file, from the stamp entry following every third directory entry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Timestamps {
+    pub created: Option<CpmDate>,
+    pub modified: Option<CpmDate>,
+    pub accessed: Option<CpmDate>,
+}
+
+/// File attributes, stored in the MSB of T1, T2 and T3
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attributes {
+    pub readonly: bool,
+    pub system: bool,
+    pub archived: bool,
+}
+
+/// Everything known about a file in an image, computed once from its extents
+#[derive(Debug, Clone, Serialize)]
+pub struct FileInfo {
+    pub user_number: u8,
+    pub filename: String,
+    pub filetype: String,
+    pub directory_index: usize,  // first directory entry used by the file
+    pub blocks: Vec<u16>,        // allocation blocks in file order
+    pub records: usize,          // 128 byte records
+    pub size: usize,             // records * 128
+    pub exact_size: Option<usize>, // when the last record byte count (S1) is set
+    pub extent_count: usize,
+    pub fragmented: bool,        // blocks are not consecutive
+    pub attributes: Attributes,
+    pub timestamps: Option<Timestamps>,
+}
+
+/// File transfer protocols for sending files over a serial line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Protocol {
+    /// XMODEM, checksum or CRC as the receiver asks for
+    Xmodem,
+}
+
+#[derive(Debug, Clone)]
+struct DirEntry {
+    directory_entry_idx: usize, // Index/Row in directory
+    user_number: u8,       // UU
+    filename: String,       // F1..F8
+    filetype: String,       // T1..T3
+    extent: u8,             // EX, low byte
+    s2: u8,                 // S2, hi byte
+    s1: u8,                 // reserved
+    record_count: u8,       // RC
+    allocation: Vec<u16>,   // AL-list (block numbers)
+    readonly: bool,
+    system: bool,
+    archived: bool,
+    entry_number: u16,
+    timestamps: Option<Timestamps>,
+}
+
+impl DirEntry {
+    pub fn extent_size(&self) -> usize {
+        self.records() * 128
+    }
+
+    /// True if the entry holds all the records its blocks have room for
+    pub fn is_full_extent(&self) -> bool {
+        let mask = geometry().extent_mask();
+        self.record_count >= 0x80 && self.extent & mask == mask
+    }
+
+    /// Records in the entry. With large blocks an entry holds several 16K
+    /// logical extents, all full but the last, which has RC records.
+    pub fn records(&self) -> usize {
+        (self.extent & geometry().extent_mask()) as usize * 128 + min(self.record_count as usize, 0x80)
+    }
+
+    pub fn write_to_file(&self, file: &mut dyn ImageFile) -> Result<()> {
+        let mut buf: Vec<u8> = Vec::new();
+
+        buf.push(self.user_number);
+
+        // Names are kept trimmed, pad them to 8.3 again
+        for c in format!("{:<8}", self.filename).chars() {
+            buf.push((c as u8) & 0x7F);
+        }
+
+        for c in format!("{:<3}", self.filetype).chars() {
+            buf.push((c as u8) & 0x7F);
+        }
+
+        // MSB of T1, T2 and T3 are the attributes
+        if self.readonly {
+            buf[9] |= 0x80;
+        }
+        if self.system {
+            buf[10] |= 0x80;
+        }
+        if self.archived {
+            buf[11] |= 0x80;
+        }
+
+        // Extent
+        buf.push(self.extent);
+        buf.push(self.s1);
+        buf.push(self.s2);
+        buf.push(self.record_count);
+        
+        for al in self.allocation.iter() {
+            let bytes = al.to_le_bytes();
+            buf.push(bytes[0]);
+            buf.push(bytes[1]);
+        }
+
+        if buf.len() > DIRENTRY_SIZE {
+            anyhow::bail!("Directroy entry is to large for {}:{}.{} at {}",self.user_number, self.filename, self.filetype, self.directory_entry_idx);
+        }
+
+        while buf.len() < DIRENTRY_SIZE {
+            buf.push(0);
+        }
+
+        let offset = geometry().catalog_offset() + self.directory_entry_idx as u64 * DIRENTRY_SIZE as u64;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&buf)?;
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self) {
+        self.user_number = 0xe5;
+    }
+
+}
+
+#[derive(Debug, Clone)]
+struct FileEntry {
+    first_directory_entry_idx: usize,
+    user_number: u8,
+    filename: String,
+    filetype: String,
+    readonly: bool,
+    system: bool,
+    archived: bool,
+    extents: Vec<DirEntry>,   // all extents for the file
+}
+
+impl FileEntry {
+    pub fn file_size(&self) -> usize {
+        self.extents.iter().map(|e| e.extent_size()).sum()
+    }
+
+    pub fn blocks(&self) -> Vec<u16> {
+        self.extents.iter().flat_map(|e| e.allocation.iter().copied()).collect()
+    }
+
+    pub fn info(&self) -> FileInfo {
+        let blocks = self.blocks();
+        let records: usize = self.extents.iter().map(|e| e.records()).sum();
+        let size = self.file_size();
+        // CP/M 3 keeps the number of bytes used in the last record in S1, 0 means all 128
+        let exact_size = match self.extents.last() {
+            Some(last) if last.s1 > 0 && last.s1 < 0x80 && size > 0 => Some(size - 128 + last.s1 as usize),
+            _ => None,
+        };
+        let fragmented = blocks.windows(2).any(|w| w[1] != w[0] + 1);
+
+        FileInfo {
+            user_number: self.user_number,
+            filename: self.filename.trim_end().to_string(),
+            filetype: self.filetype.trim_end().to_string(),
+            directory_index: self.first_directory_entry_idx,
+            blocks,
+            records,
+            size,
+            exact_size,
+            extent_count: self.extents.len(),
+            fragmented,
+            attributes: Attributes {
+                readonly: self.readonly,
+                system: self.system,
+                archived: self.archived,
+            },
+            // The stamps belong to the first extent
+            timestamps: self.extents.first().and_then(|e| e.timestamps),
+        }
+    }
+
+    pub fn write_to_file(&self, file: &mut dyn ImageFile) -> Result<()> {
+        for entry in self.extents.iter() {
+            entry.write_to_file(file)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete(&mut self) {
+        self.user_number = 0xe5;
+        for extent in &mut self.extents {
+            extent.delete();
+        }
+    }
+
+    pub fn rename(&mut self, user_number: u8, filename: &str, filetype: &str) {
+        self.user_number = user_number;
+        self.filename = filename.to_string();
+        self.filetype = filetype.to_string();
+        for extent in &mut self.extents {
+            extent.user_number = user_number;
+            extent.filename = filename.to_string();
+            extent.filetype = filetype.to_string();
+        }
+    }
+
+    // Attributes are stored in every extent, keep them all the same
+    pub fn set_attributes(&mut self, attributes: Attributes) {
+        self.readonly = attributes.readonly;
+        self.system = attributes.system;
+        self.archived = attributes.archived;
+        for extent in &mut self.extents {
+            extent.readonly = attributes.readonly;
+            extent.system = attributes.system;
+            extent.archived = attributes.archived;
+        }
+    }
+}
+
+fn read_directory(disk: &mut dyn ImageFile) -> Result<Vec<u8>> {
+    disk.seek(SeekFrom::Start(geometry().catalog_offset()))?;
+    let mut buffer = vec![0u8; geometry().dir_size()];
+    disk.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn warn_unreadable_directory(disk: &dyn ImageFile) {
+    if let Some(bad_sectors) = disk.bad_sectors() {
+        let bad = bad_sectors.bad_in(geometry().catalog_offset(), geometry().dir_size() as u64);
+        if !bad.is_empty() {
+            eprintln!("Warning: {} directory sectors are unreadable, files may be missing from the listing", bad.len());
+        }
+    }
+}
+
+// Some formatters fill the directory with 0 or their own fill byte instead
+// of 0xE5. A used entry always has a name, so an entry that is all zero or
+// all fill is empty.
+pub(crate) fn is_blank_entry(entry: &[u8]) -> bool {
+    let fill_byte = geometry().fill_byte;
+    entry[0] != 0xe5 && (entry.iter().all(|&b| b == 0) || entry.iter().all(|&b| b == fill_byte))
+}
+
+fn count_blank_entries(disk: &mut dyn ImageFile) -> Result<usize> {
+    let buffer = read_directory(disk)?;
+    Ok(buffer.chunks_exact(DIRENTRY_SIZE).take(geometry().dir_entries()).filter(|e| is_blank_entry(e)).count())
+}
+
+// Every fourth entry of a date stamped directory is an SFCB with user number
+// 0x21 that holds the stamps for the three entries before it, 10 bytes each
+// starting at byte 1: create or access stamp (4 bytes), update stamp (4 bytes),
+// password mode and a reserved byte. A stamp is a day number and BCD hour and
+// minute. Whether the first stamp is create or access is set in the label.
+const TIMESTAMP_USER: u8 = 0x21;
+const SFCB_STAMP_SIZE: usize = 10;
+const LABEL_ACCESS_STAMPS: u8 = 0x40; // in the EX byte of the label
+
+// Entries that belong to the directory but are not files: the disk label
+// (user 0x20), date stamps (0x21), password XFCBs (user 16 to 31 when user
+// numbers stop at 15) and kinds this tool doesn't know. They are never shown
+// as files, their slots are never reused and they are kept byte for byte.
+pub(crate) fn is_special_entry(entry: &[u8]) -> bool {
+    entry[0] != 0xe5 && entry[0] > MAX_USER.load(Ordering::Relaxed) && !is_blank_entry(entry)
+}
+
+const LABEL_USER: u8 = 0x20;
+
+/// The disk label as NAME.TYP, None if the directory has no label entry
+pub fn disk_label(image_path: &str, options: &ImageOptions) -> Result<Option<String>> {
+    let mut disk = open_image(image_path, false, options)?;
+    let buffer = read_directory(disk.as_mut())?;
+    let label = buffer.chunks_exact(DIRENTRY_SIZE).take(geometry().dir_entries())
+        .find(|entry| entry[0] == LABEL_USER)
+        .map(|entry| {
+            let field = |bytes: &[u8]| bytes.iter().map(|&b| (b & 0x7f) as char).collect::<String>().trim().to_string();
+            match field(&entry[9..12]) {
+                filetype if filetype.is_empty() => field(&entry[1..9]),
+                filetype => format!("{}.{}", field(&entry[1..9]), filetype),
+            }
+        });
+    Ok(label)
+}
+
+// Slots of the special entries
+fn special_entries(buffer: &[u8]) -> Vec<usize> {
+    buffer.chunks_exact(DIRENTRY_SIZE).take(geometry().dir_entries()).enumerate()
+        .filter(|(_, entry)| is_special_entry(entry))
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+fn read_date(bytes: &[u8]) -> Option<CpmDate> {
+    let day = u16::from_le_bytes([bytes[0], bytes[1]]);
+    if day == 0 {
+        return None;
+    }
+    let (hour, minute) = if bytes.len() >= 4 { (bytes[2], bytes[3]) } else { (0, 0) };
+    Some(CpmDate { day, hour, minute })
+}
+
+// True if the label says the first stamp of an SFCB is the access stamp
+fn stamps_access(buffer: &[u8]) -> bool {
+    buffer.chunks_exact(DIRENTRY_SIZE).take(geometry().dir_entries())
+        .find(|entry| entry[0] == LABEL_USER)
+        .is_some_and(|label| label[12] & LABEL_ACCESS_STAMPS != 0)
+}
+
+fn read_timestamps(buffer: &[u8], idx: usize, access: bool) -> Option<Timestamps> {
+    let stamp_idx = idx | 3;
+    if stamp_idx == idx {
+        return None;
+    }
+    let stamp = &buffer[stamp_idx * DIRENTRY_SIZE..(stamp_idx + 1) * DIRENTRY_SIZE];
+    if stamp[0] != TIMESTAMP_USER {
+        return None;
+    }
+    let offset = 1 + (idx & 3) * SFCB_STAMP_SIZE;
+    let first = read_date(&stamp[offset..offset + 4]);
+    let timestamps = Timestamps {
+        created: if access { None } else { first },
+        modified: read_date(&stamp[offset + 4..offset + 8]),
+        accessed: if access { first } else { None },
+    };
+    if timestamps == Timestamps::default() {
+        return None;
+    }
+    Some(timestamps)
+}
+
+fn read_catalog(disk: &mut dyn ImageFile) -> Result<Vec<DirEntry>> {
+    let buffer = read_directory(disk)?;
+    warn_unreadable_directory(disk);
+    Ok(parse_catalog(&buffer))
+}
+
+/// "0:NAME.TYP" of the file each allocation block belongs to, for the I/O trace
+pub(crate) fn block_owners(disk: &mut dyn ImageFile) -> Result<HashMap<u16, String>> {
+    let buffer = read_directory(disk)?;
+    let mut owners = HashMap::new();
+    for entry in parse_catalog(&buffer) {
+        let name = format!("{}:{}.{}", entry.user_number, entry.filename, entry.filetype);
+        for al in entry.allocation {
+            owners.insert(al, name.clone());
+        }
+    }
+    Ok(owners)
+}
+
+fn parse_catalog(buffer: &[u8]) -> Vec<DirEntry> {
+    let mut catalog = Vec::new();
+    let access = stamps_access(buffer);
+
+    for idx in 0..geometry().dir_entries() {
+        let offset = idx * 32; // directory entry = 32 byte
+        let entry = &buffer[offset..offset + 32];
+
+        // User number = 0xE5 => empty directory entry
+        let user_number = entry[0];
+        if user_number == 0xE5 || is_special_entry(entry) || is_blank_entry(entry) {
+            continue;
+        }
+
+        let filename = String::from_utf8_lossy(&entry[1..9]).trim().to_string();
+
+        // MSB is used as flag for readonly and system/hidden
+        let t1 = entry[9];
+        let readonly = t1 & 0x80 != 0;
+        let system = entry[10] & 0x80 != 0;
+        let archived = entry[11] & 0x80 != 0;
+
+        let extent = entry[12]; // EX
+        let s1 = entry[13];
+        let s2: u8 = entry[14];     // S2
+        let record_count = entry[15];
+
+        let entry_number = (32 * s2 as u16) + extent as u16;
+
+        let filetype: String = entry[9..12]
+            .iter()
+            .map(|b| (b & 0x7F) as char) // Remove MSB
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let mut allocation = Vec::new();
+        let al_bytes = &entry[16..32]; // 16 byte AL
+
+        for chunk in al_bytes.chunks_exact(2) {
+            let lo = chunk[0] as u16;
+            let hi = chunk[1] as u16;
+            let block = (hi << 8) | lo;
+            if block != 0 {
+                allocation.push(block);
+            }
+        }
+
+        catalog.push(DirEntry {
+            directory_entry_idx: idx,
+            user_number,
+            filename,
+            filetype,
+            extent,
+            s2,
+            s1,
+            record_count,
+            allocation,
+            readonly,
+            system,
+            archived,
+            entry_number,
+            timestamps: read_timestamps(buffer, idx, access),
+        });
+    }
+
+    catalog
+}
+
+fn merge_extents(entries: Vec<DirEntry>) -> Vec<FileEntry> {
+    let mut files: HashMap<(u8, String, String), FileEntry> = HashMap::new();
+
+    for entry in entries {
+        let key = (entry.user_number, entry.filename.clone(), entry.filetype.clone());
+        let file = files
+            .entry(key.clone())
+            .or_insert(FileEntry {
+                first_directory_entry_idx: entry.directory_entry_idx,
+                user_number: entry.user_number,
+                filename: entry.filename.clone(),
+                filetype: entry.filetype.clone(),
+                readonly: false,
+                system: false,
+                archived: false,
+                extents: Vec::new(),
+            });
+        file.first_directory_entry_idx = min(entry.directory_entry_idx,file.first_directory_entry_idx);
+        if entry.readonly {
+            // Set readonly if any entry has readonly
+            file.readonly = true;
+        }
+        if entry.system {
+            // Set system if any entry has system
+            file.system = true;
+        }
+        if entry.archived {
+            file.archived = true;
+        }
+        file.extents.push(entry);
+    }
+
+    let mut file_list: Vec<FileEntry> = files.into_values().collect();
+    file_list.sort_by_key(|f| f.first_directory_entry_idx);
+
+    for item in &mut file_list {
+        item.extents.sort_by_key(|extent| extent.entry_number);
+    }
+
+    file_list
+}
+
+fn sort_files(files: &mut [FileEntry], key: SortKey, reverse: bool) {
+    // sort_by is stable, and the tie breakers make the order total anyway
+    files.sort_by(|a, b| {
+        let by_name = a.filename.cmp(&b.filename)
+            .then_with(|| a.filetype.cmp(&b.filetype))
+            .then_with(|| a.user_number.cmp(&b.user_number))
+            .then_with(|| a.first_directory_entry_idx.cmp(&b.first_directory_entry_idx));
+        let ordering = match key {
+            SortKey::Index => a.first_directory_entry_idx.cmp(&b.first_directory_entry_idx),
+            SortKey::Name => by_name,
+            SortKey::Size => a.file_size().cmp(&b.file_size()).then(by_name),
+            SortKey::User => a.user_number.cmp(&b.user_number).then(by_name),
+            SortKey::Extension => a.filetype.cmp(&b.filetype).then(by_name),
+        };
+        if reverse { ordering.reverse() } else { ordering }
+    });
+}
+
+/// Highest user number CP/M-86 uses
+pub const MAX_USER_NUMBER: u8 = 15;
+/// Highest user number some systems allow, the directory has room for 0 to 31
+pub const EXTENDED_MAX_USER_NUMBER: u8 = 31;
+
+static MAX_USER: AtomicU8 = AtomicU8::new(MAX_USER_NUMBER);
+
+/// Allow user numbers up to `max_user` in file names, 15 or 31
+pub fn set_max_user_number(max_user: u8) -> Result<()> {
+    if max_user != MAX_USER_NUMBER && max_user != EXTENDED_MAX_USER_NUMBER {
+        anyhow::bail!("Highest user number must be {} or {}, not {}", MAX_USER_NUMBER, EXTENDED_MAX_USER_NUMBER, max_user);
+    }
+    MAX_USER.store(max_user, Ordering::Relaxed);
+    Ok(())
+}
+
+static FORCE: AtomicBool = AtomicBool::new(false);
+
+/// Allow changes to files whose entries point into the directory, for --force
+pub fn set_force(force: bool) {
+    FORCE.store(force, Ordering::Relaxed);
+}
+
+/// Blocks of a file that are part of the directory, which only a damaged
+/// entry has. Reading them gives directory entries, not file data.
+pub fn directory_blocks(blocks: &[u16]) -> Vec<u16> {
+    blocks.iter().copied().filter(|&al| (al as usize) < geometry().dir_blocks).collect()
+}
+
+fn block_list(blocks: &[u16]) -> String {
+    blocks.iter().map(|al| format!("{:#x}", al)).collect::<Vec<_>>().join(", ")
+}
+
+// Renaming or replacing a file like that writes through a damaged entry
+fn check_directory_blocks(file_entry: &FileEntry, cpm_file_name: &str) -> Result<()> {
+    let blocks = directory_blocks(&file_entry.blocks());
+    if !blocks.is_empty() && !FORCE.load(Ordering::Relaxed) {
+        anyhow::bail!("File {} has blocks {} in the directory area, its entry is damaged. Use --force to change it anyway, or delete it",
+            cpm_file_name, block_list(&blocks));
+    }
+    Ok(())
+}
+
+/// "0:NAME.TYP" to user number, name and type, upper case.
+/// Each field is trimmed on its own, so blank padded names like
+/// "0:FOO     .C  " from DIR output or fixed width scripts work too.
+pub fn split_cpm_file_name(cpm_file_name: &str) -> Result<(u8, String, String)> {
+    let (user_part, name_part) = match cpm_file_name.split_once(':') {
+        Some(parts) => parts,
+        None => anyhow::bail!("Invalid format, expected user:filename.filetype {}", cpm_file_name),
+    };
+    if name_part.contains(':') {
+        anyhow::bail!("Invalid format, more than one ':' in {}", cpm_file_name);
+    }
+    let (name, filetype) = match name_part.split_once('.') {
+        Some(parts) => parts,
+        None => anyhow::bail!("Invalid format, expected user:filename.filetype {}", cpm_file_name),
+    };
+    if filetype.contains('.') {
+        anyhow::bail!("Invalid format, more than one '.' in {}", cpm_file_name);
+    }
+
+    let user_part = user_part.trim();
+    if user_part.is_empty() {
+        anyhow::bail!("User number missing in {}, expected user:filename.filetype", cpm_file_name);
+    }
+    if !user_part.chars().all(|c| c.is_ascii_digit()) {
+        anyhow::bail!("User number '{}' in {} is not a number", user_part, cpm_file_name);
+    }
+    let max_user = MAX_USER.load(Ordering::Relaxed);
+    let user = match user_part.parse::<u8>() {
+        Ok(user) if user <= max_user => user,
+        _ => anyhow::bail!("User number {} in {} is out of range, must be 0 to {}", user_part, cpm_file_name, max_user),
+    };
+    let filename = name.trim().to_uppercase();
+    let filetype = filetype.trim().to_uppercase();
+
+    if filename.is_empty() {
+        anyhow::bail!("Filename missing in {}", cpm_file_name);
+    }
+    if filename.contains(char::is_whitespace) || filetype.contains(char::is_whitespace) {
+        anyhow::bail!("Blanks inside the name or type of {}", cpm_file_name);
+    }
+    if filename.len() > 8 || filetype.len() > 3 {
+        anyhow::bail!("Filename too long {}", cpm_file_name);
+    }
+
+    Ok((user,filename,filetype))
+}
+
+// A name or type field padded with blanks, '*' fills the rest with '?' like the CCP does
+fn expand_wildcard_field(field: &str, width: usize) -> Result<Vec<char>> {
+    let mut expanded = Vec::new();
+    for c in field.trim().to_uppercase().chars() {
+        if c == '*' {
+            expanded.resize(width, '?');
+            break;
+        }
+        expanded.push(c);
+    }
+    if expanded.len() > width {
+        anyhow::bail!("Filename too long {}", field);
+    }
+    expanded.resize(width, ' ');
+    Ok(expanded)
+}
+
+/// Files matching a CP/M wildcard like "0:*.COM" or "*:FOO?.*",
+/// '?' matches one character, '*' the rest of the field and a user of '*' every user
+pub fn match_file_pattern<'a>(files: &'a [FileInfo], pattern: &str) -> Result<Vec<&'a FileInfo>> {
+    let (user_part, name_part) = match pattern.split_once(':') {
+        Some(parts) => parts,
+        None => anyhow::bail!("Invalid pattern, expected user:filename.filetype {}", pattern),
+    };
+    let user = match user_part.trim() {
+        "*" => None,
+        user_part => match user_part.parse::<u8>() {
+            Ok(user) => Some(user),
+            Err(_) => anyhow::bail!("User number '{}' in {} is not a number or *", user_part, pattern),
+        },
+    };
+    let (name, filetype) = name_part.split_once('.').unwrap_or((name_part, ""));
+    let name = expand_wildcard_field(name, 8)?;
+    let filetype = expand_wildcard_field(filetype, 3)?;
+
+    let field_matches = |wanted: &[char], actual: &str, width: usize| {
+        let mut actual: Vec<char> = actual.to_uppercase().chars().collect();
+        actual.resize(width, ' ');
+        wanted.iter().zip(&actual).all(|(w, a)| *w == '?' || w == a)
+    };
+    Ok(files.iter()
+        .filter(|f| user.is_none_or(|user| f.user_number == user))
+        .filter(|f| field_matches(&name, &f.filename, 8) && field_matches(&filetype, &f.filetype, 3))
+        .collect())
+}
+
+fn get_file_entry<'a>(files: &'a [FileEntry], cpm_file_name: &str) -> Result<Option<&'a FileEntry>> {
+
+    let (user,filename, filetype) = split_cpm_file_name(cpm_file_name)?;
+
+    let file_entry = files.iter().find(|f| {
+        f.user_number == user &&
+        f.filename.to_uppercase() == filename &&
+        f.filetype.to_uppercase() == filetype
+    });
+
+    Ok(file_entry)
+}
+
+fn allocation_to_offset(al: u16) -> Result<u64> {
+    geometry().block_offset(al)
+}
+
+/// Random access to the contents of one file in an image.
+/// Only the blocks that are actually read are touched.
+pub struct CpmFileReader<'a> {
+    disk: &'a mut dyn ImageFile,
+    blocks: Vec<u16>,
+    size: u64,
+    pos: u64,
+}
+
+impl<'a> CpmFileReader<'a> {
+    fn new(disk: &'a mut dyn ImageFile, file_entry: &FileEntry) -> CpmFileReader<'a> {
+        CpmFileReader {
+            disk,
+            blocks: file_entry.blocks(),
+            size: file_entry.file_size() as u64,
+            pos: 0,
+        }
+    }
+
+    /// File size, rounded up to whole records
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for CpmFileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.size {
+            return Ok(0);
+        }
+        let block_idx = (self.pos / geometry().block_size as u64) as usize;
+        let within = (self.pos % geometry().block_size as u64) as usize;
+        let block = match self.blocks.get(block_idx) {
+            Some(&block) => block,
+            None => return Ok(0),
+        };
+        let len = min(buf.len(), min(geometry().block_size - within, (self.size - self.pos) as usize));
+        if (block as usize) < geometry().dir_blocks {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("block {:#x} of the file is in the directory area", block)));
+        }
+
+        let offset = allocation_to_offset(block).map_err(std::io::Error::other)?;
+        self.disk.seek(SeekFrom::Start(offset + within as u64))?;
+        self.disk.read_exact(&mut buf[..len])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for CpmFileReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.size as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start of file"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+// CP/M text files end at the first ^Z, the rest of the last record is padding
+const TEXT_EOF: u8 = 0x1a;
+
+/// How copy_file_out writes the file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyOutOptions {
+    /// Copy even if parts are unreadable or in the directory area
+    pub salvage: bool,
+    /// Set the modification time from the CP/M date stamp
+    pub preserve_times: bool,
+    /// Copy as text, ending where the policy says. Without it every file is
+    /// copied in whole records, ^Z or not.
+    pub eof: Option<TextEof>,
+}
+
+/// Where text in a file ends, for --eof. Some files, like word processor
+/// documents, have ^Z in the middle of the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum TextEof {
+    /// At the first ^Z, the way CP/M programs read text
+    #[default]
+    Stop,
+    /// At the last ^Z in the last record and the ^Z right before it,
+    /// ^Z in the text are kept
+    StripTrailing,
+    /// Nowhere, the padding of the last record is kept too
+    Keep,
+}
+
+// Length of the text in a file of `size` bytes, for the policies that
+// don't look at the whole file. `last_record` is the last 128 bytes.
+fn text_len(size: usize, last_record: &[u8], eof: TextEof) -> usize {
+    match eof {
+        TextEof::Keep | TextEof::Stop => size,
+        TextEof::StripTrailing => {
+            let start = size - last_record.len();
+            match last_record.iter().rposition(|&b| b == TEXT_EOF) {
+                Some(last) => {
+                    // Padding may be all ^Z
+                    let run = last_record[..last].iter().rev().take_while(|&&b| b == TEXT_EOF).count();
+                    start + last - run
+                }
+                None => size,
+            }
+        }
+    }
+}
+
+fn last_record(reader: &mut CpmFileReader) -> Result<Vec<u8>> {
+    let start = reader.size().saturating_sub(128);
+    let mut record = vec![0u8; (reader.size() - start) as usize];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut record)?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(record)
+}
+
+// Offset of the first ^Z, the size of the file if there is none
+fn first_eof(reader: &mut CpmFileReader) -> Result<u64> {
+    let mut pos = 0;
+    let mut record = [0u8; 128];
+    reader.seek(SeekFrom::Start(0))?;
+    loop {
+        let len = reader.read(&mut record)?;
+        if len == 0 {
+            return Ok(pos);
+        }
+        if let Some(eof) = record[..len].iter().position(|&b| b == TEXT_EOF) {
+            return Ok(pos + eof as u64);
+        }
+        pos += len as u64;
+    }
+}
+
+// Read records from the start until `lines` newlines are seen or the text ends.
+// Only --eof strip-trailing needs the last record, the others stop reading forward.
+fn head_lines(reader: &mut CpmFileReader, lines: usize, eof: TextEof) -> Result<Vec<u8>> {
+    let end = match eof {
+        TextEof::StripTrailing => text_len(reader.size() as usize, &last_record(reader)?, eof),
+        TextEof::Stop | TextEof::Keep => reader.size() as usize,
+    };
+    let mut data = Vec::new();
+    let mut newlines = 0;
+    let mut record = [0u8; 128];
+    loop {
+        let len = reader.read(&mut record)?;
+        if len == 0 {
+            return Ok(data);
+        }
+        for &b in &record[..len] {
+            if (b == TEXT_EOF && eof == TextEof::Stop) || newlines == lines || data.len() == end {
+                return Ok(data);
+            }
+            data.push(b);
+            if b == b'\n' {
+                newlines += 1;
+            }
+        }
+    }
+}
+
+// Read records backwards from the end of the text until `lines` complete
+// lines are seen. With --eof stop the text ends at the first ^Z from the
+// start, as in copyout, which takes reading the file up to there.
+fn tail_lines(reader: &mut CpmFileReader, lines: usize, eof: TextEof) -> Result<Vec<u8>> {
+    let mut data: Vec<u8> = Vec::new();
+    // Like tail -n 0
+    if lines == 0 {
+        return Ok(data);
+    }
+    let mut end = match eof {
+        TextEof::Stop => first_eof(reader)?,
+        TextEof::StripTrailing | TextEof::Keep => text_len(reader.size() as usize, &last_record(reader)?, eof) as u64,
+    };
+    while end > 0 {
+        let start = end.saturating_sub(128);
+        let mut record = vec![0u8; (end - start) as usize];
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(&mut record)?;
+        record.extend_from_slice(&data);
+        data = record;
+        end = start;
+
+        // One extra newline is needed to know the first line is complete,
+        // the last line may or may not end with one
+        let body = data.strip_suffix(b"\n").unwrap_or(&data);
+        if body.iter().filter(|&&b| b == b'\n').count() >= lines {
+            break;
+        }
+    }
+
+    let body_len = data.strip_suffix(b"\n").map_or(data.len(), |b| b.len());
+    let mut seen = 0;
+    for i in (0..body_len).rev() {
+        if data[i] == b'\n' {
+            seen += 1;
+            if seen == lines {
+                return Ok(data[i + 1..].to_vec());
+            }
+        }
+    }
+    Ok(data)
+}
+
+// File offsets of the parts of a file stored in sectors the image source could not read
+fn unreadable_parts(file_entry: &FileEntry, disk: &dyn ImageFile) -> Vec<(usize, usize)> {
+    let bad_sectors = match disk.bad_sectors() {
+        Some(bad_sectors) => bad_sectors,
+        None => return Vec::new(),
+    };
+    let mut parts = Vec::new();
+    let total_size = file_entry.file_size();
+    for (i, block) in file_entry.blocks().into_iter().enumerate() {
+        let file_offset = i * geometry().block_size;
+        if file_offset >= total_size {
+            break;
+        }
+        let len = min(geometry().block_size, total_size - file_offset);
+        let block_offset = match allocation_to_offset(block) {
+            Ok(block_offset) => block_offset,
+            Err(_) => continue,
+        };
+        for sector in bad_sectors.bad_in(block_offset, len as u64) {
+            let start = file_offset + sector.saturating_sub(block_offset) as usize;
+            let end = min(file_offset + len, file_offset + (sector + bad_sectors.sector_size - block_offset) as usize);
+            parts.push((start, end - start));
+        }
+    }
+    parts
+}
+
+fn copy_out(files: Vec<FileEntry>, cpm_file_name: &str, disk: &mut dyn ImageFile, out: &mut File, salvage: bool, eof: Option<TextEof>, observer: &Observer) -> Result<()> {
+
+    if let Some(file_entry) = get_file_entry(&files, cpm_file_name)? {
+        let total_size = file_entry.file_size();
+        let mut written: usize = 0;
+
+        let unreadable = unreadable_parts(file_entry, disk);
+        if !unreadable.is_empty() {
+            for (start, len) in &unreadable {
+                observer.warning(format!("{}: bytes {:#x}-{:#x} are in an unreadable sector", cpm_file_name, start, start + len - 1));
+            }
+            if !salvage {
+                anyhow::bail!("File {} has {} unreadable sectors, use --salvage to copy it anyway", cpm_file_name, unreadable.len());
+            }
+            observer.warning(format!("Salvaging {}, unreadable parts are filled with {:02X}", cpm_file_name, geometry().fill_byte));
+        }
+        let in_directory = directory_blocks(&file_entry.blocks());
+        if !in_directory.is_empty() {
+            observer.warning(format!("{}: blocks {} are in the directory area, the entry is damaged", cpm_file_name, block_list(&in_directory)));
+            if !salvage {
+                anyhow::bail!("File {} has blocks in the directory area, use --salvage to copy it with them filled with {:02X}", cpm_file_name, geometry().fill_byte);
+            }
+        }
+
+        observer.emit(Event::FileStarted { name: cpm_file_name.to_string(), size: total_size });
+        // Where the text ends, cut off when the file is complete
+        let mut text_end = None;
+        let mut last = Vec::new();
+        for extent in &file_entry.extents {
+            for &block in &extent.allocation {
+                if block == 0 { continue; }
+                observer.check_cancelled()?;
+                let offset = allocation_to_offset(block)?;
+                disk.seek(SeekFrom::Start(offset))?;
+
+                let remaining = total_size - written;
+                let read_size = min(geometry().block_size, remaining);
+
+                let mut buf = vec![geometry().fill_byte; read_size];
+                if !in_directory.contains(&block) {
+                    disk.read_exact(&mut buf)?;
+                }
+                out.write_all(&buf)?;
+                if eof == Some(TextEof::Stop) && text_end.is_none() {
+                    text_end = buf.iter().position(|&b| b == TEXT_EOF).map(|pos| written + pos);
+                }
+                last = buf;
+
+                written += read_size;
+                observer.emit(Event::BlockRead { block, done: written, total: total_size });
+                if written >= total_size {
+                    break;
+                }
+            }
+            if written >= total_size {
+                break;
+            }
+        }
+        if let Some(eof) = eof {
+            let end = match text_end {
+                Some(end) => end,
+                None => text_len(written, &last[last.len().saturating_sub(128)..], eof),
+            };
+            out.set_len(end as u64)?;
+        }
+
+    } else {
+        anyhow::bail!("File {} not found in image", cpm_file_name);
+    }
+
+    Ok(())
+}
+
+fn copy_in(files: Vec<FileEntry>, cpm_file_name: &str, disk: &mut dyn ImageFile, input: &mut dyn Read, replace: bool, verify: bool, observer: &Observer) -> Result<()> {
+
+    let replacing = match get_file_entry(&files, cpm_file_name)? {
+        Some(_) if !replace => anyhow::bail!("File {} already exists in image", cpm_file_name),
+        existing => existing.cloned(),
+    };
+    if let Some(old) = &replacing {
+        check_directory_blocks(old, cpm_file_name)?;
+    }
+
+    let (user,mut filename, mut filetype) = split_cpm_file_name(cpm_file_name)?;
+    // The new file is NAME.$$$ until the old one is deleted, like PIP does it
+    let temporary_type = if filetype == "$$$" { "$$1" } else { "$$$" };
+    let temporary_name = format!("{}:{}.{}", user, filename, temporary_type);
+    if replacing.is_some() && get_file_entry(&files, &temporary_name)?.is_some() {
+        anyhow::bail!("{} is in the way of replacing {}, rename or delete it first", temporary_name, cpm_file_name);
+    }
+    while filename.len() < 8 {
+        filename.push(' ');
+    }
+    while filetype.len() < 3 {
+        filetype.push(' ');
+    }
+
+    // split the file in blocks
+    let mut file_data = Vec::new();
+    input.read_to_end(&mut file_data)?;
+    let source_len = file_data.len();
+    check_file_size(cpm_file_name, source_len)?;
+    let source_crc = crc32fast::hash(&file_data);
+    // round up file length nearest 128
+    let file_len = file_data.len().div_ceil(128) * 128;
+    // Text ends at a ^Z, and the rest of its last record is ^Z padding
+    // instead of whatever the sectors held before
+    if texttype::is_text(&filetype, &file_data) {
+        file_data.resize(file_len, TEXT_EOF);
+    }
+    let mut blocks: Vec<Vec<u8>> = Vec::new();
+    while !file_data.is_empty() {
+        let chunk_size = std::cmp::min(geometry().block_size, file_data.len());
+        blocks.push(file_data.drain(..chunk_size).collect());
+    }
+    let blocks_needed = blocks.len();
+    // 8 block per DirEntry, an empty file still needs one
+    let entries_needed = max(1, blocks_needed.div_ceil(BLOCKS_PER_ENTRY));
+
+    // Make sure we have enough free entries
+    let mut used_entries = vec![false; geometry().dir_entries()];
+    for f in &files {
+        for e in &f.extents {
+            used_entries[e.directory_entry_idx] = true;
+        }
+    }
+    for idx in special_entries(&read_directory(disk)?) {
+        used_entries[idx] = true;
+    }
+
+    let mut free_entries = Vec::new();
+    for (idx, used) in used_entries.iter().enumerate() {
+        if !used {
+            free_entries.push(idx);
+        }
+    }    
+
+    if free_entries.len() < entries_needed {
+        anyhow::bail!("Not enough free entries in directory. Free: {} Needed: {}", free_entries.len(), entries_needed);
+    }
+
+    // Make sure we have enough free blocks
+    let mut used_blocks = vec![false; geometry().max_blocks()];
+    // the directory blocks are reserved
+    used_blocks[..geometry().dir_blocks].fill(true);
+    for f in &files {
+        for e in &f.extents {
+            for al in &e.allocation {
+                let tmp = *al as usize;
+                if tmp >= used_blocks.len() {
+                    println!("Invalid block number {} for file {}", al, f.filename);
+                    continue;
+                } 
+                used_blocks[tmp] = true;
+            }
+        }
+    }
+
+    let mut free_blocks = Vec::new();
+    for (idx, used) in used_blocks.iter().enumerate() {
+        if !used {
+            free_blocks.push(idx as u16);
+        }
+    }    
+
+    if free_blocks.len() < blocks_needed {
+        anyhow::bail!("Not enough free space on disk. Free: {} KB Needed: {} KB", free_blocks.len() * geometry().block_size / 1024, blocks_needed * geometry().block_size / 1024);
+    }
+
+    // Now create DirEntry and all FileEntry:s
+    let mut file_entries: Vec<DirEntry> = Vec::new();
+    let mut free_block_iter = free_blocks.into_iter();
+    let mut blocks_left = blocks_needed;
+    let mut file_len_left = file_len;
+    for (i, &directory_entry_idx) in free_entries.iter().take(entries_needed).enumerate() {
+        let mut al_list: Vec<u16> = Vec::new();
+        for _ in 0..min(BLOCKS_PER_ENTRY, blocks_left) {
+            if let Some(block) = free_block_iter.next() {
+                al_list.push(block);
+                blocks_left -= 1;
+            }
+        }
+
+        // All entries but the last are full
+        let records = min(file_len_left / 128, BLOCKS_PER_ENTRY * geometry().block_size / 128);
+        file_len_left -= records * 128;
+        // EX counts 16K logical extents, an entry holds extent_mask + 1 of them
+        let logical = records.saturating_sub(1) / 128;
+        let record_count = (records - logical * 128) as u8;
+        let extent_number = i * (geometry().extent_mask() as usize + 1) + logical;
+
+        let entry = DirEntry {
+            directory_entry_idx,
+            user_number: user,
+            filename: filename.clone(),
+            filetype: filetype.clone(),
+            extent: (extent_number & 0x1f) as u8,
+            s2: ((extent_number >> 5) & 0xff) as u8,
+            s1: 0,
+            record_count,
+            allocation: al_list,
+            readonly: false,
+            system: false,
+            archived: false,
+            entry_number: extent_number as u16,
+            timestamps: None,
+        };
+
+        file_entries.push(entry);
+    }
+
+    let mut entry = FileEntry {
+        first_directory_entry_idx: file_entries[0].directory_entry_idx,
+        user_number: file_entries[0].user_number,
+        filename: filename.clone(),
+        filetype: filetype.clone(),
+        readonly: false,
+        system: false,
+        archived: false,
+        extents: file_entries
+    };
+
+    // The data goes to free blocks first and the directory is written last,
+    // with a sync in between. Until the directory entries are written the
+    // blocks are still free as far as CP/M knows, so an interrupted copy
+    // leaves the disk as it was instead of a file pointing at garbage.
+    observer.emit(Event::FileStarted { name: cpm_file_name.to_string(), size: source_len });
+    let mut iter = blocks.into_iter(); 
+    let mut done = 0;
+    for e in &entry.extents {
+        for al in &e.allocation {
+            observer.check_cancelled()?;
+            let offset = allocation_to_offset(*al)?;
+            let block = iter.next().unwrap();
+            disk.seek(SeekFrom::Start(offset))?;
+            disk.write_all(&block)?;
+            done = (done + block.len()).min(source_len);
+            observer.emit(Event::BlockWritten { block: *al, done, total: source_len });
+        }
+    }    
+    disk.sync()?;
+
+    // A replaced file keeps its entries and blocks until the new one is
+    // complete under the temporary name. An interruption leaves the old file,
+    // the old file and NAME.$$$, or only NAME.$$$, never two files of one
+    // name that would be read as one.
+    match replacing {
+        Some(mut old) => {
+            entry.rename(user, &filename, temporary_type);
+            entry.write_to_file(disk)?;
+            disk.sync()?;
+            old.delete();
+            old.write_to_file(disk)?;
+            disk.sync()?;
+            entry.rename(user, &filename, &filetype);
+            entry.write_to_file(disk)?;
+            disk.sync()?;
+        }
+        None => {
+            entry.write_to_file(disk)?;
+            disk.sync()?;
+        }
+    }
+
+    if verify {
+        verify_copy_in(&entry, disk, source_len, source_crc)?;
+    }
+
+    Ok(())
+}
+
+// Read back what copy_in just wrote, both the directory and the data blocks,
+// and compare with the source
+fn verify_copy_in(entry: &FileEntry, disk: &mut dyn ImageFile, source_len: usize, source_crc: u32) -> Result<()> {
+    let name = format!("{}:{}.{}", entry.user_number, entry.filename.trim_end(), entry.filetype.trim_end());
+
+    // Make sure we read from the storage and not only from our own buffers
+    disk.sync()?;
+
+    let files = merge_extents(read_catalog(disk)?);
+    let written = match get_file_entry(&files, &name)? {
+        Some(written) => written,
+        None => anyhow::bail!("Verify failed for {}: file not found in directory after write", name),
+    };
+    let expected = entry.blocks();
+    let actual = written.blocks();
+    if expected != actual {
+        anyhow::bail!("Verify failed for {}: directory allocation {:?} does not match written blocks {:?}", name, actual, expected);
+    }
+    if written.file_size() < source_len {
+        anyhow::bail!("Verify failed for {}: directory size {} is smaller than source size {}", name, written.file_size(), source_len);
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    let mut remaining = source_len;
+    for &block in &actual {
+        if remaining == 0 {
+            break;
+        }
+        let read_size = min(geometry().block_size, remaining);
+        let mut buf = vec![0u8; read_size];
+        disk.seek(SeekFrom::Start(allocation_to_offset(block)?))?;
+        disk.read_exact(&mut buf)?;
+        hasher.update(&buf);
+        remaining -= read_size;
+    }
+
+    let crc = hasher.finalize();
+    if crc != source_crc {
+        anyhow::bail!("Verify failed for {}: crc32 of data read back is {:08x}, source is {:08x}", name, crc, source_crc);
+    }
+
+    println!("Verified {} ({} bytes, crc32 {:08x})", name, source_len, crc);
+
+    Ok(())
+}
+
+fn delete(files: Vec<FileEntry>, cpm_file_name: &str, disk: &mut dyn ImageFile) -> Result<()> {
+
+    if let Some(file_entry) = get_file_entry(&files, cpm_file_name)? {
+        let mut fe = file_entry.clone();
+        fe.delete();
+        fe.write_to_file(disk)?;
+
+    } else {
+        anyhow::bail!("File {} not found in image", cpm_file_name);
+    }
+
+    Ok(())
+}
+
+fn rename(files: Vec<FileEntry>, cpm_file_name: &str, new_cpm_file_name: &str, disk: &mut dyn ImageFile) -> Result<()> {
+
+    if get_file_entry(&files, new_cpm_file_name)?.is_some() {
+        anyhow::bail!("File {} already exists in image", new_cpm_file_name);
+    }
+
+    if let Some(file_entry) = get_file_entry(&files, cpm_file_name)? {
+        check_directory_blocks(file_entry, cpm_file_name)?;
+        let (user, filename, filetype) = split_cpm_file_name(new_cpm_file_name)?;
+        let mut fe = file_entry.clone();
+        fe.rename(user, &filename, &filetype);
+        fe.write_to_file(disk)?;
+
+    } else {
+        anyhow::bail!("File {} not found in image", cpm_file_name);
+    }
+
+    Ok(())
+}
+
+fn set_attributes(files: Vec<FileEntry>, cpm_file_name: &str, readonly: Option<bool>, system: Option<bool>, archived: Option<bool>, disk: &mut dyn ImageFile) -> Result<()> {
+
+    if let Some(file_entry) = get_file_entry(&files, cpm_file_name)? {
+        check_directory_blocks(file_entry, cpm_file_name)?;
+        let mut fe = file_entry.clone();
+        let attributes = Attributes {
+            readonly: readonly.unwrap_or(fe.readonly),
+            system: system.unwrap_or(fe.system),
+            archived: archived.unwrap_or(fe.archived),
+        };
+        fe.set_attributes(attributes);
+        fe.write_to_file(disk)?;
+
+    } else {
+        anyhow::bail!("File {} not found in image", cpm_file_name);
+    }
+
+    Ok(())
+}
+
+
+// How entries that are all zero or all fill byte are described
+pub(crate) fn blank_fill_name() -> String {
+    match geometry().fill_byte {
+        0x00 | 0xe5 => "00".to_string(),
+        fill_byte => format!("00 or {:02X}", fill_byte),
+    }
+}
+
+pub fn create_image(image_path: &str, size: &DiskSize) -> Result<()> {
+    // The size is for COMPIS disks, a disk of another geometry is made whole
+    let compis = geometry().is_compis_layout();
+    let image_size = if compis { size.num_bytes() } else { geometry().total_size() };
+
+    let mut out = File::create(image_path)?;
+    // The whole disk is formatted with the fill byte
+    let buf = vec![geometry().fill_byte; geometry().bytes_per_sector];
+
+    let num_tracks = image_size / geometry().bytes_per_sector / geometry().sectors_per_track;
+
+    for _ in 0..num_tracks {
+        for _ in 0..geometry().sectors_per_track {
+            out.write_all(&buf)?;
+        }
+    }
+
+    // e5 is used as empty directory entry, whatever the fill byte is
+    if geometry().fill_byte != 0xe5 && geometry().catalog_offset() < image_size as u64 {
+        let dir_size = geometry().dir_size().min(image_size - geometry().catalog_offset() as usize);
+        out.seek(SeekFrom::Start(geometry().catalog_offset()))?;
+        out.write_all(&vec![0xe5u8; dir_size])?;
+    }
+
+    // Write the magic byte to the disk type offset, on other disks it is part of the boot sector
+    if compis {
+        out.seek(SeekFrom::Start(DISKSIZE_OFFSET as u64))?;
+        out.write_all(&[size.hex_value()])?;
+    }
+    durable::sync_file(&out)?;
+    durable::sync_dir_of(std::path::Path::new(image_path))?;
+
+    Ok(())
+}
+
+/// Metadata for every file in the image, in the requested order
+pub fn file_infos(image_path: &str, options: &ImageOptions, sort: SortKey, reverse: bool) -> Result<Vec<FileInfo>> {
+    let mut disk = open_image(image_path, false, options)?;
+    read_file_infos(disk.as_mut(), sort, reverse)
+}
+
+/// User:Name.Type of every file starting with `partial`, ignoring case, for shell completion
+pub fn complete_file_names(image_path: &str, options: &ImageOptions, partial: &str) -> Result<Vec<String>> {
+    let partial = partial.to_uppercase();
+    let files = file_infos(image_path, options, SortKey::Name, false)?;
+    Ok(files.iter()
+        .map(|f| format!("{}:{}.{}", f.user_number, f.filename, f.filetype))
+        .filter(|name| name.starts_with(&partial))
+        .collect())
+}
+
+fn read_file_infos(disk: &mut dyn ImageFile, sort: SortKey, reverse: bool) -> Result<Vec<FileInfo>> {
+    let catalog = read_catalog(disk)?;
+    let mut files: Vec<FileEntry> = merge_extents(catalog);
+    sort_files(&mut files, sort, reverse);
+
+    Ok(files.iter().map(|f| f.info()).collect())
+}
+
+/// Which files a listing shows, the way DIR and DIRS do on CP/M
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shown {
+    /// Files without the SYS attribute, like DIR
+    User,
+    /// Only files with the SYS attribute, like DIRS
+    System,
+    All,
+}
+
+impl Shown {
+    pub fn includes(&self, info: &FileInfo) -> bool {
+        match self {
+            Shown::User => !info.attributes.system,
+            Shown::System => info.attributes.system,
+            Shown::All => true,
+        }
+    }
+}
+
+/// List the files in the image. Date stamps are shown when the disk has
+/// them, in ISO 8601 and with `raw_dates` also as stored.
+pub fn list_directory(image_path: &str, options: &ImageOptions, sort: SortKey, reverse: bool, shown: Shown, raw_dates: bool, json: bool) -> Result<()> {
+    let mut disk = open_image(image_path, false, options)?;
+    let (files, hidden): (Vec<FileInfo>, Vec<FileInfo>) = read_file_infos(disk.as_mut(), sort, reverse)?
+        .into_iter()
+        .partition(|info| shown.includes(info));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&files)?);
+        return Ok(());
+    }
+
+    // Only disks with date stamps get the extra columns
+    let stamped = files.iter().any(|info| info.timestamps.is_some());
+    // The label has the disk stamp either creation or access
+    let accessed = files.iter().any(|info| info.timestamps.is_some_and(|t| t.accessed.is_some()));
+    let (mut header, mut rule) = ("UID Name     Ext     Size Readonly System".to_string(), "-".repeat(42));
+    if stamped {
+        header.push_str(if accessed { "  Accessed          Updated         " } else { "  Created           Updated         " });
+        rule.push_str(&"-".repeat(36));
+        if raw_dates {
+            header.push_str(if accessed { "  CP/M access   CP/M updated" } else { "  CP/M created  CP/M updated" });
+            rule.push_str(&"-".repeat(28));
+        }
+    }
+
+    println!("Files in image '{}':", image_path);
+    println!("{}", header.trim_end());
+    println!("{}", rule);
+    for info in &files {
+        let mut line = format!("{:>3} {:>8} {:>3} {:>8} {:>8} {:>6}", info.user_number, info.filename, info.filetype, output::number(info.size), info.attributes.readonly, info.attributes.system);
+        if stamped {
+            let timestamps = info.timestamps.unwrap_or_default();
+            let stamps = [timestamps.created.or(timestamps.accessed), timestamps.modified];
+            for stamp in stamps {
+                let iso = stamp.map_or("-".to_string(), |date| date.to_iso8601().unwrap_or("invalid".to_string()));
+                line.push_str(&format!("  {:<16}", iso));
+            }
+            if raw_dates {
+                for stamp in stamps {
+                    line.push_str(&format!("  {:<12}", stamp.map_or("-".to_string(), |date| date.to_raw())));
+                }
+            }
+        }
+        println!("{}", line.trim_end());
+    }
+    if shown == Shown::User && !hidden.is_empty() {
+        println!();
+        println!("{} system files not shown, use --all to list them", hidden.len());
+    }
+
+    let damaged: Vec<&FileInfo> = files.iter().filter(|info| !directory_blocks(&info.blocks).is_empty()).collect();
+    if !damaged.is_empty() {
+        println!();
+        for info in damaged {
+            println!("Warning: {}:{}.{} has blocks {} in the directory area, its entry is damaged.", info.user_number,
+                info.filename, info.filetype, block_list(&directory_blocks(&info.blocks)));
+        }
+        println!("Reading these files gives directory entries, changing them needs --force. Deleting them is safe.");
+    }
+
+    let blank = count_blank_entries(disk.as_mut())?;
+    if blank > 0 {
+        println!();
+        println!("Note: {} directory entries are filled with {} instead of E5 and were treated as empty.", blank, blank_fill_name());
+        println!("The image was probably formatted by another tool, CP/M-86 itself sees them as files with blank names.");
+        println!("Run with --auto-fix to fill them with E5.");
+    }
+
+    Ok(())
+}
+
+// Enforce the limit of the user area the file goes to, if the image has quotas
+fn check_user_quota(image_path: &str, files: &[FileEntry], cpm_file_name: &str, size: usize) -> Result<()> {
+    let (user, _, _) = split_cpm_file_name(cpm_file_name)?;
+    // A file of the same name is about to be replaced, or the copy fails anyway
+    let replaced = get_file_entry(files, cpm_file_name)?.map(|f| f.first_directory_entry_idx);
+    let used: usize = files.iter()
+        .filter(|f| f.user_number == user && Some(f.first_directory_entry_idx) != replaced)
+        .map(|f| f.blocks().len()).sum();
+    quota::check_quota(image_path, user, used * geometry().block_size, size.div_ceil(geometry().block_size) * geometry().block_size)
+}
+
+/// Copy a file into the image. The directory is only changed after all data
+/// is written and synced, so if the copy fails or is interrupted half way
+/// the file is simply not there, and no other file is affected.
+pub fn copy_file_in(image_path: &str, options: &ImageOptions, source_path: &str, cpm_file_name: &str, verify: bool) -> Result<()> {
+    copy_file_in_observed(image_path, options, source_path, cpm_file_name, verify, &Observer::default())
+}
+
+/// copy_file_in reporting progress to an observer, see events::copy_in
+pub fn copy_file_in_observed(image_path: &str, options: &ImageOptions, source_path: &str, cpm_file_name: &str, verify: bool, observer: &Observer) -> Result<()> {
+    let mut disk = open_image(image_path, true, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    let mut input = File::open(source_path)?;
+    // Before reading it all in
+    let source_len = input.metadata()?.len() as usize;
+    check_file_size(cpm_file_name, source_len)?;
+    check_user_quota(image_path, &files, cpm_file_name, source_len)?;
+    copy_in(files, cpm_file_name, disk.as_mut(), &mut input, false, verify, observer)?;
+    
+    Ok(())
+}
+
+/// Read the whole file from the image, rounded up to whole records
+pub fn read_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str) -> Result<Vec<u8>> {
+    let mut disk = open_image(image_path, false, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    let file_entry = match get_file_entry(&files, cpm_file_name)? {
+        Some(file_entry) => file_entry,
+        None => anyhow::bail!("File {} not found in image", cpm_file_name),
+    };
+    let mut data = Vec::new();
+    CpmFileReader::new(disk.as_mut(), file_entry).read_to_end(&mut data)?;
+
+    Ok(data)
+}
+
+/// Read every file of the image, in the order the first blocks are on the
+/// disk so the image is read front to back, and hand each one to `visit`
+pub fn read_all_files(image_path: &str, options: &ImageOptions, visit: &mut dyn FnMut(FileInfo, Vec<u8>) -> Result<()>) -> Result<()> {
+    read_each_file(image_path, options, &mut |info, data| visit(info, data?))
+}
+
+/// read_all_files that goes on after a file that can't be read, `visit`
+/// gets the error for it and decides whether to stop
+pub fn read_each_file(image_path: &str, options: &ImageOptions, visit: &mut dyn FnMut(FileInfo, Result<Vec<u8>>) -> Result<()>) -> Result<()> {
+    let mut disk = open_image(image_path, false, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let mut files: Vec<FileEntry> = merge_extents(catalog);
+    files.sort_by_key(|f| f.blocks().first().and_then(|&al| allocation_to_offset(al).ok()));
+
+    for file_entry in &files {
+        let mut data = Vec::new();
+        let result = CpmFileReader::new(disk.as_mut(), file_entry).read_to_end(&mut data).map(|_| data);
+        visit(file_entry.info(), result.map_err(anyhow::Error::from))?;
+    }
+
+    Ok(())
+}
+
+/// Create a new file in the image with the given contents
+pub fn write_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str, data: &[u8]) -> Result<()> {
+    store_file(image_path, options, cpm_file_name, data, false, false)
+}
+
+/// write_file that can replace a file of the same name and read the data back.
+/// The new file is written to free blocks and entries as NAME.$$$, then the
+/// old one is deleted and the new one renamed, so there must be room for both.
+/// If that is interrupted the data is still there under one of the names.
+pub fn store_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str, data: &[u8], replace: bool, verify: bool) -> Result<()> {
+    let mut disk = open_image(image_path, true, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    check_user_quota(image_path, &files, cpm_file_name, data.len())?;
+    copy_in(files, cpm_file_name, disk.as_mut(), &mut &data[..], replace, verify, &Observer::default())?;
+
+    Ok(())
+}
+
+/// Create a file of the given size filled with one byte value, for programs
+/// that expect a fixed size data file to exist. The size is rounded up to whole records.
+pub fn alloc_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str, size: usize, fill: u8) -> Result<()> {
+    if size == 0 {
+        anyhow::bail!("Size of {} must be at least one record", cpm_file_name);
+    }
+    check_file_size(cpm_file_name, size)?;
+    let data = vec![fill; size.div_ceil(128) * 128];
+    write_file(image_path, options, cpm_file_name, &data)?;
+
+    println!("Allocated {} bytes for {}", data.len(), cpm_file_name);
+
+    Ok(())
+}
+
+/// Copy a file out of the image. The file is written under a temporary name
+/// next to the target and renamed when complete, so an interrupted copy never
+/// leaves a half written file under the real name.
+pub fn copy_file_out(image_path: &str, options: &ImageOptions, cpm_file_name: &str, output_path: &str, copy: &CopyOutOptions) -> Result<()> {
+    copy_file_out_observed(image_path, options, cpm_file_name, output_path, copy, &Observer::default())
+}
+
+/// copy_file_out reporting progress to an observer, see events::copy_out
+pub fn copy_file_out_observed(image_path: &str, options: &ImageOptions, cpm_file_name: &str, output_path: &str, copy: &CopyOutOptions, observer: &Observer) -> Result<()> {
+    let mut disk = open_image(image_path, false, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    let timestamps = get_file_entry(&files, cpm_file_name)?
+        .and_then(|f| f.extents.first())
+        .and_then(|e| e.timestamps);
+
+    let temp_path = format!("{}.part", output_path);
+    let result = (|| -> Result<()> {
+        let mut out = File::create(&temp_path)?;
+        copy_out(files, cpm_file_name, disk.as_mut(), &mut out, copy.salvage, copy.eof, observer)?;
+        if copy.preserve_times {
+            match timestamps.and_then(|t| t.modified.or(t.created)).and_then(|d| d.to_system_time()) {
+                Some(time) => out.set_modified(time)?,
+                None => observer.warning(format!("{} has no date stamp, keeping the current time", cpm_file_name)),
+            }
+        }
+        durable::sync_file(&out)?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    durable::rename(&temp_path, output_path)?;
+
+    Ok(())
+}
+
+/// Copy `len` bytes from `start` of a file out of the image, to the end of
+/// the file without `len`. Only the blocks holding the range are read.
+/// Returns the number of bytes copied.
+pub fn copy_range_out(image_path: &str, options: &ImageOptions, cpm_file_name: &str, output_path: &str, start: u64, len: Option<u64>) -> Result<u64> {
+    let mut disk = open_image(image_path, false, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    let file_entry = match get_file_entry(&files, cpm_file_name)? {
+        Some(file_entry) => file_entry,
+        None => anyhow::bail!("File {} not found in image", cpm_file_name),
+    };
+    let mut reader = CpmFileReader::new(disk.as_mut(), file_entry);
+    let size = reader.size();
+    let len = len.unwrap_or(size.saturating_sub(start));
+    match start.checked_add(len) {
+        Some(end) if end <= size => {}
+        _ => anyhow::bail!("Range {:#x}:{:#x} is past the end of {}, which is {} bytes", start, len, cpm_file_name, output::number(size)),
+    }
+    let mut data = vec![0u8; len as usize];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut data)?;
+
+    let temp_path = format!("{}.part", output_path);
+    if let Err(e) = durable::write(&temp_path, &data) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    durable::rename(&temp_path, output_path)?;
+
+    Ok(len)
+}
+
+/// Print the first lines, or bytes if given, of a file in the image.
+/// Only the records that are needed are read from the image.
+pub fn head_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str, lines: usize, bytes: Option<usize>, eof: TextEof) -> Result<()> {
+    let mut disk = open_image(image_path, false, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    let file_entry = match get_file_entry(&files, cpm_file_name)? {
+        Some(file_entry) => file_entry,
+        None => anyhow::bail!("File {} not found in image", cpm_file_name),
+    };
+    let mut reader = CpmFileReader::new(disk.as_mut(), file_entry);
+
+    let data = match bytes {
+        Some(bytes) => {
+            let mut data = Vec::new();
+            reader.take(bytes as u64).read_to_end(&mut data)?;
+            data
+        }
+        None => head_lines(&mut reader, lines, eof)?,
+    };
+    std::io::stdout().write_all(&data)?;
+
+    Ok(())
+}
+
+/// Print the last lines, or bytes if given, of a file in the image.
+/// Only the records that are needed are read from the image.
+pub fn tail_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str, lines: usize, bytes: Option<usize>, eof: TextEof) -> Result<()> {
+    let mut disk = open_image(image_path, false, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    let file_entry = match get_file_entry(&files, cpm_file_name)? {
+        Some(file_entry) => file_entry,
+        None => anyhow::bail!("File {} not found in image", cpm_file_name),
+    };
+    let mut reader = CpmFileReader::new(disk.as_mut(), file_entry);
+
+    let data = match bytes {
+        Some(bytes) => {
+            let start = reader.size().saturating_sub(bytes as u64);
+            reader.seek(SeekFrom::Start(start))?;
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            data
+        }
+        None => tail_lines(&mut reader, lines, eof)?,
+    };
+    std::io::stdout().write_all(&data)?;
+
+    Ok(())
+}
+
+/// Print how the directory leads to the data of a file: each directory slot
+/// with its EX, S2 and RC, the block numbers in it, and where each block is
+/// in the image and in the file. Missing extents, blocks outside the disk and
+/// allocations that don't match the record count are flagged with '!'.
+/// Returns the number of problems found.
+pub fn trace_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str) -> Result<usize> {
+    let mut disk = open_image(image_path, false, options)?;
+    let image_size = disk.size()?;
+    let buffer = read_directory(disk.as_mut())?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    let file_entry = match get_file_entry(&files, cpm_file_name)? {
+        Some(file_entry) => file_entry,
+        None => anyhow::bail!("File {} not found in image", cpm_file_name),
+    };
+
+    let mut problems = 0;
+    let mut flag = |message: String| {
+        println!("  ! {}", message);
+        problems += 1;
+    };
+
+    println!("{}:{}.{} has {} extents, {} bytes", file_entry.user_number, file_entry.filename, file_entry.filetype,
+        file_entry.extents.len(), output::number(file_entry.file_size()));
+    let mut file_offset = 0;
+    let mut seen_blocks: HashMap<u16, usize> = HashMap::new();
+    let mut expected_extent = 0;
+    for (n, extent) in file_entry.extents.iter().enumerate() {
+        let slot = extent.directory_entry_idx;
+        let entry_offset = geometry().catalog_offset() + (slot * DIRENTRY_SIZE) as u64;
+        println!("Slot {:>3} at {:#07x}: EX {:02x} S2 {:02x} RC {:02x}, extent {}, {} records",
+            slot, entry_offset, extent.extent, extent.s2, extent.record_count, extent.entry_number, output::number(extent.records()));
+
+        // The first logical extent in the entry, and the first after it
+        let mask = geometry().extent_mask() as usize;
+        let number = extent.entry_number as usize & !mask;
+        if number > expected_extent {
+            if number - expected_extent == 1 {
+                flag(format!("Extent {} is missing before this one", expected_extent));
+            } else {
+                flag(format!("Extents {} to {} are missing before this one", expected_extent, number - 1));
+            }
+            file_offset += (number - expected_extent) * 128 * 128;
+        } else if number < expected_extent {
+            flag(format!("Extent {} is in the directory twice", number));
+        }
+        expected_extent = (number | mask) + 1;
+        if !extent.is_full_extent() && n + 1 < file_entry.extents.len() {
+            flag("Extent is not full but is not the last one".to_string());
+        }
+
+        // The raw allocation words, read_catalog leaves out the zero ones
+        let raw = &buffer[slot * DIRENTRY_SIZE + 16..(slot + 1) * DIRENTRY_SIZE];
+        let words: Vec<u16> = raw.chunks_exact(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect();
+        let needed = (extent.records() * 128).div_ceil(geometry().block_size);
+        for (i, &al) in words.iter().enumerate() {
+            // Each allocation holds its own part of the extent, used or not
+            let extent_offset = file_offset + i * geometry().block_size;
+            if al == 0 {
+                if words[i..].iter().any(|&w| w != 0) {
+                    println!("  AL[{}] {:04x}", i, al);
+                    flag(format!("Allocation {} is empty but later ones are used", i));
+                }
+                continue;
+            }
+            let file_end = min(extent_offset + geometry().block_size, file_offset + extent.extent_size());
+            let file_range = if extent_offset < file_end {
+                format!("file {:#08x}-{:#08x}", extent_offset, file_end - 1)
+            } else {
+                "past the record count".to_string()
+            };
+            match allocation_to_offset(al) {
+                Ok(offset) => println!("  AL[{}] {:04x} -> image {:#08x}-{:#08x}, {}", i, al, offset, offset + geometry().block_size as u64 - 1, file_range),
+                Err(_) => println!("  AL[{}] {:04x} -> outside the disk, {}", i, al, file_range),
+            }
+            match allocation_to_offset(al) {
+                Err(e) => flag(e.to_string()),
+                Ok(offset) if offset + geometry().block_size as u64 > image_size => {
+                    flag(format!("Block {:#x} is past the end of the image file, which is {} bytes", al, output::number(image_size)));
+                }
+                Ok(_) => {}
+            }
+            if (al as usize) < geometry().dir_blocks {
+                flag(format!("Block {:#x} is part of the directory", al));
+            }
+            if let Some(other) = seen_blocks.insert(al, slot) {
+                flag(format!("Block {:#x} is also allocated in slot {}", al, other));
+            }
+        }
+        let allocated = words.iter().filter(|&&w| w != 0).count();
+        if allocated != needed {
+            flag(format!("RC {:02x} needs {} blocks, {} are allocated", extent.record_count, needed, allocated));
+        }
+        file_offset += extent.extent_size();
+    }
+
+    if problems == 0 {
+        println!("No problems found");
+    } else {
+        println!("{} problems found", problems);
+    }
+    Ok(problems)
+}
+
+pub fn delete_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str) -> Result<()> {
+    let mut disk = open_image(image_path, true, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    delete(files, cpm_file_name, disk.as_mut())?;
+    disk.sync()?;
+
+    Ok(())
+}
+
+pub fn rename_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str, new_cpm_file_name: &str) -> Result<()> {
+    let mut disk = open_image(image_path, true, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    rename(files, cpm_file_name, new_cpm_file_name, disk.as_mut())?;
+    disk.sync()?;
+
+    Ok(())
+}
+
+/// Change the attributes of a file, attributes given as None are kept
+pub fn set_file_attributes(image_path: &str, options: &ImageOptions, cpm_file_name: &str, readonly: Option<bool>, system: Option<bool>, archived: Option<bool>) -> Result<()> {
+    let mut disk = open_image(image_path, true, options)?;
+    let catalog = read_catalog(disk.as_mut())?;
+    let files: Vec<FileEntry> = merge_extents(catalog);
+
+    set_attributes(files, cpm_file_name, readonly, system, archived, disk.as_mut())?;
+    disk.sync()?;
+
+    Ok(())
+}
+
+/// Send a file from the image over a serial port, to a terminal program
+/// receiving it on the other end
+pub fn send_file(image_path: &str, options: &ImageOptions, cpm_file_name: &str, serial_path: &str, baud: u32, protocol: Protocol) -> Result<()> {
+    let data = read_file(image_path, options, cpm_file_name)?;
+
+    let mut port = serialport::new(serial_path, baud)
+        .timeout(std::time::Duration::from_millis(100))
+        .open()?;
+
+    println!("Sending {} ({} bytes) on {} at {} baud, start the receiver now", cpm_file_name, data.len(), serial_path, baud);
+    match protocol {
+        Protocol::Xmodem => {
+            crate::xmodem::send(&mut port, &data, &mut |sent| {
+                print!("\r{} / {} bytes", sent, data.len());
+                let _ = std::io::stdout().flush();
+            })?;
+        }
+    }
+    println!();
+    println!("Done");
+
+    Ok(())
+}
+
+/// Write the image as an SCP flux image for writing to a real floppy
+pub fn image_to_flux(image_path: &str, options: &ImageOptions, output_path: &str, format: &TrackFormat) -> Result<()> {
+    let mut disk = open_image(image_path, false, options)?;
+    let mut image = Vec::new();
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_to_end(&mut image)?;
+
+    // Sectors of a flux or IMD source that don't fit the layout go along, copy protection needs them
+    let irregular = imagefile::irregular_sectors(image_path)?;
+    let scp = flux::encode_scp(&image, &geometry().track_layout(), format, geometry().fill_byte, &irregular)?;
+    let mut out = File::create(output_path)?;
+    out.write_all(&scp)?;
+    durable::sync_file(&out)?;
+    durable::sync_dir_of(std::path::Path::new(output_path))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::sync::{Mutex, MutexGuard};
+    use crate::formats::GeometryOverrides;
+    use crate::imagefile::MemImage;
+    use crate::repair;
+    use super::*;
+
+    // The geometry and the highest user number are process wide, tests
+    // that use or change them take turns
+    static GLOBALS: Mutex<()> = Mutex::new(());
+
+    pub(crate) fn lock_globals() -> MutexGuard<'static, ()> {
+        GLOBALS.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn split(cpm_file_name: &str) -> (u8, String, String) {
+        split_cpm_file_name(cpm_file_name).unwrap()
+    }
+
+    /// Another geometry until dropped, then COMPIS again
+    pub(crate) struct UseGeometry;
+
+    impl UseGeometry {
+        pub(crate) fn new(geometry: Geometry) -> UseGeometry {
+            set_geometry(geometry).unwrap();
+            UseGeometry
+        }
+    }
+
+    impl Drop for UseGeometry {
+        fn drop(&mut self) {
+            set_geometry(Geometry::COMPIS).unwrap();
+        }
+    }
+
+    /// An empty disk of the geometry in use
+    pub(crate) fn blank_image() -> MemImage {
+        MemImage::new(vec![0xe5; geometry().total_size()])
+    }
+
+    /// A directory in the temporary directory for tests that need image
+    /// files, removed when dropped
+    pub(crate) struct Scratch(PathBuf);
+
+    impl Scratch {
+        pub(crate) fn new(name: &str) -> Scratch {
+            let dir = std::env::temp_dir().join(format!("cpm86tools-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        pub(crate) fn path(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+
+        /// Write the disk to a file in the directory, its path
+        pub(crate) fn save(&self, name: &str, disk: MemImage) -> String {
+            let path = self.path(name);
+            std::fs::write(&path, disk.into_inner()).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn files_of(disk: &mut dyn ImageFile) -> Vec<FileEntry> {
+        merge_extents(read_catalog(disk).unwrap())
+    }
+
+    pub(crate) fn store(disk: &mut dyn ImageFile, cpm_file_name: &str, data: &[u8], replace: bool) -> Result<()> {
+        copy_in(files_of(disk), cpm_file_name, disk, &mut &data[..], replace, false, &Observer::default())
+    }
+
+    /// Name and whole records of every file
+    pub(crate) fn contents_of(disk: &mut dyn ImageFile) -> Vec<(String, Vec<u8>)> {
+        files_of(disk).iter().map(|file| {
+            let mut data = Vec::new();
+            CpmFileReader::new(disk, file).read_to_end(&mut data).unwrap();
+            (format!("{}:{}.{}", file.user_number, file.filename.trim_end(), file.filetype.trim_end()), data)
+        }).collect()
+    }
+
+    pub(crate) fn test_data(seed: u8, size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    // Writes `store` does on a copy of the disk
+    fn writes_to_store(disk: &MemImage, cpm_file_name: &str, data: &[u8], replace: bool) -> usize {
+        let mut copy = disk.clone();
+        let before = copy.counts().1;
+        store(&mut copy, cpm_file_name, data, replace).unwrap();
+        copy.counts().1 - before
+    }
+
+    #[test]
+    fn user_numbers() {
+        let _globals = lock_globals();
+        assert_eq!(split("0:FOO.TXT"), (0, "FOO".to_string(), "TXT".to_string()));
+        assert_eq!(split("15:FOO.TXT").0, 15);
+        assert_eq!(split("007:FOO.TXT").0, 7);
+        for name in ["16:FOO.TXT", "200:FOO.TXT", "256:FOO.TXT", "abc:FOO.TXT", ":FOO.TXT", "-1:FOO.TXT", "+1:FOO.TXT", "1a:FOO.TXT", "FOO.TXT"] {
+            assert!(split_cpm_file_name(name).is_err(), "{} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn extended_user_numbers() {
+        let _globals = lock_globals();
+        assert!(set_max_user_number(20).is_err());
+        set_max_user_number(EXTENDED_MAX_USER_NUMBER).unwrap();
+        let highest = split_cpm_file_name("31:FOO.TXT").map(|(user, _, _)| user);
+        let above = split_cpm_file_name("32:FOO.TXT").is_err();
+        set_max_user_number(MAX_USER_NUMBER).unwrap();
+        assert_eq!(highest.unwrap(), 31);
+        assert!(above);
+        assert!(split_cpm_file_name("31:FOO.TXT").is_err());
+    }
+
+    #[test]
+    fn padded_names() {
+        let _globals = lock_globals();
+        let foo_c = (0, "FOO".to_string(), "C".to_string());
+        assert_eq!(split("0:FOO     .C  "), foo_c);
+        assert_eq!(split(" 0 :foo.c"), foo_c);
+        assert_eq!(split("0:  FOO.  C"), foo_c);
+        assert_eq!(split("0:FOO."), (0, "FOO".to_string(), String::new()));
+        assert_eq!(split("0:FOO     .   "), (0, "FOO".to_string(), String::new()));
+        assert_eq!(split("0:ABCDEFGH.XYZ"), (0, "ABCDEFGH".to_string(), "XYZ".to_string()));
+    }
+
+    #[test]
+    fn malformed_names() {
+        let _globals = lock_globals();
+        for name in [
+            "0:FOO.BAR.C", "0:.FOO.C", "0:FOO..C",
+            "0:1:FOO.C", "0:FOO:C.C", "0::FOO.C",
+            "0:FOO", "0:.C", "0:   .C", "0:FO O.C", "0:FOO.C C",
+            "0:ABCDEFGHI.C", "0:FOO.TEXT",
+        ] {
+            assert!(split_cpm_file_name(name).is_err(), "{} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn interrupted_copy_leaves_directory() {
+        let _globals = lock_globals();
+        let mut disk = blank_image();
+        store(&mut disk, "0:OLD.TXT", &test_data(1, 1024), false).unwrap();
+        let directory = read_directory(&mut disk).unwrap();
+
+        let data = test_data(2, 3 * geometry().block_size);
+        let writes = writes_to_store(&disk, "0:NEW.BIN", &data, false);
+        // The blocks, then the directory entry last
+        assert_eq!(writes, 4);
+        for n in 1..=writes {
+            let mut failing = disk.clone().fail_on_write(n);
+            assert!(store(&mut failing, "0:NEW.BIN", &data, false).is_err());
+            assert!(read_directory(&mut failing).unwrap() == directory, "write {} of {} changed the directory", n, writes);
+        }
+    }
+
+    #[test]
+    fn interrupted_replace_keeps_one_file() {
+        let _globals = lock_globals();
+        let (old, new) = (test_data(3, 2 * geometry().block_size), test_data(4, 3 * geometry().block_size));
+        let mut disk = blank_image();
+        store(&mut disk, "0:DATA.BIN", &old, false).unwrap();
+
+        let writes = writes_to_store(&disk, "0:DATA.BIN", &new, true);
+        for n in 1..=writes {
+            let mut failing = disk.clone().fail_on_write(n);
+            assert!(store(&mut failing, "0:DATA.BIN", &new, true).is_err());
+            let files = contents_of(&mut failing);
+            let names: HashSet<&String> = files.iter().map(|(name, _)| name).collect();
+            assert_eq!(names.len(), files.len(), "two files of one name after write {}", n);
+            assert!(!files.is_empty(), "no file left after write {}", n);
+            for (name, data) in &files {
+                match name.as_str() {
+                    "0:DATA.BIN" => assert!(*data == old, "DATA.BIN changed after write {}", n),
+                    "0:DATA.$$$" => assert!(*data == new, "DATA.$$$ incomplete after write {}", n),
+                    name => panic!("{} after write {}", name, n),
+                }
+            }
+        }
+
+        store(&mut disk, "0:DATA.BIN", &new, true).unwrap();
+        assert!(contents_of(&mut disk) == vec![("0:DATA.BIN".to_string(), new)]);
+    }
+
+    #[test]
+    fn short_writes() {
+        let _globals = lock_globals();
+        let mut disk = blank_image().short_writes(100);
+        assert_eq!(disk.write(&[0u8; 300]).unwrap(), 100);
+
+        let mut disk = blank_image().short_writes(100);
+        let files = [("0:SMALL.TXT", test_data(6, 1000)), ("0:LARGE.BIN", test_data(7, 9 * geometry().block_size + 128))];
+        for (name, data) in &files {
+            store(&mut disk, name, data, false).unwrap();
+        }
+        store(&mut disk, "0:SMALL.TXT", &test_data(8, 3000), true).unwrap();
+        let mut expected = vec![
+            ("0:LARGE.BIN".to_string(), files[1].1.clone()),
+            ("0:SMALL.TXT".to_string(), test_data(8, 3000)),
+        ];
+        // Text is padded to whole records with ^Z
+        expected[1].1.resize(3072, TEXT_EOF);
+        let mut read = contents_of(&mut disk);
+        read.sort();
+        assert!(read == expected);
+    }
+
+    fn text(lines: std::ops::Range<usize>) -> Vec<u8> {
+        lines.flat_map(|i| format!("line {}\n", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn tail_stops_at_first_eof() {
+        let _globals = lock_globals();
+        let mut disk = blank_image();
+        // A ^Z early on, far from the last record
+        let mut data = text(0..10);
+        data.push(TEXT_EOF);
+        data.extend(text(10..1002));
+        store(&mut disk, "0:DOC.TXT", &data, false).unwrap();
+        let file = files_of(&mut disk).remove(0);
+        let mut reader = CpmFileReader::new(&mut disk, &file);
+        assert_eq!(tail_lines(&mut reader, 2, TextEof::Stop).unwrap(), text(8..10));
+        assert_eq!(tail_lines(&mut reader, 2, TextEof::StripTrailing).unwrap(), text(1000..1002));
+    }
+
+    #[test]
+    fn head_reads_forward() {
+        let _globals = lock_globals();
+        let mut disk = blank_image();
+        store(&mut disk, "0:LOG.TXT", &text(0..5000), false).unwrap();
+        let file = files_of(&mut disk).remove(0);
+        for (eof, reads) in [(TextEof::Stop, 1), (TextEof::Keep, 1), (TextEof::StripTrailing, 2)] {
+            let before = disk.counts().0;
+            let head = head_lines(&mut CpmFileReader::new(&mut disk, &file), 2, eof).unwrap();
+            assert_eq!(head, text(0..2));
+            assert_eq!(disk.counts().0 - before, reads, "{:?}", eof);
+        }
+    }
+
+    // A hard disk like format with 512 directory entries in 4 blocks of 4K
+    #[test]
+    fn large_directory() {
+        let _globals = lock_globals();
+        let overrides = GeometryOverrides { sectors_per_track: Some(16), block_size: Some(4096), dir_entries: Some(512), ..GeometryOverrides::default() };
+        let _geometry = UseGeometry::new(overrides.apply(Geometry::COMPIS).unwrap());
+        assert_eq!((geometry().dir_entries(), geometry().dir_blocks, geometry().max_blocks()), (512, 4, 316));
+
+        let mut disk = blank_image();
+        for n in 0..300 {
+            store(&mut disk, &format!("0:F{:04}.DAT", n), &[], false).unwrap();
+        }
+        // 21 blocks in 3 entries of up to 8, from the first block after the directory
+        let big = test_data(5, 20 * 4096 + 128);
+        store(&mut disk, "1:BIG.BIN", &big, false).unwrap();
+
+        let mut golden = [0u8; 3 * DIRENTRY_SIZE];
+        for (entry, (extent, records, blocks)) in golden.chunks_mut(DIRENTRY_SIZE).zip([(1, 0x80, 4..12), (3, 0x80, 12..20), (5, 1, 20..25)]) {
+            entry[0] = 1;
+            entry[1..12].copy_from_slice(b"BIG     BIN");
+            entry[12] = extent;
+            entry[15] = records;
+            for (i, block) in blocks.enumerate() {
+                entry[16 + 2 * i] = block;
+            }
+        }
+        let directory = read_directory(&mut disk).unwrap();
+        assert!(directory[300 * DIRENTRY_SIZE..303 * DIRENTRY_SIZE] == golden[..]);
+        assert!(directory[..300 * DIRENTRY_SIZE].chunks(DIRENTRY_SIZE).enumerate()
+            .all(|(n, entry)| entry[1..12] == *format!("F{:04}   DAT", n).as_bytes() && entry[16..].iter().all(|&b| b == 0)));
+        assert!(directory[303 * DIRENTRY_SIZE..].iter().all(|&b| b == 0xe5));
+
+        let files = contents_of(&mut disk);
+        assert_eq!(files.len(), 301);
+        assert!(files.iter().any(|(name, data)| name == "1:BIG.BIN" && *data == big));
+        assert!(repair::find_repairs(&mut disk).unwrap().is_empty());
+
+        // Every entry up to the last can be used
+        for n in 303..512 {
+            store(&mut disk, &format!("2:G{:04}.DAT", n), &[], false).unwrap();
+        }
+        let error = store(&mut disk, "2:FULL.DAT", &[], false).unwrap_err();
+        assert!(error.to_string().contains("Not enough free entries"), "{}", error);
+        assert_eq!(files_of(&mut disk).len(), 510);
+    }
+
+    // The COMPIS layout as it was written with literals, before the Geometry
+    fn literal_offset(al: u16) -> u64 {
+        let (even, odd) = ((al & 0xfffe) as usize, (al & 1) as usize);
+        let offset = if al < 0x9e {
+            0x2000 + even * 0x800 * 2 + odd * 0x800
+        } else {
+            80 * 8 * 512 * 2 - (even - 0x9d) * 0x800 * 2 + odd * 0x800
+        };
+        offset as u64
+    }
+
+    #[test]
+    fn compis_offsets() {
+        let g = Geometry::COMPIS;
+        assert_eq!((g.total_size(), g.catalog_offset(), g.data_offset()), (655360, 0x2000, 0x2000));
+        assert_eq!((g.max_blocks(), g.dir_entries(), g.blocks_per_side()), (316, 128, 0x9e));
+        for block in 0..g.max_blocks() as u16 {
+            assert_eq!(g.block_offset(block).unwrap(), literal_offset(block), "block {:#x}", block);
+        }
+    }
+
+    // Every block inside the disk, on its own side and track, once
+    fn check_blocks(g: &Geometry) {
+        let mut offsets = HashSet::new();
+        for block in 0..g.max_blocks() as u16 {
+            let offset = g.block_offset(block).unwrap() as usize;
+            assert!(offset >= g.data_offset() as usize && offset + g.block_size <= g.total_size(), "{:?}: block {:#x} at {:#x}", g, block, offset);
+            assert!(offsets.insert(offset), "{:?}: block {:#x} at {:#x} twice", g, block, offset);
+            if g.sides > 1 {
+                let track = offset / g.track_size();
+                assert_eq!(track % g.sides, block as usize / g.blocks_per_side(), "{:?}: block {:#x} on the wrong side", g, block);
+                assert!(offset % g.track_size() + g.block_size <= g.track_size(), "{:?}: block {:#x} spans tracks", g, block);
+            }
+        }
+    }
+
+    #[test]
+    fn block_layouts() {
+        let shapes = [
+            GeometryOverrides::default(),
+            GeometryOverrides { sectors_per_track: Some(16), ..GeometryOverrides::default() },
+            GeometryOverrides { tracks: Some(160), block_size: Some(4096), ..GeometryOverrides::default() },
+            GeometryOverrides { sectors_per_track: Some(16), block_size: Some(4096), dir_entries: Some(512), ..GeometryOverrides::default() },
+            GeometryOverrides { sectors_per_track: Some(32), block_size: Some(8192), ..GeometryOverrides::default() },
+            GeometryOverrides { tracks: Some(82), reserved_tracks: Some(2), sectors_per_track: Some(4), bytes_per_sector: Some(1024), ..GeometryOverrides::default() },
+        ];
+        for overrides in shapes {
+            check_blocks(&overrides.apply(Geometry::COMPIS).unwrap());
+        }
+        // Blocks that span tracks are one run on a single sided disk
+        let single = Geometry { sides: 1, tracks: 640, block_size: 8192, ..Geometry::COMPIS };
+        single.validate().unwrap();
+        check_blocks(&single);
+
+        for rejected in [
+            GeometryOverrides { sectors_per_track: Some(3), ..GeometryOverrides::default() },
+            GeometryOverrides { tracks: Some(160), block_size: Some(8192), ..GeometryOverrides::default() },
+            GeometryOverrides { sectors_per_track: Some(10), ..GeometryOverrides::default() },
+        ] {
+            assert!(rejected.apply(Geometry::COMPIS).is_err(), "{:?} was accepted", rejected);
+        }
+    }
+
+    #[test]
+    fn file_on_both_sides() {
+        let _globals = lock_globals();
+        let block_size = geometry().block_size;
+        let data = test_data(9, 200 * block_size);
+        let mut disk = blank_image();
+        store(&mut disk, "0:BOTH.BIN", &data, false).unwrap();
+        assert!(contents_of(&mut disk) == vec![("0:BOTH.BIN".to_string(), data.clone())]);
+
+        // Blocks 2 to 201, the first on side 1 is 0x9e
+        let mut first_on_side_1 = vec![0u8; block_size];
+        disk.seek(SeekFrom::Start(literal_offset(0x9e))).unwrap();
+        disk.read_exact(&mut first_on_side_1).unwrap();
+        let index = 0x9e - geometry().dir_blocks;
+        assert!(first_on_side_1[..] == data[index * block_size..(index + 1) * block_size]);
+    }
+
+    #[test]
+    fn block_offsets() {
+        let g = Geometry::COMPIS;
+        let mut offsets = HashSet::new();
+        for block in 0..g.max_blocks() as u16 {
+            let offset = g.block_offset(block).unwrap();
+            assert!(offset >= g.data_offset() && offset + g.block_size as u64 <= g.total_size() as u64, "block {:#x} at {:#x}", block, offset);
+            assert!(offsets.insert(offset), "block {:#x} at {:#x} twice", block, offset);
+        }
+        assert!(g.block_offset(g.max_blocks() as u16).is_err());
+        assert!(g.block_offset(u16::MAX).is_err());
+    }
+}
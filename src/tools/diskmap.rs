@@ -0,0 +1,235 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use anyhow::Result;
+use crate::cpmimg::{self, Geometry, SortKey};
+use crate::imagefile::ImageOptions;
+use crate::output;
+
+// Which file owns each sector of the image. The picture has one row per
+// cylinder, side 0 on the left and side 1 on the right, so the COMPIS way
+// of filling side 0 outwards and then side 1 back inwards is easy to see.
+
+/// What a sector of the image is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectorUse {
+    /// Boot tracks before the directory
+    Reserved,
+    Directory,
+    Free,
+    /// Index into DiskMap::files
+    File(usize),
+}
+
+/// The use of every sector, in image order
+pub struct DiskMap {
+    pub geometry: Geometry,
+    pub sectors: Vec<SectorUse>,
+    /// User:Name.Type of each file in the map
+    pub files: Vec<String>,
+}
+
+impl DiskMap {
+    fn sector(&self, cylinder: usize, head: usize, sector: usize) -> SectorUse {
+        let g = &self.geometry;
+        self.sectors[(cylinder * g.sides + head) * g.sectors_per_track + sector]
+    }
+}
+
+/// Work out the owner of every sector from the directory
+pub fn disk_map(image_path: &str, options: &ImageOptions) -> Result<DiskMap> {
+    let geometry = cpmimg::geometry();
+    let files = cpmimg::file_infos(image_path, options, SortKey::Index, false)?;
+
+    let sector_size = geometry.bytes_per_sector as u64;
+    let catalog_start = geometry.catalog_offset() / sector_size;
+    let catalog_end = (geometry.catalog_offset() + geometry.dir_size() as u64) / sector_size;
+    let mut sectors: Vec<SectorUse> = (0..geometry.total_size() as u64 / sector_size).map(|i| {
+        if i < catalog_start {
+            SectorUse::Reserved
+        } else if i < catalog_end {
+            SectorUse::Directory
+        } else {
+            SectorUse::Free
+        }
+    }).collect();
+
+    let sectors_per_block = geometry.block_size / geometry.bytes_per_sector;
+    for (idx, info) in files.iter().enumerate() {
+        for &al in &info.blocks {
+            if al as usize >= geometry.max_blocks() {
+                continue;
+            }
+            let first = (geometry.block_offset(al)? / sector_size) as usize;
+            for sector in sectors.iter_mut().skip(first).take(sectors_per_block) {
+                *sector = SectorUse::File(idx);
+            }
+        }
+    }
+
+    Ok(DiskMap {
+        geometry,
+        sectors,
+        files: files.iter().map(|f| format!("{}:{}.{}", f.user_number, f.filename, f.filetype)).collect(),
+    })
+}
+
+const FILE_SYMBOLS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+fn symbol(sector: SectorUse) -> char {
+    match sector {
+        SectorUse::Reserved => '#',
+        SectorUse::Directory => 'D',
+        SectorUse::Free => '.',
+        SectorUse::File(idx) => FILE_SYMBOLS[idx % FILE_SYMBOLS.len()] as char,
+    }
+}
+
+/// Print the map as text, one line per cylinder
+pub fn print_map(map: &DiskMap) {
+    let g = &map.geometry;
+    println!("Cyl  Side 0    Side 1");
+    for cylinder in 0..g.tracks {
+        let mut line = format!("{:<5}", format!("{:>3} ", output::number(cylinder)));
+        for head in 0..g.sides {
+            for sector in 0..g.sectors_per_track {
+                line.push(symbol(map.sector(cylinder, head, sector)));
+            }
+            line.push_str("  ");
+        }
+        println!("{}", line.trim_end());
+    }
+    println!();
+    println!("# reserved  D directory  . free");
+    for (idx, name) in map.files.iter().enumerate() {
+        println!("{} {}", symbol(SectorUse::File(idx)), name);
+    }
+}
+
+const CELL: usize = 8;
+const SIDE_GAP: usize = 16;
+
+fn color(sector: SectorUse) -> [u8; 3] {
+    match sector {
+        SectorUse::Reserved => [0x40, 0x40, 0x40],
+        SectorUse::Directory => [0x20, 0x20, 0xa0],
+        SectorUse::Free => [0xe8, 0xe8, 0xe8],
+        SectorUse::File(idx) => {
+            // Spread the hues with the golden angle so neighbours differ
+            let hue = (idx as f64 * 137.508) % 360.0;
+            hsv_to_rgb(hue, 0.65, 0.9)
+        }
+    }
+}
+
+fn hsv_to_rgb(hue: f64, saturation: f64, value: f64) -> [u8; 3] {
+    let c = value * saturation;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = value - c;
+    let (r, g, b) = match (hue / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    [((r + m) * 255.0) as u8, ((g + m) * 255.0) as u8, ((b + m) * 255.0) as u8]
+}
+
+fn image_size(g: &Geometry) -> (usize, usize) {
+    (g.sides * g.sectors_per_track * CELL + (g.sides - 1) * SIDE_GAP, g.tracks * CELL)
+}
+
+// Top left corner of a sector in the picture
+fn cell_position(g: &Geometry, head: usize, sector: usize, cylinder: usize) -> (usize, usize) {
+    (head * (g.sectors_per_track * CELL + SIDE_GAP) + sector * CELL, cylinder * CELL)
+}
+
+// CP/M names can have & and other characters XML gives a meaning
+fn xml_escape(text: &str) -> String {
+    text.chars().map(|c| match c {
+        '&' => "&amp;".to_string(),
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        '"' => "&quot;".to_string(),
+        '\'' => "&apos;".to_string(),
+        c => c.to_string(),
+    }).collect()
+}
+
+fn write_svg(map: &DiskMap, out: &mut dyn Write) -> Result<()> {
+    let g = &map.geometry;
+    let (width, height) = image_size(g);
+    writeln!(out, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">", width, height)?;
+    for cylinder in 0..g.tracks {
+        for head in 0..g.sides {
+            for sector in 0..g.sectors_per_track {
+                let used = map.sector(cylinder, head, sector);
+                let (x, y) = cell_position(g, head, sector, cylinder);
+                let [r, gr, b] = color(used);
+                let title = match used {
+                    SectorUse::Reserved => "reserved",
+                    SectorUse::Directory => "directory",
+                    SectorUse::Free => "free",
+                    SectorUse::File(idx) => &map.files[idx],
+                };
+                writeln!(out, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#{:02x}{:02x}{:02x}\"><title>{} C{} H{} R{}</title></rect>",
+                    x, y, CELL, CELL, r, gr, b, xml_escape(title), cylinder, head, sector + 1)?;
+            }
+        }
+    }
+    writeln!(out, "</svg>")?;
+    Ok(())
+}
+
+fn write_png(map: &DiskMap, out: &mut dyn Write) -> Result<()> {
+    let g = &map.geometry;
+    let (width, height) = image_size(g);
+    let mut pixels = vec![0xffu8; width * height * 3];
+    for cylinder in 0..g.tracks {
+        for head in 0..g.sides {
+            for sector in 0..g.sectors_per_track {
+                let (x, y) = cell_position(g, head, sector, cylinder);
+                let rgb = color(map.sector(cylinder, head, sector));
+                // Leave a one pixel border so the sectors can be told apart
+                for py in y..y + CELL - 1 {
+                    for px in x..x + CELL - 1 {
+                        let i = (py * width + px) * 3;
+                        pixels[i..i + 3].copy_from_slice(&rgb);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(())
+}
+
+/// Show which file owns each sector, as text or written to an .svg or .png picture
+pub fn map_image(image_path: &str, options: &ImageOptions, output_path: Option<&str>) -> Result<()> {
+    let map = disk_map(image_path, options)?;
+
+    let output_path = match output_path {
+        Some(path) => path,
+        None => {
+            print_map(&map);
+            return Ok(());
+        }
+    };
+
+    // Before the file is created, so a wrong name doesn't leave an empty one
+    let write = match output_path.rsplit('.').next().map(|ext| ext.to_lowercase()).as_deref() {
+        Some("svg") => write_svg,
+        Some("png") => write_png,
+        _ => anyhow::bail!("Don't know how to write {}, use .svg or .png", output_path),
+    };
+    let mut out = BufWriter::new(File::create(output_path)?);
+    write(&map, &mut out)?;
+    out.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::Result;
+
+// Making what a command wrote survive pulling the USB stick or unmounting
+// the share as soon as the command returns. Images and new files are
+// fsynced before the command reports success, and so is the directory
+// after a file is created or renamed into place, which is what makes the
+// new name itself last. --no-sync skips all of it, for scratch work where
+// speed matters more.
+
+static SYNC: AtomicBool = AtomicBool::new(true);
+
+/// Turn fsyncing on or off for the whole run
+pub fn set_sync(sync: bool) {
+    SYNC.store(sync, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    SYNC.load(Ordering::Relaxed)
+}
+
+/// fsync an open file, data and metadata
+pub fn sync_file(file: &File) -> io::Result<()> {
+    if is_enabled() {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// fsync the directory holding `path`, so a new or renamed entry is on the disk.
+/// Systems that can't open a directory, like Windows, flush names with the file.
+pub fn sync_dir_of(path: &Path) -> io::Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match File::open(dir) {
+        Ok(dir) => dir.sync_all(),
+        Err(_) if cfg!(windows) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// fsync a file that has been written and closed, and its directory
+pub fn sync_path(path: &str) -> Result<()> {
+    if is_enabled() {
+        sync_file(&File::open(path)?)?;
+        sync_dir_of(Path::new(path))?;
+    }
+    Ok(())
+}
+
+/// std::fs::write that is on the disk when it returns
+pub fn write(path: &str, data: &[u8]) -> Result<()> {
+    std::fs::write(path, data)?;
+    sync_path(path)
+}
+
+/// std::fs::rename with the new name on the disk when it returns
+pub fn rename(from: &str, to: &str) -> Result<()> {
+    std::fs::rename(from, to)?;
+    sync_dir_of(Path::new(to))?;
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
+use anyhow::Result;
+use sha2::Sha256;
+use crate::durable;
+
+// Encrypted images are the whole plain image sealed with AES-256-GCM,
+// the key derived from a passphrase with PBKDF2-HMAC-SHA256:
+//   "CPMCRYPT" magic, u32 LE rounds, 16 bytes salt, 12 bytes nonce,
+//   ciphertext with the 16 byte tag at the end.
+// A wrong passphrase or a changed file fails the tag check.
+
+const MAGIC: &[u8] = b"CPMCRYPT";
+const ROUNDS: u32 = 600_000;
+/// Rounds accepted from a file, fewer is too weak and more takes minutes,
+/// either means the file wasn't written by encrypt
+const MIN_ROUNDS: u32 = 100_000;
+const MAX_ROUNDS: u32 = 10_000_000;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = MAGIC.len() + 4 + SALT_SIZE + NONCE_SIZE;
+
+/// True if the file starts like an encrypted image
+pub fn is_encrypted(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+struct CachedKey {
+    passphrase: Vec<u8>,
+    salt: Vec<u8>,
+    rounds: u32,
+    key: [u8; 32],
+}
+
+/// The last key derived, shared by the clones of a cache. A server opens
+/// the image for every command and only pays for PBKDF2 the first time.
+#[derive(Clone, Default)]
+pub struct KeyCache(Arc<Mutex<Option<CachedKey>>>);
+
+impl std::fmt::Debug for KeyCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("KeyCache")
+    }
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, rounds, &mut key);
+    key
+}
+
+fn cipher(passphrase: &[u8], salt: &[u8], rounds: u32) -> Aes256Gcm {
+    Aes256Gcm::new(&derive_key(passphrase, salt, rounds).into())
+}
+
+impl KeyCache {
+    fn cipher(&self, passphrase: &[u8], salt: &[u8], rounds: u32) -> Aes256Gcm {
+        let mut cached = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match &*cached {
+            Some(c) if c.passphrase == passphrase && c.salt == salt && c.rounds == rounds => Aes256Gcm::new(&c.key.into()),
+            _ => {
+                let key = derive_key(passphrase, salt, rounds);
+                *cached = Some(CachedKey { passphrase: passphrase.to_vec(), salt: salt.to_vec(), rounds, key });
+                Aes256Gcm::new(&key.into())
+            }
+        }
+    }
+}
+
+pub fn encrypt(image: &[u8], passphrase: &[u8]) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_SIZE];
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::fill(&mut salt).map_err(|e| anyhow::anyhow!("No random numbers for the salt: {}", e))?;
+    getrandom::fill(&mut nonce).map_err(|e| anyhow::anyhow!("No random numbers for the nonce: {}", e))?;
+
+    let sealed = cipher(passphrase, &salt, ROUNDS)
+        .encrypt(&Nonce::from(nonce), image)
+        .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + sealed.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&ROUNDS.to_le_bytes());
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&sealed);
+    Ok(out)
+}
+
+pub fn decrypt(encrypted: &[u8], passphrase: &[u8]) -> Result<Vec<u8>> {
+    decrypt_cached(encrypted, passphrase, &KeyCache::default())
+}
+
+/// Decrypt with the key in `keys` when it was derived from the same
+/// passphrase, salt and rounds
+pub fn decrypt_cached(encrypted: &[u8], passphrase: &[u8], keys: &KeyCache) -> Result<Vec<u8>> {
+    if !is_encrypted(encrypted) || encrypted.len() < HEADER_SIZE {
+        anyhow::bail!("Not an encrypted image");
+    }
+    let mut pos = MAGIC.len();
+    let rounds = u32::from_le_bytes(encrypted[pos..pos + 4].try_into()?);
+    if !(MIN_ROUNDS..=MAX_ROUNDS).contains(&rounds) {
+        anyhow::bail!("The encrypted image asks for {} PBKDF2 rounds, only {} to {} are accepted", rounds, MIN_ROUNDS, MAX_ROUNDS);
+    }
+    pos += 4;
+    let salt = &encrypted[pos..pos + SALT_SIZE];
+    pos += SALT_SIZE;
+    let nonce: [u8; NONCE_SIZE] = encrypted[pos..pos + NONCE_SIZE].try_into()?;
+    pos += NONCE_SIZE;
+
+    keys.cipher(passphrase, salt, rounds)
+        .decrypt(&Nonce::from(nonce), &encrypted[pos..])
+        .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the encrypted image is damaged"))
+}
+
+/// The passphrase is the first line of the file
+pub fn read_passphrase_file(path: &str) -> Result<Vec<u8>> {
+    let contents = std::fs::read_to_string(path)?;
+    let passphrase = contents.lines().next().unwrap_or("");
+    if passphrase.is_empty() {
+        anyhow::bail!("Passphrase file {} is empty", path);
+    }
+    Ok(passphrase.as_bytes().to_vec())
+}
+
+/// Write an encrypted copy of an image
+pub fn encrypt_image(image_path: &str, output_path: &str, passphrase_file: &str) -> Result<()> {
+    let passphrase = read_passphrase_file(passphrase_file)?;
+    let image = std::fs::read(image_path)?;
+    if is_encrypted(&image) {
+        anyhow::bail!("{} is already encrypted", image_path);
+    }
+    durable::write(output_path, &encrypt(&image, &passphrase)?)?;
+    Ok(())
+}
+
+/// Write a plain copy of an encrypted image
+pub fn decrypt_image(image_path: &str, output_path: &str, passphrase_file: &str) -> Result<()> {
+    let passphrase = read_passphrase_file(passphrase_file)?;
+    let encrypted = std::fs::read(image_path)?;
+    durable::write(output_path, &decrypt(&encrypted, &passphrase)?)?;
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use anyhow::Result;
+use crate::cpmimg::{self, CopyOutOptions};
+use crate::imagefile::ImageOptions;
+
+// Long running operations can be run on a thread of their own and report
+// what they do as events, so a GUI can show progress and offer a cancel
+// button without parsing the text the command line tool prints.
+
+/// What an operation is doing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Copying of a file has started, size in bytes
+    FileStarted { name: String, size: usize },
+    /// One allocation block was written, `done` of `total` bytes
+    BlockWritten { block: u16, done: usize, total: usize },
+    /// One allocation block was read, `done` of `total` bytes
+    BlockRead { block: u16, done: usize, total: usize },
+    /// Something that did not stop the operation
+    Warning(String),
+    /// The last event of a task, with the error if it failed
+    Done { error: Option<String> },
+}
+
+/// Handed to an operation to report events and check for cancellation.
+/// The default observer has nobody listening, warnings go to stderr.
+#[derive(Debug, Clone, Default)]
+pub struct Observer {
+    sender: Option<Sender<Event>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Observer {
+    pub fn emit(&self, event: Event) {
+        if let Some(sender) = &self.sender {
+            // Nobody listening any more is not an error for the operation
+            let _ = sender.send(event);
+        }
+    }
+
+    pub fn warning(&self, text: String) {
+        match &self.sender {
+            Some(_) => self.emit(Event::Warning(text)),
+            None => eprintln!("{}", text),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Fails once the task has been cancelled
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            anyhow::bail!("Cancelled");
+        }
+        Ok(())
+    }
+}
+
+/// An operation running on its own thread
+pub struct Task {
+    events: Receiver<Event>,
+    cancelled: Arc<AtomicBool>,
+    handle: JoinHandle<Result<()>>,
+}
+
+impl Task {
+    /// Events from the operation, the last one is always Event::Done
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+
+    /// Ask the operation to stop at the next block
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Wait for the operation to finish
+    pub fn wait(self) -> Result<()> {
+        match self.handle.join() {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!("Task panicked"),
+        }
+    }
+}
+
+/// Run an operation on a new thread
+pub fn spawn<F>(operation: F) -> Task
+where
+    F: FnOnce(&Observer) -> Result<()> + Send + 'static,
+{
+    let (sender, events) = mpsc::channel();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let observer = Observer { sender: Some(sender), cancelled: cancelled.clone() };
+    let handle = thread::spawn(move || {
+        let result = operation(&observer);
+        observer.emit(Event::Done { error: result.as_ref().err().map(|e| e.to_string()) });
+        result
+    });
+    Task { events, cancelled, handle }
+}
+
+/// copy_file_in as a task. A cancelled copy leaves no file behind.
+pub fn copy_in(image_path: &str, options: &ImageOptions, source_path: &str, cpm_file_name: &str, verify: bool) -> Task {
+    let (image_path, options, source_path, cpm_file_name) = (image_path.to_string(), options.clone(), source_path.to_string(), cpm_file_name.to_string());
+    spawn(move |observer| cpmimg::copy_file_in_observed(&image_path, &options, &source_path, &cpm_file_name, verify, observer))
+}
+
+/// copy_file_out as a task. A cancelled copy leaves no output file.
+pub fn copy_out(image_path: &str, options: &ImageOptions, cpm_file_name: &str, output_path: &str, copy: &CopyOutOptions) -> Task {
+    let (image_path, options, cpm_file_name, output_path, copy) = (image_path.to_string(), options.clone(), cpm_file_name.to_string(), output_path.to_string(), *copy);
+    spawn(move |observer| cpmimg::copy_file_out_observed(&image_path, &options, &cpm_file_name, &output_path, &copy, observer))
+}
@@ -0,0 +1,566 @@
+use std::collections::{HashMap, HashSet};
+use anyhow::Result;
+
+// Flux level disk captures, as made by GreaseWeazle, KryoFlux or FluxEngine,
+// stored in the SuperCard Pro (.scp) format.
+// https://www.cbmstuff.com/downloads/scp/scp_image_specs.txt
+//
+// The COMPIS drives are standard double density MFM, IBM System/34 layout:
+// each sector is an ID field (A1 A1 A1 FE C H R N crc) followed by a
+// data field (A1 A1 A1 FB data crc). A1 is written with a missing clock
+// bit, 0x4489 on the disk, which never occurs in normal data.
+
+const SCP_SIGNATURE: &[u8] = b"SCP";
+const SCP_TRACK_SIGNATURE: &[u8] = b"TRK";
+const SCP_HEADER_SIZE: usize = 0x10;
+const SCP_MAX_TRACKS: usize = 168;
+// Flux times are counted in 25 ns ticks unless the header says otherwise
+const SCP_BASE_RESOLUTION_NS: f64 = 25.0;
+
+const SYNC_WORD: u16 = 0x4489; // A1 with missing clock
+const ID_MARK: u8 = 0xfe;
+const DATA_MARK: u8 = 0xfb;
+const DELETED_DATA_MARK: u8 = 0xf8;
+const INDEX_SYNC_WORD: u16 = 0x5224; // C2 with missing clock
+const INDEX_MARK: u8 = 0xfc;
+const GAP_BYTE: u8 = 0x4e;
+const SYNC_BYTES: usize = 12;
+
+/// Physical layout of the tracks on a disk, and how sectors map to an image file
+#[derive(Debug, Clone, Copy)]
+pub struct TrackLayout {
+    pub cylinders: usize,
+    pub heads: usize,
+    pub sectors: usize,
+    pub sector_size: usize,
+    pub first_sector: u8,
+    // Data rate in kbit/s, 250 for double density
+    pub data_rate: u32,
+    pub rpm: u32,
+}
+
+impl TrackLayout {
+    /// 80 cylinders, 2 sides, 8 sectors of 512 bytes, DD at 300 rpm
+    pub const COMPIS: TrackLayout = TrackLayout {
+        cylinders: 80,
+        heads: 2,
+        sectors: 8,
+        sector_size: 512,
+        first_sector: 1,
+        data_rate: 250,
+        rpm: 300,
+    };
+
+    // Raw MFM cells are twice the data rate, 2 us for double density
+    fn cell_ns(&self) -> f64 {
+        1_000_000.0 / (self.data_rate as f64 * 2.0)
+    }
+
+    // Images hold the tracks in cylinder order with the sides interleaved,
+    // the same order as the data in the image files
+    pub fn image_offset(&self, cylinder: usize, head: usize, sector: u8) -> usize {
+        ((cylinder * self.heads + head) * self.sectors + (sector - self.first_sector) as usize) * self.sector_size
+    }
+}
+
+/// Gaps and sector order used when writing tracks, IBM System/34 style.
+/// The gap after the last sector (gap 4b) fills up the rest of the track.
+#[derive(Debug, Clone, Copy)]
+pub struct TrackFormat {
+    pub gap4a: usize,  // before the index mark
+    pub gap1: usize,   // after the index mark
+    pub gap2: usize,   // between ID and data field
+    pub gap3: usize,   // after each data field
+    pub interleave: usize,
+}
+
+impl Default for TrackFormat {
+    fn default() -> Self {
+        TrackFormat { gap4a: 80, gap1: 50, gap2: 22, gap3: 84, interleave: 1 }
+    }
+}
+
+/// Sectors found on one side of one track
+#[derive(Debug, Default)]
+pub struct DecodedTrack {
+    // sector number to data, only sectors with a good data crc
+    pub sectors: HashMap<u8, Vec<u8>>,
+    // sectors whose ID was found but whose data field was missing or failed the crc
+    pub bad: Vec<u8>,
+    // every ID with a good crc in the order on the track, with the data if it could be read
+    pub found: Vec<(SectorId, Option<Vec<u8>>)>,
+}
+
+/// The ID field of a sector, what the disk says it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SectorId {
+    pub cylinder: u8,
+    pub head: u8,
+    pub sector: u8,
+    pub size: usize,
+}
+
+/// What is unusual about a sector, the things copy protection relies on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Irregularity {
+    /// A sector number the layout doesn't use
+    SectorNumber,
+    /// The ID names another cylinder or head than the track it is on
+    WrongTrack,
+    /// Another size than the sectors of the layout
+    Size,
+    /// The same ID a second time on the track
+    Duplicate,
+}
+
+/// A sector that a disk written by a normal formatter doesn't have
+#[derive(Debug, Clone)]
+pub struct IrregularSector {
+    /// The physical track it is on
+    pub cylinder: usize,
+    pub head: usize,
+    pub id: SectorId,
+    pub irregularity: Irregularity,
+    /// None if the data couldn't be read
+    pub data: Option<Vec<u8>>,
+}
+
+impl TrackLayout {
+    /// Why a sector found on a track is not one of the layout, None if it is.
+    /// `seen` holds the IDs found on the track so far.
+    pub fn irregularity(&self, cylinder: usize, head: usize, id: &SectorId, seen: &mut HashSet<SectorId>) -> Option<Irregularity> {
+        if !seen.insert(*id) {
+            Some(Irregularity::Duplicate)
+        } else if id.size != self.sector_size {
+            Some(Irregularity::Size)
+        } else if id.cylinder as usize != cylinder || id.head as usize != head {
+            Some(Irregularity::WrongTrack)
+        } else if id.sector < self.first_sector || (id.sector - self.first_sector) as usize >= self.sectors {
+            Some(Irregularity::SectorNumber)
+        } else {
+            None
+        }
+    }
+}
+
+/// Result of decoding a whole flux capture into an image
+pub struct DecodedImage {
+    pub data: Vec<u8>,
+    // (cylinder, head, sector) for every sector that could not be read
+    pub missing: Vec<(usize, usize, u8)>,
+    // sectors that don't fit the layout, as they were found
+    pub irregular: Vec<IrregularSector>,
+}
+
+fn crc16_ccitt(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    match data.get(offset..offset + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => anyhow::bail!("SCP file is truncated at offset {:#x}", offset),
+    }
+}
+
+/// Flux intervals in nanoseconds for every track in an SCP file, first revolution only
+fn read_scp(data: &[u8]) -> Result<HashMap<usize, Vec<f64>>> {
+    if data.len() < SCP_HEADER_SIZE || &data[0..3] != SCP_SIGNATURE {
+        anyhow::bail!("Not an SCP flux image");
+    }
+    let revolutions = data[5] as usize;
+    let start_track = data[6] as usize;
+    let end_track = data[7] as usize;
+    let cell_width = data[9];
+    let tick_ns = SCP_BASE_RESOLUTION_NS * (data[11] as f64 + 1.0);
+    if cell_width != 0 && cell_width != 16 {
+        anyhow::bail!("SCP files with {} bit flux values are not supported", cell_width);
+    }
+    if revolutions == 0 {
+        anyhow::bail!("SCP file has no revolutions");
+    }
+
+    let mut tracks = HashMap::new();
+    for track in start_track..=end_track.min(SCP_MAX_TRACKS - 1) {
+        let track_offset = read_u32(data, SCP_HEADER_SIZE + track * 4)? as usize;
+        if track_offset == 0 {
+            continue;
+        }
+        if data.get(track_offset..track_offset + 3) != Some(SCP_TRACK_SIGNATURE) {
+            anyhow::bail!("Bad track header for track {} in SCP file", track);
+        }
+        let flux_count = read_u32(data, track_offset + 8)? as usize;
+        let flux_offset = track_offset + read_u32(data, track_offset + 12)? as usize;
+        let flux_bytes = match data.get(flux_offset..flux_offset + flux_count * 2) {
+            Some(flux_bytes) => flux_bytes,
+            None => anyhow::bail!("Flux data for track {} is truncated", track),
+        };
+
+        let mut intervals = Vec::with_capacity(flux_count);
+        let mut overflow = 0u32;
+        for pair in flux_bytes.chunks_exact(2) {
+            let value = u16::from_be_bytes([pair[0], pair[1]]) as u32;
+            // 0 means the counter wrapped without a transition
+            if value == 0 {
+                overflow += 0x10000;
+                continue;
+            }
+            intervals.push((overflow + value) as f64 * tick_ns);
+            overflow = 0;
+        }
+        tracks.insert(track, intervals);
+    }
+
+    Ok(tracks)
+}
+
+// Turn flux intervals into raw MFM cells. Each interval is 2, 3 or 4 cells,
+// the cell time follows slow speed changes so real captures with some drift decode too.
+fn flux_to_bits(intervals: &[f64], nominal_cell_ns: f64) -> Vec<bool> {
+    let mut bits = Vec::with_capacity(intervals.len() * 3);
+    let mut cell = nominal_cell_ns;
+    for &interval in intervals {
+        let cells = (interval / cell).round().clamp(1.0, 8.0);
+        bits.resize(bits.len() + cells as usize - 1, false);
+        bits.push(true);
+        // Only trust intervals that look like valid MFM for the adjustment
+        if (2.0..=4.0).contains(&cells) {
+            let measured = interval / cells;
+            cell += (measured - cell) * 0.05;
+            cell = cell.clamp(nominal_cell_ns * 0.8, nominal_cell_ns * 1.2);
+        }
+    }
+    bits
+}
+
+// Data bits are every second raw cell, the others are clock bits
+fn decode_bytes(bits: &[bool], start: usize, count: usize) -> Option<Vec<u8>> {
+    if start + count * 16 > bits.len() {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut byte = 0u8;
+        for bit in 0..8 {
+            byte = (byte << 1) | bits[start + i * 16 + bit * 2 + 1] as u8;
+        }
+        bytes.push(byte);
+    }
+    Some(bytes)
+}
+
+// Positions just after every run of three sync words
+fn find_address_marks(bits: &[bool]) -> Vec<usize> {
+    let mut marks = Vec::new();
+    let mut shift: u64 = 0;
+    for (i, &bit) in bits.iter().enumerate() {
+        shift = (shift << 1) | bit as u64;
+        let sync = SYNC_WORD as u64;
+        if i >= 47 && (shift & 0xffff_ffff_ffff) == (sync << 32 | sync << 16 | sync) {
+            marks.push(i + 1);
+        }
+    }
+    marks
+}
+
+/// Decode the sectors in the raw MFM cells of one track
+pub fn decode_track(bits: &[bool], sector_size: usize) -> DecodedTrack {
+    let mut track = DecodedTrack::default();
+    let marks = find_address_marks(bits);
+    let mut pending_id: Option<SectorId> = None;
+
+    for &mark in &marks {
+        let kind = match decode_bytes(bits, mark, 1) {
+            Some(kind) => kind[0],
+            None => continue,
+        };
+        match kind {
+            ID_MARK => {
+                if let Some(id) = pending_id.take() {
+                    track.bad.push(id.sector);
+                    track.found.push((id, None));
+                }
+                let id = match decode_bytes(bits, mark, 7) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let mut crc_data = vec![0xa1, 0xa1, 0xa1];
+                crc_data.extend_from_slice(&id[..5]);
+                if crc16_ccitt(&crc_data) != u16::from_be_bytes([id[5], id[6]]) {
+                    continue;
+                }
+                let size = 128usize << (id[4] & 0x07);
+                pending_id = Some(SectorId { cylinder: id[1], head: id[2], sector: id[3], size });
+            }
+            DATA_MARK | DELETED_DATA_MARK => {
+                let id = match pending_id.take() {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let size = id.size;
+                let data = decode_bytes(bits, mark, 1 + size + 2).filter(|field| {
+                    let mut crc_data = vec![0xa1, 0xa1, 0xa1];
+                    crc_data.extend_from_slice(&field[..1 + size]);
+                    crc16_ccitt(&crc_data) == u16::from_be_bytes([field[1 + size], field[2 + size]])
+                }).map(|field| field[1..1 + size].to_vec());
+                track.found.push((id, data.clone()));
+                match data {
+                    Some(data) if size == sector_size => {
+                        track.sectors.entry(id.sector).or_insert(data);
+                    }
+                    _ => track.bad.push(id.sector),
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(id) = pending_id {
+        track.bad.push(id.sector);
+        track.found.push((id, None));
+    }
+    // A sector that was read fine once is not bad
+    track.bad.retain(|s| !track.sectors.contains_key(s));
+    track.bad.sort();
+    track.bad.dedup();
+
+    track
+}
+
+/// Decode an SCP capture into a plain image, sectors that can't be read are filled with `fill`
+pub fn decode_scp(scp: &[u8], layout: &TrackLayout, fill: u8) -> Result<DecodedImage> {
+    let tracks = read_scp(scp)?;
+    let mut data = vec![fill; layout.cylinders * layout.heads * layout.sectors * layout.sector_size];
+    let mut missing = Vec::new();
+    let mut irregular = Vec::new();
+
+    for cylinder in 0..layout.cylinders {
+        for head in 0..layout.heads {
+            let decoded = match tracks.get(&(cylinder * 2 + head)) {
+                Some(intervals) => decode_track(&flux_to_bits(intervals, layout.cell_ns()), layout.sector_size),
+                None => DecodedTrack::default(),
+            };
+            let mut seen = HashSet::new();
+            for (id, data) in &decoded.found {
+                if let Some(irregularity) = layout.irregularity(cylinder, head, id, &mut seen) {
+                    irregular.push(IrregularSector { cylinder, head, id: *id, irregularity, data: data.clone() });
+                }
+            }
+            for i in 0..layout.sectors {
+                let sector = layout.first_sector + i as u8;
+                match decoded.sectors.get(&sector) {
+                    Some(sector_data) => {
+                        let offset = layout.image_offset(cylinder, head, sector);
+                        data[offset..offset + layout.sector_size].copy_from_slice(sector_data);
+                    }
+                    None => missing.push((cylinder, head, sector)),
+                }
+            }
+        }
+    }
+
+    Ok(DecodedImage { data, missing, irregular })
+}
+
+// Physical order of the sectors on a track for an interleave factor
+fn sector_order(layout: &TrackLayout, interleave: usize) -> Vec<u8> {
+    let mut order: Vec<Option<u8>> = vec![None; layout.sectors];
+    let mut pos = 0;
+    for i in 0..layout.sectors {
+        while order[pos].is_some() {
+            pos = (pos + 1) % layout.sectors;
+        }
+        order[pos] = Some(layout.first_sector + i as u8);
+        pos = (pos + interleave.max(1)) % layout.sectors;
+    }
+    order.into_iter().flatten().collect()
+}
+
+struct MfmWriter {
+    bits: Vec<bool>,
+    previous: bool,
+}
+
+impl MfmWriter {
+    // A clock bit is written between two 0 data bits
+    fn byte(&mut self, byte: u8) {
+        for i in (0..8).rev() {
+            let data = (byte >> i) & 1 != 0;
+            self.bits.push(!self.previous && !data);
+            self.bits.push(data);
+            self.previous = data;
+        }
+    }
+
+    fn bytes(&mut self, byte: u8, count: usize) {
+        for _ in 0..count {
+            self.byte(byte);
+        }
+    }
+
+    // Marks with a missing clock bit, written as is
+    fn raw(&mut self, word: u16) {
+        for i in (0..16).rev() {
+            self.bits.push((word >> i) & 1 != 0);
+        }
+        self.previous = word & 1 != 0;
+    }
+
+    // Sync, three marks, the mark byte and the field with its crc
+    fn field(&mut self, mark: u8, data: &[u8]) {
+        self.bytes(0x00, SYNC_BYTES);
+        for _ in 0..3 {
+            self.raw(SYNC_WORD);
+        }
+        let mut crc_data = vec![0xa1, 0xa1, 0xa1, mark];
+        crc_data.extend_from_slice(data);
+        self.byte(mark);
+        for &b in data {
+            self.byte(b);
+        }
+        for b in crc16_ccitt(&crc_data).to_be_bytes() {
+            self.byte(b);
+        }
+    }
+}
+
+/// Raw MFM cells for one side of one track. `sectors` is the track data in
+/// logical sector order, as it is stored in the image. `extra` sectors are
+/// written after them with their own IDs, the ones without data as an ID only.
+pub fn encode_track(layout: &TrackLayout, format: &TrackFormat, cylinder: usize, head: usize, sectors: &[u8], extra: &[&IrregularSector]) -> Result<Vec<bool>> {
+    let mut writer = MfmWriter { bits: Vec::new(), previous: false };
+    let size_code = (layout.sector_size / 128).trailing_zeros() as u8;
+
+    writer.bytes(GAP_BYTE, format.gap4a);
+    writer.bytes(0x00, SYNC_BYTES);
+    for _ in 0..3 {
+        writer.raw(INDEX_SYNC_WORD);
+    }
+    writer.byte(INDEX_MARK);
+    writer.bytes(GAP_BYTE, format.gap1);
+
+    for sector in sector_order(layout, format.interleave) {
+        let id = [cylinder as u8, head as u8, sector, size_code];
+        writer.field(ID_MARK, &id);
+        writer.bytes(GAP_BYTE, format.gap2);
+        let start = (sector - layout.first_sector) as usize * layout.sector_size;
+        writer.field(DATA_MARK, &sectors[start..start + layout.sector_size]);
+        writer.bytes(GAP_BYTE, format.gap3);
+    }
+    for sector in extra {
+        let size_code = (sector.id.size / 128).trailing_zeros() as u8;
+        writer.field(ID_MARK, &[sector.id.cylinder, sector.id.head, sector.id.sector, size_code]);
+        writer.bytes(GAP_BYTE, format.gap2);
+        if let Some(data) = &sector.data {
+            writer.field(DATA_MARK, data);
+        }
+        writer.bytes(GAP_BYTE, format.gap3);
+    }
+
+    let track_cells = (60_000_000_000.0 / layout.rpm as f64 / layout.cell_ns()) as usize;
+    if writer.bits.len() > track_cells {
+        anyhow::bail!("Track {}.{} needs {} cells but only {} fit in one revolution, make the gaps smaller",
+            cylinder, head, writer.bits.len(), track_cells);
+    }
+    while writer.bits.len() + 16 <= track_cells {
+        writer.byte(GAP_BYTE);
+    }
+
+    Ok(writer.bits)
+}
+
+// Flux intervals in ticks between the transitions, a 1 cell is a transition
+fn bits_to_flux(bits: &[bool], ticks_per_cell: u32) -> Vec<u32> {
+    let mut flux = Vec::new();
+    let mut cells = 0;
+    for &bit in bits {
+        cells += 1;
+        if bit {
+            flux.push(cells * ticks_per_cell);
+            cells = 0;
+        }
+    }
+    flux
+}
+
+/// Encode a plain image as an SCP flux image, one revolution per track,
+/// ready to be written with GreaseWeazle. Missing data at the end of the
+/// image is written as `fill`. The irregular sectors of the source are
+/// written on the tracks they were found on.
+pub fn encode_scp(image: &[u8], layout: &TrackLayout, format: &TrackFormat, fill: u8, irregular: &[IrregularSector]) -> Result<Vec<u8>> {
+    let track_size = layout.sectors * layout.sector_size;
+    let num_tracks = layout.cylinders * layout.heads;
+    if num_tracks > SCP_MAX_TRACKS {
+        anyhow::bail!("SCP files can hold at most {} tracks", SCP_MAX_TRACKS);
+    }
+    let ticks_per_cell = (layout.cell_ns() / SCP_BASE_RESOLUTION_NS).round() as u32;
+
+    let mut header = vec![0u8; SCP_HEADER_SIZE];
+    header[0..3].copy_from_slice(SCP_SIGNATURE);
+    header[3] = 0x22; // version 2.2
+    header[4] = 0x80; // disk type: other
+    header[5] = 1; // revolutions
+    header[6] = 0; // start track
+    header[7] = (num_tracks - 1) as u8;
+    header[8] = 0x03; // flags: index aligned, 96 tpi
+    header[9] = 0; // 16 bit flux values
+    header[10] = if layout.heads == 1 { 1 } else { 0 };
+    header[11] = 0; // 25 ns resolution
+
+    let mut table = vec![0u8; SCP_MAX_TRACKS * 4];
+    let mut body = Vec::new();
+    let body_start = SCP_HEADER_SIZE + table.len();
+
+    for cylinder in 0..layout.cylinders {
+        for head in 0..layout.heads {
+            let track = cylinder * 2 + head;
+            let start = (cylinder * layout.heads + head) * track_size;
+            let mut data = vec![fill; track_size];
+            if start < image.len() {
+                let end = image.len().min(start + track_size);
+                data[..end - start].copy_from_slice(&image[start..end]);
+            }
+
+            let extra: Vec<&IrregularSector> = irregular.iter().filter(|s| s.cylinder == cylinder && s.head == head).collect();
+            let bits = encode_track(layout, format, cylinder, head, &data, &extra)?;
+            let mut flux_data = Vec::new();
+            for interval in bits_to_flux(&bits, ticks_per_cell) {
+                // Intervals longer than 16 bits are written as 0 for every wrap
+                for _ in 0..interval / 0x10000 {
+                    flux_data.extend_from_slice(&[0, 0]);
+                }
+                flux_data.extend_from_slice(&((interval % 0x10000) as u16).to_be_bytes());
+            }
+            let duration = bits.len() as u32 * ticks_per_cell;
+
+            let offset = (body_start + body.len()) as u32;
+            table[track * 4..track * 4 + 4].copy_from_slice(&offset.to_le_bytes());
+            body.extend_from_slice(SCP_TRACK_SIGNATURE);
+            body.push(track as u8);
+            body.extend_from_slice(&duration.to_le_bytes());
+            body.extend_from_slice(&((flux_data.len() / 2) as u32).to_le_bytes());
+            body.extend_from_slice(&16u32.to_le_bytes()); // flux data follows the track header
+            body.extend_from_slice(&flux_data);
+        }
+    }
+
+    let mut scp = header;
+    scp.extend_from_slice(&table);
+    scp.extend_from_slice(&body);
+    // The checksum covers everything after the header
+    let checksum = scp[SCP_HEADER_SIZE..].iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+    scp[12..16].copy_from_slice(&checksum.to_le_bytes());
+
+    Ok(scp)
+}
+
+/// True if the file looks like an SCP flux image
+pub fn is_scp(header: &[u8]) -> bool {
+    header.starts_with(SCP_SIGNATURE)
+}
@@ -0,0 +1,262 @@
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use crate::cpmimg::{DiskSize, Geometry};
+
+/// Support for a disk image format this crate doesn't know about.
+/// A handler turns its own file layout into sectors, the image is then
+/// seen as the plain sector by sector image the rest of the tools work on.
+/// Register it with register_handler before opening images, from then on
+/// open_image tries it on every file that isn't a raw image or a known container.
+pub trait FormatHandler: Send + Sync {
+    /// Short name, shown by `formats`
+    fn name(&self) -> &str;
+
+    /// True if the file is in this format. `header` is the first 512 bytes,
+    /// or all of the file if it is shorter.
+    fn detect(&self, header: &[u8], size: u64) -> bool;
+
+    /// The layout of the disk the file holds. Opening a file in this format
+    /// makes it the geometry in use, in place of --format and the geometry options.
+    fn geometry(&self) -> Geometry;
+
+    /// Read one sector, `sector` counting from 0 within the track
+    fn read_sector(&self, file: &mut File, cylinder: usize, head: usize, sector: usize, buf: &mut [u8]) -> Result<()>;
+
+    /// Write one sector, the default is a read only format
+    fn write_sector(&self, _file: &mut File, _cylinder: usize, _head: usize, _sector: usize, _buf: &[u8]) -> Result<()> {
+        anyhow::bail!("Images in the {} format can't be written", self.name())
+    }
+
+    /// True if write_sector is implemented
+    fn writable(&self) -> bool {
+        false
+    }
+}
+
+static HANDLERS: Mutex<Vec<Arc<dyn FormatHandler>>> = Mutex::new(Vec::new());
+
+/// Make a format handler known to open_image and `formats`
+pub fn register_handler(handler: Arc<dyn FormatHandler>) {
+    HANDLERS.lock().unwrap().push(handler);
+}
+
+/// All registered handlers, in the order they were registered
+pub fn handlers() -> Vec<Arc<dyn FormatHandler>> {
+    HANDLERS.lock().unwrap().clone()
+}
+
+/// How much of a format the tools can handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Support {
+    /// Files can be listed, read and written
+    ReadWrite,
+    /// Only an empty image with the right media byte can be created,
+    /// the directory layout is not known
+    Create,
+    /// Files can be listed and read, from a registered handler
+    ReadOnly,
+}
+
+/// One of the formats `create` knows about, or a registered handler
+#[derive(Debug, Clone, Serialize)]
+pub struct FormatInfo {
+    /// The name used on the command line
+    pub name: String,
+    /// Size of the image file in bytes
+    pub capacity: usize,
+    /// Byte at offset 0x1ff that CP/M-86 uses to tell the formats apart,
+    /// not known for formats from a handler
+    pub media_byte: Option<u8>,
+    /// Only known for formats that can be read and written
+    pub geometry: Option<Geometry>,
+    pub support: Support,
+}
+
+/// All supported formats, in the order they are offered by `create`,
+/// followed by the registered handlers
+pub fn all() -> Vec<FormatInfo> {
+    let handlers = handlers().into_iter().map(|handler| {
+        let geometry = handler.geometry();
+        FormatInfo {
+            name: handler.name().to_string(),
+            capacity: geometry.total_size(),
+            media_byte: None,
+            geometry: Some(geometry),
+            support: if handler.writable() { Support::ReadWrite } else { Support::ReadOnly },
+        }
+    });
+
+    DiskSize::value_variants().iter().map(|size| {
+        let name = size.to_possible_value().map_or(String::new(), |v| v.get_name().to_string());
+        let geometry = match size {
+            DiskSize::K640 => Some(Geometry::COMPIS),
+            _ => None,
+        };
+        FormatInfo {
+            name,
+            capacity: size.num_bytes(),
+            media_byte: Some(size.hex_value()),
+            support: if geometry.is_some() { Support::ReadWrite } else { Support::Create },
+            geometry,
+        }
+    }).chain(handlers).collect()
+}
+
+/// The geometry of a format by the name `formats` shows, case doesn't matter
+pub fn find_geometry(name: &str) -> Result<Geometry> {
+    let formats = all();
+    match formats.iter().find(|format| format.name.eq_ignore_ascii_case(name)) {
+        Some(FormatInfo { geometry: Some(geometry), .. }) => Ok(*geometry),
+        Some(_) => anyhow::bail!("The directory layout of the {} format is not known, describe the disk with 640K and --tracks, --blocksize and the others", name),
+        None => anyhow::bail!("Unknown format {}, the formats are: {}", name,
+            formats.iter().map(|format| format.name.as_str()).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+/// Changes to the geometry of a format, for a disk that is almost like
+/// one of the known formats, without registering a handler for it
+#[derive(Debug, Clone, Default)]
+pub struct GeometryOverrides {
+    pub tracks: Option<usize>,
+    pub sectors_per_track: Option<usize>,
+    pub bytes_per_sector: Option<usize>,
+    pub block_size: Option<usize>,
+    /// Directory entries, must fill whole blocks
+    pub dir_entries: Option<usize>,
+    pub reserved_tracks: Option<usize>,
+    pub fill_byte: Option<u8>,
+    pub skew: Option<usize>,
+}
+
+impl GeometryOverrides {
+
+    /// The geometry with the overrides applied, checked that it can be used
+    pub fn apply(&self, geometry: Geometry) -> Result<Geometry> {
+        let mut geometry = Geometry {
+            tracks: self.tracks.unwrap_or(geometry.tracks),
+            sectors_per_track: self.sectors_per_track.unwrap_or(geometry.sectors_per_track),
+            bytes_per_sector: self.bytes_per_sector.unwrap_or(geometry.bytes_per_sector),
+            block_size: self.block_size.unwrap_or(geometry.block_size),
+            reserved_tracks: self.reserved_tracks.unwrap_or(geometry.reserved_tracks),
+            fill_byte: self.fill_byte.unwrap_or(geometry.fill_byte),
+            skew: self.skew.unwrap_or(geometry.skew),
+            ..geometry
+        };
+        if let Some(entries) = self.dir_entries {
+            if entries == 0 || (entries * 32) % geometry.block_size != 0 {
+                anyhow::bail!("{} directory entries don't fill whole blocks of {} bytes", entries, geometry.block_size);
+            }
+            geometry.dir_blocks = entries * 32 / geometry.block_size;
+        }
+        geometry.validate()?;
+        Ok(geometry)
+    }
+}
+
+/// Print the supported formats as a table or as JSON
+pub fn list_formats(json: bool) -> Result<()> {
+    let formats = all();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&formats)?);
+        return Ok(());
+    }
+
+    println!("Name      Size Media Support   Geometry");
+    println!("----------------------------------------");
+    for format in &formats {
+        let support = match format.support {
+            Support::ReadWrite => "read/write",
+            Support::Create => "create",
+            Support::ReadOnly => "read",
+        };
+        let geometry = match &format.geometry {
+            Some(g) => format!("{}x{}x{}x{}, {} reserved track, fill {:02X}", g.sides, g.tracks, g.sectors_per_track, g.bytes_per_sector, g.reserved_tracks, g.fill_byte),
+            None => String::new(),
+        };
+        let media_byte = format.media_byte.map_or("--".to_string(), |b| format!("{:02x}", b));
+        println!("{:<6} {:>7}   {}  {:<10} {}", format.name, format.capacity, media_byte, support, geometry);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use crate::cpmimg::{self, tests::{Scratch, UseGeometry, blank_image, contents_of, lock_globals, store, test_data}};
+    use crate::imagefile::{ImageOptions, open_image};
+    use super::*;
+
+    const MAGIC: &[u8] = b"TESTFMT\0";
+
+    // The sectors in image order after a magic number, on a disk of 16 sectors per track
+    struct TestHandler;
+
+    impl TestHandler {
+        fn offset(cylinder: usize, head: usize, sector: usize) -> u64 {
+            let g = TestHandler.geometry();
+            (MAGIC.len() + ((cylinder * g.sides + head) * g.sectors_per_track + sector) * g.bytes_per_sector) as u64
+        }
+    }
+
+    impl FormatHandler for TestHandler {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn detect(&self, header: &[u8], _size: u64) -> bool {
+            header.starts_with(MAGIC)
+        }
+
+        fn geometry(&self) -> Geometry {
+            Geometry { sectors_per_track: 16, ..Geometry::COMPIS }
+        }
+
+        fn read_sector(&self, file: &mut File, cylinder: usize, head: usize, sector: usize, buf: &mut [u8]) -> Result<()> {
+            file.seek(SeekFrom::Start(TestHandler::offset(cylinder, head, sector)))?;
+            file.read_exact(buf)?;
+            Ok(())
+        }
+
+        fn write_sector(&self, file: &mut File, cylinder: usize, head: usize, sector: usize, buf: &[u8]) -> Result<()> {
+            file.seek(SeekFrom::Start(TestHandler::offset(cylinder, head, sector)))?;
+            file.write_all(buf)?;
+            Ok(())
+        }
+
+        fn writable(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn handler_sets_geometry() {
+        let _globals = lock_globals();
+        let scratch = Scratch::new("formats-handler");
+        // More than a COMPIS disk holds, so the file has to be on both halves of the larger disk
+        let data = test_data(3, 700 * 1024);
+        let image = {
+            let _geometry = UseGeometry::new(TestHandler.geometry());
+            let mut disk = blank_image();
+            store(&mut disk, "0:BIG.BIN", &data, false).unwrap();
+            let mut image = MAGIC.to_vec();
+            image.extend(disk.into_inner());
+            image
+        };
+        let path = scratch.path("disk.tst");
+        std::fs::write(&path, image).unwrap();
+
+        register_handler(std::sync::Arc::new(TestHandler));
+        let _geometry = UseGeometry::new(Geometry::COMPIS);
+        let mut disk = open_image(&path, true, &ImageOptions::default()).unwrap();
+        assert!(cpmimg::geometry() == TestHandler.geometry());
+        store(disk.as_mut(), "0:SMALL.TXT", b"hello\r\n", false).unwrap();
+        let mut padded = b"hello\r\n".to_vec();
+        padded.resize(128, 0x1a);
+        assert!(contents_of(disk.as_mut()) == vec![("0:BIG.BIN".to_string(), data), ("0:SMALL.TXT".to_string(), padded)]);
+    }
+}
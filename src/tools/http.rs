@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use anyhow::Result;
+use crate::imagefile::ImageFile;
+
+// Images on web servers, read with range requests. Only the parts that are
+// used are fetched, in chunks that are kept for the rest of the run, so
+// listing an image costs the boot sector and the directory and copying a
+// file out costs its blocks. A server that doesn't do ranges sends the
+// whole image on the first request, which is kept as one chunk.
+
+const CHUNK_SIZE: u64 = 8 * 1024;
+
+/// An image behind an http or https URL, read only
+pub struct HttpImage {
+    agent: ureq::Agent,
+    url: String,
+    size: u64,
+    pos: u64,
+    chunks: HashMap<u64, Vec<u8>>,
+}
+
+// "bytes 0-8191/737280" to 737280
+fn total_size(content_range: &str) -> Option<u64> {
+    content_range.rsplit('/').next()?.trim().parse().ok()
+}
+
+impl HttpImage {
+    pub fn open(url: &str) -> Result<HttpImage> {
+        let agent = ureq::Agent::new_with_defaults();
+        let mut response = match agent.get(url).header("Range", format!("bytes=0-{}", CHUNK_SIZE - 1)).call() {
+            Ok(response) => response,
+            Err(e) => anyhow::bail!("Could not read {}: {}", url, e),
+        };
+        let mut image = HttpImage { agent, url: url.to_string(), size: 0, pos: 0, chunks: HashMap::new() };
+
+        if response.status() == 206 {
+            let content_range = response.headers().get("content-range").and_then(|v| v.to_str().ok());
+            image.size = match content_range.and_then(total_size) {
+                Some(size) => size,
+                None => anyhow::bail!("{} doesn't give the size of the image", url),
+            };
+            let data = response.body_mut().with_config().limit(CHUNK_SIZE + 1).read_to_vec()?;
+            image.chunks.insert(0, data);
+        } else {
+            eprintln!("Warning: {} doesn't support range requests, reading the whole image", url);
+            let data = response.body_mut().with_config().limit(u64::MAX).read_to_vec()?;
+            image.size = data.len() as u64;
+            image.chunks.insert(0, data);
+        }
+        Ok(image)
+    }
+
+    fn fetch(&self, start: u64) -> Result<Vec<u8>, ureq::Error> {
+        let end = (start + CHUNK_SIZE).min(self.size) - 1;
+        let mut response = self.agent.get(&self.url).header("Range", format!("bytes={}-{}", start, end)).call()?;
+        match response.status().as_u16() {
+            206 => response.body_mut().with_config().limit(CHUNK_SIZE + 1).read_to_vec(),
+            status => Err(ureq::Error::StatusCode(status)),
+        }
+    }
+
+    // The chunk holding offset pos and where pos is in it
+    fn chunk(&mut self, pos: u64) -> io::Result<(&[u8], usize)> {
+        // Everything is in chunk 0 when the server sent the whole image
+        let start = if self.chunks.get(&0).is_some_and(|c| c.len() as u64 == self.size) { 0 } else { pos / CHUNK_SIZE * CHUNK_SIZE };
+        if !self.chunks.contains_key(&start) {
+            // A kept connection the server has closed in the meantime fails
+            // once, a second try opens a new one
+            let data = self.fetch(start).or_else(|_| self.fetch(start))
+                .map_err(|e| io::Error::other(format!("Reading {} at {:#x}: {}", self.url, start, e)))?;
+            self.chunks.insert(start, data);
+        }
+        Ok((&self.chunks[&start], (pos - start) as usize))
+    }
+}
+
+impl Read for HttpImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let (chunk, offset) = self.chunk(self.pos)?;
+        let n = buf.len().min(chunk.len().saturating_sub(offset));
+        buf[..n].copy_from_slice(&chunk[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for HttpImage {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "images on a web server are read only"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for HttpImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(d) => self.pos as i64 + d,
+            SeekFrom::End(d) => self.size as i64 + d,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the image"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl ImageFile for HttpImage {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,791 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Write, Seek, SeekFrom};
+use anyhow::Result;
+use memmap2::{Mmap, MmapMut};
+use crate::flux::{self, DecodedImage, IrregularSector, TrackLayout};
+use crate::chunked::{self, ChunkedImage};
+use crate::cpmimg::{self, Geometry};
+use crate::durable;
+use crate::encryption::{self, KeyCache};
+use crate::formats::{self, FormatHandler};
+use crate::imd;
+use crate::iotrace::{self, TracedImage};
+
+// Images at or above this size are memory mapped even without --mmap.
+// Floppy images are read in a few small chunks and gain nothing from it,
+// hard disk images are scanned in full by the catalog code.
+pub const MMAP_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+/// Backing storage for a disk image.
+/// Everything that reads or writes an image goes through this trait,
+/// so the catalog and copy code don't care where the bytes live.
+pub trait ImageFile: Read + Write + Seek {
+    /// Size of the image in bytes
+    fn size(&mut self) -> Result<u64>;
+
+    /// Make sure everything written so far has reached the storage
+    fn sync(&mut self) -> Result<()>;
+
+    /// Sectors that could not be read from the source of the image,
+    /// only known for images unpacked from containers and flux captures
+    fn bad_sectors(&self) -> Option<&SectorMap> {
+        None
+    }
+}
+
+/// The sectors of an image that were unreadable in the container it came from
+#[derive(Debug, Clone, Default)]
+pub struct SectorMap {
+    pub sector_size: u64,
+    // image offsets of the first byte of each bad sector
+    pub bad: BTreeSet<u64>,
+}
+
+impl SectorMap {
+    pub fn from_missing(layout: &TrackLayout, missing: &[(usize, usize, u8)]) -> SectorMap {
+        SectorMap {
+            sector_size: layout.sector_size as u64,
+            bad: missing.iter().map(|&(c, h, r)| layout.image_offset(c, h, r) as u64).collect(),
+        }
+    }
+
+    /// Bad sectors overlapping the byte range
+    pub fn bad_in(&self, start: u64, len: u64) -> Vec<u64> {
+        let first = start - start % self.sector_size;
+        self.bad.range(first..start + len).copied().collect()
+    }
+}
+
+/// An image unpacked in memory from a container or flux capture, read only
+pub struct ContainerImage {
+    data: Cursor<Vec<u8>>,
+    bad_sectors: SectorMap,
+}
+
+impl ContainerImage {
+    pub fn new(decoded: DecodedImage, layout: &TrackLayout) -> ContainerImage {
+        ContainerImage {
+            bad_sectors: SectorMap::from_missing(layout, &decoded.missing),
+            data: Cursor::new(decoded.data),
+        }
+    }
+}
+
+impl Read for ContainerImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Write for ContainerImage {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "images unpacked from a container are read only"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ContainerImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl ImageFile for ContainerImage {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.data.get_ref().len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn bad_sectors(&self) -> Option<&SectorMap> {
+        Some(&self.bad_sectors)
+    }
+}
+
+impl ImageFile for File {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        durable::sync_file(self)?;
+        Ok(())
+    }
+}
+
+// Images decoded or built in memory
+impl ImageFile for Cursor<Vec<u8>> {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An image kept entirely in memory that can be told to fail, so error
+/// paths can be run without a broken disk at hand.
+/// Ex: MemImage::new(data).fail_on_write(3) fails the third write
+#[derive(Debug, Clone, Default)]
+pub struct MemImage {
+    data: Cursor<Vec<u8>>,
+    reads: usize,
+    writes: usize,
+    fail_read: Option<usize>,
+    fail_write: Option<usize>,
+    max_write: Option<usize>,
+}
+
+impl MemImage {
+    pub fn new(data: Vec<u8>) -> MemImage {
+        MemImage { data: Cursor::new(data), ..MemImage::default() }
+    }
+
+    /// Make the nth read from now on fail, counting from 1
+    pub fn fail_on_read(mut self, n: usize) -> MemImage {
+        self.fail_read = Some(self.reads + n);
+        self
+    }
+
+    /// Make the nth write from now on fail, counting from 1
+    pub fn fail_on_write(mut self, n: usize) -> MemImage {
+        self.fail_write = Some(self.writes + n);
+        self
+    }
+
+    /// Take at most `bytes` of each write, like a pipe or a full disk can,
+    /// for finding writes whose count isn't checked
+    pub fn short_writes(mut self, bytes: usize) -> MemImage {
+        self.max_write = Some(bytes.max(1));
+        self
+    }
+
+    /// Number of reads and writes done so far
+    pub fn counts(&self) -> (usize, usize) {
+        (self.reads, self.writes)
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data.into_inner()
+    }
+}
+
+impl Read for MemImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        if self.fail_read == Some(self.reads) {
+            return Err(io::Error::other(format!("injected failure on read {}", self.reads)));
+        }
+        self.data.read(buf)
+    }
+}
+
+impl Write for MemImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        if self.fail_write == Some(self.writes) {
+            return Err(io::Error::other(format!("injected failure on write {}", self.writes)));
+        }
+        let len = self.max_write.map_or(buf.len(), |max| buf.len().min(max));
+        self.data.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl ImageFile for MemImage {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.data.get_ref().len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// How an image should be opened
+#[derive(Debug, Clone, Default)]
+pub struct ImageOptions {
+    /// Always memory map the image, not only above MMAP_THRESHOLD
+    pub mmap: bool,
+    /// Passphrase for encrypted images, the first line of this file
+    pub passphrase_file: Option<String>,
+    /// Where the CP/M image starts in the file, for hard disk and USB dumps.
+    /// Found from the partition table if not given.
+    pub offset: Option<u64>,
+    /// Keys derived for encrypted images, shared by the clones of these options
+    pub keys: KeyCache,
+}
+
+enum Mapping {
+    ReadOnly(Mmap),
+    ReadWrite(MmapMut),
+}
+
+/// An image file accessed through a memory mapping.
+/// Reads are plain copies out of the mapping and writes go straight
+/// into the page cache. The image can not grow, writing past the end
+/// is an error.
+pub struct MmapImage {
+    map: Mapping,
+    pos: u64,
+}
+
+impl MmapImage {
+    pub fn open(path: &str, writable: bool) -> Result<MmapImage> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)?;
+        // Safety: the mapping is only valid as long as nobody else truncates
+        // the file, the same assumption the rest of the tool makes about images
+        let map = if writable {
+            Mapping::ReadWrite(unsafe { MmapMut::map_mut(&file)? })
+        } else {
+            Mapping::ReadOnly(unsafe { Mmap::map(&file)? })
+        };
+        Ok(MmapImage { map, pos: 0 })
+    }
+
+    /// The whole image as a slice, without copying
+    pub fn as_slice(&self) -> &[u8] {
+        match &self.map {
+            Mapping::ReadOnly(map) => map,
+            Mapping::ReadWrite(map) => map,
+        }
+    }
+}
+
+impl Read for MmapImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.as_slice();
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MmapImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pos = self.pos as usize;
+        let map = match &mut self.map {
+            Mapping::ReadWrite(map) => map,
+            Mapping::ReadOnly(_) => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "image is opened read only")),
+        };
+        if pos + buf.len() > map.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "write past end of memory mapped image"));
+        }
+        map[pos..pos + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.map {
+            Mapping::ReadWrite(map) => map.flush(),
+            Mapping::ReadOnly(_) => Ok(()),
+        }
+    }
+}
+
+impl Seek for MmapImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.as_slice().len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of image"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl ImageFile for MmapImage {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.as_slice().len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if durable::is_enabled() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A part of a larger image file, such as a partition of a hard disk dump.
+/// Offsets are relative to the start of the part and it can not grow.
+pub struct OffsetImage {
+    inner: Box<dyn ImageFile>,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl OffsetImage {
+    pub fn new(mut inner: Box<dyn ImageFile>, start: u64, len: Option<u64>) -> Result<OffsetImage> {
+        let size = inner.size()?;
+        if start >= size {
+            anyhow::bail!("Offset {:#x} is past the end of the image, {} bytes", start, size);
+        }
+        let len = len.unwrap_or(size - start).min(size - start);
+        Ok(OffsetImage { inner, start, len, pos: 0 })
+    }
+}
+
+impl Read for OffsetImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.len.saturating_sub(self.pos) as usize;
+        let len = buf.len().min(available);
+        if len == 0 {
+            return Ok(0);
+        }
+        self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.inner.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for OffsetImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos + buf.len() as u64 > self.len {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "write past end of partition"));
+        }
+        self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.inner.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for OffsetImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of partition"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl ImageFile for OffsetImage {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+/// An image with the sectors of each data track in physical order, seen in
+/// logical order. The reserved tracks are read as they are stored.
+pub struct SkewedImage {
+    inner: Box<dyn ImageFile>,
+    geometry: Geometry,
+    table: Vec<usize>,
+    pos: u64,
+}
+
+impl SkewedImage {
+    pub fn new(inner: Box<dyn ImageFile>, geometry: Geometry) -> SkewedImage {
+        SkewedImage { inner, table: geometry.skew_table(), geometry, pos: 0 }
+    }
+
+    // Where the logical position is stored, and how many bytes from there are in the same sector
+    fn physical(&self, pos: u64) -> (u64, usize) {
+        let g = &self.geometry;
+        let sector_size = g.bytes_per_sector as u64;
+        let in_sector = (pos % sector_size) as usize;
+        let left = g.bytes_per_sector - in_sector;
+        let track = pos / g.track_size() as u64;
+        if track < (g.reserved_tracks * g.sides) as u64 {
+            return (pos, left);
+        }
+        let sector = (pos % g.track_size() as u64 / sector_size) as usize;
+        (track * g.track_size() as u64 + self.table[sector] as u64 * sector_size + in_sector as u64, left)
+    }
+}
+
+impl Read for SkewedImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (physical, left) = self.physical(self.pos);
+        let len = buf.len().min(left);
+        self.inner.seek(SeekFrom::Start(physical))?;
+        let n = self.inner.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for SkewedImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (physical, left) = self.physical(self.pos);
+        let len = buf.len().min(left);
+        self.inner.seek(SeekFrom::Start(physical))?;
+        let n = self.inner.write(&buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for SkewedImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.inner.size().map_err(io::Error::other)? as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of image"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl ImageFile for SkewedImage {
+    fn size(&mut self) -> Result<u64> {
+        self.inner.size()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn bad_sectors(&self) -> Option<&SectorMap> {
+        self.inner.bad_sectors()
+    }
+}
+
+/// An image read and written sector by sector through a registered FormatHandler
+pub struct HandlerImage {
+    handler: Arc<dyn FormatHandler>,
+    geometry: Geometry,
+    file: File,
+    pos: u64,
+}
+
+impl HandlerImage {
+    pub fn new(handler: Arc<dyn FormatHandler>, file: File) -> Result<HandlerImage> {
+        let geometry = handler.geometry();
+        // The catalog code reads every image with the geometry in use
+        cpmimg::set_geometry(geometry)?;
+        Ok(HandlerImage { handler, geometry, file, pos: 0 })
+    }
+
+    // Cylinder, head and sector of a sector number in image order
+    fn address(&self, index: u64) -> (usize, usize, usize) {
+        let g = &self.geometry;
+        let index = index as usize;
+        let track = index / g.sectors_per_track;
+        (track / g.sides, track % g.sides, index % g.sectors_per_track)
+    }
+
+    fn read_sector(&mut self, index: u64, buf: &mut [u8]) -> io::Result<()> {
+        let (cylinder, head, sector) = self.address(index);
+        self.handler.read_sector(&mut self.file, cylinder, head, sector, buf).map_err(io::Error::other)
+    }
+}
+
+impl Read for HandlerImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let sector_size = self.geometry.bytes_per_sector as u64;
+        let size = self.geometry.total_size() as u64;
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let mut sector = vec![0u8; sector_size as usize];
+        self.read_sector(self.pos / sector_size, &mut sector)?;
+        let start = (self.pos % sector_size) as usize;
+        let len = buf.len().min(sector.len() - start);
+        buf[..len].copy_from_slice(&sector[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for HandlerImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sector_size = self.geometry.bytes_per_sector as u64;
+        if self.pos + buf.len() as u64 > self.geometry.total_size() as u64 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "write past end of image"));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Whole sectors only, read the rest of a partly written one first
+        let index = self.pos / sector_size;
+        let start = (self.pos % sector_size) as usize;
+        let len = buf.len().min(sector_size as usize - start);
+        let mut sector = vec![0u8; sector_size as usize];
+        if len < sector.len() {
+            self.read_sector(index, &mut sector)?;
+        }
+        sector[start..start + len].copy_from_slice(&buf[..len]);
+        let (cylinder, head, sector_number) = self.address(index);
+        self.handler.write_sector(&mut self.file, cylinder, head, sector_number, &sector).map_err(io::Error::other)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for HandlerImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.geometry.total_size() as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of image"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl ImageFile for HandlerImage {
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.geometry.total_size() as u64)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        durable::sync_file(&self.file)?;
+        Ok(())
+    }
+}
+
+// Partition types used for CP/M: 0x52 CP/M, 0xdb CP/M-86 and Concurrent DOS
+const CPM_PARTITION_TYPES: [u8; 2] = [0x52, 0xdb];
+const MBR_SIGNATURE_OFFSET: usize = 0x1fe;
+const MBR_PARTITIONS_OFFSET: usize = 0x1be;
+// Anything this small is a floppy, where 55 AA in the boot sector means nothing
+const MIN_PARTITIONED_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Start and length in bytes of the first CP/M partition in an MBR
+pub fn find_cpm_partition(mbr: &[u8]) -> Option<(u64, u64)> {
+    if mbr.len() < 512 || mbr[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != [0x55, 0xaa] {
+        return None;
+    }
+    mbr[MBR_PARTITIONS_OFFSET..MBR_SIGNATURE_OFFSET].chunks(16).find_map(|entry| {
+        let lba = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        if CPM_PARTITION_TYPES.contains(&entry[4]) && sectors > 0 {
+            Some((lba * 512, sectors * 512))
+        } else {
+            None
+        }
+    })
+}
+
+/// True for flux captures, containers and encrypted images, they are never opened for writing
+pub fn is_read_only_format(path: &str) -> Result<bool> {
+    if is_url(path) {
+        return Ok(true);
+    }
+    let mut header = [0u8; 8];
+    let header_len = File::open(path)?.read(&mut header)?;
+    let header = &header[..header_len];
+    Ok(flux::is_scp(header) || imd::is_imd(header) || encryption::is_encrypted(header))
+}
+
+// The sectors of an SCP or IMD file, as a plain image
+fn decode_container(path: &str, layout: &TrackLayout) -> Result<DecodedImage> {
+    let contents = std::fs::read(path)?;
+    if flux::is_scp(&contents) {
+        flux::decode_scp(&contents, layout, cpmimg::geometry().fill_byte)
+    } else {
+        imd::decode_imd(&contents, layout, cpmimg::geometry().fill_byte)
+    }
+}
+
+/// The sector level contents of a flux capture or IMD file, None for
+/// images that don't keep sectors, like plain and encrypted ones
+pub fn read_container(path: &str) -> Result<Option<DecodedImage>> {
+    // Containers are not read from URLs, see open_url
+    if is_url(path) {
+        return Ok(None);
+    }
+    let mut header = [0u8; 8];
+    let header_len = File::open(path)?.read(&mut header)?;
+    if !flux::is_scp(&header[..header_len]) && !imd::is_imd(&header[..header_len]) {
+        return Ok(None);
+    }
+    Ok(Some(decode_container(path, &cpmimg::geometry().track_layout())?))
+}
+
+/// Sectors of a container that a normal disk doesn't have, for conversions
+/// to keep them
+pub fn irregular_sectors(path: &str) -> Result<Vec<IrregularSector>> {
+    Ok(read_container(path)?.map_or(Vec::new(), |decoded| decoded.irregular))
+}
+
+/// True for images on a web server, "http://" or "https://"
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+// Where the CP/M part is in an image of `size` bytes, None for all of it
+fn find_part(options: &ImageOptions, first_sector: &[u8], size: u64) -> Option<(u64, Option<u64>)> {
+    match options.offset {
+        Some(offset) => Some((offset, None)),
+        None if size >= MIN_PARTITIONED_SIZE => {
+            find_cpm_partition(first_sector).map(|(start, len)| (start, Some(len)))
+        }
+        None => None,
+    }
+}
+
+// Plain images only, containers and encrypted images are read in full to
+// decode them and are better downloaded first
+#[cfg(feature = "http")]
+fn open_url(path: &str, writable: bool, options: &ImageOptions) -> Result<Box<dyn ImageFile>> {
+    if writable {
+        anyhow::bail!("{} is on a web server, it can only be read", path);
+    }
+    let mut image = crate::http::HttpImage::open(path)?;
+    let size = image.size()?;
+    let mut first_sector = vec![0u8; 512];
+    let first_len = image.read(&mut first_sector)?;
+    let first_sector = &first_sector[..first_len];
+    if flux::is_scp(first_sector) || imd::is_imd(first_sector) || encryption::is_encrypted(first_sector) {
+        anyhow::bail!("{} is a container or encrypted image, download it to read it", path);
+    }
+    if let Some(handler) = formats::handlers().into_iter().find(|handler| handler.detect(first_sector, size)) {
+        anyhow::bail!("{} is in the {} format, download it to read it", path, handler.name());
+    }
+
+    match find_part(options, first_sector, size) {
+        Some((start, len)) => Ok(Box::new(OffsetImage::new(Box::new(image), start, len)?)),
+        None => Ok(Box::new(image)),
+    }
+}
+
+#[cfg(not(feature = "http"))]
+fn open_url(path: &str, _writable: bool, _options: &ImageOptions) -> Result<Box<dyn ImageFile>> {
+    anyhow::bail!("{} is a URL, this build can't read them, build with --features http", path)
+}
+
+/// Open an existing image with the backend selected by the options
+pub fn open_image(path: &str, writable: bool, options: &ImageOptions) -> Result<Box<dyn ImageFile>> {
+    let mut image = open_backend(path, writable, options)?;
+    let geometry = cpmimg::geometry();
+    if geometry.skew > 1 {
+        image = Box::new(SkewedImage::new(image, geometry));
+    }
+    if iotrace::is_active() {
+        return Ok(Box::new(TracedImage::new(image, path)?));
+    }
+    Ok(image)
+}
+
+fn open_backend(path: &str, writable: bool, options: &ImageOptions) -> Result<Box<dyn ImageFile>> {
+    if is_url(path) {
+        return open_url(path, writable, options);
+    }
+    let size = std::fs::metadata(path)?.len();
+
+    let mut header = [0u8; 8];
+    let header_len = File::open(path)?.read(&mut header)?;
+    let header = &header[..header_len];
+    if flux::is_scp(header) || imd::is_imd(header) {
+        if writable {
+            anyhow::bail!("{} is a flux capture or container image, it can only be read", path);
+        }
+        let layout = cpmimg::geometry().track_layout();
+        let decoded = decode_container(path, &layout)?;
+        if !decoded.missing.is_empty() {
+            eprintln!("Warning: {} sectors could not be read from {}", decoded.missing.len(), path);
+        }
+        return Ok(Box::new(ContainerImage::new(decoded, &layout)));
+    }
+
+    if encryption::is_encrypted(header) {
+        if writable {
+            anyhow::bail!("{} is encrypted, decrypt it to change it", path);
+        }
+        let passphrase_file = match &options.passphrase_file {
+            Some(passphrase_file) => passphrase_file,
+            None => anyhow::bail!("{} is encrypted, give the passphrase with --passphrase-file", path),
+        };
+        let passphrase = encryption::read_passphrase_file(passphrase_file)?;
+        let data = encryption::decrypt_cached(&std::fs::read(path)?, &passphrase, &options.keys)?;
+        let decoded = DecodedImage { data, missing: Vec::new(), irregular: Vec::new() };
+        return Ok(Box::new(ContainerImage::new(decoded, &cpmimg::geometry().track_layout())));
+    }
+
+    if chunked::is_chunked(header) {
+        return Ok(Box::new(ChunkedImage::open(path, writable)?));
+    }
+
+    let mut first_sector = vec![0u8; 512];
+    let first_len = File::open(path)?.read(&mut first_sector)?;
+    for handler in formats::handlers() {
+        if handler.detect(&first_sector[..first_len], size) {
+            if writable && !handler.writable() {
+                anyhow::bail!("{} is in the {} format, it can only be read", path, handler.name());
+            }
+            let file = OpenOptions::new().read(true).write(writable).open(path)?;
+            return Ok(Box::new(HandlerImage::new(handler, file)?));
+        }
+    }
+
+    let part = find_part(options, &first_sector[..first_len], size);
+
+    // An empty file can't be mapped, let the catalog code report it
+    let image: Box<dyn ImageFile> = if size > 0 && (options.mmap || size >= MMAP_THRESHOLD) {
+        Box::new(MmapImage::open(path, writable)?)
+    } else {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)?;
+        Box::new(file)
+    };
+
+    match part {
+        Some((start, len)) => Ok(Box::new(OffsetImage::new(image, start, len)?)),
+        None => Ok(image),
+    }
+}
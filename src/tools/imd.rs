@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+use anyhow::Result;
+use crate::flux::{DecodedImage, IrregularSector, SectorId, TrackLayout};
+
+// ImageDisk (.IMD) container, as written by Dave Dunfield's ImageDisk.
+// http://dunfield.classiccmp.org/img/index.htm
+//
+// An ASCII comment ending with 0x1A, then one record per track:
+//   mode, cylinder, head, sector count, sector size code
+//   sector numbering map, [cylinder map], [head map]
+//   one data record per sector, starting with a type byte
+// Bit 7 of head means a cylinder map follows, bit 6 a head map.
+
+const COMMENT_END: u8 = 0x1a;
+const CYLINDER_MAP: u8 = 0x80;
+const HEAD_MAP: u8 = 0x40;
+const VARIABLE_SIZES: u8 = 0xff;
+/// 8192 byte sectors, the largest IMD has
+const LAST_SIZE_CODE: u8 = 6;
+
+// Sector data record types
+const UNAVAILABLE: u8 = 0x00;
+const LAST_TYPE: u8 = 0x08;
+
+fn is_compressed(kind: u8) -> bool {
+    kind.is_multiple_of(2)
+}
+
+// Types 5 to 8 were read with a data error
+fn has_error(kind: u8) -> bool {
+    kind >= 0x05
+}
+
+/// True if the file looks like an ImageDisk container
+pub fn is_imd(header: &[u8]) -> bool {
+    header.starts_with(b"IMD ")
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    match data.get(*pos..*pos + len) {
+        Some(slice) => {
+            *pos += len;
+            Ok(slice)
+        }
+        None => anyhow::bail!("IMD file is truncated at offset {:#x}", *pos),
+    }
+}
+
+/// Unpack an IMD container into a plain image. Sectors that are missing or
+/// were imaged with a data error are listed in `missing`, missing sectors read as `fill`.
+/// Sectors that don't fit the layout are only listed in `irregular`, they
+/// don't go into the image, so a second copy of a sector or one whose ID names
+/// another track doesn't replace the sector there.
+pub fn decode_imd(imd: &[u8], layout: &TrackLayout, fill: u8) -> Result<DecodedImage> {
+    let mut pos = match imd.iter().position(|&b| b == COMMENT_END) {
+        Some(end) => end + 1,
+        None => anyhow::bail!("IMD file has no end of comment"),
+    };
+
+    let mut data = vec![fill; layout.cylinders * layout.heads * layout.sectors * layout.sector_size];
+    let mut seen = vec![false; layout.cylinders * layout.heads * layout.sectors];
+    let mut missing = Vec::new();
+    let mut irregular = Vec::new();
+
+    while pos < imd.len() {
+        let header = take(imd, &mut pos, 5)?;
+        let (cylinder, head_flags, count, size_code) = (header[1], header[2], header[3] as usize, header[4]);
+        let head = head_flags & 0x0f;
+        if size_code == VARIABLE_SIZES {
+            anyhow::bail!("IMD tracks with mixed sector sizes are not supported (cylinder {} head {})", cylinder, head);
+        }
+        if size_code > LAST_SIZE_CODE {
+            anyhow::bail!("Unknown IMD sector size code {} at offset {:#x} (cylinder {} head {})", size_code, pos - 1, cylinder, head);
+        }
+        let size = 128usize << size_code;
+
+        let numbers = take(imd, &mut pos, count)?.to_vec();
+        let cylinders = if head_flags & CYLINDER_MAP != 0 { Some(take(imd, &mut pos, count)?.to_vec()) } else { None };
+        let heads = if head_flags & HEAD_MAP != 0 { Some(take(imd, &mut pos, count)?.to_vec()) } else { None };
+
+        let mut ids = HashSet::new();
+        for i in 0..count {
+            let kind = take(imd, &mut pos, 1)?[0];
+            if kind > LAST_TYPE {
+                anyhow::bail!("Unknown IMD sector record type {:#x} at offset {:#x}", kind, pos - 1);
+            }
+            let contents = match kind {
+                UNAVAILABLE => None,
+                _ if is_compressed(kind) => Some(vec![take(imd, &mut pos, 1)?[0]; size]),
+                _ => Some(take(imd, &mut pos, size)?.to_vec()),
+            };
+
+            // The ID on the disk may say something else than the physical position
+            let c = cylinders.as_ref().map_or(cylinder, |m| m[i]) as usize;
+            let h = heads.as_ref().map_or(head, |m| m[i]) as usize;
+            let r = numbers[i];
+            let id = SectorId { cylinder: c as u8, head: h as u8, sector: r, size };
+            let irregularity = layout.irregularity(cylinder as usize, head as usize, &id, &mut ids);
+            if let Some(irregularity) = irregularity {
+                let data = contents.clone().filter(|_| !has_error(kind));
+                irregular.push(IrregularSector { cylinder: cylinder as usize, head: head as usize, id, irregularity, data });
+            }
+            if irregularity.is_some() || c >= layout.cylinders || h >= layout.heads {
+                continue;
+            }
+
+            let offset = layout.image_offset(c, h, r);
+            seen[offset / layout.sector_size] = true;
+            if let Some(contents) = &contents {
+                data[offset..offset + size].copy_from_slice(contents);
+            }
+            if contents.is_none() || has_error(kind) {
+                missing.push((c, h, r));
+            }
+        }
+    }
+
+    // Sectors not in the file at all are missing as well
+    for cylinder in 0..layout.cylinders {
+        for head in 0..layout.heads {
+            for i in 0..layout.sectors {
+                let sector = layout.first_sector + i as u8;
+                if !seen[layout.image_offset(cylinder, head, sector) / layout.sector_size] {
+                    missing.push((cylinder, head, sector));
+                }
+            }
+        }
+    }
+
+    Ok(DecodedImage { data, missing, irregular })
+}
+
+// 250 kbit/s MFM, double density
+const MODE_250K_MFM: u8 = 5;
+const NORMAL: u8 = 0x01;
+const COMPRESSED: u8 = 0x02;
+
+/// Pack a plain image into an IMD container. Sectors listed in `missing`
+/// are written as unavailable, so readers see them as unreadable. The
+/// `irregular` sectors are added to the tracks they were found on, an IMD
+/// track has one sector size so sectors of another size can't be kept.
+pub fn encode_imd(image: &[u8], layout: &TrackLayout, missing: &[(usize, usize, u8)], irregular: &[IrregularSector], comment: &str) -> Result<Vec<u8>> {
+    let size_code = match layout.sector_size {
+        128 => 0,
+        256 => 1,
+        512 => 2,
+        1024 => 3,
+        2048 => 4,
+        4096 => 5,
+        8192 => 6,
+        size => anyhow::bail!("IMD can not hold sectors of {} bytes", size),
+    };
+    if image.len() < layout.cylinders * layout.heads * layout.sectors * layout.sector_size {
+        anyhow::bail!("Image of {} bytes is too small for {} cylinders", image.len(), layout.cylinders);
+    }
+
+    let mut imd = format!("IMD 1.18: {}\r\n", comment).into_bytes();
+    imd.push(COMMENT_END);
+    for cylinder in 0..layout.cylinders {
+        for head in 0..layout.heads {
+            let extra: Vec<&IrregularSector> = irregular.iter()
+                .filter(|s| s.cylinder == cylinder && s.head == head && s.id.size == layout.sector_size)
+                .collect();
+            // IDs for another track need the cylinder and head maps
+            let maps = extra.iter().any(|s| s.id.cylinder as usize != cylinder || s.id.head as usize != head);
+            let flags = if maps { CYLINDER_MAP | HEAD_MAP } else { 0 };
+            imd.extend_from_slice(&[MODE_250K_MFM, cylinder as u8, head as u8 | flags, (layout.sectors + extra.len()) as u8, size_code]);
+            imd.extend((0..layout.sectors).map(|i| layout.first_sector + i as u8));
+            imd.extend(extra.iter().map(|s| s.id.sector));
+            if maps {
+                imd.extend(std::iter::repeat_n(cylinder as u8, layout.sectors).chain(extra.iter().map(|s| s.id.cylinder)));
+                imd.extend(std::iter::repeat_n(head as u8, layout.sectors).chain(extra.iter().map(|s| s.id.head)));
+            }
+            for i in 0..layout.sectors {
+                let sector = layout.first_sector + i as u8;
+                if missing.contains(&(cylinder, head, sector)) {
+                    imd.push(UNAVAILABLE);
+                    continue;
+                }
+                let offset = layout.image_offset(cylinder, head, sector);
+                let contents = &image[offset..offset + layout.sector_size];
+                if contents.iter().all(|&b| b == contents[0]) {
+                    imd.extend_from_slice(&[COMPRESSED, contents[0]]);
+                } else {
+                    imd.push(NORMAL);
+                    imd.extend_from_slice(contents);
+                }
+            }
+            for sector in &extra {
+                match &sector.data {
+                    Some(data) => {
+                        imd.push(NORMAL);
+                        imd.extend_from_slice(data);
+                    }
+                    None => imd.push(UNAVAILABLE),
+                }
+            }
+        }
+    }
+
+    Ok(imd)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flux::Irregularity;
+    use super::*;
+
+    // Two cylinders of two 128 byte sectors, on one side
+    const LAYOUT: TrackLayout = TrackLayout { cylinders: 2, heads: 1, sectors: 2, sector_size: 128, ..TrackLayout::COMPIS };
+
+    #[test]
+    fn wrong_track_stays_irregular() {
+        let mut imd = b"IMD 1.18: test\x1a".to_vec();
+        // Cylinder 0 with a cylinder map, the third sector says it is 1:0:1.
+        // Cylinder 1 is not in the file.
+        imd.extend([MODE_250K_MFM, 0, CYLINDER_MAP, 3, 0]);
+        imd.extend([1, 2, 1]);
+        imd.extend([0, 0, 1]);
+        imd.extend([COMPRESSED, 0x11, COMPRESSED, 0x22, COMPRESSED, 0x33]);
+
+        let decoded = decode_imd(&imd, &LAYOUT, 0xe5).unwrap();
+        let sector = |i: usize| &decoded.data[i * 128..(i + 1) * 128];
+        assert_eq!(sector(0), [0x11; 128]);
+        assert_eq!(sector(1), [0x22; 128]);
+        assert_eq!(sector(2), [0xe5; 128]);
+        assert_eq!(decoded.missing, [(1, 0, 1), (1, 0, 2)]);
+
+        assert_eq!(decoded.irregular.len(), 1);
+        let wrong = &decoded.irregular[0];
+        assert_eq!((wrong.cylinder, wrong.head), (0, 0));
+        assert_eq!(wrong.id, SectorId { cylinder: 1, head: 0, sector: 1, size: 128 });
+        assert_eq!(wrong.irregularity, Irregularity::WrongTrack);
+        assert_eq!(wrong.data, Some(vec![0x33; 128]));
+    }
+}
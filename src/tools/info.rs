@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::Result;
+use crate::chunked;
+use crate::cpmimg::{self, SortKey};
+use crate::encryption;
+use crate::flux::{self, Irregularity};
+use crate::imagefile::{ImageOptions, is_url, open_image, read_container};
+use crate::imd;
+
+// A summary of an image: what kind of file it is, the format and how full
+// it is. The physical part is for flux captures and IMD files, which keep
+// the sectors as they were on the disk. Sector numbers the format doesn't
+// use, IDs for other tracks, odd sizes and sectors that are there twice
+// are what copy protection is made of, and are listed so they aren't lost
+// on a conversion without anyone noticing.
+
+pub(crate) fn container_name(path: &str) -> Result<&'static str> {
+    // Only plain images are read from web servers
+    if is_url(path) {
+        return Ok("plain image on a web server");
+    }
+    let mut header = [0u8; 8];
+    let header_len = File::open(path)?.read(&mut header)?;
+    let header = &header[..header_len];
+    Ok(if flux::is_scp(header) {
+        "SCP flux capture"
+    } else if imd::is_imd(header) {
+        "ImageDisk container"
+    } else if chunked::is_chunked(header) {
+        "chunked image"
+    } else if encryption::is_encrypted(header) {
+        "encrypted image"
+    } else {
+        "plain image"
+    })
+}
+
+fn describe(irregularity: Irregularity) -> &'static str {
+    match irregularity {
+        Irregularity::SectorNumber => "sector number outside the format",
+        Irregularity::WrongTrack => "ID for another track",
+        Irregularity::Size => "odd sector size",
+        Irregularity::Duplicate => "duplicate sector",
+    }
+}
+
+/// Print what the image is and how full, with `physical` also the sectors
+/// of a container that a normal disk doesn't have
+pub fn print_info(image_path: &str, options: &ImageOptions, physical: bool) -> Result<()> {
+    let geometry = cpmimg::geometry();
+    let mut disk = open_image(image_path, false, options)?;
+    let file_size = if is_url(image_path) { disk.size()? } else { std::fs::metadata(image_path)?.len() };
+    println!("Image:  {}, {} of {} bytes", image_path, container_name(image_path)?, file_size);
+
+    let mut media_byte = [0u8; 1];
+    disk.seek(SeekFrom::Start(cpmimg::DISKSIZE_OFFSET as u64))?;
+    disk.read_exact(&mut media_byte)?;
+    println!("Format: {}x{}x{}x{}, {} byte blocks, media byte {:02X}", geometry.sides, geometry.tracks,
+        geometry.sectors_per_track, geometry.bytes_per_sector, geometry.block_size, media_byte[0]);
+
+    let files = cpmimg::file_infos(image_path, options, SortKey::Index, false)?;
+    let used: usize = files.iter().map(|f| f.blocks.len()).sum();
+    let data_blocks = geometry.max_blocks() - geometry.dir_blocks;
+    println!("Files:  {}, {} of {} KB used", files.len(), used * geometry.block_size / 1024, data_blocks * geometry.block_size / 1024);
+
+    if !physical {
+        return Ok(());
+    }
+    println!();
+    let decoded = match read_container(image_path)? {
+        Some(decoded) => decoded,
+        None => {
+            println!("A {} doesn't keep sector IDs, there is nothing physical to check", container_name(image_path)?);
+            return Ok(());
+        }
+    };
+    println!("{} sectors could not be read", decoded.missing.len());
+    for (cylinder, head, sector) in &decoded.missing {
+        println!("  {}:{}:{}", cylinder, head, sector);
+    }
+    if decoded.irregular.is_empty() {
+        println!("All sector IDs are standard for the format");
+        return Ok(());
+    }
+    println!("{} sectors are not standard for the format, a sign of copy protection:", decoded.irregular.len());
+    println!("  Track  ID C:H:R   Size  Data  What");
+    for sector in &decoded.irregular {
+        println!("  {:>2}:{}   {:>3}:{}:{:<3} {:>5}  {:<4}  {}", sector.cylinder, sector.head, sector.id.cylinder, sector.id.head,
+            sector.id.sector, sector.id.size, if sector.data.is_some() { "yes" } else { "no" }, describe(sector.irregularity));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::sync::Mutex;
+use anyhow::Result;
+use serde::Serialize;
+use crate::cpmimg::{self, Geometry};
+use crate::imagefile::{ImageFile, SectorMap};
+
+// A log of every sector the tool reads or writes, for --trace-io. Each
+// access is split in the sectors it touches and every sector gets one JSON
+// line, in the order they happen, numbered across all images the command
+// opens. Sectors are given as image offset and as cylinder, head and sector
+// of the geometry in use, with what the sector is for and the file that owns
+// it as the directory says at that moment. Files written by a command are
+// only known as owners once their directory entries are written, which
+// copyin does last. Only access through open_image is logged, files that are
+// written in one go like a new image from create are not.
+
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+struct Trace {
+    out: File,
+    seq: u64,
+}
+
+/// Log all image access to `path` from now on, as JSON lines
+pub fn start(path: &str) -> Result<()> {
+    let out = File::create(path)?;
+    *TRACE.lock().unwrap() = Some(Trace { out, seq: 0 });
+    Ok(())
+}
+
+pub fn is_active() -> bool {
+    TRACE.lock().unwrap().is_some()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Purpose {
+    /// The reserved tracks before the directory
+    Boot,
+    Directory,
+    /// Allocation blocks after the directory, used by a file or free
+    Data,
+    /// Past the end of the geometry, like a partition table or padding
+    Outside,
+}
+
+/// One line of the log
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    seq: u64,
+    image: &'a str,
+    op: &'a str,
+    offset: u64,
+    length: usize,
+    cylinder: usize,
+    head: usize,
+    // Numbered as in the sector ID on the disk, from 1 on COMPIS disks
+    sector: usize,
+    purpose: Purpose,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<&'a str>,
+}
+
+/// An image that logs each sector accessed through it
+pub struct TracedImage {
+    inner: Box<dyn ImageFile>,
+    image: String,
+    geometry: Geometry,
+    // allocation block of each sector of the data area
+    blocks: HashMap<u64, u16>,
+    owners: HashMap<u16, String>,
+    pos: u64,
+}
+
+impl TracedImage {
+    pub fn new(mut inner: Box<dyn ImageFile>, image: &str) -> Result<TracedImage> {
+        let geometry = cpmimg::geometry();
+        let sectors_per_block = (geometry.block_size / geometry.bytes_per_sector) as u64;
+        let mut blocks = HashMap::new();
+        for al in 0..geometry.max_blocks() as u16 {
+            let first = geometry.block_offset(al)? / geometry.bytes_per_sector as u64;
+            for sector in first..first + sectors_per_block {
+                blocks.insert(sector, al);
+            }
+        }
+        // Reading the directory for the owners is not logged
+        let owners = cpmimg::block_owners(inner.as_mut()).unwrap_or_default();
+        inner.seek(SeekFrom::Start(0))?;
+        Ok(TracedImage { inner, image: image.to_string(), geometry, blocks, owners, pos: 0 })
+    }
+
+    fn log(&self, op: &str, offset: u64, length: usize) {
+        let mut trace = TRACE.lock().unwrap();
+        let trace = match trace.as_mut() {
+            Some(trace) => trace,
+            None => return,
+        };
+        let g = &self.geometry;
+        let sector_size = g.bytes_per_sector as u64;
+        let mut pos = offset;
+        let end = offset + length as u64;
+        while pos < end {
+            let index = pos / sector_size;
+            let len = ((index + 1) * sector_size).min(end) - pos;
+            let track = index as usize / g.sectors_per_track;
+            let block = self.blocks.get(&index).copied();
+            let purpose = match block {
+                _ if pos >= g.total_size() as u64 => Purpose::Outside,
+                Some(al) if (al as usize) < g.dir_blocks => Purpose::Directory,
+                Some(_) => Purpose::Data,
+                None if pos < g.catalog_offset() => Purpose::Boot,
+                // The end of a side too short for a whole block
+                None => Purpose::Data,
+            };
+            let record = Record {
+                seq: trace.seq,
+                image: &self.image,
+                op,
+                offset: pos,
+                length: len as usize,
+                cylinder: track / g.sides,
+                head: track % g.sides,
+                sector: index as usize % g.sectors_per_track + g.track_layout().first_sector as usize,
+                purpose,
+                block,
+                file: block.and_then(|al| self.owners.get(&al)).map(String::as_str),
+            };
+            // A trace that can't be written shouldn't stop the command
+            if let Ok(line) = serde_json::to_string(&record) {
+                let _ = writeln!(trace.out, "{}", line);
+            }
+            trace.seq += 1;
+            pos += len;
+        }
+    }
+
+    // New entries and deleted ones change the owners
+    fn reread_owners(&mut self) -> io::Result<()> {
+        if let Ok(owners) = cpmimg::block_owners(self.inner.as_mut()) {
+            self.owners = owners;
+        }
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        Ok(())
+    }
+}
+
+impl Read for TracedImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.log("read", self.pos, n);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for TracedImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.log("write", self.pos, n);
+        let catalog = self.geometry.catalog_offset();
+        let touches_directory = self.pos < catalog + self.geometry.dir_size() as u64 && self.pos + n as u64 > catalog;
+        self.pos += n as u64;
+        if touches_directory {
+            self.reread_owners()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for TracedImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl ImageFile for TracedImage {
+    fn size(&mut self) -> Result<u64> {
+        self.inner.size()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn bad_sectors(&self) -> Option<&SectorMap> {
+        self.inner.bad_sectors()
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::Result;
+use serde::Serialize;
+use crate::cpmimg::{self, DISKSIZE_OFFSET, Geometry};
+use crate::flux::TrackFormat;
+use crate::imagefile::{ImageOptions, open_image};
+use crate::output;
+
+// The layout of a disk as emulators and disk controllers need it, from the
+// geometry in use: the physical tracks and sectors, how the image file is
+// ordered, the reserved tracks, where the directory is, and where every
+// allocation block lands. The JSON form is meant to be read by programs,
+// fields are only ever added to it.
+
+/// Version of the JSON layout, raised when a field changes meaning
+const DESCRIPTOR_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+struct Physical {
+    cylinders: usize,
+    heads: usize,
+    sectors_per_track: usize,
+    bytes_per_sector: usize,
+    /// Number of the first sector on a track, sector IDs count up from it
+    first_sector: u8,
+    /// Sectors on the track are in this order, 1 for consecutive numbers
+    interleave: usize,
+    encoding: &'static str,
+    data_rate_kbps: u32,
+    rpm: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct Area {
+    offset: u64,
+    size: usize,
+}
+
+/// The blocks of one side, in the order they are numbered
+#[derive(Debug, Serialize)]
+struct Side {
+    head: usize,
+    first_block: u16,
+    last_block: u16,
+    /// "up" if block numbers grow with the cylinder, "down" if they shrink
+    direction: &'static str,
+}
+
+/// Where one allocation block is, on the disk and in the image
+#[derive(Debug, Serialize)]
+struct Block {
+    block: u16,
+    offset: u64,
+    cylinder: usize,
+    head: usize,
+    /// Sector ID of the first sector of the block
+    sector: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct Descriptor {
+    version: u32,
+    image_size: u64,
+    media_byte: u8,
+    physical: Physical,
+    /// How a sector is found in the image file
+    image_order: &'static str,
+    /// Sector translation of the data tracks as in a cpmtools diskdef, 0 for
+    /// none, logical sector n is then physical sector first_sector + n
+    skew: usize,
+    fill_byte: u8,
+    reserved_tracks: usize,
+    reserved: Area,
+    directory: Area,
+    directory_entries: usize,
+    block_size: usize,
+    directory_blocks: usize,
+    max_file_size: usize,
+    sides: Vec<Side>,
+    blocks: Vec<Block>,
+}
+
+fn describe(geometry: &Geometry, image_size: u64, media_byte: u8) -> Result<Descriptor> {
+    let layout = geometry.track_layout();
+    let track_size = geometry.track_size();
+    let mut blocks = Vec::new();
+    for al in 0..geometry.max_blocks() as u16 {
+        let offset = geometry.block_offset(al)?;
+        let track = offset as usize / track_size;
+        blocks.push(Block {
+            block: al,
+            offset,
+            cylinder: track / geometry.sides,
+            head: track % geometry.sides,
+            sector: layout.first_sector + ((offset as usize % track_size) / geometry.bytes_per_sector) as u8,
+        });
+    }
+
+    let sides = (0..geometry.sides).filter_map(|head| {
+        let on_side: Vec<&Block> = blocks.iter().filter(|b| b.head == head).collect();
+        let (first, last) = (on_side.first()?, on_side.last()?);
+        Some(Side {
+            head,
+            first_block: first.block,
+            last_block: last.block,
+            direction: if last.cylinder >= first.cylinder { "up" } else { "down" },
+        })
+    }).collect();
+
+    Ok(Descriptor {
+        version: DESCRIPTOR_VERSION,
+        image_size,
+        media_byte,
+        physical: Physical {
+            cylinders: geometry.tracks,
+            heads: geometry.sides,
+            sectors_per_track: geometry.sectors_per_track,
+            bytes_per_sector: geometry.bytes_per_sector,
+            first_sector: layout.first_sector,
+            interleave: TrackFormat::default().interleave,
+            encoding: "MFM",
+            data_rate_kbps: layout.data_rate,
+            rpm: layout.rpm,
+        },
+        image_order: "((cylinder * heads + head) * sectors_per_track + sector - first_sector) * bytes_per_sector",
+        skew: geometry.skew,
+        fill_byte: geometry.fill_byte,
+        reserved_tracks: geometry.reserved_tracks,
+        reserved: Area { offset: 0, size: geometry.catalog_offset() as usize },
+        directory: Area { offset: geometry.catalog_offset(), size: geometry.dir_size() },
+        directory_entries: geometry.dir_entries(),
+        block_size: geometry.block_size,
+        directory_blocks: geometry.dir_blocks,
+        max_file_size: geometry.max_file_size(),
+        sides,
+        blocks,
+    })
+}
+
+/// Print the physical layout of the image, for emulators and bridges
+pub fn print_geometry(image_path: &str, options: &ImageOptions, json: bool) -> Result<()> {
+    let mut disk = open_image(image_path, false, options)?;
+    let image_size = disk.size()?;
+    let mut media_byte = [0u8; 1];
+    disk.seek(SeekFrom::Start(DISKSIZE_OFFSET as u64))?;
+    disk.read_exact(&mut media_byte)?;
+
+    let geometry = cpmimg::geometry();
+    let descriptor = describe(&geometry, image_size, media_byte[0])?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&descriptor)?);
+        return Ok(());
+    }
+
+    let p = &descriptor.physical;
+    println!("Physical:  {} cylinders, {} heads, {} sectors of {} bytes numbered from {}, {} {} kbit/s at {} rpm",
+        p.cylinders, p.heads, p.sectors_per_track, p.bytes_per_sector, p.first_sector, p.encoding, p.data_rate_kbps, p.rpm);
+    println!("Image:     {} bytes, sector at {}", output::number(image_size), descriptor.image_order);
+    if descriptor.skew > 1 {
+        println!("Skew:      {}, the sectors of the data tracks are in physical order", descriptor.skew);
+    }
+    println!("Reserved:  {} track per side, {:#x}-{:#x}", descriptor.reserved_tracks, 0, descriptor.reserved.size.saturating_sub(1));
+    println!("Directory: {} entries at {:#x}-{:#x}, blocks 0-{}", descriptor.directory_entries, descriptor.directory.offset,
+        descriptor.directory.offset + descriptor.directory.size as u64 - 1, descriptor.directory_blocks - 1);
+    println!("Blocks:    {} of {} bytes", output::number(descriptor.blocks.len()), output::number(descriptor.block_size));
+    println!("Files:     at most {} bytes", output::number(descriptor.max_file_size));
+    for side in &descriptor.sides {
+        println!("  Side {}: blocks {:#x}-{:#x}, counting {} the cylinders", side.head, side.first_block, side.last_block, side.direction);
+    }
+    if image_size != geometry.total_size() as u64 {
+        println!("Note: the image is {} bytes, the geometry is {}", output::number(image_size), output::number(geometry.total_size()));
+    }
+
+    Ok(())
+}
@@ -1,2 +1,39 @@
 
+pub mod backup;
+pub mod batch;
+pub mod boot;
+pub mod changes;
+pub mod checksum;
+pub mod chunked;
+pub mod cmd;
+pub mod compat;
+pub mod conflict;
+pub mod corrupt;
 pub mod cpmimg;
+pub mod diskmap;
+pub mod durable;
+pub mod encryption;
+pub mod events;
+pub mod flux;
+pub mod formats;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod imagefile;
+pub mod imd;
+pub mod info;
+pub mod iotrace;
+pub mod layout;
+pub mod mangle;
+pub mod output;
+pub mod probe;
+pub mod quota;
+pub mod recover;
+pub mod repair;
+pub mod seal;
+pub mod selftest;
+pub mod submit;
+pub mod survey;
+pub mod tar;
+pub mod texttype;
+pub mod xmodem;
+pub mod zipfile;
@@ -1,19 +1,123 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use anyhow::Result;
+use cpm86_tools::backup;
 
-mod lib;
-use crate::lib::cpmimg;
+use cpm86_tools::boot;
+use cpm86_tools::changes;
+use cpm86_tools::checksum;
+use cpm86_tools::chunked;
+use cpm86_tools::cmd;
+use cpm86_tools::compat;
+use cpm86_tools::conflict;
+use cpm86_tools::corrupt;
+use cpm86_tools::cpmimg;
+use cpm86_tools::diskmap;
+use cpm86_tools::durable;
+use cpm86_tools::encryption;
+use cpm86_tools::flux::TrackFormat;
+use cpm86_tools::formats;
+use cpm86_tools::imagefile::ImageOptions;
+use cpm86_tools::info;
+use cpm86_tools::iotrace;
+use cpm86_tools::layout;
+use cpm86_tools::mangle;
+use cpm86_tools::output;
+use cpm86_tools::probe;
+use cpm86_tools::quota;
+use cpm86_tools::recover;
+use cpm86_tools::repair;
+use cpm86_tools::seal;
+use cpm86_tools::selftest;
+use cpm86_tools::submit;
+use cpm86_tools::survey;
+use cpm86_tools::tar;
+use cpm86_tools::texttype;
+use cpm86_tools::zipfile;
 
 #[derive(Parser)]
 #[clap(version, about = "A tool for COMPIS CP/M 86 raw floppy images.")]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+    /// Memory map the image (done automatically for images of 4 MB and above)
+    #[clap(long, global = true)]
+    mmap: bool,
+    /// File with the passphrase for encrypted images on its first line
+    #[clap(long, global = true)]
+    passphrase_file: Option<String>,
+    /// Byte offset of the CP/M image in the file, 0x prefix for hex.
+    /// Found from an MBR partition of type 52 or DB if not given.
+    #[clap(long, global = true, value_parser = output::parse_offset)]
+    offset: Option<u64>,
+    /// Highest user number allowed in file names, 15 or 31
+    #[clap(long, global = true, default_value_t = cpmimg::MAX_USER_NUMBER)]
+    max_user: u8,
+    /// Repair a wrong media byte, 00-filled directory entries and bad record
+    /// counts in the image without asking
+    #[clap(long, global = true)]
+    auto_fix: bool,
+    /// Change files whose directory entries point into the directory area
+    #[clap(long, global = true)]
+    force: bool,
+    /// Don't wait for images and written files to be on the disk before finishing.
+    /// Faster, but pulling a USB stick right after may lose the changes.
+    #[clap(long, global = true)]
+    no_sync: bool,
+    /// Don't copy the image before changing it
+    #[clap(long, global = true)]
+    no_backup: bool,
+    /// Copies of the image to keep before changes, rotated as IMAGE_FILE.bak1 (newest)
+    /// to IMAGE_FILE.bakN
+    #[clap(long, global = true, default_value_t = 3)]
+    backups: usize,
+    /// Keep the copies in this directory instead, named by the time they were taken
+    #[clap(long, global = true, value_name = "DIR")]
+    backup_dir: Option<String>,
+    /// JSON file with lists of file types that are text or binary, like
+    /// {"text": ["ME"], "binary": ["DAT"]}. Default is text-types.json in ~/.config/cpm86_tools.
+    #[clap(long, global = true, value_name = "JSON_FILE")]
+    text_types: Option<String>,
+    /// Log every sector read and written to this file as JSON lines,
+    /// with what the sector is for and the file it belongs to
+    #[clap(long, global = true, value_name = "JSONL_FILE")]
+    trace_io: Option<String>,
+    /// Print sizes, counts and block numbers in hex
+    #[clap(long, global = true)]
+    hex: bool,
+    /// Format of the image, one of the names `formats` lists with a geometry.
+    /// The options below change single values of it.
+    #[clap(short = 'f', long, global = true, default_value = "640K")]
+    format: String,
+    /// Tracks on each side
+    #[clap(long, global = true)]
+    tracks: Option<usize>,
+    /// Sectors per track
+    #[clap(long, global = true)]
+    sectors: Option<usize>,
+    /// Bytes per sector
+    #[clap(long, global = true)]
+    secsize: Option<usize>,
+    /// Bytes per allocation block, K suffix allowed
+    #[clap(long, global = true, value_parser = output::parse_size)]
+    blocksize: Option<usize>,
+    /// Directory entries, filling whole blocks
+    #[clap(long, global = true)]
+    maxdir: Option<usize>,
+    /// Reserved tracks before the directory
+    #[clap(long, global = true)]
+    boottracks: Option<usize>,
+    /// Byte the disk was formatted with, what unwritten sectors hold
+    #[clap(long, global = true, value_parser = parse_byte)]
+    fillbyte: Option<u8>,
+    /// Sector skew of an image stored in physical sector order, as in a cpmtools diskdef
+    #[clap(long, global = true)]
+    skew: Option<usize>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Create a new empty floppy image: 512 bytes per sector, 8 sectors per track, 84 tracks.
+    /// With geometry options or another --format the image is the whole disk of that geometry.
     /// Ex: cpmtool create mycompis.img
     Create {
         /// Path to the new floppy image.
@@ -23,6 +127,8 @@ enum Commands {
         size: cpmimg::DiskSize,
     },
     /// Copy a file from local filesystem to the floppy image.
+    /// The last record of a text file is padded with ^Z.
+    /// Without a name in the image the source name is used, shortened to 8.3 if needed.
     /// Ex: cpmtool copyin mycompis.img myprog.bin 0:myprog.cmd
     Copyin {
         /// Path to the floppy image
@@ -31,22 +137,96 @@ enum Commands {
         /// Path to file in local filesystem
         #[clap(name = "SOURCE_FILE")]
         source_path: String,
-        /// User:Name.Type of destination file in image
+        /// User:Name.Type of destination file in image, only User: or nothing to name it after the source
+        #[clap(name = "CPM_FILE")]
+        cpm_file_name: Option<String>,
+        /// Read the file back after writing and compare it with the source
+        #[clap(long)]
+        verify: bool,
+        /// How a source name longer than 8.3 is shortened
+        #[clap(long, value_enum, default_value_t = mangle::Strategy::Numbered)]
+        mangle: mangle::Strategy,
+        /// JSON file of source names and the CP/M names to give them
+        #[clap(long, value_name = "JSON_FILE")]
+        name_map: Option<String>,
+    },
+    /// Wrap a binary as a .CMD-file (8080 memory model, assembled at org $100)
+    /// and copy it into the floppy image, in one step.
+    /// Ex: cpmtool deploy --bin prog.bin --cmd-name 0:PROG.CMD --image work.img --replace --verify
+    Deploy {
+        /// Path to the assembled code
+        #[clap(long = "bin")]
+        bin_path: String,
+        /// User:Name.Type of the .CMD-file in the image
+        #[clap(long)]
+        cmd_name: String,
+        /// Path to the floppy image
+        #[clap(long = "image")]
+        image_path: String,
+        /// Load address of the code group, 0x prefix for hex. Relocatable if not given.
+        #[clap(long, value_parser = output::parse_offset)]
+        load: Option<u64>,
+        /// Stack size in bytes, adds a stack group
+        #[clap(long)]
+        stack_size: Option<u32>,
+        /// Replace the file if it is already in the image
+        #[clap(long)]
+        replace: bool,
+        /// Read the file back after writing and compare it
+        #[clap(long)]
+        verify: bool,
+    },
+    /// Create a file of a given size in the floppy image, without a source file.
+    /// Ex: cpmtool alloc mycompis.img 0:data.dbf --size 64K --fill 0x00
+    Alloc {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// User:Name.Type of new file in image
         #[clap(name = "CPM_FILE")]
         cpm_file_name: String,
+        /// Size in bytes, K or M suffix allowed
+        #[clap(long, value_parser = output::parse_size)]
+        size: usize,
+        /// Byte value to fill the file with, 0x prefix for hex
+        #[clap(long, value_parser = parse_byte, default_value = "0xe5")]
+        fill: u8,
     },
     /// Copy a file from the floppy image to the local filesystem.
+    /// The file is copied in whole records, --eof stop ends text at the first ^Z.
     /// Ex: cpmtool copyout mycompis.img 0:myprog.cmd myprog.bin
+    /// Ex: cpmtool copyout mycompis.img "*:*.*" --tar - | tar x
     Copyout {
         /// Path to the floppy image
         #[clap(name = "IMAGE_FILE")]
         image_path: String,
-        /// User:Name.Type of source file in image
+        /// User:Name.Type of source file in image, with --tar wildcards like 0:*.*
         #[clap(name = "CPM_FILE")]
         cpm_file_name: String,
         /// Path to file in local filesystem
-        #[clap(name = "TARGET_FILE")]
-        output_path: String,
+        #[clap(name = "TARGET_FILE", required_unless_present = "tar")]
+        output_path: Option<String>,
+        /// Write all matching files as a tar archive to this path, - for stdout.
+        /// Each user area becomes a directory.
+        #[clap(long, conflicts_with_all = ["TARGET_FILE", "salvage", "preserve_times"])]
+        tar: Option<String>,
+        /// Copy the file even if parts of it are in sectors that could not be read
+        #[clap(long)]
+        salvage: bool,
+        /// Set the modification time from the CP/M date stamp, if there is one
+        #[clap(long)]
+        preserve_times: bool,
+        /// With --tar, stop at the first file that can't be read instead of leaving it out
+        #[clap(long, requires = "tar")]
+        fail_fast: bool,
+        /// Only copy LEN bytes from START, or to the end of the file without LEN.
+        /// Both may be hex with 0x or have a K or M suffix. Ex: --range 0x80:512
+        #[clap(long, value_name = "START:LEN", value_parser = parse_range, conflicts_with_all = ["tar", "salvage", "preserve_times", "eof"])]
+        range: Option<(u64, Option<u64>)>,
+        /// Copy as text ending where this says, keep copies the whole file like
+        /// without --eof.
+        #[clap(long, value_enum, conflicts_with = "tar")]
+        eof: Option<cpmimg::TextEof>,
     },
     /// Delete a file from the floppy image.
     /// Ex: cpmtool delete mycompis.img 0:myprog.cmd
@@ -58,35 +238,858 @@ enum Commands {
         #[clap(name = "CPM_FILE")]
         cpm_file_name: String,
     },
+    /// Write the floppy image as an SCP flux image, MFM encoded with
+    /// IBM System/34 track layout, for writing to a floppy with GreaseWeazle.
+    /// Ex: cpmtool toflux mycompis.img mycompis.scp --interleave 2
+    Toflux {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Path to the new SCP file
+        #[clap(name = "SCP_FILE")]
+        output_path: String,
+        /// Bytes of gap after each data field
+        #[clap(long, default_value_t = 84)]
+        gap3: usize,
+        /// Bytes of gap between ID and data field
+        #[clap(long, default_value_t = 22)]
+        gap2: usize,
+        /// Sector interleave, 1 writes the sectors in order
+        #[clap(long, default_value_t = 1)]
+        interleave: usize,
+    },
+    /// Write all files of the floppy image to a zip file, a folder per user area.
+    /// Attributes and date stamps are kept in an extra field of each entry.
+    /// Ex: cpmtool tozip mycompis.img mycompis.zip
+    Tozip {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Path to the new zip file
+        #[clap(name = "ZIP_FILE")]
+        zip_path: String,
+        /// Stop at the first file that can't be read, instead of leaving it out
+        #[clap(long)]
+        fail_fast: bool,
+    },
+    /// Copy the files of a zip file into a floppy image, created if it does not exist.
+    /// Folders named by a number give the user area. Names longer than 8.3 are shortened.
+    /// Ex: cpmtool fromzip mycompis.zip mycompis.img --mangle hash
+    Fromzip {
+        /// Path to the zip file
+        #[clap(name = "ZIP_FILE")]
+        zip_path: String,
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Stop at the first file that can't be copied, instead of going on with the others
+        #[clap(long)]
+        fail_fast: bool,
+        /// What to do with files whose name is already in the image
+        #[clap(long, value_enum, default_value_t = conflict::ConflictPolicy::Ask)]
+        on_conflict: conflict::ConflictPolicy,
+        /// How a name longer than 8.3 is shortened
+        #[clap(long, value_enum, default_value_t = mangle::Strategy::Numbered)]
+        mangle: mangle::Strategy,
+        /// JSON file of names in the zip and the CP/M names to give them
+        #[clap(long, value_name = "JSON_FILE")]
+        name_map: Option<String>,
+    },
+    /// Write a damaged copy of an image, for trying salvage and repairs on.
+    /// Killed sectors are only reported as unreadable in an .imd copy.
+    /// Ex: cpmtool corrupt mycompis.img damaged.imd --flip-bits 10 --kill-sector 2:0:3
+    Corrupt {
+        /// Path to the floppy image, it is not changed
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Path to the damaged copy, a plain image or .imd
+        #[clap(name = "OUTPUT_FILE")]
+        output_path: String,
+        /// Number of random bits to flip
+        #[clap(long, default_value_t = 0)]
+        flip_bits: usize,
+        /// Sector to make unreadable as Cylinder:Head:Sector, sectors count from 1
+        #[clap(long, value_parser = parse_sector)]
+        kill_sector: Vec<(usize, usize, u8)>,
+        /// Seed for the bit positions, to get the same damage again
+        #[clap(long)]
+        seed: Option<u64>,
+    },
+    /// Find files in the data blocks of an image whose directory is lost, by .CMD
+    /// headers and text, and copy them to a new image as RECOVER1.CMD, RECOVER1.TXT...
+    /// Ex: cpmtool recover damaged.img recovered.img
+    Recover {
+        /// Path to the floppy image, it is not changed
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Path to the new floppy image
+        #[clap(name = "OUTPUT_FILE")]
+        output_path: String,
+    },
+    /// Send a file from the floppy image over a serial port.
+    /// Ex: cpmtool send --serial /dev/ttyUSB0 --protocol xmodem mycompis.img 0:myprog.cmd
+    Send {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// User:Name.Type of file in image
+        #[clap(name = "CPM_FILE")]
+        cpm_file_name: String,
+        /// Serial port
+        #[clap(long)]
+        serial: String,
+        /// Baud rate
+        #[clap(long, default_value_t = 9600)]
+        baud: u32,
+        /// Transfer protocol
+        #[clap(long, value_enum, default_value_t = cpmimg::Protocol::Xmodem)]
+        protocol: cpmimg::Protocol,
+    },
+    /// Rename a file in the floppy image, the user number may change as well.
+    /// Ex: cpmtool rename mycompis.img 0:myprog.cmd 1:newname.cmd
+    Rename {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// User:Name.Type of file in image
+        #[clap(name = "CPM_FILE")]
+        cpm_file_name: String,
+        /// New User:Name.Type
+        #[clap(name = "NEW_CPM_FILE")]
+        new_cpm_file_name: String,
+    },
+    /// Set or clear the attributes of a file in the floppy image.
+    /// Ex: cpmtool attrib mycompis.img 0:myprog.cmd --readonly true --system true
+    Attrib {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// User:Name.Type of file in image
+        #[clap(name = "CPM_FILE")]
+        cpm_file_name: String,
+        /// Read only (R/O), T1'
+        #[clap(long)]
+        readonly: Option<bool>,
+        /// System (SYS, hidden from DIR), T2'
+        #[clap(long)]
+        system: Option<bool>,
+        /// Archived, T3'
+        #[clap(long)]
+        archived: Option<bool>,
+    },
+    /// Print the first lines of a file in the floppy image.
+    /// Text ends at the first ^Z, or where --eof says.
+    /// Ex: cpmtool head mycompis.img 0:readme.txt -n 20
+    Head {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// User:Name.Type of file in image
+        #[clap(name = "CPM_FILE")]
+        cpm_file_name: String,
+        /// Number of lines
+        #[clap(short = 'n', long, default_value_t = 10)]
+        lines: usize,
+        /// Number of bytes, instead of lines
+        #[clap(short = 'c', long)]
+        bytes: Option<usize>,
+        /// Where the text ends
+        #[clap(long, value_enum, default_value_t = cpmimg::TextEof::Stop)]
+        eof: cpmimg::TextEof,
+    },
+    /// Trace a file from its directory slots to its bytes in the image: EX, S2 and RC
+    /// of each extent, the block numbers, and the image and file range of each block.
+    /// Gaps and blocks outside the disk are flagged, exits with 1 if there are any.
+    /// Ex: cpmtool trace mycompis.img 0:readme.txt
+    Trace {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// User:Name.Type of file in image
+        #[clap(name = "CPM_FILE")]
+        cpm_file_name: String,
+    },
+    /// Print the last lines of a file in the floppy image.
+    /// Text ends at the first ^Z, or where --eof says.
+    /// Ex: cpmtool tail mycompis.img 0:data.log -c 512
+    Tail {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// User:Name.Type of file in image
+        #[clap(name = "CPM_FILE")]
+        cpm_file_name: String,
+        /// Number of lines
+        #[clap(short = 'n', long, default_value_t = 10)]
+        lines: usize,
+        /// Number of bytes, instead of lines
+        #[clap(short = 'c', long)]
+        bytes: Option<usize>,
+        /// Where the text ends
+        #[clap(long, value_enum, default_value_t = cpmimg::TextEof::Stop)]
+        eof: cpmimg::TextEof,
+    },
+    /// Show which file owns each sector of the floppy image, one line per cylinder,
+    /// or as a picture with --image.
+    /// Ex: cpmtool map mycompis.img --image map.png
+    Map {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Write the map to an .svg or .png file instead
+        #[clap(long)]
+        image: Option<String>,
+    },
+    /// Store the floppy image compressed in chunks, leaving out empty chunks and,
+    /// with --base, chunks that are the same as in the base image. Chunked images
+    /// can be used by all commands like plain ones.
+    /// Ex: cpmtool pack game.img game.cpc --base blank.img
+    Pack {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Path to the new chunked image
+        #[clap(name = "CHUNKED_FILE")]
+        output_path: String,
+        /// Image to take unchanged chunks from, relative to the chunked image
+        #[clap(long)]
+        base: Option<String>,
+    },
+    /// Write a plain copy of a chunked floppy image.
+    /// Ex: cpmtool unpack game.cpc game.img
+    Unpack {
+        /// Path to the chunked image
+        #[clap(name = "CHUNKED_FILE")]
+        image_path: String,
+        /// Path to the new plain image
+        #[clap(name = "IMAGE_FILE")]
+        output_path: String,
+    },
+    /// Encrypt the floppy image with AES-256-GCM. Encrypted images can be read
+    /// directly with --passphrase-file, but must be decrypted to be changed.
+    /// Ex: cpmtool encrypt mycompis.img mycompis.enc --passphrase-file key.txt
+    Encrypt {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Path to the new encrypted image
+        #[clap(name = "ENCRYPTED_FILE")]
+        output_path: String,
+    },
+    /// Decrypt an encrypted floppy image.
+    /// Ex: cpmtool decrypt mycompis.enc mycompis.img --passphrase-file key.txt
+    Decrypt {
+        /// Path to the encrypted image
+        #[clap(name = "ENCRYPTED_FILE")]
+        image_path: String,
+        /// Path to the new floppy image
+        #[clap(name = "IMAGE_FILE")]
+        output_path: String,
+    },
+    /// Check that the floppy image has what it needs to boot on COMPIS:
+    /// boot code in the system tracks, a media byte matching the size and CPM.SYS.
+    /// Ex: cpmtool verify-bootable mycompis.img --require 0:autoexec.sub
+    VerifyBootable {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// More User:Name.Type files that must be present
+        #[clap(long)]
+        require: Vec<String>,
+    },
+    /// Save a crc32 of every sector in IMAGE_FILE.seal, for checking the image with audit later.
+    /// Ex: cpmtool seal mycompis.img
+    Seal {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+    },
+    /// Make the image run commands when it is booted, with $$$.SUB for CP/M-86 1.x
+    /// and PROFILE.SUB for CP/M-86 Plus. The first --run is run first.
+    /// Ex: cpmtool autorun boot.img --run "DIR" --run "GAME LEVEL1"
+    Autorun {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// A command line to run at boot, can be given more than once
+        #[clap(long = "run", required = true)]
+        commands: Vec<String>,
+        /// Overwrite an existing $$$.SUB and PROFILE.SUB
+        #[clap(long)]
+        replace: bool,
+    },
+    /// Show what kind of image a file is, its format and how full it is.
+    /// With --physical also unreadable and nonstandard sectors of flux and IMD files.
+    /// Ex: cpmtool info --physical game.imd
+    Info {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Check the sector IDs, for copy protection
+        #[clap(long)]
+        physical: bool,
+    },
+    /// Compare two floppy images sector by sector, or only their files with --logical.
+    /// Exits with 1 if they differ.
+    /// Ex: cpmtool diff --logical mine.img theirs.img
+    Diff {
+        /// Path to the first floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_a: String,
+        /// Path to the second floppy image
+        #[clap(name = "OTHER_IMAGE_FILE")]
+        image_b: String,
+        /// Compare the files and their attributes, not free space, fill or where the files are
+        #[clap(long)]
+        logical: bool,
+    },
+    /// Show the sectors that changed since the image was sealed, exits with 1 if any did.
+    /// Ex: cpmtool audit mycompis.img
+    Audit {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+    },
+    /// Print the SHA-256 of every file in one or more floppy images.
+    /// Each image is read once from front to back, the hashing runs in parallel.
+    /// Ex: cpmtool checksum *.img --jobs 4
+    Checksum {
+        /// Paths to the floppy images
+        #[clap(name = "IMAGE_FILE", required = true)]
+        image_paths: Vec<String>,
+        /// Number of hashing threads, all cores by default
+        #[clap(long)]
+        jobs: Option<usize>,
+        /// Print the checksums as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print a CSV line per image for a whole collection: container, geometry, capacity,
+    /// files, used and free KB, whether it would boot, problems found and the label.
+    /// Wildcards in the file name are expanded if the shell didn't.
+    /// Ex: cpmtool survey "archive/*.img" > survey.csv
+    Survey {
+        /// Paths to the floppy images, or patterns like *.img
+        #[clap(name = "IMAGE_FILE", required = true)]
+        patterns: Vec<String>,
+        /// Print the survey as JSON instead
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print a shell completion script. In bash and fish, words with a colon
+    /// are completed from the directory of the image on the command line.
+    /// Ex: cpmtool completions bash > /etc/bash_completion.d/cpmtool
+    Completions {
+        #[clap(name = "SHELL", value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the files in the image matching a partial User:Name.Type,
+    /// used by the completion scripts
+    #[clap(name = "__complete", hide = true)]
+    Complete {
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        #[clap(name = "PARTIAL", default_value = "")]
+        partial: String,
+    },
+    /// Guess block size and directory offset of an image in an unknown format,
+    /// from how much the entries found there look like a CP/M directory.
+    /// Ex: cpmtool probe unknown.img
+    Probe {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Number of candidates to show
+        #[clap(long, default_value_t = 5)]
+        top: usize,
+        /// Print the candidates as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Compare reading and writing files with cpmtools, if cpmls, cpmcp and
+    /// mkfs.cpm are installed. Exits with 1 when the tools disagree.
+    /// Ex: cpmtool compat
+    Compat {
+        /// Keep the images and the diskdefs file in the temporary directory
+        #[clap(long)]
+        keep: bool,
+    },
+    /// Create a scratch image in the temporary directory and copy generated files
+    /// in, out, rename and delete them, comparing every file after each step.
+    /// Checks the format in use, give another with --format. Exits with 1 when a step fails.
+    /// Ex: cpmtool selftest --format 640K --tracks 160
+    Selftest {
+        /// Keep the image and the files in the temporary directory
+        #[clap(long)]
+        keep: bool,
+    },
+    /// List the floppy formats this tool knows about.
+    /// Ex: cpmtool formats --json
+    Formats {
+        /// Print the formats as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Show the physical layout of the disk for emulators and disk controllers:
+    /// tracks, sides, sectors, skew, reserved tracks, directory and where each block is.
+    /// Ex: cpmtool geometry mycompis.img --json
+    Geometry {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// Print the layout as JSON, with the place of every block
+        #[clap(long)]
+        json: bool,
+    },
+    /// Show or set per user space limits, kept in IMAGE_FILE.quota next to the image.
+    /// Ex: cpmtool quota mycompis.img 3 200
+    Quota {
+        /// Path to the floppy image
+        #[clap(name = "IMAGE_FILE")]
+        image_path: String,
+        /// User number to set the limit for, all users are shown without it
+        #[clap(name = "USER")]
+        user: Option<u8>,
+        /// Limit in KB
+        #[clap(name = "KB", requires = "USER")]
+        limit: Option<u64>,
+        /// Remove the limit of the user
+        #[clap(long, requires = "USER", conflicts_with = "KB")]
+        remove: bool,
+    },
     /// List content of floppy image.
-    /// Ex: cpmtool list mycompis.img
+    /// Files are listed in directory order unless --sort is given,
+    /// equal keys are ordered by name, type, user and directory index.
+    /// Files with the SYS attribute are only listed with --all or --system.
+    /// Ex: cpmtool list mycompis.img --sort size --reverse
     List {
         /// Path to the floppy image
         #[clap(name = "IMAGE_FILE")]
         image_path: String,
+        /// Sort key
+        #[clap(long, value_enum, default_value_t = cpmimg::SortKey::Index)]
+        sort: cpmimg::SortKey,
+        /// Reverse the sort order
+        #[clap(long)]
+        reverse: bool,
+        /// Include files with the SYS attribute, which are hidden like DIR does
+        #[clap(long)]
+        all: bool,
+        /// Only list files with the SYS attribute, like DIRS
+        #[clap(long, visible_alias = "dirs", conflicts_with = "all")]
+        system: bool,
+        /// Also show date stamps as stored, day number since 1978 and BCD time
+        #[clap(long)]
+        raw_dates: bool,
+        /// Print the file metadata as JSON
+        #[clap(long)]
+        json: bool,
     },
 }
 
+impl Commands {
+    // The CP/M image a command works on, checked for fixable problems first
+    fn image_path(&self) -> Option<&str> {
+        match self {
+            Commands::Copyin { image_path, .. }
+            | Commands::Alloc { image_path, .. }
+            | Commands::Deploy { image_path, .. }
+            | Commands::Copyout { image_path, .. }
+            | Commands::Delete { image_path, .. }
+            | Commands::Toflux { image_path, .. }
+            | Commands::Tozip { image_path, .. }
+            | Commands::Send { image_path, .. }
+            | Commands::Rename { image_path, .. }
+            | Commands::Attrib { image_path, .. }
+            | Commands::Head { image_path, .. }
+            | Commands::Tail { image_path, .. }
+            | Commands::Map { image_path, .. }
+            | Commands::VerifyBootable { image_path, .. }
+            | Commands::Quota { image_path, .. }
+            | Commands::Autorun { image_path, .. }
+            | Commands::List { image_path, .. } => Some(image_path),
+            _ => None,
+        }
+    }
+
+    // The existing image a command changes, backed up before
+    fn changed_image(&self) -> Option<&str> {
+        match self {
+            Commands::Create { image_path, .. }
+            | Commands::Copyin { image_path, .. }
+            | Commands::Alloc { image_path, .. }
+            | Commands::Deploy { image_path, .. }
+            | Commands::Delete { image_path, .. }
+            | Commands::Fromzip { image_path, .. }
+            | Commands::Rename { image_path, .. }
+            | Commands::Attrib { image_path, .. }
+            | Commands::Autorun { image_path, .. } => Some(image_path),
+            _ => None,
+        }
+    }
+}
+
+fn passphrase_file(cli: &Cli) -> Result<&str> {
+    match &cli.passphrase_file {
+        Some(passphrase_file) => Ok(passphrase_file),
+        None => anyhow::bail!("Give the passphrase with --passphrase-file"),
+    }
+}
+
+// Completion of CP/M file names for words containing a colon. The image is
+// the first word after the subcommand, the words after options taking a value
+// are skipped. The options are filled in from the command line definition.
+const BASH_FILE_COMPLETION: &str = r#"
+_BIN_cpm_files() {
+    local line="${COMP_LINE:0:$COMP_POINT}"
+    local cur="${line##* }"
+    if [[ "$cur" == *:* ]]; then
+        local image="" command="" skip="" word
+        for word in "${COMP_WORDS[@]:1:$((COMP_CWORD - 1))}"; do
+            if [[ -n "$skip" ]]; then skip=""; continue; fi
+            case "$word" in
+                VALUE_OPTIONS) skip=1 ;;
+                -*) ;;
+                *) if [[ -z "$command" ]]; then command="$word"; else image="$word"; break; fi ;;
+            esac
+        done
+        local IFS=$'
+'
+        local matches=($(BIN __complete "$image" "$cur" 2>/dev/null))
+        # Bash splits words at the colon, only the part after it is replaced
+        if [[ "$COMP_WORDBREAKS" == *:* ]]; then
+            local prefix="${cur%"${cur##*:}"}"
+            matches=("${matches[@]#"$prefix"}")
+        fi
+        COMPREPLY=("${matches[@]}")
+        return 0
+    fi
+    _BIN "$@"
+}
+complete -F _BIN_cpm_files -o nosort -o bashdefault -o default BIN
+"#;
+
+const FISH_FILE_COMPLETION: &str = r#"
+function __BIN_image
+    set -l words (commandline -opc)
+    set -e words[1]
+    set -l command
+    set -l skip
+    for word in $words
+        if test -n "$skip"
+            set skip
+            continue
+        end
+        switch $word
+            case VALUE_OPTIONS
+                set skip 1
+            case '-*'
+            case '*'
+                if test -z "$command"
+                    set command $word
+                else
+                    echo $word
+                    return
+                end
+        end
+    end
+end
+complete -c BIN -n 'string match -q -- "*:*" (commandline -ct)' -f -a '(BIN __complete (__BIN_image) (commandline -ct))'
+"#;
+
+// --name and -n of every option of the command or a subcommand that takes a value
+fn value_options(command: &clap::Command) -> Vec<String> {
+    let mut options = Vec::new();
+    for arg in command.get_arguments().filter(|arg| !arg.is_positional() && arg.get_action().takes_values()) {
+        options.extend(arg.get_long_and_visible_aliases().into_iter().flatten().map(|long| format!("--{}", long)));
+        options.extend(arg.get_short_and_visible_aliases().into_iter().flatten().map(|short| format!("-{}", short)));
+    }
+    for subcommand in command.get_subcommands() {
+        options.extend(value_options(subcommand));
+    }
+    options.sort();
+    options.dedup();
+    options
+}
+
+fn print_completions(shell: clap_complete::Shell) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    let options = value_options(&command);
+    clap_complete::generate(shell, &mut command, &name, &mut std::io::stdout());
+    match shell {
+        clap_complete::Shell::Bash => print!("{}", BASH_FILE_COMPLETION.replace("BIN", &name).replace("VALUE_OPTIONS", &options.join("|"))),
+        clap_complete::Shell::Fish => print!("{}", FISH_FILE_COMPLETION.replace("BIN", &name).replace("VALUE_OPTIONS", &options.join(" "))),
+        _ => {}
+    }
+}
+
+// "0x80:512", "4K:" to the end of the file
+fn parse_range(s: &str) -> Result<(u64, Option<u64>), String> {
+    let (start, len) = match s.split_once(':') {
+        Some(parts) => parts,
+        None => return Err(format!("'{}' is not a range, use START:LEN", s)),
+    };
+    let len = match len.trim() {
+        "" => None,
+        len => Some(output::parse_offset(len)?),
+    };
+    Ok((output::parse_offset(start.trim())?, len))
+}
+
+// "229", "0xe5" or "0XE5"
+fn parse_sector(s: &str) -> Result<(usize, usize, u8), String> {
+    let parts: Vec<&str> = s.split(':').collect();
+    match parts[..] {
+        [c, h, r] => match (c.parse(), h.parse(), r.parse()) {
+            (Ok(c), Ok(h), Ok(r)) => Ok((c, h, r)),
+            _ => Err(format!("Invalid sector '{}', use Cylinder:Head:Sector", s)),
+        },
+        _ => Err(format!("Invalid sector '{}', use Cylinder:Head:Sector", s)),
+    }
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let value = match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse::<u8>(),
+    };
+    value.map_err(|_| format!("'{}' is not a byte value", s))
+}
+
 
 fn main() -> Result<()> {
 
     let cli = Cli::parse();
+    let options = ImageOptions { mmap: cli.mmap, passphrase_file: cli.passphrase_file.clone(), offset: cli.offset, ..Default::default() };
+    cpmimg::set_max_user_number(cli.max_user)?;
+    output::set_hex(cli.hex);
+    cpmimg::set_force(cli.force);
+    durable::set_sync(!cli.no_sync);
+    texttype::load_rules(cli.text_types.as_deref())?;
+    if let Some(trace_path) = &cli.trace_io {
+        iotrace::start(trace_path)?;
+    }
+    let overrides = formats::GeometryOverrides {
+        tracks: cli.tracks,
+        sectors_per_track: cli.sectors,
+        bytes_per_sector: cli.secsize,
+        block_size: cli.blocksize,
+        dir_entries: cli.maxdir,
+        reserved_tracks: cli.boottracks,
+        fill_byte: cli.fillbyte,
+        skew: cli.skew,
+    };
+    cpmimg::set_geometry(overrides.apply(formats::find_geometry(&cli.format)?)?)?;
+
+    // The image as it was, before repairs or the command change it. A repair
+    // of the image of another command is backed up when it is made.
+    let backups = backup::BackupOptions { keep: if cli.no_backup { 0 } else { cli.backups }, dir: cli.backup_dir.clone() };
+    let changed_image = cli.command.changed_image();
+    if let Some(image_path) = changed_image {
+        backup::backup_image(image_path, &backups)?;
+    }
+
+    if let Some(image_path) = cli.command.image_path() {
+        let backups = if changed_image.is_some() { None } else { Some(&backups) };
+        repair::check_image(image_path, &options, cli.auto_fix, changed_image.is_some(), backups)?;
+    }
 
     match &cli.command {
         Commands::Create { image_path, size } => {
             cpmimg::create_image(image_path, size)?;
         }
-        Commands::Copyin { image_path, source_path, cpm_file_name } => {
-            cpmimg::copy_file_in(image_path, source_path, cpm_file_name)?;
+        Commands::Copyin { image_path, source_path, cpm_file_name, verify, mangle, name_map } => {
+            let cpm_file_name = match cpm_file_name.as_deref().unwrap_or("0:").strip_suffix(':') {
+                Some(user) => {
+                    let user = match user.trim().parse::<u8>() {
+                        Ok(user) => user,
+                        Err(_) => anyhow::bail!("User number '{}' is not a number", user),
+                    };
+                    let source_name = match std::path::Path::new(source_path).file_name() {
+                        Some(name) => name.to_string_lossy().to_string(),
+                        None => anyhow::bail!("'{}' has no file name to use in the image", source_path),
+                    };
+                    let mut mangler = mangle::Mangler::new(*mangle, name_map.as_deref())?;
+                    let cpm_file_name = mangler.cpm_name(user, &source_name)?;
+                    if !mangler.renamed.is_empty() {
+                        println!("{} -> {}", source_name, cpm_file_name);
+                    }
+                    cpm_file_name
+                }
+                None => cpm_file_name.clone().unwrap_or_default(),
+            };
+            cpmimg::copy_file_in(image_path, &options, source_path, &cpm_file_name, *verify)?;
         }
-        Commands::Copyout { image_path, cpm_file_name, output_path } => {
-            cpmimg::copy_file_out(image_path, cpm_file_name, output_path)?;
+        Commands::Deploy { bin_path, cmd_name, image_path, load, stack_size, replace, verify } => {
+            let mut spec = cmd::CmdSpec::new(std::fs::read(bin_path)?);
+            if let Some(load) = load {
+                match u32::try_from(*load) {
+                    Ok(load) => spec = spec.load_address(load),
+                    Err(_) => anyhow::bail!("Load address {:#x} is above the 1MB address space", load),
+                }
+            }
+            if let Some(stack_size) = stack_size {
+                spec = spec.stack_size(*stack_size);
+            }
+            let data = cmd::build(spec)?;
+            cpmimg::store_file(image_path, &options, cmd_name, &data, *replace, *verify)?;
+            println!("Deployed {} as {} ({} bytes)", bin_path, cmd_name, data.len());
+        }
+        Commands::Alloc { image_path, cpm_file_name, size, fill } => {
+            cpmimg::alloc_file(image_path, &options, cpm_file_name, *size, *fill)?;
+        }
+        Commands::Copyout { image_path, cpm_file_name, output_path, salvage, preserve_times, tar, fail_fast, range, eof } => {
+            match (tar, output_path) {
+                (None, Some(output_path)) if let Some((start, len)) = range => {
+                    let copied = cpmimg::copy_range_out(image_path, &options, cpm_file_name, output_path, *start, *len)?;
+                    println!("Copied {} bytes from {:#x} of {} to {}", output::number(copied), start, cpm_file_name, output_path);
+                }
+                (Some(tar_path), _) if tar_path == "-" => {
+                    tar::export_tar(image_path, &options, cpm_file_name, &mut std::io::stdout().lock(), *fail_fast)?;
+                }
+                (Some(tar_path), _) => {
+                    let count = tar::export_tar(image_path, &options, cpm_file_name, &mut std::fs::File::create(tar_path)?, *fail_fast)?;
+                    println!("Wrote {} files to {}", count, tar_path);
+                }
+                (None, Some(output_path)) => {
+                    let copy = cpmimg::CopyOutOptions { salvage: *salvage, preserve_times: *preserve_times, eof: *eof };
+                    cpmimg::copy_file_out(image_path, &options, cpm_file_name, output_path, &copy)?;
+                }
+                (None, None) => unreachable!("clap requires TARGET_FILE without --tar"),
+            }
         }
         Commands::Delete { image_path, cpm_file_name } => {
-            cpmimg::delete_file(image_path, cpm_file_name)?;
+            cpmimg::delete_file(image_path, &options, cpm_file_name)?;
+        }
+        Commands::Toflux { image_path, output_path, gap3, gap2, interleave } => {
+            let format = TrackFormat { gap2: *gap2, gap3: *gap3, interleave: *interleave, ..TrackFormat::default() };
+            cpmimg::image_to_flux(image_path, &options, output_path, &format)?;
+        }
+        Commands::Tozip { image_path, zip_path, fail_fast } => {
+            zipfile::export_zip(image_path, &options, zip_path, *fail_fast)?;
+        }
+        Commands::Recover { image_path, output_path } => {
+            recover::recover_image(image_path, &options, output_path)?;
+        }
+        Commands::Fromzip { zip_path, image_path, fail_fast, on_conflict, mangle, name_map } => {
+            let mut mangler = mangle::Mangler::new(*mangle, name_map.as_deref())?;
+            zipfile::import_zip(zip_path, image_path, &options, *fail_fast, *on_conflict, &mut mangler)?;
+        }
+        Commands::Corrupt { image_path, output_path, flip_bits, kill_sector, seed } => {
+            let damage = corrupt::Damage { flip_bits: *flip_bits, kill_sectors: kill_sector.clone(), seed: *seed };
+            corrupt::corrupt_image(image_path, &options, output_path, &damage)?;
+        }
+        Commands::Send { image_path, cpm_file_name, serial, baud, protocol } => {
+            cpmimg::send_file(image_path, &options, cpm_file_name, serial, *baud, *protocol)?;
+        }
+        Commands::Rename { image_path, cpm_file_name, new_cpm_file_name } => {
+            cpmimg::rename_file(image_path, &options, cpm_file_name, new_cpm_file_name)?;
+        }
+        Commands::Attrib { image_path, cpm_file_name, readonly, system, archived } => {
+            cpmimg::set_file_attributes(image_path, &options, cpm_file_name, *readonly, *system, *archived)?;
+        }
+        Commands::Head { image_path, cpm_file_name, lines, bytes, eof } => {
+            cpmimg::head_file(image_path, &options, cpm_file_name, *lines, *bytes, *eof)?;
+        }
+        Commands::Tail { image_path, cpm_file_name, lines, bytes, eof } => {
+            cpmimg::tail_file(image_path, &options, cpm_file_name, *lines, *bytes, *eof)?;
+        }
+        Commands::Trace { image_path, cpm_file_name } => {
+            if cpmimg::trace_file(image_path, &options, cpm_file_name)? > 0 {
+                std::process::exit(1);
+            }
+        }
+        Commands::Map { image_path, image } => {
+            diskmap::map_image(image_path, &options, image.as_deref())?;
+        }
+        Commands::Pack { image_path, output_path, base } => {
+            chunked::pack_image(image_path, &options, output_path, base.as_deref())?;
+        }
+        Commands::Unpack { image_path, output_path } => {
+            chunked::unpack_image(image_path, &options, output_path)?;
+        }
+        Commands::Encrypt { image_path, output_path } => {
+            encryption::encrypt_image(image_path, output_path, passphrase_file(&cli)?)?;
+        }
+        Commands::Decrypt { image_path, output_path } => {
+            encryption::decrypt_image(image_path, output_path, passphrase_file(&cli)?)?;
+        }
+        Commands::VerifyBootable { image_path, require } => {
+            if !boot::verify_bootable(image_path, &options, require)? {
+                std::process::exit(1);
+            }
+        }
+        Commands::Seal { image_path } => {
+            seal::seal_image(image_path, &options)?;
+        }
+        Commands::Autorun { image_path, commands, replace } => {
+            submit::write_autorun(image_path, &options, commands, *replace)?;
+        }
+        Commands::Info { image_path, physical } => {
+            info::print_info(image_path, &options, *physical)?;
+        }
+        Commands::Diff { image_a, image_b, logical } => {
+            if !changes::diff_images(image_a, image_b, &options, *logical)? {
+                std::process::exit(1);
+            }
+        }
+        Commands::Audit { image_path } => {
+            if !seal::audit_image(image_path, &options)? {
+                std::process::exit(1);
+            }
+        }
+        Commands::Checksum { image_paths, jobs, json } => {
+            checksum::print_checksums(image_paths, &options, *jobs, *json)?;
+        }
+        Commands::Survey { patterns, json } => {
+            survey::print_survey(patterns, &options, *json)?;
+        }
+        Commands::Completions { shell } => {
+            print_completions(*shell);
+        }
+        Commands::Complete { image_path, partial } => {
+            // A completion must never print errors into the command line
+            if let Ok(names) = cpmimg::complete_file_names(image_path, &options, partial) {
+                for name in names {
+                    println!("{}", name);
+                }
+            }
+        }
+        Commands::Probe { image_path, top, json } => {
+            probe::print_probe(image_path, &options, *top, *json)?;
+        }
+        Commands::Compat { keep } => {
+            if !compat::check_compat(*keep)? {
+                std::process::exit(1);
+            }
+        }
+        Commands::Selftest { keep } => {
+            if !selftest::run_selftest(*keep)? {
+                std::process::exit(1);
+            }
+        }
+        Commands::Formats { json } => {
+            formats::list_formats(*json)?;
+        }
+        Commands::Geometry { image_path, json } => {
+            layout::print_geometry(image_path, &options, *json)?;
+        }
+        Commands::Quota { image_path, user, limit, remove } => {
+            match (user, limit) {
+                (None, _) => quota::print_quotas(image_path, &options)?,
+                (Some(user), _) if *remove => quota::set_quota(image_path, *user, None)?,
+                (Some(user), Some(limit)) => quota::set_quota(image_path, *user, Some(*limit))?,
+                (Some(user), None) => anyhow::bail!("Give a limit in KB for user {}, or --remove", user),
+            }
         }
-        Commands::List { image_path } => {
-            cpmimg::list_directory(image_path)?;
+        Commands::List { image_path, sort, reverse, all, system, raw_dates, json } => {
+            let shown = match (all, system) {
+                (true, _) => cpmimg::Shown::All,
+                (_, true) => cpmimg::Shown::System,
+                _ => cpmimg::Shown::User,
+            };
+            cpmimg::list_directory(image_path, &options, *sort, *reverse, shown, *raw_dates, *json)?;
         }
     }
 
@@ -0,0 +1,135 @@
+use std::collections::{HashMap, HashSet};
+use anyhow::Result;
+use clap::ValueEnum;
+use crate::cpmimg;
+
+// Host file names made into CP/M 8.3 names. Characters CP/M can't have
+// in a name are dropped and the rest is upper case, the type is what
+// follows the last dot. Names that fit after that are kept as they are by
+// every strategy, the strategies only differ in how a name that is too
+// long is shortened. Numbers only avoid names given earlier in the same
+// command, a name already in the image is left to the conflict handling,
+// so importing the same files again gives the same names. A name map file
+// gives the CP/M name for single host file names and wins over the
+// strategy, it is JSON like
+// {"Long report 1985.text": "REPORT85.TXT", "data file.bin": "1:DATA.BIN"}.
+
+/// Characters CP/M allows in names besides letters and digits
+const NAME_CHARS: &str = "$#@!%&'()-_{}~^`";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Cut the name at 8 characters, LONGFILENAME.TXT is LONGFILE.TXT
+    Truncate,
+    /// The first 4 characters and 4 hex digits of a hash of the whole name, LONG3F2A.TXT
+    Hash,
+    /// The first 6 characters and ~ with a number that is still free, LONGFI~1.TXT
+    Numbered,
+}
+
+/// Makes CP/M names for the host files of one command
+pub struct Mangler {
+    strategy: Strategy,
+    map: HashMap<String, String>,
+    given: HashSet<String>,
+    /// Host name and CP/M name of every file that was renamed
+    pub renamed: Vec<(String, String)>,
+}
+
+fn clean(part: &str, width: Option<usize>) -> String {
+    let cleaned = part.chars()
+        .map(|c| c.to_ascii_uppercase())
+        .filter(|&c| c.is_ascii_uppercase() || c.is_ascii_digit() || NAME_CHARS.contains(c));
+    match width {
+        Some(width) => cleaned.take(width).collect(),
+        None => cleaned.collect(),
+    }
+}
+
+impl Mangler {
+    /// `map_file` is a JSON name map
+    pub fn new(strategy: Strategy, map_file: Option<&str>) -> Result<Mangler> {
+        let map = match map_file {
+            Some(path) => {
+                let text = match std::fs::read_to_string(path) {
+                    Ok(text) => text,
+                    Err(e) => anyhow::bail!("Could not read the name map {}: {}", path, e),
+                };
+                match serde_json::from_str(&text) {
+                    Ok(map) => map,
+                    Err(e) => anyhow::bail!("Name map {} is not valid: {}", path, e),
+                }
+            }
+            None => HashMap::new(),
+        };
+        Ok(Mangler { strategy, map, given: HashSet::new(), renamed: Vec::new() })
+    }
+
+    fn shorten(&self, user: u8, host_name: &str, stem: &str, filetype: &str) -> Result<String> {
+        match self.strategy {
+            Strategy::Truncate => Ok(stem.chars().take(8).collect()),
+            Strategy::Hash => {
+                let hash = crc32fast::hash(host_name.as_bytes()) as u16;
+                Ok(format!("{}{:04X}", stem.chars().take(4).collect::<String>(), hash))
+            }
+            Strategy::Numbered => {
+                for n in 1..1000 {
+                    let suffix = format!("~{}", n);
+                    let name = format!("{}{}", stem.chars().take(8 - suffix.len()).collect::<String>(), suffix);
+                    if !self.given.contains(&format!("{}:{}.{}", user, name, filetype)) {
+                        return Ok(name);
+                    }
+                }
+                anyhow::bail!("No free numbered name for {}", host_name)
+            }
+        }
+    }
+
+    /// User:Name.Type for a host file name without its directory, in `user`
+    /// unless the name map says otherwise
+    pub fn cpm_name(&mut self, user: u8, host_name: &str) -> Result<String> {
+        let cpm_name = match self.map.get(host_name) {
+            Some(mapped) if mapped.contains(':') => mapped.clone(),
+            Some(mapped) if mapped.contains('.') => format!("{}:{}", user, mapped),
+            Some(mapped) => format!("{}:{}.", user, mapped),
+            None => {
+                let (stem, filetype) = match host_name.rsplit_once('.') {
+                    Some((stem, filetype)) if !stem.is_empty() => (clean(stem, None), clean(filetype, Some(3))),
+                    _ => (clean(host_name, None), String::new()),
+                };
+                if stem.is_empty() {
+                    anyhow::bail!("'{}' has no characters CP/M allows in a name, give it one in a name map", host_name);
+                }
+                let stem = if stem.len() > 8 { self.shorten(user, host_name, &stem, &filetype)? } else { stem };
+                format!("{}:{}.{}", user, stem, filetype)
+            }
+        };
+        // The map may have anything in it
+        let (user, filename, filetype) = cpmimg::split_cpm_file_name(&cpm_name)?;
+        let cpm_name = format!("{}:{}.{}", user, filename, filetype);
+
+        // Only upper case isn't worth reporting
+        let host_upper = host_name.to_uppercase();
+        let unchanged = match host_upper.rsplit_once('.') {
+            Some((stem, host_type)) => stem == filename && host_type == filetype,
+            None => host_upper == filename && filetype.is_empty(),
+        };
+        if !unchanged {
+            self.renamed.push((host_name.to_string(), cpm_name.clone()));
+        }
+        self.given.insert(cpm_name.clone());
+        Ok(cpm_name)
+    }
+
+    /// Print the host and CP/M name of every file that was renamed
+    pub fn print_renamed(&self) {
+        if self.renamed.is_empty() {
+            return;
+        }
+        let width = self.renamed.iter().map(|(host, _)| host.len()).max().unwrap_or(0);
+        println!("Renamed for CP/M:");
+        for (host, cpm) in &self.renamed {
+            println!("  {:<width$}  {}", host, cpm, width = width);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt::{Display, LowerHex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// How numbers are printed in tables and reports. Sizes, counts and block
+// numbers are decimal unless --hex is given. Offsets and addresses are hex
+// either way, they are what a hex dump of the image shows. The callers pad
+// the strings to their columns, so tables line up in both modes. JSON output
+// always has plain numbers. Sizes and offsets given on the command line are
+// read the same way by every tool.
+
+static HEX: AtomicBool = AtomicBool::new(false);
+
+/// Print numbers as hex from now on, for the --hex option
+pub fn set_hex(hex: bool) {
+    HEX.store(hex, Ordering::Relaxed);
+}
+
+pub fn hex() -> bool {
+    HEX.load(Ordering::Relaxed)
+}
+
+/// A size, count or block number, "2048" or "0x800"
+pub fn number<N: Display + LowerHex>(n: N) -> String {
+    if hex() {
+        format!("{:#x}", n)
+    } else {
+        n.to_string()
+    }
+}
+
+/// A size given on the command line, "512", "64K" or "1M"
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let upper = s.trim().to_uppercase();
+    let (number, multiplier) = match upper.strip_suffix('K') {
+        Some(number) => (number, 1024),
+        None => match upper.strip_suffix('M') {
+            Some(number) => (number, 1024 * 1024),
+            None => (upper.as_str(), 1),
+        },
+    };
+    number.parse::<usize>().ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or(format!("'{}' is not a size, use bytes or a number with K or M", s))
+}
+
+/// An offset given on the command line, "32256", "0x7e00" or "1M"
+pub fn parse_offset(s: &str) -> Result<u64, String> {
+    match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).map_err(|_| format!("'{}' is not an offset", s)),
+        None => parse_size(s).map(|size| size as u64),
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::Result;
+use serde::Serialize;
+use crate::imagefile::{ImageOptions, open_image};
+
+// Guessing where the directory is on a disk of an unknown format, and how
+// large its allocation blocks are. Every candidate block size is tried at
+// every directory offset, and the 32 byte entries found there are scored
+// by how much they look like CP/M directory entries: names in ASCII, sane
+// user, extent and record counts, block numbers inside the disk, and as
+// many blocks allocated as the record count needs with that block size.
+// The last check is what tells the block sizes apart.
+
+const DIRENTRY_SIZE: usize = 32;
+const RECORD_SIZE: usize = 128;
+const BLOCK_SIZES: [usize; 5] = [1024, 2048, 4096, 8192, 16384];
+/// Directories start on a sector, and within the first tracks
+const OFFSET_STEP: usize = 512;
+const MAX_OFFSET: usize = 0x10000;
+/// Entries scored at each offset, the smallest directory there is
+const SCORED_ENTRIES: usize = 32;
+
+/// One block size and directory offset, and how well the directory fits
+#[derive(Debug, Clone, Serialize)]
+pub struct Candidate {
+    pub block_size: usize,
+    pub directory_offset: usize,
+    /// Block numbers are 16 bits when the disk has more than 256 blocks
+    pub wide_blocks: bool,
+    /// Entries that look like files, including the ones with unlikely record counts
+    pub used_entries: usize,
+    pub empty_entries: usize,
+    /// Entries that can't be directory entries at all
+    pub bad_entries: usize,
+    /// Used entries with as many blocks as their record count needs
+    pub matching_entries: usize,
+    pub score: i64,
+}
+
+// Marked empty, or never written on a disk formatted with 00, F6 or any other fill byte
+fn is_empty_entry(entry: &[u8]) -> bool {
+    entry[0] == 0xe5 || entry.iter().all(|&b| b == entry[0])
+}
+
+/// Score one entry for a block size, None if it is not a file entry at all
+fn entry_matches(entry: &[u8], block_size: usize, blocks: usize, wide_blocks: bool, seen: &mut HashSet<u16>) -> Option<bool> {
+    let user = entry[0];
+    if user > 15 {
+        return None;
+    }
+    let name_ok = entry[1..12].iter().all(|&b| (0x20..0x7f).contains(&(b & 0x7f)) && !(b & 0x7f).is_ascii_lowercase());
+    if !name_ok || entry[1] & 0x7f == b' ' {
+        return None;
+    }
+    let (extent, s1, s2, rc) = (entry[12], entry[13], entry[14], entry[15]);
+    if extent > 31 || s1 != 0 || s2 > 0x3f || rc > 0x80 {
+        return None;
+    }
+
+    let allocation: Vec<u16> = if wide_blocks {
+        entry[16..32].chunks(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect()
+    } else {
+        entry[16..32].iter().map(|&b| b as u16).collect()
+    };
+    let used: Vec<u16> = allocation.iter().copied().filter(|&al| al != 0).collect();
+    if used.iter().any(|&al| al as usize >= blocks) {
+        return None;
+    }
+    // A block in two files means the directory is read wrong
+    if !used.iter().all(|&al| seen.insert(al)) {
+        return Some(false);
+    }
+
+    // One entry covers several logical 16K extents with large blocks,
+    // the low bits of EX count the ones before the last
+    let extent_mask = (block_size * allocation.len() / (RECORD_SIZE * RECORD_SIZE)).max(1) - 1;
+    let records = (extent as usize & extent_mask) * RECORD_SIZE + rc as usize;
+    let needed = (records * RECORD_SIZE).div_ceil(block_size);
+    let contiguous = allocation.iter().take(used.len()).all(|&al| al != 0);
+    Some(needed == used.len() && contiguous)
+}
+
+fn score_candidate(image: &[u8], block_size: usize, directory_offset: usize) -> Candidate {
+    let blocks = image.len() / block_size;
+    let wide_blocks = blocks > 256;
+    let mut candidate = Candidate {
+        block_size, directory_offset, wide_blocks,
+        used_entries: 0, empty_entries: 0, bad_entries: 0, matching_entries: 0, score: 0,
+    };
+
+    let mut seen = HashSet::new();
+    let mut leading_empty = 0;
+    let end = (directory_offset + SCORED_ENTRIES * DIRENTRY_SIZE).min(image.len());
+    for entry in image[directory_offset..end].chunks_exact(DIRENTRY_SIZE) {
+        if is_empty_entry(entry) {
+            candidate.empty_entries += 1;
+            if candidate.used_entries + candidate.bad_entries == 0 {
+                leading_empty += 1;
+            }
+            continue;
+        }
+        match entry_matches(entry, block_size, blocks, wide_blocks, &mut seen) {
+            Some(true) => {
+                candidate.used_entries += 1;
+                candidate.matching_entries += 1;
+            }
+            Some(false) => candidate.used_entries += 1,
+            None => candidate.bad_entries += 1,
+        }
+    }
+
+    // CP/M fills the directory from the front, empty entries before the
+    // first file are more likely the unused end of the reserved tracks
+    candidate.score = 4 * candidate.matching_entries as i64 + candidate.used_entries as i64
+        + (candidate.empty_entries - leading_empty) as i64 / 4 - 4 * candidate.bad_entries as i64;
+    candidate
+}
+
+/// All candidates, best first. Offsets where no entry looks like a file are left out.
+pub fn probe_geometry(image_path: &str, options: &ImageOptions) -> Result<Vec<Candidate>> {
+    let mut disk = open_image(image_path, false, options)?;
+    let mut image = Vec::new();
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_to_end(&mut image)?;
+
+    let mut candidates = Vec::new();
+    for directory_offset in (0..MAX_OFFSET.min(image.len())).step_by(OFFSET_STEP) {
+        for block_size in BLOCK_SIZES {
+            // A directory doesn't start in the middle of file entries
+            if directory_offset >= DIRENTRY_SIZE {
+                let before = &image[directory_offset - DIRENTRY_SIZE..directory_offset];
+                let blocks = image.len() / block_size;
+                if entry_matches(before, block_size, blocks, blocks > 256, &mut HashSet::new()).is_some() {
+                    continue;
+                }
+            }
+            let candidate = score_candidate(&image, block_size, directory_offset);
+            if candidate.used_entries > 0 {
+                candidates.push(candidate);
+            }
+        }
+    }
+    // Equal scores go to the smaller block size and the earlier offset
+    candidates.sort_by_key(|c| (-c.score, c.block_size, c.directory_offset));
+    Ok(candidates)
+}
+
+/// Print the best fitting candidates and the proposed geometry, or JSON
+pub fn print_probe(image_path: &str, options: &ImageOptions, count: usize, json: bool) -> Result<()> {
+    let candidates = probe_geometry(image_path, options)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&candidates.iter().take(count).collect::<Vec<_>>())?);
+        return Ok(());
+    }
+
+    let best = match candidates.first() {
+        Some(best) if best.score > 0 => best,
+        _ => {
+            println!("No directory found in '{}', it is empty or not a CP/M disk", image_path);
+            return Ok(());
+        }
+    };
+
+    println!("Block  Directory  Width  Used  Matching  Empty  Bad  Score");
+    println!("----------------------------------------------------------");
+    for c in candidates.iter().take(count) {
+        println!("{:>5}  {:>#9x}  {:>5}  {:>4}  {:>8}  {:>5}  {:>3}  {:>5}", c.block_size, c.directory_offset,
+            if c.wide_blocks { 16 } else { 8 }, c.used_entries, c.matching_entries, c.empty_entries, c.bad_entries, c.score);
+    }
+    println!();
+    println!("Best fit: {} byte blocks with {} bit block numbers, directory at {:#x}",
+        best.block_size, if best.wide_blocks { 16 } else { 8 }, best.directory_offset);
+    if best.matching_entries < best.used_entries {
+        println!("{} of {} file entries don't have the blocks their record count needs, the guess may be wrong",
+            best.used_entries - best.matching_entries, best.used_entries);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use anyhow::Result;
+use crate::cpmimg::{self, SortKey};
+use crate::durable;
+use crate::imagefile::ImageOptions;
+use crate::output;
+
+// Per user space limits for disks shared by several people, one user area
+// each. The limits are kept in a sidecar file next to the image, so the
+// image itself stays an ordinary CP/M disk that CP/M-86 knows nothing
+// special about. Only this tool enforces them.
+
+/// Limits in KB by user number
+pub type Quotas = BTreeMap<u8, u64>;
+
+/// Path of the sidecar file holding the limits for an image
+pub fn quota_path(image_path: &str) -> String {
+    format!("{}.quota", image_path)
+}
+
+/// The limits for an image, none if it has no sidecar file
+pub fn read_quotas(image_path: &str) -> Result<Quotas> {
+    let path = quota_path(image_path);
+    match std::fs::read_to_string(&path) {
+        Ok(text) => match serde_json::from_str(&text) {
+            Ok(quotas) => Ok(quotas),
+            Err(e) => anyhow::bail!("Could not read quotas from {}: {}", path, e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Quotas::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Save the limits, the sidecar file is removed when there are none left
+pub fn write_quotas(image_path: &str, quotas: &Quotas) -> Result<()> {
+    let path = quota_path(image_path);
+    if quotas.is_empty() {
+        if std::path::Path::new(&path).exists() {
+            std::fs::remove_file(&path)?;
+        }
+        return Ok(());
+    }
+    durable::write(&path, serde_json::to_string_pretty(quotas)?.as_bytes())?;
+    Ok(())
+}
+
+/// Fail if adding `adding` bytes to the `used` bytes of a user goes over its limit
+pub fn check_quota(image_path: &str, user: u8, used: usize, adding: usize) -> Result<()> {
+    let quotas = read_quotas(image_path)?;
+    if let Some(&limit) = quotas.get(&user) {
+        let needed = (used + adding).div_ceil(1024) as u64;
+        if needed > limit {
+            anyhow::bail!("User {} is limited to {} KB, {} KB is used and this needs {} KB more",
+                user, limit, used.div_ceil(1024), adding.div_ceil(1024));
+        }
+    }
+    Ok(())
+}
+
+/// Set the limit of a user in KB, or remove it with None
+pub fn set_quota(image_path: &str, user: u8, limit: Option<u64>) -> Result<()> {
+    let mut quotas = read_quotas(image_path)?;
+    match limit {
+        Some(limit) => quotas.insert(user, limit),
+        None => quotas.remove(&user),
+    };
+    write_quotas(image_path, &quotas)
+}
+
+/// Print space used and limit for every user that has either
+pub fn print_quotas(image_path: &str, options: &ImageOptions) -> Result<()> {
+    let quotas = read_quotas(image_path)?;
+    let files = cpmimg::file_infos(image_path, options, SortKey::Index, false)?;
+
+    let mut used: BTreeMap<u8, usize> = quotas.keys().map(|&user| (user, 0)).collect();
+    for info in &files {
+        *used.entry(info.user_number).or_insert(0) += info.blocks.len() * cpmimg::geometry().block_size;
+    }
+
+    println!("User  Used KB  Limit KB");
+    for (user, bytes) in used {
+        let limit = quotas.get(&user).map_or("-".to_string(), |&limit| output::number(limit));
+        let over = match quotas.get(&user) {
+            Some(&limit) if bytes.div_ceil(1024) as u64 > limit => "  over the limit",
+            _ => "",
+        };
+        println!("{:>4} {:>8} {:>9}{}", user, output::number(bytes.div_ceil(1024)), limit, over);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::Result;
+use crate::cmd::{self, GType, PARAGRAPH_SIZE, RECORD_SIZE};
+use crate::cpmimg::{self, DiskSize};
+use crate::imagefile::{ImageOptions, open_image};
+use crate::texttype;
+
+// Getting files back when the directory itself is lost. Without it there
+// are no names and no block lists, but CP/M hands out blocks in order, so
+// most files are in consecutive blocks. A file is taken to start in a block
+// that begins with a .CMD header or with text, and to run until its header
+// says it ends, a ^Z ends the text, or the next block starts another file
+// or is still empty. The files get made up names and go to a new image.
+
+const CTRL_Z: u8 = 0x1a;
+
+/// What a recovered file was recognized by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Cmd,
+    Text,
+}
+
+/// A file found in the data blocks
+#[derive(Debug, Clone)]
+pub struct Recovered {
+    pub name: String,
+    pub kind: Kind,
+    pub first_block: u16,
+    pub data: Vec<u8>,
+}
+
+// Length of the .CMD-file whose header starts the block, None if it isn't one
+fn cmd_length(block: &[u8]) -> Option<usize> {
+    let header = cmd::read_header(block).ok()?;
+    let mut length = RECORD_SIZE;
+    let mut groups = 0;
+    for group in &header.groups {
+        let g_type = GType::try_from(group.g_form.raw() & 0x0f).ok()?;
+        if g_type == GType::Null {
+            if group.g_form.raw() != 0 || group.g_length != 0 || group.g_min != 0 {
+                return None;
+            }
+            continue;
+        }
+        // A group needs at least the memory its contents take
+        if group.g_min < group.g_length {
+            return None;
+        }
+        length += group.g_length as usize * PARAGRAPH_SIZE;
+        groups += 1;
+    }
+    let first = header.groups[0].g_form.g_type();
+    (groups > 0 && length > RECORD_SIZE && (first == GType::Code || first == GType::Data)).then_some(length)
+}
+
+// Text if the first record is almost all printable, and not just fill
+fn is_text(block: &[u8]) -> bool {
+    texttype::looks_like_text(&block[..RECORD_SIZE])
+}
+
+// Never written since the disk was formatted
+fn is_empty(block: &[u8]) -> bool {
+    let fill_byte = cpmimg::geometry().fill_byte;
+    block.iter().all(|&b| b == fill_byte)
+}
+
+/// Look through all data blocks for files, in block order
+pub fn find_files(image_path: &str, options: &ImageOptions) -> Result<Vec<Recovered>> {
+    let geometry = cpmimg::geometry();
+    let mut disk = open_image(image_path, false, options)?;
+    let mut image = Vec::new();
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_to_end(&mut image)?;
+
+    // Blocks past the end of a short image are read as empty
+    let read_block = |al: u16| -> Vec<u8> {
+        let mut block = vec![geometry.fill_byte; geometry.block_size];
+        if let Ok(offset) = geometry.block_offset(al) {
+            let start = (offset as usize).min(image.len());
+            let end = (start + geometry.block_size).min(image.len());
+            block[..end - start].copy_from_slice(&image[start..end]);
+        }
+        block
+    };
+    let blocks: Vec<Vec<u8>> = (0..geometry.max_blocks() as u16).map(read_block).collect();
+
+    let mut found = Vec::new();
+    let (mut cmds, mut texts) = (0, 0);
+    let mut al = geometry.dir_blocks;
+    while al < blocks.len() {
+        let first_block = al as u16;
+        let (kind, mut data) = if let Some(length) = cmd_length(&blocks[al]) {
+            let mut data = Vec::new();
+            while data.len() < length && al < blocks.len() && (data.is_empty() || !is_empty(&blocks[al])) {
+                data.extend_from_slice(&blocks[al]);
+                al += 1;
+            }
+            data.truncate(length);
+            (Kind::Cmd, data)
+        } else if is_text(&blocks[al]) {
+            let mut data = Vec::new();
+            loop {
+                data.extend_from_slice(&blocks[al]);
+                al += 1;
+                // Text goes on in text, so only a .CMD header ends it early
+                if data.contains(&CTRL_Z) || al >= blocks.len() || is_empty(&blocks[al]) || cmd_length(&blocks[al]).is_some() {
+                    break;
+                }
+            }
+            (Kind::Text, data)
+        } else {
+            al += 1;
+            continue;
+        };
+
+        let name = match kind {
+            Kind::Cmd => { cmds += 1; format!("0:RECOVER{}.CMD", cmds) }
+            Kind::Text => { texts += 1; format!("0:RECOVER{}.TXT", texts) }
+        };
+        if kind == Kind::Text {
+            // Keep the ^Z, drop the rest of the record after it,
+            // or without one the unwritten rest of the last block
+            let end = match data.iter().position(|&b| b == CTRL_Z) {
+                Some(end) => end + 1,
+                None => data.iter().rposition(|&b| b != cpmimg::geometry().fill_byte).map_or(0, |end| end + 1),
+            };
+            data.truncate(end.div_ceil(RECORD_SIZE) * RECORD_SIZE);
+        }
+        found.push(Recovered { name, kind, first_block, data });
+    }
+
+    Ok(found)
+}
+
+/// Find files without the directory and write them to a new image
+pub fn recover_image(image_path: &str, options: &ImageOptions, output_path: &str) -> Result<()> {
+    if std::path::Path::new(output_path).exists() {
+        anyhow::bail!("{} already exists, recovered files go to a new image", output_path);
+    }
+    let found = find_files(image_path, options)?;
+
+    cpmimg::create_image(output_path, &DiskSize::K640)?;
+    let new_options = ImageOptions::default();
+    for file in &found {
+        cpmimg::write_file(output_path, &new_options, &file.name, &file.data)?;
+        println!("{:<16} {:>7} bytes from block {:#x}", file.name, file.data.len(), file.first_block);
+    }
+    println!("Recovered {} files into '{}'", found.len(), output_path);
+
+    Ok(())
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::io::{BufRead, IsTerminal, SeekFrom, Write};
+use anyhow::Result;
+use clap::ValueEnum;
+use crate::backup::{self, BackupOptions};
+use crate::cpmimg::{self, DISKSIZE_OFFSET, DiskSize, Geometry};
+use crate::imagefile::{ImageFile, ImageOptions, is_read_only_format, open_image};
+
+// Repairs for problems that are common in images from other tools and
+// have only one sensible fix. Anything that needs judgement, like blocks
+// used by two files, is left alone.
+
+const DIRENTRY_SIZE: usize = 32;
+const EMPTY: u8 = 0xe5;
+const RECORDS_PER_EXTENT: usize = 0x80;
+const BLOCKS_PER_EXTENT: usize = 8;
+
+// (extent number, directory index) of each entry of a file
+type Extents = Vec<(u16, usize)>;
+
+/// A fix for one problem: bytes to write at image offsets
+#[derive(Debug, Clone)]
+pub struct Repair {
+    pub description: String,
+    writes: Vec<(u64, Vec<u8>)>,
+}
+
+fn media_byte_repair(disk: &mut dyn ImageFile) -> Result<Option<Repair>> {
+    // On other formats 0x1FF is part of the boot sector, not a media byte
+    if cpmimg::geometry() != Geometry::COMPIS {
+        return Ok(None);
+    }
+    let image_size = disk.size()?;
+    let sizes: Vec<&DiskSize> = DiskSize::value_variants().iter()
+        .filter(|size| size.num_bytes() as u64 == image_size)
+        .collect();
+    let expected = match sizes.first() {
+        Some(size) => size.hex_value(),
+        // Nothing to compare with
+        None => return Ok(None),
+    };
+
+    let mut media_byte = [0u8];
+    disk.seek(SeekFrom::Start(DISKSIZE_OFFSET as u64))?;
+    disk.read_exact(&mut media_byte)?;
+    if sizes.iter().any(|size| size.hex_value() == media_byte[0]) {
+        return Ok(None);
+    }
+
+    Ok(Some(Repair {
+        description: format!("Media byte is {:02X}, set it to {:02X} for an image of {} bytes", media_byte[0], expected, image_size),
+        writes: vec![(DISKSIZE_OFFSET as u64, vec![expected])],
+    }))
+}
+
+fn directory_repairs(disk: &mut dyn ImageFile) -> Result<Vec<Repair>> {
+    let geometry = cpmimg::geometry();
+    let mut directory = vec![0u8; geometry.dir_size()];
+    disk.seek(SeekFrom::Start(geometry.catalog_offset()))?;
+    disk.read_exact(&mut directory)?;
+
+    let mut repairs = Vec::new();
+    let entry_offset = |idx: usize| geometry.catalog_offset() + (idx * DIRENTRY_SIZE) as u64;
+    let mut zero_filled = Vec::new();
+
+    // Extents of each file, by user and name with the attribute bits masked off
+    let mut files: HashMap<(u8, Vec<u8>), Extents> = HashMap::new();
+    for (idx, entry) in directory.chunks_exact(DIRENTRY_SIZE).enumerate() {
+        if cpmimg::is_blank_entry(entry) {
+            zero_filled.push((entry_offset(idx), vec![EMPTY; DIRENTRY_SIZE]));
+            continue;
+        }
+        // Empty entries, date stamps, labels and XFCBs
+        if entry[0] == EMPTY || cpmimg::is_special_entry(entry) {
+            continue;
+        }
+        let name: Vec<u8> = entry[1..12].iter().map(|b| b & 0x7f).collect();
+        let extent_number = entry[14] as u16 * 32 + entry[12] as u16;
+        files.entry((entry[0], name)).or_default().push((extent_number, idx));
+    }
+
+    if !zero_filled.is_empty() {
+        repairs.push(Repair {
+            description: format!("{} directory entries are filled with {}, fill them with E5 to mark them empty", zero_filled.len(), cpmimg::blank_fill_name()),
+            writes: zero_filled,
+        });
+    }
+
+    for extents in files.values_mut() {
+        extents.sort();
+        let last = extents.len() - 1;
+        for (i, &(_, idx)) in extents.iter().enumerate() {
+            let entry = &directory[idx * DIRENTRY_SIZE..(idx + 1) * DIRENTRY_SIZE];
+            let blocks = entry[16..32].chunks_exact(2).filter(|al| al[0] != 0 || al[1] != 0).count();
+            // Records in the logical extents before the last one in the entry
+            let before = (entry[12] & geometry.extent_mask()) as usize * RECORDS_PER_EXTENT;
+            let capacity = (blocks * geometry.block_size / 128).saturating_sub(before).min(RECORDS_PER_EXTENT);
+            let record_count = entry[15] as usize;
+
+            // Records beyond the allocated blocks, or an extent in the middle
+            // of a file that is fully allocated but claims to be short
+            let fixed = if record_count > capacity {
+                capacity
+            } else if i < last && blocks == BLOCKS_PER_EXTENT && record_count < RECORDS_PER_EXTENT {
+                RECORDS_PER_EXTENT
+            } else {
+                continue;
+            };
+            let name = String::from_utf8_lossy(&entry[1..12]).to_string();
+            repairs.push(Repair {
+                description: format!("Directory entry {} ({}:{}) has record count {:02X}, set it to {:02X}", idx, entry[0], name, record_count, fixed),
+                writes: vec![(entry_offset(idx) + 15, vec![fixed as u8])],
+            });
+        }
+    }
+    repairs.sort_by_key(|r| r.writes[0].0);
+
+    Ok(repairs)
+}
+
+/// Problems in the image that have a standard fix
+pub fn find_repairs(disk: &mut dyn ImageFile) -> Result<Vec<Repair>> {
+    let mut repairs = Vec::new();
+    repairs.extend(media_byte_repair(disk)?);
+    repairs.extend(directory_repairs(disk)?);
+    Ok(repairs)
+}
+
+pub fn apply_repairs(disk: &mut dyn ImageFile, repairs: &[Repair]) -> Result<()> {
+    for (offset, bytes) in repairs.iter().flat_map(|r| &r.writes) {
+        disk.seek(SeekFrom::Start(*offset))?;
+        disk.write_all(bytes)?;
+    }
+    disk.sync()
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Look for fixable problems before a command uses the image. They are
+/// repaired with `auto_fix`, or after asking from a terminal when the
+/// command changes the image anyway, and otherwise only reported. The image
+/// is backed up before a repair unless `backups` is None because it already was.
+pub fn check_image(image_path: &str, options: &ImageOptions, auto_fix: bool, changes_image: bool, backups: Option<&BackupOptions>) -> Result<()> {
+    // Nothing could be written back to these
+    if is_read_only_format(image_path)? {
+        return Ok(());
+    }
+
+    let repairs = {
+        let mut disk = open_image(image_path, false, options)?;
+        find_repairs(disk.as_mut())?
+    };
+    if repairs.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("Image '{}' has problems that can be repaired:", image_path);
+    for repair in &repairs {
+        eprintln!("  {}", repair.description);
+    }
+
+    let apply = if auto_fix {
+        true
+    } else if changes_image && std::io::stdin().is_terminal() {
+        confirm("Repair the image before going on?")?
+    } else {
+        eprintln!("Run with --auto-fix to repair them.");
+        false
+    };
+    if !apply {
+        return Ok(());
+    }
+
+    if let Some(backups) = backups {
+        backup::backup_image(image_path, backups)?;
+    }
+    let mut disk = open_image(image_path, true, options)?;
+    apply_repairs(disk.as_mut(), &repairs)?;
+    eprintln!("Applied {} repairs", repairs.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpmimg::tests::{blank_image, contents_of, lock_globals, store};
+    use super::*;
+
+    // A label, a date stamp entry, an XFCB and an entry of a type no CP/M uses
+    fn special_entries() -> Vec<(usize, [u8; DIRENTRY_SIZE])> {
+        [(0, 0x20), (3, 0x21), (5, 0x10), (7, 0x33)].iter().map(|&(idx, kind)| {
+            let mut entry = [0u8; DIRENTRY_SIZE];
+            entry[0] = kind;
+            for (i, b) in entry.iter_mut().enumerate().skip(1) {
+                *b = (i as u8).wrapping_mul(kind) | 1;
+            }
+            (idx, entry)
+        }).collect()
+    }
+
+    fn read_entry(disk: &mut dyn ImageFile, idx: usize) -> [u8; DIRENTRY_SIZE] {
+        let mut entry = [0u8; DIRENTRY_SIZE];
+        disk.seek(SeekFrom::Start(cpmimg::geometry().catalog_offset() + (idx * DIRENTRY_SIZE) as u64)).unwrap();
+        disk.read_exact(&mut entry).unwrap();
+        entry
+    }
+
+    fn write_entry(disk: &mut dyn ImageFile, idx: usize, entry: &[u8]) {
+        disk.seek(SeekFrom::Start(cpmimg::geometry().catalog_offset() + (idx * DIRENTRY_SIZE) as u64)).unwrap();
+        disk.write_all(entry).unwrap();
+    }
+
+    #[test]
+    fn special_entries_survive_copyin_and_repair() {
+        let _globals = lock_globals();
+        let mut disk = blank_image();
+        for (idx, entry) in special_entries() {
+            write_entry(&mut disk, idx, &entry);
+        }
+        // Something for repair to do, after the slots copyin takes
+        for idx in 120..123 {
+            write_entry(&mut disk, idx, &[0u8; DIRENTRY_SIZE]);
+        }
+
+        let block_size = cpmimg::geometry().block_size;
+        let mut files = Vec::new();
+        for n in 0..12 {
+            let file = (format!("0:FILE{}.BIN", n), vec![n as u8; n * block_size + 128]);
+            store(&mut disk, &file.0, &file.1, false).unwrap();
+            files.push(file);
+        }
+        let repairs = find_repairs(&mut disk).unwrap();
+        assert!(!repairs.is_empty());
+        apply_repairs(&mut disk, &repairs).unwrap();
+        assert!(find_repairs(&mut disk).unwrap().is_empty());
+
+        for (idx, entry) in special_entries() {
+            assert_eq!(read_entry(&mut disk, idx), entry, "entry {} changed", idx);
+        }
+        let mut listed = contents_of(&mut disk);
+        listed.sort();
+        files.sort();
+        assert!(listed == files);
+    }
+}
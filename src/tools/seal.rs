@@ -0,0 +1,105 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::durable;
+use crate::flux::TrackLayout;
+use crate::imagefile::{ImageOptions, open_image};
+
+// A crc32 of every sector, stored next to the image when it is sealed.
+// Checking them later shows which sectors changed, where a hash of the
+// whole file would only say that something did.
+
+const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Seal {
+    pub sector_size: usize,
+    pub image_size: u64,
+    pub crcs: Vec<u32>,
+}
+
+/// A sector that differs from the seal, None for sectors only in one of them
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub sector: usize,
+    pub sealed: Option<u32>,
+    pub current: Option<u32>,
+}
+
+/// Path of the sidecar file holding the seal of an image
+pub fn seal_path(image_path: &str) -> String {
+    format!("{}.seal", image_path)
+}
+
+fn sector_crcs(image_path: &str, options: &ImageOptions) -> Result<Seal> {
+    let mut disk = open_image(image_path, false, options)?;
+    let mut image = Vec::new();
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_to_end(&mut image)?;
+
+    Ok(Seal {
+        sector_size: SECTOR_SIZE,
+        image_size: image.len() as u64,
+        crcs: image.chunks(SECTOR_SIZE).map(crc32fast::hash).collect(),
+    })
+}
+
+pub fn seal_image(image_path: &str, options: &ImageOptions) -> Result<()> {
+    let seal = sector_crcs(image_path, options)?;
+    durable::write(&seal_path(image_path), serde_json::to_string(&seal)?.as_bytes())?;
+    println!("Sealed {} sectors of '{}'", seal.crcs.len(), image_path);
+    Ok(())
+}
+
+/// Sectors that changed since the image was sealed
+pub fn changes(image_path: &str, options: &ImageOptions) -> Result<Vec<Change>> {
+    let path = seal_path(image_path);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => anyhow::bail!("Could not read the seal {}: {}", path, e),
+    };
+    let sealed: Seal = serde_json::from_str(&text)?;
+    if sealed.sector_size != SECTOR_SIZE {
+        anyhow::bail!("Seal {} has sectors of {} bytes, expected {}", path, sealed.sector_size, SECTOR_SIZE);
+    }
+    let current = sector_crcs(image_path, options)?;
+
+    let sectors = sealed.crcs.len().max(current.crcs.len());
+    Ok((0..sectors).filter_map(|sector| {
+        let change = Change { sector, sealed: sealed.crcs.get(sector).copied(), current: current.crcs.get(sector).copied() };
+        (change.sealed != change.current).then_some(change)
+    }).collect())
+}
+
+// Cylinder:Head:Sector on a COMPIS disk, just the number beyond it
+fn sector_name(sector: usize) -> String {
+    let layout = TrackLayout::COMPIS;
+    let track = sector / layout.sectors;
+    if track < layout.cylinders * layout.heads {
+        format!("{}:{}:{}", track / layout.heads, track % layout.heads, sector % layout.sectors + layout.first_sector as usize)
+    } else {
+        format!("sector {}", sector)
+    }
+}
+
+/// Print the sectors that changed since sealing, true if none did
+pub fn audit_image(image_path: &str, options: &ImageOptions) -> Result<bool> {
+    let changes = changes(image_path, options)?;
+    for change in &changes {
+        let describe = |crc: Option<u32>| crc.map_or("missing".to_string(), |crc| format!("{:08x}", crc));
+        println!("{:<10} offset {:#08x}  sealed {}  now {}", sector_name(change.sector), change.sector * SECTOR_SIZE,
+            describe(change.sealed), describe(change.current));
+    }
+
+    let tracks = {
+        let mut tracks: Vec<usize> = changes.iter().map(|c| c.sector / TrackLayout::COMPIS.sectors).collect();
+        tracks.dedup();
+        tracks.len()
+    };
+    if changes.is_empty() {
+        println!("'{}' is unchanged since it was sealed", image_path);
+    } else {
+        println!("{} sectors on {} tracks changed since '{}' was sealed", changes.len(), tracks, image_path);
+    }
+    Ok(changes.is_empty())
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use crate::checksum::sha256_hex;
+use crate::cpmimg::{self, CopyOutOptions, DiskSize, FileTooLarge, SortKey};
+use crate::imagefile::{ImageOptions, open_image};
+use crate::repair;
+
+// A quick check that this build reads and writes the format in use on this
+// machine, for a new build or a format described with --format and the
+// geometry options. A scratch image in the temporary directory goes
+// through create, copyin, copyout, rename and delete with generated files,
+// and every file is compared by SHA-256 with what was written after each
+// step. The steps build on each other, after a failure the rest are skipped.
+
+const STEPS: [&str; 8] = ["create", "copyin", "copyout", "rename", "delete", "refill", "limits", "check"];
+
+const TEXT_FILE: &str = "3:NOTES.TXT";
+
+// Contents that differ between files and between records of a file
+fn test_data(seed: u64, size: usize) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e3779b97f4a7c15) | 1;
+    (0..size).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect()
+}
+
+// Lines that don't end on a record, without ^Z
+fn test_text() -> Vec<u8> {
+    (1..=60).map(|n| format!("Line {} of the self test\r\n", n)).collect::<String>().into_bytes()
+}
+
+/// Name and contents of the files written, covering an empty file, one
+/// record, a part record, one block and files over several extents and
+/// directory entries of the format in use
+fn test_files() -> Vec<(String, Vec<u8>)> {
+    let block_size = cpmimg::geometry().block_size;
+    let sizes = [
+        ("0:EMPTY.BIN", 0),
+        ("0:RECORD.BIN", 128),
+        ("0:ODD.BIN", 1000),
+        ("0:BLOCK.BIN", block_size),
+        ("0:EXTENT.BIN", 16384),
+        ("0:ENTRIES.BIN", 9 * block_size + 128),
+    ];
+    let mut files: Vec<(String, Vec<u8>)> = sizes.iter().enumerate()
+        .map(|(seed, (name, size))| (name.to_string(), test_data(seed as u64 + 1, *size)))
+        .collect();
+    files.push((TEXT_FILE.to_string(), test_text()));
+    files
+}
+
+/// The scratch image and what each file in it should hold
+struct Scratch {
+    dir: PathBuf,
+    image_path: String,
+    options: ImageOptions,
+    expected: BTreeMap<String, Vec<u8>>,
+}
+
+impl Scratch {
+    fn host_path(&self, cpm_file_name: &str) -> PathBuf {
+        self.dir.join(cpm_file_name.replace(':', "_"))
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        let files = cpmimg::file_infos(&self.image_path, &self.options, SortKey::Name, false)?;
+        let mut names: Vec<String> = files.iter().map(|f| format!("{}:{}.{}", f.user_number, f.filename, f.filetype)).collect();
+        names.sort();
+        Ok(names)
+    }
+
+    // Files come back in whole records, text padded with ^Z
+    fn verify_file(&self, cpm_file_name: &str) -> Result<()> {
+        let expected = &self.expected[cpm_file_name];
+        let output = self.host_path(&format!("out_{}", cpm_file_name));
+        let output_path = output.to_string_lossy().into_owned();
+        cpmimg::copy_file_out(&self.image_path, &self.options, cpm_file_name, &output_path, &CopyOutOptions::default())?;
+        let read = std::fs::read(&output)?;
+        std::fs::remove_file(&output)?;
+
+        let size = expected.len().div_ceil(128) * 128;
+        if read.len() != size {
+            anyhow::bail!("{} reads back as {} bytes, {} were expected", cpm_file_name, read.len(), size);
+        }
+        if sha256_hex(&read[..expected.len()]) != sha256_hex(expected) {
+            anyhow::bail!("{} reads back with other contents than were written", cpm_file_name);
+        }
+        Ok(())
+    }
+
+    fn verify_all(&self) -> Result<()> {
+        let names = self.names()?;
+        let expected: Vec<String> = self.expected.keys().cloned().collect();
+        if names != expected {
+            anyhow::bail!("The image lists {}, expected {}", names.join(" "), expected.join(" "));
+        }
+        for name in self.expected.keys() {
+            self.verify_file(name)?;
+        }
+        Ok(())
+    }
+
+    fn create(&mut self) -> Result<String> {
+        cpmimg::create_image(&self.image_path, &DiskSize::K640)?;
+        if !self.names()?.is_empty() {
+            anyhow::bail!("A new image lists files");
+        }
+        let repairs = repair::find_repairs(open_image(&self.image_path, false, &self.options)?.as_mut())?;
+        if let Some(repair) = repairs.first() {
+            anyhow::bail!("A new image needs repairs: {}", repair.description);
+        }
+        Ok(format!("empty image of {} bytes", std::fs::metadata(&self.image_path)?.len()))
+    }
+
+    fn copyin(&mut self) -> Result<String> {
+        let files = test_files();
+        for (name, data) in &files {
+            let source = self.host_path(name);
+            std::fs::write(&source, data)?;
+            cpmimg::copy_file_in(&self.image_path, &self.options, &source.to_string_lossy(), name, false)?;
+            self.expected.insert(name.clone(), data.clone());
+        }
+        let bytes: usize = files.iter().map(|(_, data)| data.len()).sum();
+        Ok(format!("{} files of {} bytes", files.len(), bytes))
+    }
+
+    fn copyout(&mut self) -> Result<String> {
+        self.verify_all()?;
+        Ok(format!("{} files match by SHA-256", self.expected.len()))
+    }
+
+    fn rename(&mut self) -> Result<String> {
+        // To another user too
+        let (from, to) = ("0:ODD.BIN", "5:RENAMED.DAT");
+        cpmimg::rename_file(&self.image_path, &self.options, from, to)?;
+        let data = self.expected.remove(from).unwrap_or_default();
+        self.expected.insert(to.to_string(), data);
+        self.verify_all()?;
+        Ok(format!("{} to {}", from, to))
+    }
+
+    fn delete(&mut self) -> Result<String> {
+        let deleted = ["0:BLOCK.BIN", "0:EXTENT.BIN"];
+        for name in deleted {
+            cpmimg::delete_file(&self.image_path, &self.options, name)?;
+            self.expected.remove(name);
+        }
+        self.verify_all()?;
+        Ok(format!("{}, the other files are unchanged", deleted.join(" and ")))
+    }
+
+    fn refill(&mut self) -> Result<String> {
+        // Larger than the blocks freed by delete, so it is spread over them and new ones
+        let name = "0:REFILL.BIN";
+        let data = test_data(99, cpmimg::geometry().block_size * 10);
+        cpmimg::write_file(&self.image_path, &self.options, name, &data)?;
+        self.expected.insert(name.to_string(), data);
+        self.verify_all()?;
+        let files = cpmimg::file_infos(&self.image_path, &self.options, SortKey::Name, false)?;
+        let fragmented = files.iter().any(|f| f.fragmented);
+        Ok(format!("{} in the freed blocks{}", name, if fragmented { ", fragmented" } else { "" }))
+    }
+
+    fn limits(&mut self) -> Result<String> {
+        let limit = cpmimg::geometry().max_file_size();
+        let result = cpmimg::write_file(&self.image_path, &self.options, "0:TOOBIG.BIN", &vec![0u8; limit + 128]);
+        match result {
+            Err(e) if e.downcast_ref::<FileTooLarge>().is_some() => {}
+            Err(e) => anyhow::bail!("A file over the limit failed with another error: {}", e),
+            Ok(()) => anyhow::bail!("A file of {} bytes was stored, the limit is {}", limit + 128, limit),
+        }
+        self.verify_all()?;
+        Ok(format!("a file over {} bytes is refused", limit))
+    }
+
+    fn check(&mut self) -> Result<String> {
+        let repairs = repair::find_repairs(open_image(&self.image_path, false, &self.options)?.as_mut())?;
+        if let Some(repair) = repairs.first() {
+            anyhow::bail!("The image needs repairs: {}", repair.description);
+        }
+        let mut files = 0;
+        cpmimg::read_all_files(&self.image_path, &self.options, &mut |_, _| {
+            files += 1;
+            Ok(())
+        })?;
+        Ok(format!("directory is consistent, all {} files read", files))
+    }
+
+    fn run_step(&mut self, step: &str) -> Result<String> {
+        match step {
+            "create" => self.create(),
+            "copyin" => self.copyin(),
+            "copyout" => self.copyout(),
+            "rename" => self.rename(),
+            "delete" => self.delete(),
+            "refill" => self.refill(),
+            "limits" => self.limits(),
+            _ => self.check(),
+        }
+    }
+}
+
+fn run_steps(scratch: &mut Scratch) -> bool {
+    let mut failed = false;
+    for step in STEPS {
+        if failed {
+            println!("  skip  {}", step);
+            continue;
+        }
+        match scratch.run_step(step) {
+            Ok(what) => println!("  pass  {:<8} {}", step, what),
+            Err(e) => {
+                println!("  FAIL  {:<8} {}", step, e);
+                failed = true;
+            }
+        }
+    }
+    !failed
+}
+
+/// Run the steps on a scratch image with the geometry in use, print pass
+/// or fail for each and true if all passed
+pub fn run_selftest(keep: bool) -> Result<bool> {
+    let dir: PathBuf = std::env::temp_dir().join(format!("cpm86tools-selftest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let geometry = cpmimg::geometry();
+    println!("Self test of {}x{}x{}x{} with {} byte blocks", geometry.sides, geometry.tracks,
+        geometry.sectors_per_track, geometry.bytes_per_sector, geometry.block_size);
+
+    // The image is a plain file made here, whatever the command line says
+    let mut scratch = Scratch {
+        image_path: dir.join("selftest.img").to_string_lossy().into_owned(),
+        dir: dir.clone(),
+        options: ImageOptions::default(),
+        expected: BTreeMap::new(),
+    };
+    let passed = run_steps(&mut scratch);
+
+    if keep {
+        println!("The image and files are kept in {}", dir.display());
+    } else {
+        std::fs::remove_dir_all(Path::new(&dir))?;
+    }
+    match passed {
+        true => println!("All {} steps passed", STEPS.len()),
+        false => println!("The self test failed"),
+    }
+    Ok(passed)
+}
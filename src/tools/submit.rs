@@ -0,0 +1,99 @@
+use anyhow::Result;
+use crate::cpmimg::{self, SortKey};
+use crate::imagefile::ImageOptions;
+
+// Disks that run a program when they are booted. The CCP of CP/M-86 1.x
+// looks for $$$.SUB on the boot drive when it starts, and runs the command
+// in its last record, then drops that record. SUBMIT writes it with one
+// 128 byte record per command, the last command first:
+//   length, the command in upper case, 00
+// CP/M-86 Plus runs PROFILE.SUB at a cold start instead, a text file that
+// is handed to SUBMIT.CMD. Both are written, each system uses its own.
+
+const RECORD_SIZE: usize = 128;
+/// The length byte and the 00 after the command take two bytes of the record
+const MAX_COMMAND: usize = RECORD_SIZE - 2;
+/// The CCP only counts down the record count of one extent
+const MAX_COMMANDS: usize = 128;
+const CTRL_Z: u8 = 0x1a;
+/// Commands of the CCP itself, there is no program file for them
+const BUILT_IN: [&str; 6] = ["DIR", "ERA", "REN", "TYPE", "USER", "SUBMIT"];
+
+pub const SUB_NAME: &str = "0:$$$.SUB";
+pub const PROFILE_NAME: &str = "0:PROFILE.SUB";
+
+fn check_commands(commands: &[String]) -> Result<()> {
+    if commands.is_empty() {
+        anyhow::bail!("Give at least one command to run");
+    }
+    if commands.len() > MAX_COMMANDS {
+        anyhow::bail!("{} commands, at most {} fit in a $$$.SUB file", commands.len(), MAX_COMMANDS);
+    }
+    for command in commands {
+        if command.trim().is_empty() {
+            anyhow::bail!("Empty command in the list");
+        }
+        if !command.is_ascii() || command.chars().any(|c| c.is_ascii_control()) {
+            anyhow::bail!("Command '{}' has characters the CCP can't take", command);
+        }
+        if command.len() > MAX_COMMAND {
+            anyhow::bail!("Command '{}' is {} characters, at most {} fit in a record", command, command.len(), MAX_COMMAND);
+        }
+    }
+    Ok(())
+}
+
+/// The contents of $$$.SUB for the commands, in the order they are to run
+pub fn sub_records(commands: &[String]) -> Result<Vec<u8>> {
+    check_commands(commands)?;
+    let mut records = Vec::with_capacity(commands.len() * RECORD_SIZE);
+    for command in commands.iter().rev() {
+        let command = command.trim().to_uppercase();
+        let mut record = vec![0u8; RECORD_SIZE];
+        record[0] = command.len() as u8;
+        record[1..=command.len()].copy_from_slice(command.as_bytes());
+        records.extend_from_slice(&record);
+    }
+    Ok(records)
+}
+
+/// The contents of PROFILE.SUB, one command per line ending with ^Z
+pub fn profile_text(commands: &[String]) -> Result<Vec<u8>> {
+    check_commands(commands)?;
+    let mut text: Vec<u8> = commands.iter().flat_map(|c| format!("{}\r\n", c.trim()).into_bytes()).collect();
+    text.push(CTRL_Z);
+    text.resize(text.len().div_ceil(RECORD_SIZE) * RECORD_SIZE, CTRL_Z);
+    Ok(text)
+}
+
+/// Make the image run the commands when it is booted
+pub fn write_autorun(image_path: &str, options: &ImageOptions, commands: &[String], replace: bool) -> Result<()> {
+    let files = cpmimg::file_infos(image_path, options, SortKey::Name, false)?;
+    let has = |name: &str, filetype: &str| files.iter().any(|f| f.user_number == 0 && f.filename == name && f.filetype == filetype);
+    if !replace && (has("$$$", "SUB") || has("PROFILE", "SUB")) {
+        anyhow::bail!("The image already has {} or {}, use --replace to write new ones", SUB_NAME, PROFILE_NAME);
+    }
+
+    cpmimg::store_file(image_path, options, SUB_NAME, &sub_records(commands)?, replace, true)?;
+    cpmimg::store_file(image_path, options, PROFILE_NAME, &profile_text(commands)?, replace, true)?;
+    println!("Wrote {} and {} to run {} commands at boot", SUB_NAME, PROFILE_NAME, commands.len());
+
+    // PROFILE.SUB is run by SUBMIT, CP/M-86 1.x doesn't need it
+    if !has("SUBMIT", "CMD") {
+        println!("Note: there is no 0:SUBMIT.CMD in the image, CP/M-86 Plus needs it to run {}", PROFILE_NAME);
+    }
+    for command in commands {
+        let program = command.split_whitespace().next().unwrap_or("").to_uppercase();
+        let (name, filetype) = program.split_once('.').unwrap_or((&program, "CMD"));
+        let name = name.rsplit(':').next().unwrap_or(name);
+        // A drive change like "B:" runs nothing
+        if name.is_empty() || BUILT_IN.contains(&name) {
+            continue;
+        }
+        if !files.iter().any(|f| f.filename == name && f.filetype == filetype) {
+            println!("Note: {}.{} is not in the image, the command '{}' may fail", name, filetype, command.trim());
+        }
+    }
+
+    Ok(())
+}
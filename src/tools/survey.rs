@@ -0,0 +1,160 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use anyhow::Result;
+use rayon::prelude::*;
+use serde::Serialize;
+use crate::boot::{self, Status};
+use crate::cpmimg::{self, DISKSIZE_OFFSET, SortKey};
+use crate::imagefile::{ImageOptions, open_image};
+use crate::info;
+use crate::repair;
+
+// One line of facts per image for a whole collection, as CSV for a
+// spreadsheet. Every image is read with the geometry in use, the same as
+// the other commands would. An image that can't be read still gets its
+// line, with the error, so the count of lines is the count of images.
+
+const CSV_HEADER: &str = "image,container,geometry,media_byte,capacity_kb,files,used_kb,free_kb,bootable,check,label,error";
+
+/// What survey found out about one image
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImageSurvey {
+    pub image: String,
+    pub container: String,
+    /// Sides x tracks x sectors x bytes per sector
+    pub geometry: String,
+    pub media_byte: Option<u8>,
+    pub capacity_kb: Option<usize>,
+    pub files: Option<usize>,
+    pub used_kb: Option<usize>,
+    pub free_kb: Option<usize>,
+    pub bootable: Option<bool>,
+    /// "ok", or how many problems --auto-fix would repair
+    pub check: String,
+    pub label: String,
+    pub error: String,
+}
+
+fn fill_survey(survey: &mut ImageSurvey, image_path: &str, options: &ImageOptions) -> Result<()> {
+    let geometry = cpmimg::geometry();
+    survey.container = info::container_name(image_path)?.to_string();
+    survey.geometry = format!("{}x{}x{}x{}", geometry.sides, geometry.tracks, geometry.sectors_per_track, geometry.bytes_per_sector);
+
+    let repairs = {
+        let mut disk = open_image(image_path, false, options)?;
+        let mut media_byte = [0u8; 1];
+        disk.seek(SeekFrom::Start(DISKSIZE_OFFSET as u64))?;
+        disk.read_exact(&mut media_byte)?;
+        survey.media_byte = Some(media_byte[0]);
+        repair::find_repairs(disk.as_mut())?
+    };
+    survey.check = match repairs.len() {
+        0 => "ok".to_string(),
+        n => format!("{} problems", n),
+    };
+
+    let files = cpmimg::file_infos(image_path, options, SortKey::Index, false)?;
+    let used: usize = files.iter().map(|f| f.blocks.len()).sum();
+    let capacity = (geometry.max_blocks() - geometry.dir_blocks) * geometry.block_size / 1024;
+    survey.files = Some(files.len());
+    survey.capacity_kb = Some(capacity);
+    survey.used_kb = Some(used * geometry.block_size / 1024);
+    survey.free_kb = Some(capacity.saturating_sub(used * geometry.block_size / 1024));
+    survey.label = cpmimg::disk_label(image_path, options)?.unwrap_or_default();
+    survey.bootable = Some(boot::boot_checks(image_path, options, &[])?.iter().all(|c| c.status != Status::Fail));
+    Ok(())
+}
+
+/// Survey one image, errors end up in the `error` field
+pub fn survey_image(image_path: &str, options: &ImageOptions) -> ImageSurvey {
+    let mut survey = ImageSurvey { image: image_path.to_string(), ..ImageSurvey::default() };
+    if let Err(e) = fill_survey(&mut survey, image_path, options) {
+        survey.error = e.to_string();
+    }
+    survey
+}
+
+/// Survey the images in parallel, in the order given
+pub fn survey_images(image_paths: &[String], options: &ImageOptions) -> Vec<ImageSurvey> {
+    image_paths.par_iter().map(|image_path| survey_image(image_path, options)).collect()
+}
+
+// "*" for any characters, "?" for one
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..])),
+        (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => matches(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// The files matching each pattern, in name order. Wildcards are only
+/// expanded in the last part of a path, for shells that don't do it.
+pub fn expand_patterns(patterns: &[String]) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let path = Path::new(pattern);
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        if !name.contains(['*', '?']) {
+            paths.push(pattern.clone());
+            continue;
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if dir.to_string_lossy().contains(['*', '?']) {
+            anyhow::bail!("Wildcards are only allowed in the file name of {}", pattern);
+        }
+        let pattern_chars: Vec<char> = name.chars().collect();
+        let mut found: Vec<String> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .filter(|entry| matches(&pattern_chars, &entry.file_name().to_string_lossy().chars().collect::<Vec<char>>()))
+            .map(|entry| match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.join(entry.file_name()).to_string_lossy().to_string(),
+                _ => entry.file_name().to_string_lossy().to_string(),
+            })
+            .collect();
+        if found.is_empty() {
+            eprintln!("Warning: no files match {}", pattern);
+        }
+        found.sort();
+        paths.extend(found);
+    }
+    Ok(paths)
+}
+
+// Quoted when it has a comma, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Print a CSV line for each image the patterns match, or JSON
+pub fn print_survey(patterns: &[String], options: &ImageOptions, json: bool) -> Result<()> {
+    let surveys = survey_images(&expand_patterns(patterns)?, options);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&surveys)?);
+        return Ok(());
+    }
+    println!("{}", CSV_HEADER);
+    for s in &surveys {
+        let fields = [
+            s.image.clone(), s.container.clone(), s.geometry.clone(), optional(s.media_byte.map(|b| format!("{:02X}", b))),
+            optional(s.capacity_kb), optional(s.files), optional(s.used_kb), optional(s.free_kb), optional(s.bootable),
+            s.check.clone(), s.label.clone(), s.error.clone(),
+        ];
+        println!("{}", fields.iter().map(|f| csv_field(f)).collect::<Vec<String>>().join(","));
+    }
+    Ok(())
+}
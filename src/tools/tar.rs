@@ -0,0 +1,127 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use crate::batch::Batch;
+use crate::cpmimg::{self, FileInfo, SortKey};
+use crate::imagefile::ImageOptions;
+
+// POSIX ustar archives of the files in an image, one directory per user
+// area, written as a stream so they can go straight into a pipe.
+
+const BLOCK_SIZE: usize = 512;
+const REGULAR_FILE: u8 = b'0';
+const DIRECTORY: u8 = b'5';
+
+pub struct TarWriter<W: Write> {
+    out: W,
+}
+
+// Octal number, zero padded and NUL terminated, filling the field
+fn octal(field: &mut [u8], value: u64) {
+    let text = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(text.as_bytes());
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> TarWriter<W> {
+        TarWriter { out }
+    }
+
+    fn header(&mut self, path: &str, size: u64, mode: u32, mtime: u64, kind: u8) -> Result<()> {
+        if path.len() > 100 {
+            anyhow::bail!("Path {} is too long for a tar header", path);
+        }
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        octal(&mut header[100..108], mode as u64);
+        octal(&mut header[108..116], 0);
+        octal(&mut header[116..124], 0);
+        octal(&mut header[124..136], size);
+        octal(&mut header[136..148], mtime);
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // The checksum is taken with its own field as blanks
+        header[148..156].fill(b' ');
+        let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+        octal(&mut header[148..155], checksum);
+
+        self.out.write_all(&header)?;
+        Ok(())
+    }
+
+    pub fn add_directory(&mut self, path: &str, mtime: u64) -> Result<()> {
+        self.header(&format!("{}/", path.trim_end_matches('/')), 0, 0o755, mtime, DIRECTORY)
+    }
+
+    pub fn add_file(&mut self, path: &str, data: &[u8], mode: u32, mtime: u64) -> Result<()> {
+        self.header(path, data.len() as u64, mode, mtime, REGULAR_FILE)?;
+        self.out.write_all(data)?;
+        let padding = data.len().next_multiple_of(BLOCK_SIZE) - data.len();
+        self.out.write_all(&vec![0u8; padding])?;
+        Ok(())
+    }
+
+    /// Write the end of archive marker and hand back the output
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_all(&[0u8; 2 * BLOCK_SIZE])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// The CP/M modification stamp, or the creation stamp, or now
+fn modification_time(info: &FileInfo) -> u64 {
+    let stamp = info.timestamps
+        .and_then(|t| t.modified.or(t.created))
+        .and_then(|d| d.to_system_time());
+    unix_seconds(stamp.unwrap_or_else(SystemTime::now))
+}
+
+/// Write the files matching `pattern` as a tar stream with a directory per
+/// user, "0/NAME.TYP". Returns the number of files written. Files that
+/// can't be read are left out and fail it at the end, unless `fail_fast`
+/// stops it at the first one.
+pub fn export_tar(image_path: &str, options: &ImageOptions, pattern: &str, out: &mut dyn Write, fail_fast: bool) -> Result<usize> {
+    let files = cpmimg::file_infos(image_path, options, SortKey::Index, false)?;
+    let wanted: BTreeSet<usize> = cpmimg::match_file_pattern(&files, pattern)?
+        .iter()
+        .map(|f| f.directory_index)
+        .collect();
+    if wanted.is_empty() {
+        anyhow::bail!("No files matching {} in image", pattern);
+    }
+
+    let now = unix_seconds(SystemTime::now());
+    let mut tar = TarWriter::new(out);
+    let mut users = BTreeSet::new();
+    let mut batch = Batch::new(fail_fast);
+    cpmimg::read_each_file(image_path, options, &mut |info, data| {
+        if !wanted.contains(&info.directory_index) {
+            return Ok(());
+        }
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => return batch.record(&format!("{}:{}.{}", info.user_number, info.filename, info.filetype), Err(e)),
+        };
+        if users.insert(info.user_number) {
+            tar.add_directory(&info.user_number.to_string(), now)?;
+        }
+        let path = match info.filetype.as_str() {
+            "" => format!("{}/{}", info.user_number, info.filename),
+            filetype => format!("{}/{}.{}", info.user_number, info.filename, filetype),
+        };
+        let mode = if info.attributes.readonly { 0o444 } else { 0o644 };
+        tar.add_file(&path, &data, mode, modification_time(&info))?;
+        batch.record(&path, Ok(()))
+    })?;
+    tar.finish()?;
+
+    batch.finish()
+}
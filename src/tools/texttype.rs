@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+use anyhow::Result;
+use serde::Deserialize;
+
+// Whether a file is text, decided in one place for every command that
+// treats text differently. The file type decides for the usual ones, the
+// first record decides for the rest. The lists can be changed in a JSON
+// file like {"text": ["ME", "DOC"], "binary": ["DAT"]}, by default
+// text-types.json in the cpm86_tools config directory, which wins over
+// the built-in lists. DOC isn't built in, word processor documents can
+// have ^Z in the middle of the text.
+
+const CTRL_Z: u8 = 0x1a;
+
+const TEXT_TYPES: &[&str] = &["TXT", "SUB", "BAS", "ASM", "A86", "MAC", "PRN", "LST", "SYM", "HEX", "C", "H", "PAS", "FOR", "BAT", "ME", "NEW"];
+const BINARY_TYPES: &[&str] = &["CMD", "COM", "OVR", "OVL", "SYS", "REL", "OBJ", "BIN", "LBR", "H86", "IMG"];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    text: Vec<String>,
+    #[serde(default)]
+    binary: Vec<String>,
+}
+
+/// The file types known to be text or binary
+#[derive(Debug, Clone)]
+pub struct Rules {
+    text: HashSet<String>,
+    binary: HashSet<String>,
+}
+
+impl Default for Rules {
+    fn default() -> Rules {
+        Rules {
+            text: TEXT_TYPES.iter().map(|t| t.to_string()).collect(),
+            binary: BINARY_TYPES.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+impl Rules {
+    /// The built-in lists with the types of a rules file moved to the list it gives
+    pub fn from_file(path: &str) -> Result<Rules> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => anyhow::bail!("Could not read the text types {}: {}", path, e),
+        };
+        let file: RulesFile = match serde_json::from_str(&text) {
+            Ok(file) => file,
+            Err(e) => anyhow::bail!("Text types {} are not valid: {}", path, e),
+        };
+        let mut rules = Rules::default();
+        for filetype in file.text.iter().map(|t| t.trim().to_uppercase()) {
+            rules.binary.remove(&filetype);
+            rules.text.insert(filetype);
+        }
+        for filetype in file.binary.iter().map(|t| t.trim().to_uppercase()) {
+            rules.text.remove(&filetype);
+            rules.binary.insert(filetype);
+        }
+        Ok(rules)
+    }
+
+    /// Text by the file type, or by the start of the data for other types
+    pub fn is_text(&self, filetype: &str, data: &[u8]) -> bool {
+        let filetype = filetype.trim().to_uppercase();
+        if self.text.contains(&filetype) {
+            true
+        } else if self.binary.contains(&filetype) {
+            false
+        } else {
+            looks_like_text(&data[..data.len().min(128)])
+        }
+    }
+}
+
+static RULES: RwLock<Option<Rules>> = RwLock::new(None);
+
+/// Where the rules file is looked for when --text-types isn't given
+pub fn default_rules_path() -> Option<std::path::PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("cpm86_tools").join("text-types.json"))
+}
+
+/// Use the rules of `path`, or of the default rules file if there is one
+pub fn load_rules(path: Option<&str>) -> Result<()> {
+    let rules = match path {
+        Some(path) => Rules::from_file(path)?,
+        None => match default_rules_path().filter(|p| p.is_file()) {
+            Some(path) => Rules::from_file(&path.to_string_lossy())?,
+            None => Rules::default(),
+        },
+    };
+    *RULES.write().unwrap() = Some(rules);
+    Ok(())
+}
+
+/// Whether a file of this type starting with `data` is text, by the rules in use
+pub fn is_text(filetype: &str, data: &[u8]) -> bool {
+    match RULES.read().unwrap().as_ref() {
+        Some(rules) => rules.is_text(filetype, data),
+        None => Rules::default().is_text(filetype, data),
+    }
+}
+
+fn is_text_byte(b: u8) -> bool {
+    (0x20..0x7f).contains(&b) || matches!(b, b'\r' | b'\n' | b'\t' | 0x0c)
+}
+
+/// Text if the record is almost all printable up to a ^Z, and not just fill.
+/// Short records need fewer visible characters.
+pub fn looks_like_text(record: &[u8]) -> bool {
+    if record.is_empty() {
+        return false;
+    }
+    let text = &record[..record.iter().position(|&b| b == CTRL_Z).unwrap_or(record.len())];
+    let printable = text.iter().filter(|&&b| is_text_byte(b)).count();
+    let visible = text.iter().filter(|b| b.is_ascii_graphic()).count();
+    printable * 100 >= text.len() * 95 && visible >= 16.min(record.len() / 2) && record.iter().any(|&b| b != record[0])
+}
@@ -0,0 +1,109 @@
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+
+// XMODEM as described in Ward Christensen's original protocol notes,
+// with the CRC-16 extension. Blocks are 128 bytes, the same as a CP/M record,
+// so a file from an image is sent without any extra padding.
+
+const SOH: u8 = 0x01;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_REQUEST: u8 = b'C';
+const PAD: u8 = 0x1a;
+
+const BLOCK_SIZE: usize = 128;
+const MAX_RETRIES: usize = 10;
+// The receiver starts the transfer, give the user time to start it
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+// Read one byte, None on timeout. The port is expected to have a short read timeout.
+fn read_byte(port: &mut dyn ReadWrite, timeout: Duration) -> Result<Option<u8>> {
+    let start = Instant::now();
+    let mut buf = [0u8; 1];
+    while start.elapsed() < timeout {
+        match port.read(&mut buf) {
+            Ok(1) => return Ok(Some(buf[0])),
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(None)
+}
+
+pub trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+/// Send data with XMODEM. The receiver picks checksum (NAK) or CRC ('C') mode.
+/// `progress` is called with the number of bytes acknowledged so far.
+pub fn send(port: &mut dyn ReadWrite, data: &[u8], progress: &mut dyn FnMut(usize)) -> Result<()> {
+    let use_crc = loop {
+        match read_byte(port, START_TIMEOUT)? {
+            Some(NAK) => break false,
+            Some(CRC_REQUEST) => break true,
+            Some(CAN) => anyhow::bail!("Receiver cancelled the transfer"),
+            Some(_) => continue,
+            None => anyhow::bail!("Receiver did not start the transfer within {} seconds", START_TIMEOUT.as_secs()),
+        }
+    };
+
+    for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+        let block_number = ((i + 1) & 0xff) as u8;
+        let mut block = [PAD; BLOCK_SIZE];
+        block[..chunk.len()].copy_from_slice(chunk);
+
+        let mut packet = vec![SOH, block_number, !block_number];
+        packet.extend_from_slice(&block);
+        if use_crc {
+            packet.extend_from_slice(&crc16(&block).to_be_bytes());
+        } else {
+            packet.push(checksum(&block));
+        }
+
+        let mut retries = 0;
+        loop {
+            port.write_all(&packet)?;
+            port.flush()?;
+            match read_byte(port, ACK_TIMEOUT)? {
+                Some(ACK) => break,
+                Some(CAN) => anyhow::bail!("Receiver cancelled the transfer at block {}", i + 1),
+                _ => {
+                    retries += 1;
+                    if retries >= MAX_RETRIES {
+                        port.write_all(&[CAN, CAN])?;
+                        anyhow::bail!("Block {} was not acknowledged after {} tries", i + 1, MAX_RETRIES);
+                    }
+                }
+            }
+        }
+        progress(std::cmp::min(data.len(), (i + 1) * BLOCK_SIZE));
+    }
+
+    for _ in 0..MAX_RETRIES {
+        port.write_all(&[EOT])?;
+        port.flush()?;
+        if read_byte(port, ACK_TIMEOUT)? == Some(ACK) {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("End of transfer was not acknowledged")
+}
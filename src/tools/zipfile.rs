@@ -0,0 +1,348 @@
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use crate::batch::Batch;
+use crate::conflict::{ConflictPolicy, Resolution, Resolver};
+use crate::durable;
+use crate::cpmimg::{self, Attributes, CpmDate, DiskSize, FileInfo, Timestamps};
+use crate::imagefile::ImageOptions;
+use crate::mangle::Mangler;
+
+// Images as zip files, for people without this tool. Every user area is
+// a folder, "0/NAME.TYP". What zip has no place for, the user number,
+// attributes and date stamps, goes in an extra field of our own that
+// other zip tools skip:
+//   user (1 byte), attributes (1 byte: 1 R/O, 2 SYS, 4 archived),
+//   created, modified and accessed stamps, 4 bytes each:
+//   day (2 bytes LE), hour and minute BCD, day 0 when there is no stamp
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const VERSION: u16 = 20;
+// Version made by: Unix, so the external attributes hold a file mode
+const MADE_BY_UNIX: u16 = 3 << 8 | VERSION;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+const CPM_EXTRA_ID: u16 = 0x4d43; // "CM"
+const CPM_EXTRA_SIZE: usize = 2 + 3 * 4;
+
+struct Entry {
+    name: String,
+    extra: Vec<u8>,
+    method: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    time: u16,
+    date: u16,
+    mode: u32,
+    offset: u32,
+}
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+// Seconds since 1970 to MS-DOS time and date, from 1980 on
+fn dos_date_time(seconds: u64) -> (u16, u16) {
+    let (year, month, day) = cpmimg::civil_from_days((seconds / 86400) as i64);
+    let secs = seconds % 86400;
+    if year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let time = (secs / 3600) << 11 | (secs % 3600 / 60) << 5 | ((secs % 60) / 2);
+    let date = ((year - 1980) as u64) << 9 | (month as u64) << 5 | day as u64;
+    (time as u16, date as u16)
+}
+
+fn write_stamp(extra: &mut Vec<u8>, stamp: Option<CpmDate>) {
+    let stamp = stamp.unwrap_or(CpmDate { day: 0, hour: 0, minute: 0 });
+    extra.extend_from_slice(&stamp.day.to_le_bytes());
+    extra.extend_from_slice(&[stamp.hour, stamp.minute]);
+}
+
+fn read_stamp(bytes: &[u8]) -> Option<CpmDate> {
+    let day = u16_at(bytes, 0);
+    (day != 0).then_some(CpmDate { day, hour: bytes[2], minute: bytes[3] })
+}
+
+fn cpm_extra(info: &FileInfo) -> Vec<u8> {
+    let attributes = info.attributes;
+    let flags = attributes.readonly as u8 | (attributes.system as u8) << 1 | (attributes.archived as u8) << 2;
+    let mut extra = Vec::new();
+    extra.extend_from_slice(&CPM_EXTRA_ID.to_le_bytes());
+    extra.extend_from_slice(&(CPM_EXTRA_SIZE as u16).to_le_bytes());
+    extra.extend_from_slice(&[info.user_number, flags]);
+    let timestamps = info.timestamps.unwrap_or_default();
+    for stamp in [timestamps.created, timestamps.modified, timestamps.accessed] {
+        write_stamp(&mut extra, stamp);
+    }
+    extra
+}
+
+/// User number, attributes and stamps from our extra field, if the entry has one
+fn parse_cpm_extra(extra: &[u8]) -> Option<(u8, Attributes, Timestamps)> {
+    let mut pos = 0;
+    while pos + 4 <= extra.len() {
+        let (id, len) = (u16_at(extra, pos), u16_at(extra, pos + 2) as usize);
+        let data = extra.get(pos + 4..pos + 4 + len)?;
+        if id == CPM_EXTRA_ID && len >= CPM_EXTRA_SIZE {
+            let attributes = Attributes { readonly: data[1] & 1 != 0, system: data[1] & 2 != 0, archived: data[1] & 4 != 0 };
+            let timestamps = Timestamps { created: read_stamp(&data[2..6]), modified: read_stamp(&data[6..10]), accessed: read_stamp(&data[10..14]) };
+            return Some((data[0], attributes, timestamps));
+        }
+        pos += 4 + len;
+    }
+    None
+}
+
+fn write_zip(entries: &[(Entry, Vec<u8>)], out: &mut dyn Write) -> Result<()> {
+    let mut offset = 0u32;
+    let mut central = Vec::new();
+    for (entry, data) in entries {
+        let mut local = Vec::new();
+        local.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        for value in [VERSION, 0, entry.method, entry.time, entry.date] {
+            local.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [entry.crc, entry.compressed_size, entry.size] {
+            local.extend_from_slice(&value.to_le_bytes());
+        }
+        local.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        local.extend_from_slice(&(entry.extra.len() as u16).to_le_bytes());
+        local.extend_from_slice(entry.name.as_bytes());
+        local.extend_from_slice(&entry.extra);
+        out.write_all(&local)?;
+        out.write_all(data)?;
+
+        central.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        for value in [MADE_BY_UNIX, VERSION, 0, entry.method, entry.time, entry.date] {
+            central.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [entry.crc, entry.compressed_size, entry.size] {
+            central.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [entry.name.len() as u16, entry.extra.len() as u16, 0, 0, 0] {
+            central.extend_from_slice(&value.to_le_bytes());
+        }
+        central.extend_from_slice(&(entry.mode << 16).to_le_bytes());
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(entry.name.as_bytes());
+        central.extend_from_slice(&entry.extra);
+
+        offset = match u32::try_from(offset as usize + local.len() + data.len()) {
+            Ok(offset) => offset,
+            Err(_) => anyhow::bail!("Zip file would be larger than 4 GB"),
+        };
+    }
+    out.write_all(&central)?;
+
+    let mut end = Vec::new();
+    end.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    for value in [0, 0, entries.len() as u16, entries.len() as u16] {
+        end.extend_from_slice(&value.to_le_bytes());
+    }
+    end.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    end.extend_from_slice(&offset.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    out.write_all(&end)?;
+
+    Ok(())
+}
+
+fn read_zip(zip: &[u8]) -> Result<Vec<Entry>> {
+    // The end record is last, followed only by a comment of at most 64K
+    let end = match (0..zip.len().saturating_sub(21)).rev().take(0x10000 + 22).find(|&pos| u32_at(zip, pos) == END_OF_CENTRAL_DIRECTORY) {
+        Some(end) => end,
+        None => anyhow::bail!("Not a zip file, no end of central directory"),
+    };
+    let count = u16_at(zip, end + 10) as usize;
+    let mut pos = u32_at(zip, end + 16) as usize;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        if pos + 46 > zip.len() || u32_at(zip, pos) != CENTRAL_HEADER {
+            anyhow::bail!("Zip central directory is damaged at offset {:#x}", pos);
+        }
+        let name_len = u16_at(zip, pos + 28) as usize;
+        let extra_len = u16_at(zip, pos + 30) as usize;
+        let comment_len = u16_at(zip, pos + 32) as usize;
+        let name_end = pos + 46 + name_len;
+        let extra_end = name_end + extra_len;
+        if extra_end > zip.len() {
+            anyhow::bail!("Zip central directory is truncated");
+        }
+        entries.push(Entry {
+            name: String::from_utf8_lossy(&zip[pos + 46..name_end]).to_string(),
+            extra: zip[name_end..extra_end].to_vec(),
+            method: u16_at(zip, pos + 10),
+            time: u16_at(zip, pos + 12),
+            date: u16_at(zip, pos + 14),
+            crc: u32_at(zip, pos + 16),
+            compressed_size: u32_at(zip, pos + 20),
+            size: u32_at(zip, pos + 24),
+            mode: u32_at(zip, pos + 38) >> 16,
+            offset: u32_at(zip, pos + 42),
+        });
+        pos = extra_end + comment_len;
+    }
+
+    Ok(entries)
+}
+
+fn entry_data(zip: &[u8], entry: &Entry) -> Result<Vec<u8>> {
+    let pos = entry.offset as usize;
+    if pos + 30 > zip.len() || u32_at(zip, pos) != LOCAL_HEADER {
+        anyhow::bail!("Zip entry {} has no local header", entry.name);
+    }
+    let start = pos + 30 + u16_at(zip, pos + 26) as usize + u16_at(zip, pos + 28) as usize;
+    let compressed = match zip.get(start..start + entry.compressed_size as usize) {
+        Some(compressed) => compressed,
+        None => anyhow::bail!("Zip entry {} is truncated", entry.name),
+    };
+
+    let data = match entry.method {
+        STORED => compressed.to_vec(),
+        DEFLATED => {
+            let mut data = Vec::with_capacity(entry.size as usize);
+            DeflateDecoder::new(compressed).read_to_end(&mut data)?;
+            data
+        }
+        method => anyhow::bail!("Zip entry {} uses compression method {}, only stored and deflated are supported", entry.name, method),
+    };
+    if crc32fast::hash(&data) != entry.crc {
+        anyhow::bail!("Zip entry {} fails its crc check", entry.name);
+    }
+    Ok(data)
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Write every file of the image to a zip file with a folder per user area
+pub fn export_zip(image_path: &str, options: &ImageOptions, zip_path: &str, fail_fast: bool) -> Result<()> {
+    let now = unix_seconds(SystemTime::now());
+    let mut entries = Vec::new();
+    let mut batch = Batch::new(fail_fast);
+    cpmimg::read_each_file(image_path, options, &mut |info, data| {
+        let item = format!("{}:{}.{}", info.user_number, info.filename, info.filetype);
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => return batch.record(&item, Err(e)),
+        };
+        let modified = info.timestamps
+            .and_then(|t| t.modified.or(t.created))
+            .and_then(|d| d.to_system_time())
+            .map_or(now, unix_seconds);
+        let (time, date) = dos_date_time(modified);
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data)?;
+        let compressed = encoder.finish()?;
+
+        let name = match info.filetype.as_str() {
+            "" => format!("{}/{}", info.user_number, info.filename),
+            filetype => format!("{}/{}.{}", info.user_number, info.filename, filetype),
+        };
+        let entry = Entry {
+            name,
+            extra: cpm_extra(&info),
+            method: DEFLATED,
+            crc: crc32fast::hash(&data),
+            compressed_size: compressed.len() as u32,
+            size: data.len() as u32,
+            time,
+            date,
+            mode: if info.attributes.readonly { 0o100444 } else { 0o100644 },
+            offset: 0,
+        };
+        entries.push((entry, compressed));
+        batch.record(&item, Ok(()))
+    })?;
+
+    // The files that could be read are written even if some failed
+    write_zip(&entries, &mut std::fs::File::create(zip_path)?)?;
+    durable::sync_path(zip_path)?;
+    println!("Wrote {} files to {}", batch.succeeded(), zip_path);
+    batch.finish()?;
+
+    Ok(())
+}
+
+/// Copy the files of a zip into the image, which is created if it does
+/// not exist. Folders name the user area, files outside one go to user 0.
+/// Our extra field gives the user and attributes when it is there.
+/// A file that fails doesn't stop the others unless `fail_fast` is set.
+/// Names that aren't 8.3 are made so by `mangler`.
+pub fn import_zip(zip_path: &str, image_path: &str, options: &ImageOptions, fail_fast: bool, on_conflict: ConflictPolicy,
+        mangler: &mut Mangler) -> Result<()> {
+    if !std::path::Path::new(image_path).exists() {
+        cpmimg::create_image(image_path, &DiskSize::K640)?;
+    }
+
+    let zip = std::fs::read(zip_path)?;
+    let mut batch = Batch::new(fail_fast);
+    let mut resolver = Resolver::new(on_conflict);
+    let mut skipped = 0;
+    for entry in read_zip(&zip)? {
+        if entry.name.ends_with('/') {
+            continue;
+        }
+        match import_entry(&zip, &entry, image_path, options, &mut resolver, mangler) {
+            Ok(false) => skipped += 1,
+            result => batch.record(&entry.name, result.map(|_| ()))?,
+        }
+    }
+    mangler.print_renamed();
+    match skipped {
+        0 => println!("Copied {} files into {}", batch.succeeded(), image_path),
+        _ => println!("Copied {} files into {}, skipped {}", batch.succeeded(), image_path, skipped),
+    }
+    batch.finish()?;
+
+    Ok(())
+}
+
+// False if the file was skipped for a name already in the image
+fn import_entry(zip: &[u8], entry: &Entry, image_path: &str, options: &ImageOptions, resolver: &mut Resolver,
+        mangler: &mut Mangler) -> Result<bool> {
+    let (folder, file_name) = entry.name.rsplit_once('/').unwrap_or(("0", &entry.name));
+    let cpm_extra = parse_cpm_extra(&entry.extra);
+    let user = match cpm_extra {
+        Some((user, _, _)) => user,
+        None => match folder.rsplit('/').next().unwrap_or("0").parse::<u8>() {
+            Ok(user) => user,
+            Err(_) => anyhow::bail!("Zip entry {} is not in a user area folder like 0/", entry.name),
+        },
+    };
+    let cpm_file_name = mangler.cpm_name(user, file_name)?;
+
+    let data = entry_data(zip, entry)?;
+    let cpm_file_name = match resolver.resolve(image_path, options, &cpm_file_name)? {
+        Resolution::Store { cpm_file_name, replace } => {
+            cpmimg::store_file(image_path, options, &cpm_file_name, &data, replace, false)?;
+            cpm_file_name
+        }
+        Resolution::Skip => {
+            println!("{} skipped, {} is already in the image", entry.name, cpm_file_name);
+            return Ok(false);
+        }
+    };
+    if let Some((_, attributes, _)) = cpm_extra {
+        cpmimg::set_file_attributes(image_path, options, &cpm_file_name,
+            Some(attributes.readonly), Some(attributes.system), Some(attributes.archived))?;
+    }
+    println!("{} -> {}", entry.name, cpm_file_name);
+
+    Ok(true)
+}